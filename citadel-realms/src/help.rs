@@ -24,6 +24,8 @@ pub fn help_panel(screen: usize) -> impl View {
                 .child(help_item("n", "Create a new realm."))
                 .child(help_item("r", "Restart currently selected realm."))
                 .child(help_item("u", "Open shell to update RealmFS image of selected realm."))
+                .child(help_item("x", "Transfer a file from selected realm to another realm."))
+                .child(help_item("f", "Choose shared folders mounted in selected realm."))
                 .child(help_item(".", "Toggle display of system realms."))
                 .child(DummyView)
         } else {
@@ -32,6 +34,7 @@ pub fn help_panel(screen: usize) -> impl View {
                 .child(DummyView)
                 .child(help_item("n", "Create new RealmFS as fork of selected image."))
                 .child(help_item("u", "Open shell to update selected RealmFS image."))
+                .child(help_item("H", "Browse previous versions of selected RealmFS image and roll back."))
                 .child(help_item(".", "Toggle display of system RealmFS images."))
                 .child(DummyView)
         }
@@ -8,6 +8,9 @@ use crate::realm::config_realm::ConfigDialog;
 use crate::ui::{DeferredAction, GlobalState};
 use crate::realm::delete_realm::DeleteRealmDialog;
 use crate::realm::new_realm::NewRealmDialog;
+use crate::realm::transfer_file::TransferFileDialog;
+use crate::realm::shared_folders::SharedFoldersDialog;
+use crate::realm::unlock_home::UnlockHomeDialog;
 use crate::dialogs::confirm_dialog;
 use crate::item_list::ItemList;
 use crate::notes::NotesDialog;
@@ -68,9 +71,16 @@ impl RealmAction {
     }
 
     fn start_realm() -> EventResult {
-        Self::action(|r| {
-            let manager = r.manager();
-            Self::log_fail("starting realm", || manager.start_realm(r));
+        EventResult::with_cb(|s| {
+            let realm = RealmAction::current_realm(s);
+            if realm.manager().home_needs_unlock(&realm) {
+                UnlockHomeDialog::open(s, realm);
+                return;
+            }
+            RealmAction::new(s, Arc::new(|r: &Realm| {
+                let manager = r.manager();
+                Self::log_fail("starting realm", || manager.start_realm(r));
+            })).run_action();
         })
     }
 
@@ -139,6 +149,20 @@ impl RealmAction {
         })
     }
 
+    pub fn transfer_file() -> EventResult {
+        EventResult::with_cb(move |s| {
+            let realm = RealmAction::current_realm(s);
+            TransferFileDialog::open(s, realm);
+        })
+    }
+
+    pub fn shared_folders() -> EventResult {
+        EventResult::with_cb(move |s| {
+            let realm = RealmAction::current_realm(s);
+            SharedFoldersDialog::open(s, realm);
+        })
+    }
+
     pub fn edit_notes() -> EventResult {
 
         EventResult::with_cb(|s| {
@@ -450,6 +450,7 @@ impl RealmOptions {
             OptionEntry::new("Use Wayland in Realm", |c| &mut c.use_wayland),
             OptionEntry::new("Use X11 in Realm", |c| &mut c.use_x11),
             OptionEntry::new("Use Sound in Realm", |c| &mut c.use_sound),
+            OptionEntry::new("Use Microphone in Realm", |c| &mut c.use_microphone),
             OptionEntry::new("Mount /Shared directory in Realm", |c| &mut c.use_shared_dir),
             OptionEntry::new("Realm has network access", |c| &mut c.use_network),
             OptionEntry::new("Use KVM (/dev/kvm) in Realm", |c| &mut c.use_kvm),
@@ -9,7 +9,7 @@ use cursive::{
 };
 
 
-use libcitadel::{Realm, RealmManager, RealmConfig, RealmFS};
+use libcitadel::{Realm, RealmManager, RealmConfig, RealmFS, PortForward, SharedFolderAccess, RealmQuota};
 
 
 use self::actions::RealmAction;
@@ -20,6 +20,9 @@ mod actions;
 mod new_realm;
 mod delete_realm;
 mod config_realm;
+mod transfer_file;
+mod shared_folders;
+mod unlock_home;
 
 pub struct RealmListContent {
     show_system_realms: bool,
@@ -119,6 +122,8 @@ impl ItemListContent<Realm> for RealmListContent {
             Event::Char('$') => RealmAction::open_shell(false),
             Event::Char('#') => RealmAction::open_shell(true),
             Event::Char('u') => RealmAction::update_realmfs(),
+            Event::Char('x') => RealmAction::transfer_file(),
+            Event::Char('f') => RealmAction::shared_folders(),
             Event::Char('.') => {
                 self.show_system_realms = !self.show_system_realms;
                 EventResult::with_cb(|s| ItemList::<Realm>::call_reload("realms", s))
@@ -145,6 +150,9 @@ impl <'a> RealmInfoRender <'a> {
         let config = self.realm.config();
         self.render_realmfs_info(&config);
         self.render_options(&config);
+        self.render_disk_usage();
+        self.render_shared_folders();
+        self.render_port_forwards();
         self.render_notes();
     }
 
@@ -247,6 +255,7 @@ impl <'a> RealmInfoRender <'a> {
         option("X11", config.use_x11, config.x11());
         option("Wayland", config.use_wayland, config.wayland());
         option("Sound", config.use_sound, config.sound());
+        option("Microphone", config.use_microphone, config.sound() && config.microphone());
         option("GPU", config.use_gpu, config.gpu());
         option("KVM", config.use_kvm, config.kvm());
         option("SharedDir", config.use_shared_dir, config.shared_dir());
@@ -263,6 +272,52 @@ impl <'a> RealmInfoRender <'a> {
         self.newline();
     }
 
+    fn render_disk_usage(&self) {
+        let quota = RealmQuota::new(self.realm);
+        let home = quota.home_usage();
+        let overlay = quota.overlay_usage();
+        if home.is_none() && overlay.is_none() {
+            return;
+        }
+
+        self.heading("Disk Usage").newlines(2);
+        if let Some(usage) = home {
+            self.print("   Home: ").dim_style().println(usage.to_string()).pop();
+        }
+        if let Some(usage) = overlay {
+            self.print("   Overlay: ").dim_style().println(usage.to_string()).pop();
+        }
+        self.newline();
+    }
+
+    fn render_shared_folders(&self) {
+        let folders = SharedFolderAccess::for_realm(self.realm);
+        if folders.is_empty() {
+            return;
+        }
+
+        self.heading("Shared Folders").newlines(2);
+        for access in &folders {
+            let mode = if access.is_read_only() { "read-only" } else { "read-write" };
+            self.print("   ").print(access.folder().name());
+            self.dim_style().println(format!("  ({})", mode)).pop();
+        }
+        self.newline();
+    }
+
+    fn render_port_forwards(&self) {
+        let forwards = PortForward::for_realm(self.realm);
+        if forwards.is_empty() {
+            return;
+        }
+
+        self.heading("Port Forwards").newlines(2);
+        for forward in &forwards {
+            self.print("   ").println(forward.to_string());
+        }
+        self.newline();
+    }
+
     fn render_notes(&self) {
         let notes = match self.realm.notes() {
             Some(notes) => notes,
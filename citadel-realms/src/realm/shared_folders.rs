@@ -0,0 +1,233 @@
+use cursive::views::{ViewBox, SelectView, EditView, Dialog, LinearLayout, TextView, DummyView, PaddedView};
+use cursive::traits::{View,Identifiable,Finder,Scrollable};
+use cursive::view::ViewWrapper;
+use cursive::Cursive;
+use cursive::event::{EventResult, Event};
+use libcitadel::{Realm, SharedFolder, SharedFolderAccess};
+
+use crate::dialogs::{DialogButtonAdapter, FieldDialogBuilder};
+use crate::item_list::ItemList;
+
+#[derive(Clone,Copy,PartialEq)]
+enum Access {
+    None,
+    ReadWrite,
+    ReadOnly,
+}
+
+impl Access {
+    fn next(self) -> Self {
+        match self {
+            Access::None => Access::ReadWrite,
+            Access::ReadWrite => Access::ReadOnly,
+            Access::ReadOnly => Access::None,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Access::None => "[  ]",
+            Access::ReadWrite => "[rw]",
+            Access::ReadOnly => "[ro]",
+        }
+    }
+}
+
+struct FolderEntry {
+    name: String,
+    access: Access,
+    missing: bool,
+}
+
+impl FolderEntry {
+    fn config_value(&self) -> Option<String> {
+        match self.access {
+            Access::None => None,
+            Access::ReadWrite => Some(format!("{}:rw", self.name)),
+            Access::ReadOnly => Some(format!("{}:ro", self.name)),
+        }
+    }
+
+    fn label(&self) -> String {
+        if self.missing {
+            format!("{}  {} (missing)", self.access.label(), self.name)
+        } else {
+            format!("{}  {}", self.access.label(), self.name)
+        }
+    }
+}
+
+pub struct SharedFoldersDialog {
+    realm: Realm,
+    entries: Vec<FolderEntry>,
+    original: Vec<String>,
+    inner: ViewBox,
+}
+
+impl SharedFoldersDialog {
+
+    fn call_dialog<F,R>(s: &mut Cursive, f: F) -> R
+        where F: FnOnce(&mut SharedFoldersDialog) -> R
+    {
+        s.call_on_id("shared-folders-dialog", f)
+            .expect("call_on_id(shared-folders-dialog)")
+    }
+
+    pub fn open(s: &mut Cursive, realm: Realm) {
+        let mut dialog = SharedFoldersDialog::new(realm);
+        dialog.update_list(0);
+        s.add_layer(dialog.with_id("shared-folders-dialog"));
+    }
+
+    fn new(realm: Realm) -> Self {
+        let (entries, original) = Self::load_entries(&realm);
+
+        let text = format!("Select the shared folders to mount in realm-{}.\n\nPress <Enter> to cycle between no access, read-write and read-only access. Folders appear in the realm under ~/Folders.", realm.name());
+        let list = SelectView::<usize>::new()
+            .on_submit(|s, idx| {
+                let idx = *idx;
+                Self::call_dialog(s, |d| d.cycle_access(idx));
+            })
+            .with_id("shared-folders-list")
+            .scrollable();
+
+        let content = LinearLayout::vertical()
+            .child(TextView::new(text))
+            .child(DummyView)
+            .child(list);
+
+        let dialog = Dialog::around(PaddedView::new((2,2,1,1), content))
+            .title("Shared Folders")
+            .button("New Folder", Self::open_new_folder)
+            .button("Apply", |s| {
+                Self::call_dialog(s, |d| d.apply_changes());
+                ItemList::<Realm>::call_update_info("realms", s);
+                s.pop_layer();
+            })
+            .dismiss_button("Cancel")
+            .with_id("shared-folders-dialog-inner");
+
+        SharedFoldersDialog { realm, entries, original, inner: ViewBox::boxed(dialog) }
+    }
+
+    fn load_entries(realm: &Realm) -> (Vec<FolderEntry>, Vec<String>) {
+        let configured = SharedFolderAccess::for_realm(realm);
+        let original = configured.iter().map(|a| a.to_string()).collect();
+
+        let access_for = |name: &str| configured.iter()
+            .find(|a| a.folder().name() == name)
+            .map(|a| if a.is_read_only() { Access::ReadOnly } else { Access::ReadWrite })
+            .unwrap_or(Access::None);
+
+        let mut entries = SharedFolder::list().into_iter()
+            .map(|f| FolderEntry { access: access_for(f.name()), name: f.name().to_string(), missing: false })
+            .collect::<Vec<_>>();
+
+        // Keep memberships of folders which have been removed so they are not silently dropped
+        for access in &configured {
+            if !access.folder().exists() {
+                entries.push(FolderEntry { access: access_for(access.folder().name()), name: access.folder().name().to_string(), missing: true });
+            }
+        }
+        (entries, original)
+    }
+
+    fn update_list(&mut self, selection: usize) {
+        let labels = self.entries.iter().map(|e| e.label()).collect::<Vec<_>>();
+        self.call_on_id("shared-folders-list", |v: &mut SelectView<usize>| {
+            v.clear();
+            for (idx, label) in labels.into_iter().enumerate() {
+                v.add_item(label, idx);
+            }
+            if selection < v.len() {
+                v.set_selection(selection);
+            }
+        });
+    }
+
+    fn cycle_access(&mut self, idx: usize) {
+        if let Some(entry) = self.entries.get_mut(idx) {
+            entry.access = entry.access.next();
+        }
+        self.update_list(idx);
+    }
+
+    fn add_folder(&mut self, name: &str) {
+        self.entries.push(FolderEntry { name: name.to_string(), access: Access::ReadWrite, missing: false });
+        self.entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let idx = self.entries.iter().position(|e| e.name == name).unwrap_or(0);
+        self.update_list(idx);
+    }
+
+    fn apply_changes(&mut self) {
+        let folders = self.entries.iter()
+            .filter_map(|e| e.config_value())
+            .collect::<Vec<_>>();
+
+        if folders == self.original {
+            return;
+        }
+
+        self.realm.with_mut_config(|c| c.shared_folders = Some(folders));
+        let path = self.realm.base_path_file("config");
+        if let Err(e) = self.realm.config().write_to(&path) {
+            warn!("Error writing config file {}: {}", path.display(), e);
+        }
+    }
+
+    fn open_new_folder(s: &mut Cursive) {
+        let dialog = FieldDialogBuilder::new(&["Name"], "Create a new shared folder in /storage/realms/shared")
+            .title("New Shared Folder")
+            .id("new-shared-folder")
+            .edit_view("new-shared-folder-name", 24)
+            .build(Self::handle_new_folder);
+        s.add_layer(dialog);
+    }
+
+    fn handle_new_folder(s: &mut Cursive) {
+        let name = s.call_on_id("new-shared-folder-name", |v: &mut EditView| v.get_content())
+            .expect("call_on_id(new-shared-folder-name)");
+
+        if !SharedFolder::is_valid_name(&name) {
+            s.add_layer(Dialog::info("Folder names must start with a letter and contain only letters, numbers and dashes."));
+            return;
+        }
+
+        match SharedFolder::create(&name) {
+            Ok(_) => {
+                s.pop_layer();
+                Self::call_dialog(s, |d| d.add_folder(&name));
+            },
+            Err(e) => {
+                warn!("error creating shared folder: {}", e);
+                s.add_layer(Dialog::info(format!("Failed to create shared folder: {}", e)));
+            }
+        }
+    }
+}
+
+impl DialogButtonAdapter for SharedFoldersDialog {
+    fn inner_id(&self) -> &'static str {
+        "shared-folders-dialog-inner"
+    }
+}
+
+impl ViewWrapper for SharedFoldersDialog {
+    type V = dyn View;
+
+    fn with_view<F, R>(&self, f: F) -> Option<R>
+        where F: FnOnce(&Self::V) -> R
+    {
+        Some(f(&*self.inner))
+    }
+
+    fn with_view_mut<F, R>(&mut self, f: F) -> Option<R>
+        where F: FnOnce(&mut Self::V) -> R
+    {
+        Some(f(&mut *self.inner))
+    }
+
+    fn wrap_on_event(&mut self, event: Event) -> EventResult {
+        self.handle_event("nac", event)
+    }
+}
@@ -0,0 +1,111 @@
+use cursive::views::{ViewBox, SelectView, EditView, ViewRef, Dialog};
+use cursive::traits::{View,Identifiable,Finder};
+use cursive::view::ViewWrapper;
+use cursive::Cursive;
+use cursive::event::{EventResult, Event};
+use libcitadel::Realm;
+
+use crate::dialogs::{DialogButtonAdapter, FieldDialogBuilder};
+
+pub struct TransferFileDialog {
+    realm: Realm,
+    inner: ViewBox,
+}
+
+impl TransferFileDialog {
+
+    fn get_dialog(s: &mut Cursive) -> ViewRef<TransferFileDialog> {
+        s.find_id::<TransferFileDialog>("transfer-file-dialog")
+            .expect("could not find TransferFileDialog instance")
+    }
+
+    pub fn open(s: &mut Cursive, realm: Realm) {
+        let targets = realm.manager().realm_list()
+            .into_iter()
+            .filter(|r| r.name() != realm.name() && !r.is_system())
+            .collect::<Vec<_>>();
+
+        if targets.is_empty() {
+            s.add_layer(Dialog::info("There are no other realms to transfer files to.").title("Transfer File"));
+            return;
+        }
+
+        let dialog = TransferFileDialog::new(realm, targets);
+        s.add_layer(dialog.with_id("transfer-file-dialog"));
+    }
+
+    fn new(realm: Realm, targets: Vec<Realm>) -> Self {
+        let text = format!("Copy a file from the home directory of realm-{} to the Inbox directory of another realm.", realm.name());
+        let dialog = FieldDialogBuilder::new(&["File", "Destination"], &text)
+            .title("Transfer File")
+            .id("transfer-file-dialog-inner")
+            .edit_view("transfer-file-path", 32)
+            .field(Self::create_target_select(targets))
+            .build(Self::handle_ok);
+
+        TransferFileDialog { realm, inner: ViewBox::boxed(dialog) }
+    }
+
+    fn create_target_select(targets: Vec<Realm>) -> impl View {
+        let mut select = SelectView::new().popup();
+        for realm in targets {
+            select.add_item(format!("realm-{}", realm.name()), realm);
+        }
+        select.with_id("transfer-file-target")
+    }
+
+    fn handle_ok(s: &mut Cursive) {
+        let mut dialog = Self::get_dialog(s);
+        let path = dialog.call_id("transfer-file-path", |v: &mut EditView| v.get_content());
+        if path.is_empty() {
+            s.add_layer(Dialog::info("Enter the path of a file relative to the realm home directory."));
+            return;
+        }
+        let target = dialog.call_id("transfer-file-target", |v: &mut SelectView<Realm>| v.selection())
+            .expect("transfer target selection list was empty");
+
+        let source = dialog.realm.clone();
+        s.pop_layer();
+
+        let msg = match source.manager().transfer_file(&source, &target, path.as_str()) {
+            Ok(record) => format!("Copied {} to realm-{} as {}", record.source_path, record.target, record.target_path),
+            Err(e) => {
+                warn!("error transferring file: {}", e);
+                format!("Failed to transfer file: {}", e)
+            }
+        };
+        s.add_layer(Dialog::info(msg).title("Transfer File"));
+    }
+
+    fn call_id<V: View, F: FnOnce(&mut V) -> R, R>(&mut self, id: &str, callback: F) -> R
+    {
+        self.call_on_id(id, callback)
+            .unwrap_or_else(|| panic!("failed call_on_id({})", id))
+    }
+}
+
+impl DialogButtonAdapter for TransferFileDialog {
+    fn inner_id(&self) -> &'static str {
+        "transfer-file-dialog-inner"
+    }
+}
+
+impl ViewWrapper for TransferFileDialog {
+    type V = dyn View;
+
+    fn with_view<F, R>(&self, f: F) -> Option<R>
+        where F: FnOnce(&Self::V) -> R
+    {
+        Some(f(&*self.inner))
+    }
+
+    fn with_view_mut<F, R>(&mut self, f: F) -> Option<R>
+        where F: FnOnce(&mut Self::V) -> R
+    {
+        Some(f(&mut *self.inner))
+    }
+
+    fn wrap_on_event(&mut self, event: Event) -> EventResult {
+        self.handle_event("co", event)
+    }
+}
@@ -0,0 +1,58 @@
+use std::thread;
+
+use cursive::views::{EditView, Dialog};
+use cursive::traits::{Boxable,Identifiable};
+use cursive::theme::ColorStyle;
+use cursive::Cursive;
+use libcitadel::Realm;
+
+use crate::dialogs::FieldDialogBuilder;
+use crate::item_list::ItemList;
+
+pub struct UnlockHomeDialog;
+
+impl UnlockHomeDialog {
+
+    /// Ask for the passphrase of the encrypted home directory of `realm`, then unlock
+    /// the home directory and start the realm.
+    pub fn open(s: &mut Cursive, realm: Realm) {
+        let text = format!("The home directory of realm-{} is encrypted. Enter the passphrase to unlock it and start the realm.", realm.name());
+        let passphrase = EditView::new()
+            .secret()
+            .style(ColorStyle::tertiary())
+            .filler(" ")
+            .with_id("unlock-home-passphrase")
+            .fixed_width(32);
+
+        let dialog = FieldDialogBuilder::new(&["Passphrase"], &text)
+            .title("Unlock Home")
+            .id("unlock-home-dialog")
+            .field(passphrase)
+            .build(move |s| Self::handle_ok(s, realm.clone()));
+        s.add_layer(dialog);
+    }
+
+    fn handle_ok(s: &mut Cursive, realm: Realm) {
+        let passphrase = s.call_on_id("unlock-home-passphrase", |v: &mut EditView| v.get_content().to_string())
+            .expect("call_on_id(unlock-home-passphrase)");
+        if passphrase.is_empty() {
+            return;
+        }
+        s.pop_layer();
+
+        let sink = s.cb_sink().clone();
+        thread::spawn(move || {
+            let manager = realm.manager();
+            let result = manager.unlock_home(&realm, &passphrase)
+                .and_then(|_| manager.start_realm(&realm));
+
+            sink.send(Box::new(move |s: &mut Cursive| {
+                if let Err(e) = result {
+                    warn!("error starting realm-{} with encrypted home: {}", realm.name(), e);
+                    s.add_layer(Dialog::info(format!("Failed to start realm-{}: {}", realm.name(), e)).title("Unlock Home"));
+                }
+                ItemList::<Realm>::call_reload("realms", s);
+            })).unwrap();
+        });
+    }
+}
@@ -9,6 +9,7 @@ use crate::ui::{DeferredAction, GlobalState};
 use cursive::views::Dialog;
 use crate::item_list::ItemList;
 use crate::realmfs::fork_dialog::ForkDialog;
+use crate::realmfs::history_dialog::HistoryDialog;
 use crate::notes::NotesDialog;
 
 type ActionCallback = dyn Fn(&RealmFS)+Send+Sync;
@@ -97,6 +98,13 @@ impl RealmFSAction {
         })
     }
 
+    pub fn realmfs_history() -> EventResult {
+        EventResult::with_cb(move |s| {
+            let realmfs = RealmFSAction::current_realmfs(s);
+            HistoryDialog::open(s, realmfs);
+        })
+    }
+
     pub fn update_realmfs() -> EventResult {
         EventResult::with_cb(move |s| {
             let realmfs = Self::current_realmfs(s);
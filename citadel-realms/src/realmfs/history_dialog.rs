@@ -0,0 +1,82 @@
+use libcitadel::{RealmFS, SignatureStatus};
+use cursive::Cursive;
+use cursive::traits::{Boxable, Identifiable};
+use cursive::views::{Dialog, LinearLayout, PaddedView, SelectView, TextView, DummyView};
+
+use crate::dialogs::confirm_dialog;
+use crate::item_list::ItemList;
+
+///
+/// Lists the backup copies of previous versions of a RealmFS image and allows
+/// one of them to be swapped back into place.
+///
+pub struct HistoryDialog;
+
+impl HistoryDialog {
+
+    pub fn open(s: &mut Cursive, realmfs: RealmFS) {
+        let history = realmfs.history();
+        if history.is_empty() {
+            let msg = format!("There are no backup copies of {}-realmfs.img. A backup is kept each time the image is updated.", realmfs.name());
+            s.add_layer(Dialog::info(msg).title("RealmFS History"));
+            return;
+        }
+
+        let mut select = SelectView::<usize>::new();
+        for backup in &history {
+            select.add_item(backup.to_string(), backup.index());
+        }
+        let select = select.on_submit({
+            let realmfs = realmfs.clone();
+            move |s, index| Self::confirm_rollback(s, realmfs.clone(), *index)
+        });
+
+        let text = format!("Previous versions of {}-realmfs.img, most recent first. Rolling back swaps the selected version with the current image.", realmfs.name());
+        let content = LinearLayout::vertical()
+            .child(TextView::new(text))
+            .child(DummyView)
+            .child(select.with_id("realmfs-history-select"));
+
+        let dialog = Dialog::around(PaddedView::new((2,2,1,0), content))
+            .title("RealmFS History")
+            .button("Roll Back", move |s| {
+                let selected = s.call_on_id("realmfs-history-select", |v: &mut SelectView<usize>| v.selection())
+                    .and_then(|sel| sel);
+                if let Some(index) = selected {
+                    Self::confirm_rollback(s, realmfs.clone(), *index);
+                }
+            })
+            .dismiss_button("Cancel")
+            .max_width(100);
+
+        s.add_layer(dialog);
+    }
+
+    fn confirm_rollback(s: &mut Cursive, realmfs: RealmFS, index: usize) {
+        let backup = match realmfs.history().into_iter().find(|b| b.index() == index) {
+            Some(backup) => backup,
+            None => return,
+        };
+        if backup.signature_status() != SignatureStatus::Valid {
+            let msg = format!("Cannot roll back to this version because the image signature is {}.", backup.signature_status());
+            s.add_layer(Dialog::info(msg).title("Cannot Roll Back"));
+            return;
+        }
+        if realmfs.is_in_use() {
+            s.add_layer(Dialog::info("RealmFS is in use and cannot be rolled back. Stop the realms using it first.").title("Cannot Roll Back"));
+            return;
+        }
+
+        let msg = format!("Replace {}-realmfs.img with the version from {}?", realmfs.name(), backup.timestamp());
+        let dialog = confirm_dialog("Roll Back RealmFS?", &msg, move |s| {
+            s.pop_layer();
+            if let Err(e) = realmfs.rollback(index) {
+                let msg = format!("Failed to roll back RealmFS '{}': {}", realmfs.name(), e);
+                warn!("{}", msg);
+                s.add_layer(Dialog::info(msg));
+            }
+            ItemList::<RealmFS>::call_reload("realmfs", s);
+        });
+        s.add_layer(dialog);
+    }
+}
@@ -8,6 +8,7 @@ use cursive::theme::{PaletteColor, ColorStyle, Style, Effect};
 
 mod actions;
 mod fork_dialog;
+mod history_dialog;
 pub use self::actions::RealmFSAction;
 
 pub struct RealmFSListContent {
@@ -97,6 +98,7 @@ impl ItemListContent<RealmFS> for RealmFSListContent {
             Event::Char('u') => RealmFSAction::update_realmfs(),
             Event::Char('n') => RealmFSAction::fork_realmfs(),
             Event::Char('e') => RealmFSAction::edit_notes(),
+            Event::Char('H') => RealmFSAction::realmfs_history(),
             Event::Char('.') => {
                 self.show_system = !self.show_system;
                 EventResult::with_cb(|s| ItemList::<RealmFS>::call_reload("realmfs", s))
@@ -0,0 +1,70 @@
+use std::process::exit;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use clap::AppSettings::*;
+use libcitadel::{AuditLog, Result};
+
+pub fn main(args: Vec<String>) {
+    let app = App::new("citadel-audit")
+        .about("Display and verify the Citadel audit log")
+        .settings(&[ArgRequiredElseHelp, ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder, VersionlessSubcommands])
+
+        .subcommand(SubCommand::with_name("show")
+            .about("Display entries in the audit log")
+            .arg(Arg::with_name("category")
+                .long("category")
+                .takes_value(true)
+                .help("Only display entries in this category (realm, config, realmfs, transfer, update, keys, backup)"))
+            .arg(Arg::with_name("last")
+                .long("last")
+                .takes_value(true)
+                .help("Only display the last N entries")))
+
+        .subcommand(SubCommand::with_name("verify")
+            .about("Verify the hash chain of the audit log"));
+
+    let matches = app.get_matches_from(args);
+    let log = AuditLog::system();
+
+    let result = match matches.subcommand() {
+        ("show", Some(m)) => show(&log, m),
+        ("verify", Some(_)) => verify(&log),
+        _ => Ok(()),
+    };
+
+    if let Err(ref e) = result {
+        eprintln!("Error: {}", e);
+        exit(1);
+    }
+}
+
+fn show(log: &AuditLog, matches: &ArgMatches) -> Result<()> {
+    let mut entries = log.entries()?;
+    if let Some(category) = matches.value_of("category") {
+        entries.retain(|e| e.category == category);
+    }
+    if let Some(last) = matches.value_of("last") {
+        let last = last.parse::<usize>()
+            .map_err(|_| format_err!("Invalid value for --last: {}", last))?;
+        if entries.len() > last {
+            entries.drain(..entries.len() - last);
+        }
+    }
+    for entry in &entries {
+        println!("{}", entry);
+    }
+    Ok(())
+}
+
+fn verify(log: &AuditLog) -> Result<()> {
+    let count = log.verify()?;
+    let last_hash = log.entries()?
+        .last()
+        .map(|e| e.hash().to_string())
+        .unwrap_or_default();
+    println!("Audit log {} is intact ({} entries)", log.path().display(), count);
+    if !last_hash.is_empty() {
+        println!("Last entry hash: {}", last_hash);
+    }
+    Ok(())
+}
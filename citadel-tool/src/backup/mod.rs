@@ -0,0 +1,254 @@
+use std::process::exit;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use clap::AppSettings::*;
+use libcitadel::{Backup, BackupRepository, ItemKind, Logger, LogLevel, RealmManager, Restore, Result, Retention};
+use libcitadel::util::is_euid_root;
+
+pub fn main(args: Vec<String>) {
+    Logger::set_log_level(LogLevel::Info);
+
+    let repository_arg = || Arg::with_name("repository")
+        .help("Path to backup repository directory")
+        .required(true);
+
+    let retention_args = || vec![
+        Arg::with_name("keep-last")
+            .long("keep-last")
+            .takes_value(true)
+            .help("Number of most recent snapshots to keep"),
+        Arg::with_name("keep-daily")
+            .long("keep-daily")
+            .takes_value(true)
+            .help("Number of days to keep the last snapshot of"),
+        Arg::with_name("keep-weekly")
+            .long("keep-weekly")
+            .takes_value(true)
+            .help("Number of weeks to keep the last snapshot of"),
+    ];
+
+    let app = App::new("citadel-backup")
+        .about("Create and restore encrypted backups of realms and RealmFS images")
+        .settings(&[ArgRequiredElseHelp, ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder, VersionlessSubcommands])
+
+        .subcommand(SubCommand::with_name("init")
+            .about("Create a new backup repository in an empty directory")
+            .arg(repository_arg())
+            .args(&retention_args()))
+
+        .subcommand(SubCommand::with_name("create")
+            .about("Back up realms, user RealmFS images and the keyring, then prune old snapshots")
+            .arg(repository_arg())
+            .arg(Arg::with_name("no-prune")
+                .long("no-prune")
+                .help("Do not remove old snapshots after creating the backup")))
+
+        .subcommand(SubCommand::with_name("list")
+            .about("List the snapshots in a backup repository")
+            .arg(repository_arg()))
+
+        .subcommand(SubCommand::with_name("show")
+            .about("List the items in a snapshot")
+            .arg(repository_arg())
+            .arg(Arg::with_name("snapshot")
+                .help("Id of snapshot")
+                .required(true)))
+
+        .subcommand(SubCommand::with_name("verify")
+            .about("Decrypt and check the integrity of every object in a backup repository")
+            .arg(repository_arg())
+            .arg(Arg::with_name("snapshot")
+                .help("Only verify the objects of this snapshot")))
+
+        .subcommand(SubCommand::with_name("prune")
+            .about("Remove snapshots which are not kept by the retention rules of the repository")
+            .arg(repository_arg())
+            .args(&retention_args()))
+
+        .subcommand(SubCommand::with_name("restore")
+            .about("Restore an item from a snapshot")
+            .arg(repository_arg())
+            .arg(Arg::with_name("snapshot")
+                .help("Id of snapshot to restore from")
+                .required(true))
+            .arg(Arg::with_name("item")
+                .help("Name of item to restore such as realm-main/home or realmfs/main")
+                .required(true))
+            .arg(Arg::with_name("path")
+                .long("path")
+                .takes_value(true)
+                .help("Only restore this file or directory relative to the item"))
+            .arg(Arg::with_name("target")
+                .long("target")
+                .takes_value(true)
+                .help("Restore to this path instead of the original location")));
+
+    let matches = app.get_matches_from(args);
+
+    let result = match matches.subcommand() {
+        ("init", Some(m)) => init(m),
+        ("create", Some(m)) => create(m),
+        ("list", Some(m)) => list(m),
+        ("show", Some(m)) => show(m),
+        ("verify", Some(m)) => verify(m),
+        ("prune", Some(m)) => prune(m),
+        ("restore", Some(m)) => restore(m),
+        _ => Ok(()),
+    };
+
+    if let Err(ref e) = result {
+        eprintln!("Error: {}", e);
+        exit(1);
+    }
+}
+
+fn require_root() -> Result<()> {
+    if !is_euid_root() {
+        bail!("This command must be run as root");
+    }
+    Ok(())
+}
+
+fn repository(matches: &ArgMatches) -> Result<BackupRepository> {
+    let path = matches.value_of("repository")
+        .ok_or_else(|| format_err!("repository argument required"))?;
+    let passphrase = rpassword::read_password_from_tty(Some("Backup passphrase: "))
+        .map_err(context!("error reading passphrase"))?;
+    BackupRepository::open(path, &passphrase)
+}
+
+fn retention(matches: &ArgMatches, mut retention: Retention) -> Result<Retention> {
+    let parse = |name: &str, value: &mut usize| -> Result<()> {
+        if let Some(s) = matches.value_of(name) {
+            *value = s.parse()
+                .map_err(|_| format_err!("Invalid value for --{}: {}", name, s))?;
+        }
+        Ok(())
+    };
+    parse("keep-last", &mut retention.keep_last)?;
+    parse("keep-daily", &mut retention.keep_daily)?;
+    parse("keep-weekly", &mut retention.keep_weekly)?;
+    Ok(retention)
+}
+
+fn init(matches: &ArgMatches) -> Result<()> {
+    let path = matches.value_of("repository")
+        .ok_or_else(|| format_err!("repository argument required"))?;
+    let retention = retention(matches, Retention::default())?;
+
+    let passphrase = rpassword::read_password_from_tty(Some("New backup passphrase: "))
+        .map_err(context!("error reading passphrase"))?;
+    let confirm = rpassword::read_password_from_tty(Some("Confirm passphrase: "))
+        .map_err(context!("error reading passphrase"))?;
+    if passphrase != confirm {
+        bail!("Passphrases do not match");
+    }
+
+    let repo = BackupRepository::init(path, &passphrase, retention)?;
+    println!("Created backup repository {}", repo.path().display());
+    Ok(())
+}
+
+fn create(matches: &ArgMatches) -> Result<()> {
+    require_root()?;
+    let repo = repository(matches)?;
+    let snapshot = Backup::new(&repo)?.run()?;
+    println!("Created snapshot {}", snapshot);
+    if !matches.is_present("no-prune") {
+        let _lock = repo.lock()?;
+        for id in repo.prune()? {
+            println!("Removed snapshot {}", id);
+        }
+    }
+    Ok(())
+}
+
+fn list(matches: &ArgMatches) -> Result<()> {
+    let repo = repository(matches)?;
+    for snapshot in repo.snapshots()? {
+        println!("{}", snapshot);
+    }
+    Ok(())
+}
+
+fn show(matches: &ArgMatches) -> Result<()> {
+    let repo = repository(matches)?;
+    let id = matches.value_of("snapshot")
+        .ok_or_else(|| format_err!("snapshot argument required"))?;
+    let snapshot = repo.load_snapshot(id)?;
+    println!("{}", snapshot);
+    println!();
+    for item in snapshot.items() {
+        match item.kind {
+            ItemKind::Files => println!("  {:<32} {:>6} files  {:>12} bytes  {}", item.name, item.files.len(), item.size(), item.source.display()),
+            ItemKind::Btrfs => println!("  {:<32} {:>6} btrfs streams        {}", item.name, item.streams.len(), item.source.display()),
+        }
+    }
+    Ok(())
+}
+
+fn verify(matches: &ArgMatches) -> Result<()> {
+    let repo = repository(matches)?;
+    let report = repo.verify(matches.value_of("snapshot"))?;
+    for error in &report.errors {
+        println!("{}", error);
+    }
+    if !report.errors.is_empty() {
+        bail!("{} of {} objects failed verification", report.errors.len(), report.objects);
+    }
+    println!("Verified {} objects in {} snapshots", report.objects, report.snapshots);
+    Ok(())
+}
+
+fn prune(matches: &ArgMatches) -> Result<()> {
+    let mut repo = repository(matches)?;
+    let retention = retention(matches, repo.retention())?;
+    let _lock = repo.lock()?;
+    repo.set_retention(retention)?;
+    let removed = repo.prune()?;
+    for id in &removed {
+        println!("Removed snapshot {}", id);
+    }
+    println!("{} snapshots removed", removed.len());
+    Ok(())
+}
+
+fn restore(matches: &ArgMatches) -> Result<()> {
+    require_root()?;
+    let snapshot = matches.value_of("snapshot")
+        .ok_or_else(|| format_err!("snapshot argument required"))?;
+    let item = matches.value_of("item")
+        .ok_or_else(|| format_err!("item argument required"))?;
+
+    if matches.value_of("target").is_none() {
+        check_realm_stopped(item)?;
+    }
+
+    let repo = repository(matches)?;
+    let _lock = repo.lock()?;
+    let mut restore = Restore::new(&repo, snapshot, item)?;
+    if let Some(path) = matches.value_of("path") {
+        restore = restore.path(path);
+    }
+    if let Some(target) = matches.value_of("target") {
+        restore = restore.target(target);
+    }
+    restore.run()?;
+    println!("Restored {}", item);
+    Ok(())
+}
+
+/// Refuse to restore files of a realm over its original location while it is running.
+fn check_realm_stopped(item: &str) -> Result<()> {
+    let name = match item.split('/').next().and_then(|s| s.strip_prefix("realm-")) {
+        Some(name) => name,
+        None => return Ok(()),
+    };
+    let manager = RealmManager::load()?;
+    if let Some(realm) = manager.realm_by_name(name) {
+        if realm.is_active() {
+            bail!("Realm-{} must be stopped before restoring {}", name, item);
+        }
+    }
+    Ok(())
+}
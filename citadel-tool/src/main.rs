@@ -8,14 +8,18 @@ use std::ffi::OsStr;
 use std::iter;
 use libcitadel::RealmManager;
 
+mod audit;
+mod backup;
 mod boot;
 mod image;
 mod install;
 mod install_backend;
 mod mkimage;
+mod realmctl;
 mod realmfs;
 mod sync;
 mod update;
+mod verify;
 
 fn main() {
     let exe = match env::current_exe() {
@@ -37,10 +41,14 @@ fn main() {
         image::main(args);
     } else if exe == Path::new("/usr/bin/citadel-realmfs") {
         realmfs::main(args);
+    } else if exe == Path::new("/usr/bin/realmctl") {
+        realmctl::main(args);
     } else if exe == Path::new("/usr/bin/citadel-update") {
         update::main(args);
     } else if exe == Path::new("/usr/libexec/citadel-desktop-sync") {
         sync::main(args);
+    } else if exe == Path::new("/usr/libexec/citadel-verify-all") {
+        verify::main(args);
     } else if exe == Path::new("/usr/libexec/citadel-run") {
         do_citadel_run(args);
     } else if exe.file_name() == Some(OsStr::new("citadel-mkimage")) {
@@ -55,14 +63,18 @@ fn main() {
 fn dispatch_command(args: Vec<String>) {
     if let Some(command) = args.get(1) {
         match command.as_str() {
+            "audit" => audit::main(rebuild_args("citadel-audit", args)),
+            "backup" => backup::main(rebuild_args("citadel-backup", args)),
             "boot" => boot::main(rebuild_args("citadel-boot", args)),
             "install" => install::main(rebuild_args("citadel-install", args)),
             "image" => image::main(rebuild_args("citadel-image", args)),
             "realmfs" => realmfs::main(rebuild_args("citadel-realmfs", args)),
+            "realmctl" => realmctl::main(rebuild_args("realmctl", args)),
             "update" => update::main(rebuild_args("citadel-update", args)),
             "mkimage" => mkimage::main(rebuild_args("citadel-mkimage", args)),
             "sync" => sync::main(rebuild_args("citadel-desktop-sync", args)),
             "run" => do_citadel_run(rebuild_args("citadel-run", args)),
+            "verify-all" => verify::main(rebuild_args("citadel-verify-all", args)),
             _ => println!("Error: unknown command {}", command),
         }
     } else {
@@ -77,8 +89,24 @@ fn rebuild_args(command: &str, args: Vec<String>) -> Vec<String> {
 }
 
 fn do_citadel_run(args: Vec<String>) {
+    if args.get(1).map(|s| s.as_str()) == Some("--disposable") {
+        do_citadel_run_disposable(&args[2..]);
+        return;
+    }
     if let Err(e) = RealmManager::run_in_current(&args[1..], true) {
         println!("RealmManager::run_in_current({:?}) failed: {}", &args[1..], e);
     }
 }
 
+fn do_citadel_run_disposable(args: &[String]) {
+    if args.is_empty() {
+        println!("Usage: citadel-run --disposable COMMAND [ARGS...]");
+        return;
+    }
+    let result = RealmManager::load()
+        .and_then(|manager| manager.run_disposable(None, args));
+
+    if let Err(e) = result {
+        println!("RealmManager::run_disposable({:?}) failed: {}", args, e);
+    }
+}
@@ -0,0 +1,473 @@
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use clap::AppSettings::*;
+use dbus::arg::{AppendAll, ReadAll};
+use dbus::blocking::{Connection, Proxy};
+use dbus::message::{MatchRule, MessageType};
+use dbus::Message;
+use serde_json::{json, Map, Value};
+
+use libcitadel::Result;
+
+const BUS_NAME: &str = "com.subgraph.realms";
+const OBJECT_PATH: &str = "/com/subgraph/realms";
+const INTERFACE_NAME: &str = "com.subgraph.realms.Manager";
+
+const CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+const STATUS_REALM_RUNNING: u8 = 1;
+const STATUS_REALM_CURRENT: u8 = 2;
+const STATUS_REALM_SYSTEM_REALM: u8  = 4;
+
+pub fn main(args: Vec<String>) {
+    let app = App::new("realmctl")
+        .about("Manage realms through the realmsd service")
+        .settings(&[ArgRequiredElseHelp, ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder, VersionlessSubcommands])
+
+        .arg(Arg::with_name("json")
+            .long("json")
+            .global(true)
+            .help("Print output as JSON"))
+
+        .subcommand(SubCommand::with_name("list")
+            .about("List all realms"))
+
+        .subcommand(SubCommand::with_name("start")
+            .about("Start a realm")
+            .arg(Arg::with_name("realm")
+                .help("Name of realm to start")
+                .required(true)))
+
+        .subcommand(SubCommand::with_name("stop")
+            .about("Stop a running realm")
+            .arg(Arg::with_name("realm")
+                .help("Name of realm to stop")
+                .required(true)))
+
+        .subcommand(SubCommand::with_name("restart")
+            .about("Restart a running realm")
+            .arg(Arg::with_name("realm")
+                .help("Name of realm to restart")
+                .required(true)))
+
+        .subcommand(SubCommand::with_name("unlock")
+            .about("Unlock the encrypted home directory of a realm so that it can be started")
+            .arg(Arg::with_name("realm")
+                .help("Name of realm to unlock")
+                .required(true)))
+
+        .subcommand(SubCommand::with_name("encrypt-home")
+            .about("Move the home directory of a stopped realm into a new encrypted image")
+            .arg(Arg::with_name("size")
+                .long("size")
+                .takes_value(true)
+                .required(true)
+                .help("Size of the encrypted image in megabytes"))
+            .arg(Arg::with_name("realm")
+                .help("Name of realm")
+                .required(true)))
+
+        .subcommand(SubCommand::with_name("run")
+            .about("Run a command in a realm, starting the realm if necessary")
+            .setting(TrailingVarArg)
+            .arg(Arg::with_name("realm")
+                .help("Name of realm to run command in")
+                .required(true))
+            .arg(Arg::with_name("command")
+                .help("Command and arguments to run")
+                .required(true)
+                .multiple(true)))
+
+        .subcommand(SubCommand::with_name("disposable")
+            .about("Run a command in a new disposable realm which is deleted when the command exits")
+            .setting(TrailingVarArg)
+            .arg(Arg::with_name("base")
+                .long("base")
+                .takes_value(true)
+                .help("Name of realm to copy configuration from"))
+            .arg(Arg::with_name("command")
+                .help("Command and arguments to run")
+                .required(true)
+                .multiple(true)))
+
+        .subcommand(SubCommand::with_name("config")
+            .about("Display or change realm configuration")
+            .setting(SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("get")
+                .about("Display configuration of a realm")
+                .arg(Arg::with_name("realm")
+                    .help("Name of realm")
+                    .required(true))
+                .arg(Arg::with_name("key")
+                    .help("Only display the value of this configuration key")))
+            .subcommand(SubCommand::with_name("set")
+                .about("Change a configuration value of a realm")
+                .arg(Arg::with_name("realm")
+                    .help("Name of realm")
+                    .required(true))
+                .arg(Arg::with_name("key")
+                    .help("Configuration key to change")
+                    .required(true))
+                .arg(Arg::with_name("value")
+                    .help("New value, or 'default' to remove the value from the realm config")
+                    .required(true))))
+
+        .subcommand(SubCommand::with_name("realmfs")
+            .about("List, update or show packages of RealmFS images")
+            .setting(SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("list")
+                .about("List RealmFS images"))
+            .subcommand(SubCommand::with_name("update")
+                .about("Open a terminal with an update shell for a RealmFS image")
+                .arg(Arg::with_name("realmfs")
+                    .help("Name of RealmFS image to update")
+                    .required(true)))
+            .subcommand(SubCommand::with_name("packages")
+                .about("List the packages installed in a RealmFS image")
+                .arg(Arg::with_name("realmfs")
+                    .help("Name of RealmFS image")
+                    .required(true))))
+
+        .subcommand(SubCommand::with_name("transfer")
+            .about("Transfer files between realms")
+            .setting(SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("send")
+                .about("Copy a file from the home directory of one realm to the Inbox directory of another")
+                .arg(Arg::with_name("source")
+                    .help("Name of realm to copy file from")
+                    .required(true))
+                .arg(Arg::with_name("path")
+                    .help("Path of file relative to home directory of source realm")
+                    .required(true))
+                .arg(Arg::with_name("target")
+                    .help("Name of realm to copy file to")
+                    .required(true)))
+            .subcommand(SubCommand::with_name("list")
+                .about("List transfers waiting for confirmation"))
+            .subcommand(SubCommand::with_name("accept")
+                .about("Accept a pending transfer")
+                .arg(Arg::with_name("id")
+                    .help("Id of pending transfer")
+                    .required(true)))
+            .subcommand(SubCommand::with_name("reject")
+                .about("Reject a pending transfer")
+                .arg(Arg::with_name("id")
+                    .help("Id of pending transfer")
+                    .required(true))))
+
+        .subcommand(SubCommand::with_name("watch")
+            .about("Print realm events as they occur"));
+
+    let matches = app.get_matches_from(args);
+    let json = matches.is_present("json");
+
+    let result = RealmsClient::connect().and_then(|client| {
+        match matches.subcommand() {
+            ("list", Some(_)) => client.list(json),
+            ("start", Some(m)) => client.realm_call("Start", m),
+            ("stop", Some(m)) => client.realm_call("Stop", m),
+            ("restart", Some(m)) => client.realm_call("Restart", m),
+            ("unlock", Some(m)) => client.unlock(m),
+            ("encrypt-home", Some(m)) => client.encrypt_home(m),
+            ("run", Some(m)) => client.run(m),
+            ("disposable", Some(m)) => client.run_disposable(m),
+            ("config", Some(m)) => match m.subcommand() {
+                ("get", Some(m)) => client.config_get(m, json),
+                ("set", Some(m)) => client.config_set(m),
+                _ => Ok(()),
+            },
+            ("realmfs", Some(m)) => match m.subcommand() {
+                ("list", Some(_)) => client.realmfs_list(json),
+                ("update", Some(m)) => client.realmfs_update(m),
+                ("packages", Some(m)) => client.realmfs_packages(m, json),
+                _ => Ok(()),
+            },
+            ("transfer", Some(m)) => match m.subcommand() {
+                ("send", Some(m)) => client.transfer_send(m, json),
+                ("list", Some(_)) => client.transfer_list(json),
+                ("accept", Some(m)) => client.transfer_confirm(m, true),
+                ("reject", Some(m)) => client.transfer_confirm(m, false),
+                _ => Ok(()),
+            },
+            ("watch", Some(_)) => client.watch(json),
+            _ => Ok(()),
+        }
+    });
+
+    if let Err(ref e) = result {
+        eprintln!("Error: {}", e);
+        exit(1);
+    }
+}
+
+struct RealmsClient {
+    connection: Connection,
+}
+
+impl RealmsClient {
+    fn connect() -> Result<Self> {
+        let connection = Connection::new_system()
+            .map_err(|e| format_err!("Failed to connect to DBUS system bus: {}", e))?;
+        Ok(RealmsClient { connection })
+    }
+
+    fn proxy(&self) -> Proxy<'_, &Connection> {
+        self.connection.with_proxy(BUS_NAME, OBJECT_PATH, CALL_TIMEOUT)
+    }
+
+    fn call<A: AppendAll, R: ReadAll + 'static>(&self, method: &str, args: A) -> Result<R> {
+        self.proxy().method_call(INTERFACE_NAME, method, args)
+            .map_err(|e| format_err!("{} failed: {}", method, e.message().unwrap_or("unknown error")))
+    }
+
+    fn realm_call(&self, method: &str, matches: &ArgMatches) -> Result<()> {
+        let realm = required(matches, "realm")?;
+        self.call::<_,()>(method, (realm,))
+    }
+
+    fn list(&self, json: bool) -> Result<()> {
+        let (realms,): (Vec<(String, String, String, u8)>,) = self.call("List", ())?;
+        if json {
+            let items = realms.iter().map(|(name, description, realmfs, status)| json!({
+                "name": name,
+                "description": description,
+                "realmfs": realmfs,
+                "running": status & STATUS_REALM_RUNNING != 0,
+                "current": status & STATUS_REALM_CURRENT != 0,
+                "system": status & STATUS_REALM_SYSTEM_REALM != 0,
+            })).collect::<Vec<_>>();
+            println!("{}", Value::Array(items));
+            return Ok(());
+        }
+
+        for (name, _, realmfs, status) in &realms {
+            let marker = if status & STATUS_REALM_CURRENT != 0 { ">" } else { " " };
+            let state = if status & STATUS_REALM_RUNNING != 0 { "running" } else { "stopped" };
+            let system = if status & STATUS_REALM_SYSTEM_REALM != 0 { " (system)" } else { "" };
+            println!("{} {:<16} {:<8} {}{}", marker, name, state, realmfs, system);
+        }
+        Ok(())
+    }
+
+    fn unlock(&self, matches: &ArgMatches) -> Result<()> {
+        let realm = required(matches, "realm")?;
+        let passphrase = rpassword::read_password_from_tty(Some("Passphrase: "))
+            .map_err(context!("error reading passphrase"))?;
+        self.call_and_wait("UnlockRealmHome", (realm, passphrase), realm, "RealmHomeUnlocked")
+    }
+
+    fn encrypt_home(&self, matches: &ArgMatches) -> Result<()> {
+        let realm = required(matches, "realm")?;
+        let size = required(matches, "size")?;
+        let size_mb = size.parse::<u64>()
+            .map_err(|_| format_err!("invalid size '{}', expecting a number of megabytes", size))?;
+        let passphrase = rpassword::read_password_from_tty(Some("New passphrase: "))
+            .map_err(context!("error reading passphrase"))?;
+        let confirm = rpassword::read_password_from_tty(Some("Confirm passphrase: "))
+            .map_err(context!("error reading passphrase"))?;
+        if passphrase != confirm {
+            bail!("passphrases do not match");
+        }
+        // Encrypting a home directory copies its entire contents into the new image
+        println!("Encrypting home directory of realm-{}, this may take some time", realm);
+        self.call_and_wait("EncryptRealmHome", (realm, passphrase, size_mb), realm, "RealmHomeEncrypted")
+    }
+
+    /// Call `method` on `realm` and wait for the `completed` or `RealmHomeFailed`
+    /// signal for that realm, which realmsd sends when the operation finishes.
+    fn call_and_wait<A: AppendAll>(&self, method: &str, args: A, realm: &str, completed: &'static str) -> Result<()> {
+        let mut rule = MatchRule::new();
+        rule.msg_type = Some(MessageType::Signal);
+        rule.interface = Some(INTERFACE_NAME.into());
+
+        let outcome = Arc::new(Mutex::new(None));
+        let (name, result) = (realm.to_string(), outcome.clone());
+        self.connection.add_match(rule, move |_: (), _, msg| {
+            if msg.get1::<&str>() == Some(name.as_str()) {
+                match msg.member().as_deref() {
+                    Some(member) if member == completed => *result.lock().unwrap() = Some(Ok(())),
+                    Some("RealmHomeFailed") => {
+                        let reason = msg.get2::<&str, &str>().1.unwrap_or("unknown error");
+                        *result.lock().unwrap() = Some(Err(reason.to_string()));
+                    },
+                    _ => {},
+                }
+            }
+            true
+        }).map_err(|e| format_err!("Failed to add signal match: {}", e))?;
+
+        self.call::<_,()>(method, args)?;
+        loop {
+            if let Some(result) = outcome.lock().unwrap().take() {
+                return result.map_err(|reason| format_err!("{} failed: {}", method, reason));
+            }
+            self.connection.process(Duration::from_millis(1000))
+                .map_err(|e| format_err!("Error receiving dbus messages: {}", e))?;
+        }
+    }
+
+    fn run(&self, matches: &ArgMatches) -> Result<()> {
+        let realm = required(matches, "realm")?;
+        let command = matches.values_of("command")
+            .map(|vals| vals.map(String::from).collect::<Vec<_>>())
+            .unwrap_or_default();
+        self.call::<_,()>("Run", (realm, command))
+    }
+
+    fn run_disposable(&self, matches: &ArgMatches) -> Result<()> {
+        let base = matches.value_of("base").unwrap_or("");
+        let command = matches.values_of("command")
+            .map(|vals| vals.map(String::from).collect::<Vec<_>>())
+            .unwrap_or_default();
+        self.call::<_,()>("RunDisposable", (base, command))
+    }
+
+    fn config_get(&self, matches: &ArgMatches, json: bool) -> Result<()> {
+        let realm = required(matches, "realm")?;
+        let (config,): (Vec<(String, String)>,) = self.call("RealmConfig", (realm,))?;
+
+        if let Some(key) = matches.value_of("key") {
+            let value = config.iter()
+                .find(|(k,_)| k == key)
+                .map(|(_,v)| v)
+                .ok_or_else(|| format_err!("No configuration key '{}'", key))?;
+            if json {
+                let mut item = Map::new();
+                item.insert(key.to_string(), Value::String(value.to_string()));
+                println!("{}", Value::Object(item));
+            } else {
+                println!("{}", value);
+            }
+            return Ok(());
+        }
+
+        if json {
+            let items = config.into_iter()
+                .map(|(k,v)| (k, Value::String(v)))
+                .collect::<Map<_,_>>();
+            println!("{}", Value::Object(items));
+        } else {
+            for (k,v) in &config {
+                println!("{} = {}", k, v);
+            }
+        }
+        Ok(())
+    }
+
+    fn config_set(&self, matches: &ArgMatches) -> Result<()> {
+        let realm = required(matches, "realm")?;
+        let key = required(matches, "key")?;
+        let value = required(matches, "value")?;
+        self.call::<_,()>("SetRealmConfig", (realm, key, value))
+    }
+
+    fn realmfs_list(&self, json: bool) -> Result<()> {
+        let (names,): (Vec<String>,) = self.call("ListRealmFS", ())?;
+        if json {
+            println!("{}", json!(names));
+        } else {
+            for name in &names {
+                println!("{}", name);
+            }
+        }
+        Ok(())
+    }
+
+    fn realmfs_update(&self, matches: &ArgMatches) -> Result<()> {
+        let realmfs = required(matches, "realmfs")?;
+        self.call::<_,()>("UpdateRealmFS", (realmfs,))
+    }
+
+    fn realmfs_packages(&self, matches: &ArgMatches, json: bool) -> Result<()> {
+        let realmfs = required(matches, "realmfs")?;
+        let (packages,): (Vec<(String,String)>,) = self.call("RealmFSPackages", (realmfs,))?;
+        if json {
+            let items = packages.iter()
+                .map(|(name, version)| json!({ "name": name, "version": version }))
+                .collect::<Vec<_>>();
+            println!("{}", Value::Array(items));
+        } else {
+            for (name, version) in &packages {
+                println!("{:<40} {}", name, version);
+            }
+        }
+        Ok(())
+    }
+
+    fn transfer_send(&self, matches: &ArgMatches, json: bool) -> Result<()> {
+        let source = required(matches, "source")?;
+        let path = required(matches, "path")?;
+        let target = required(matches, "target")?;
+        let (id,): (u32,) = self.call("TransferFile", (source, path, target))?;
+        if json {
+            println!("{}", json!({ "id": id }));
+        } else {
+            println!("Transfer {} requested", id);
+        }
+        Ok(())
+    }
+
+    fn transfer_list(&self, json: bool) -> Result<()> {
+        let (transfers,): (Vec<(u32, String, String, String)>,) = self.call("ListPendingTransfers", ())?;
+        if json {
+            let items = transfers.iter()
+                .map(|(id, source, path, target)| json!({ "id": id, "source": source, "path": path, "target": target }))
+                .collect::<Vec<_>>();
+            println!("{}", Value::Array(items));
+            return Ok(());
+        }
+        for (id, source, path, target) in &transfers {
+            println!("{:>4}  {} -> {}  {}", id, source, target, path);
+        }
+        Ok(())
+    }
+
+    fn transfer_confirm(&self, matches: &ArgMatches, accept: bool) -> Result<()> {
+        let id = required(matches, "id")?;
+        let id = id.parse::<u32>()
+            .map_err(|_| format_err!("Invalid transfer id '{}'", id))?;
+        self.call::<_,()>("ConfirmTransfer", (id, accept))
+    }
+
+    fn watch(&self, json: bool) -> Result<()> {
+        let mut rule = MatchRule::new();
+        rule.msg_type = Some(MessageType::Signal);
+        rule.interface = Some(INTERFACE_NAME.into());
+
+        self.connection.add_match(rule, move |_: (), _, msg| {
+            Self::print_event(msg, json);
+            true
+        }).map_err(|e| format_err!("Failed to add signal match: {}", e))?;
+
+        loop {
+            self.connection.process(Duration::from_millis(1000))
+                .map_err(|e| format_err!("Error receiving dbus messages: {}", e))?;
+        }
+    }
+
+    fn print_event(msg: &Message, json: bool) {
+        let event = msg.member().map(|m| m.to_string()).unwrap_or_default();
+        if let Some(id) = msg.get1::<u32>() {
+            if json {
+                println!("{}", json!({ "event": event, "id": id }));
+            } else {
+                println!("{} {}", event, id);
+            }
+            return;
+        }
+        let realm = msg.get1::<&str>().unwrap_or("");
+        if json {
+            println!("{}", json!({ "event": event, "realm": realm }));
+        } else {
+            println!("{} {}", event, realm);
+        }
+    }
+}
+
+fn required<'a>(matches: &'a ArgMatches, name: &str) -> Result<&'a str> {
+    matches.value_of(name)
+        .ok_or_else(|| format_err!("{} argument required", name))
+}
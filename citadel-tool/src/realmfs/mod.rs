@@ -1,12 +1,13 @@
 use clap::App;
 use clap::ArgMatches;
 
-use libcitadel::{Result,RealmFS,Logger,LogLevel};
+use libcitadel::{Result,RealmFS,RealmManager,Logger,LogLevel,UpdateScript,RealmFSImport,ImportSource,RealmFSExport,ExportFormat,RealmFSDiff,PublisherTrustStore,KeyRing};
 use libcitadel::util::is_euid_root;
 use clap::SubCommand;
 use clap::AppSettings::*;
 use clap::Arg;
-use libcitadel::ResizeSize;
+use libcitadel::{ResizeSize,AutoResizePolicy};
+use std::path::{Path,PathBuf};
 use std::process::exit;
 
 pub fn main(args: Vec<String>) {
@@ -35,6 +36,29 @@ is the final absolute size of the image.")
                 .required(true)))
 
 
+        .subcommand(SubCommand::with_name("shrink")
+            .about("Shrink a RealmFS image to the minimum size of the filesystem plus some free space, and reseal it.")
+            .arg(Arg::with_name("image")
+                .help("Path or name of RealmFS image to shrink")
+                .required(true))
+            .arg(Arg::with_name("headroom")
+                .long("headroom")
+                .takes_value(true)
+                .help("Free space to leave in the image (default: 2g)")
+                .long_help("\
+Amount of free space to leave in the image after shrinking. \
+The size can be followed by a 'g' or 'm' character \
+to indicate a quantity of gigabytes or megabytes. If no size unit \
+is provided the size is measured in blocks (of 4096 bytes). \
+The default is 2g.")))
+
+        .subcommand(SubCommand::with_name("compact")
+            .about("Fill free space of a RealmFS image with zeros and release it from storage, then reseal the image. \
+The uncompacted image is kept as a backup copy, so storage is only released once the backups are rotated out by later updates.")
+            .arg(Arg::with_name("image")
+                .help("Path or name of RealmFS image to compact")
+                .required(true)))
+
         .subcommand(SubCommand::with_name("fork")
             .about("Create a new RealmFS image as an unsealed copy of an existing image")
             .arg(Arg::with_name("image")
@@ -45,18 +69,140 @@ is the final absolute size of the image.")
                 .help("Name of new image to create")
                 .required(true)))
 
+        .subcommand(SubCommand::with_name("import")
+            .about("Create a new sealed RealmFS image from a root filesystem directory, tar archive or OCI image layout")
+            .arg(Arg::with_name("name")
+                .help("Name of new image to create")
+                .required(true))
+            .arg(Arg::with_name("source")
+                .help("Directory, tar archive or OCI image layout directory to import")
+                .required(true))
+            .arg(Arg::with_name("headroom")
+                .long("headroom")
+                .takes_value(true)
+                .help("Free space to leave in the image (default: 2g)"))
+            .arg(Arg::with_name("reference")
+                .long("reference")
+                .takes_value(true)
+                .help("Name of the image to import from an OCI image layout containing several images")))
+
+        .subcommand(SubCommand::with_name("export")
+            .about("Write the contents of a RealmFS image to a tar archive or OCI image layout")
+            .arg(Arg::with_name("image")
+                .help("Path or name of RealmFS image to export")
+                .required(true))
+            .arg(Arg::with_name("target")
+                .help("Path of tar archive or OCI image layout directory to create")
+                .required(true))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["tar", "oci"])
+                .default_value("tar")
+                .help("Format to export the image contents in"))
+            .arg(Arg::with_name("reference")
+                .long("reference")
+                .takes_value(true)
+                .help("Name of the image in the OCI image layout (default: RealmFS name)")))
+
+        .subcommand(SubCommand::with_name("diff")
+            .about("Show the files and packages which differ between two RealmFS images")
+            .arg(Arg::with_name("old")
+                .help("Path or name of first RealmFS image, or NAME@N for backup N of an image")
+                .required(true))
+            .arg(Arg::with_name("new")
+                .help("Path or name of second RealmFS image, or NAME@N for backup N of an image")
+                .required(true))
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Print differences as JSON")))
+
+        .subcommand(SubCommand::with_name("packages")
+            .about("List the packages installed in a RealmFS image when it was last sealed")
+            .arg(Arg::with_name("image")
+                .help("Path or name of RealmFS image")
+                .required(true))
+            .arg(Arg::with_name("package")
+                .long("package")
+                .takes_value(true)
+                .help("Only show the installed version of this package"))
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Print package manifest as JSON")))
+
+        .subcommand(SubCommand::with_name("import-signed")
+            .about("Copy a RealmFS image signed by a trusted publisher into the image directory without resealing it")
+            .arg(Arg::with_name("source")
+                .help("Path of signed RealmFS image file")
+                .required(true))
+            .arg(Arg::with_name("publisher")
+                .long("publisher")
+                .takes_value(true)
+                .help("Require the image to be signed by this publisher")))
+
+        .subcommand(SubCommand::with_name("publisher")
+            .about("Manage the trust store of RealmFS publisher keys")
+            .setting(SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("list")
+                .about("List trusted publishers"))
+            .subcommand(SubCommand::with_name("add")
+                .about("Trust RealmFS images signed with a publisher key")
+                .arg(Arg::with_name("name")
+                    .help("Name for the publisher")
+                    .required(true))
+                .arg(Arg::with_name("key")
+                    .help("Public key of the publisher as a hex string")
+                    .required(true)))
+            .subcommand(SubCommand::with_name("remove")
+                .about("Stop trusting a publisher and all images imported from it")
+                .arg(Arg::with_name("name")
+                    .help("Name of publisher to remove")
+                    .required(true)))
+            .subcommand(SubCommand::with_name("show-key")
+                .about("Print the public key of the local RealmFS sealing key for sharing with other machines")))
+
         .subcommand(SubCommand::with_name("autoresize")
             .about("Increase size of RealmFS image if not enough free space remains")
             .arg(Arg::with_name("image")
                 .help("Path or name of RealmFS image")
-                .required(true)))
+                .required(true))
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Show the auto resize policy and what it decides without resizing the image")))
 
         .subcommand(SubCommand::with_name("update")
-            .about("Open an update shell on the image")
+            .about("Open an update shell on the image, or run an update command without user interaction")
+            .arg(Arg::with_name("image")
+                .help("Path or name of RealmFS image")
+                .required(true))
+            .arg(Arg::with_name("run")
+                .long("run")
+                .takes_value(true)
+                .conflicts_with("script")
+                .help("Command to run in the update container instead of opening a shell"))
+            .arg(Arg::with_name("script")
+                .long("script")
+                .takes_value(true)
+                .help("Script file to run in the update container instead of opening a shell"))
+            .arg(Arg::with_name("log")
+                .long("log")
+                .takes_value(true)
+                .help("File to append output of --run or --script to (default: NAME-realmfs.update.log next to the image)")))
+
+        .subcommand(SubCommand::with_name("history")
+            .about("List the backup copies of previous versions of a RealmFS image")
             .arg(Arg::with_name("image")
                 .help("Path or name of RealmFS image")
                 .required(true)))
 
+        .subcommand(SubCommand::with_name("rollback")
+            .about("Swap a backup copy of a RealmFS image back into place. The current image takes the place of the backup.")
+            .arg(Arg::with_name("image")
+                .help("Name of RealmFS image to roll back")
+                .required(true))
+            .arg(Arg::with_name("backup")
+                .help("Index of backup to restore as listed by the history command (default: 0)")))
+
         .subcommand(SubCommand::with_name("activate")
             .about("Activate a RealmFS by creating a block device for the image and mounting it.")
             .arg(Arg::with_name("image")
@@ -78,8 +224,24 @@ is the final absolute size of the image.")
     let result = match matches.subcommand() {
         ("resize", Some(m)) => resize(m),
         ("autoresize", Some(m)) => autoresize(m),
+        ("shrink", Some(m)) => shrink(m),
+        ("compact", Some(m)) => compact(m),
         ("fork", Some(m)) => fork(m),
+        ("import", Some(m)) => import(m),
+        ("export", Some(m)) => export(m),
+        ("diff", Some(m)) => diff(m),
+        ("packages", Some(m)) => packages(m),
+        ("import-signed", Some(m)) => import_signed(m),
+        ("publisher", Some(m)) => match m.subcommand() {
+            ("list", Some(_)) => publisher_list(),
+            ("add", Some(m)) => publisher_add(m),
+            ("remove", Some(m)) => publisher_remove(m),
+            ("show-key", Some(_)) => publisher_show_key(),
+            _ => Ok(()),
+        },
         ("update", Some(m)) => update(m),
+        ("history", Some(m)) => history(m),
+        ("rollback", Some(m)) => rollback(m),
         ("activate", Some(m)) => activate(m),
         ("deactivate", Some(m)) => deactivate(m),
         _ => image_info(&matches),
@@ -110,29 +272,14 @@ fn realmfs_image(arg_matches: &ArgMatches) -> Result<RealmFS> {
 fn image_info(arg_matches: &ArgMatches) -> Result<()> {
     let img = realmfs_image(arg_matches)?;
     print!("{}", String::from_utf8(img.header().metainfo_bytes())?);
+    if let Some(provenance) = img.provenance() {
+        if provenance.verity_root() == img.metainfo().verity_root() {
+            println!("# imported {} from {} signed by publisher {}", provenance.imported(), provenance.source(), provenance.publisher());
+        }
+    }
     Ok(())
 }
 
-fn parse_resize_size(s: &str) -> Result<ResizeSize> {
-    let unit = s.chars().last().filter(|c| c.is_alphabetic());
-
-    let skip = if s.starts_with('+') { 1 } else { 0 };
-    let size = s.chars()
-        .skip(skip)
-        .take_while(|c| c.is_numeric())
-        .collect::<String>()
-        .parse::<usize>()
-        .map_err(|_| format_err!("Unable to parse size value '{}'",s))?;
-
-    let sz = match unit {
-        Some('g') | Some('G') => ResizeSize::gigs(size),
-        Some('m') | Some('M') => ResizeSize::megs(size),
-        Some(c) => bail!("Unknown size unit '{}'", c),
-        None => ResizeSize::blocks(size),
-    };
-    Ok(sz)
-}
-
 fn resize(arg_matches: &ArgMatches) -> Result<()> {
     let img = realmfs_image(arg_matches)?;
     info!("image is {}", img.path().display());
@@ -143,7 +290,7 @@ fn resize(arg_matches: &ArgMatches) -> Result<()> {
     };
     info!("Size is {}", size_arg);
     let mode_add = size_arg.starts_with('+');
-    let size = parse_resize_size(size_arg)?;
+    let size = ResizeSize::parse(size_arg)?;
 
     if mode_add {
         img.resize_grow_by(size)
@@ -154,15 +301,55 @@ fn resize(arg_matches: &ArgMatches) -> Result<()> {
 
 fn autoresize(arg_matches: &ArgMatches) -> Result<()> {
     let img = realmfs_image(arg_matches)?;
+    let policy = AutoResizePolicy::load(img.name())?;
+    let decision = policy.decide(&img)?;
 
-    if let Some(size) = img.auto_resize_size() {
-        img.resize_grow_to(size)
-    } else {
-        info!("RealmFS image {} has sufficient free space, doing nothing", img.path().display());
-        Ok(())
+    if arg_matches.is_present("dry-run") {
+        println!("policy: {}", policy);
+        println!("decision: {}", decision);
+        return Ok(());
+    }
+    match decision.grow_size() {
+        Some(size) => img.resize_grow_to(size),
+        None => {
+            info!("RealmFS image {}: {}", img.path().display(), decision);
+            Ok(())
+        }
     }
 }
 
+fn shrink(arg_matches: &ArgMatches) -> Result<()> {
+    if !is_euid_root() {
+        bail!("RealmFS images must be shrunk as root");
+    }
+    let img = realmfs_image(arg_matches)?;
+    let headroom = match arg_matches.value_of("headroom") {
+        Some(size) => ResizeSize::parse(size)?,
+        None => ResizeSize::default_headroom(),
+    };
+    let before = img.metainfo().nblocks();
+    img.shrink(headroom)?;
+    let after = RealmFS::load_from_path(img.path())?.metainfo().nblocks();
+    info!("RealmFS image {} is now {} blocks (was {} blocks)", img.name(), after, before);
+    Ok(())
+}
+
+fn compact(arg_matches: &ArgMatches) -> Result<()> {
+    if !is_euid_root() {
+        bail!("RealmFS images must be compacted as root");
+    }
+    let img = realmfs_image(arg_matches)?;
+    let before = img.total_allocated_size_blocks()?;
+    img.compact()?;
+    let img = RealmFS::load_from_path(img.path())?;
+    let (image, total) = (img.allocated_size_blocks()?, img.total_allocated_size_blocks()?);
+    info!("RealmFS image {} now uses {} blocks of storage, {} blocks including backup copies (was {} blocks)", img.name(), image, total, before);
+    if total >= before {
+        info!("Storage is released when the uncompacted backup copy {}-realmfs.img.0 is rotated out by later updates", img.name());
+    }
+    Ok(())
+}
+
 fn fork(arg_matches: &ArgMatches) -> Result<()> {
     let img = realmfs_image(arg_matches)?;
     let forkname = match arg_matches.value_of("forkname") {
@@ -179,12 +366,187 @@ fn fork(arg_matches: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
+fn import(arg_matches: &ArgMatches) -> Result<()> {
+    if !is_euid_root() {
+        bail!("RealmFS images must be imported as root");
+    }
+    let name = match arg_matches.value_of("name") {
+        Some(name) => name,
+        None => bail!("No image name argument"),
+    };
+    if !RealmFS::is_valid_name(name) {
+        bail!("Not a valid RealmFS image name '{}'", name);
+    }
+    if RealmFS::named_image_exists(name) {
+        bail!("A RealmFS image named '{}' already exists", name);
+    }
+    let source = match arg_matches.value_of("source") {
+        Some(source) => ImportSource::from_path(source)?,
+        None => bail!("No import source argument"),
+    };
+    let mut import = RealmFSImport::new(name, source);
+    if let Some(size) = arg_matches.value_of("headroom") {
+        import = import.headroom(ResizeSize::parse(size)?);
+    }
+    if let Some(reference) = arg_matches.value_of("reference") {
+        import = import.reference(reference);
+    }
+    let img = import.run()?;
+    info!("Created RealmFS image {} with {} blocks", img.path().display(), img.metainfo().nblocks());
+    Ok(())
+}
+
+fn export(arg_matches: &ArgMatches) -> Result<()> {
+    if !is_euid_root() {
+        bail!("RealmFS images must be exported as root");
+    }
+    let img = realmfs_image(arg_matches)?;
+    let target = match arg_matches.value_of("target") {
+        Some(target) => target,
+        None => bail!("No export target argument"),
+    };
+    let format = arg_matches.value_of("format")
+        .and_then(ExportFormat::from_str_value)
+        .unwrap_or(ExportFormat::Tar);
+    let mut export = RealmFSExport::new(&img, format, target);
+    if let Some(reference) = arg_matches.value_of("reference") {
+        export = export.reference(reference);
+    }
+    export.run()
+}
+
+fn diff(arg_matches: &ArgMatches) -> Result<()> {
+    if !is_euid_root() {
+        bail!("RealmFS images must be compared as root");
+    }
+    let image_path = |arg| match arg_matches.value_of(arg) {
+        Some(spec) => RealmFSDiff::image_path(spec),
+        None => bail!("Image argument required."),
+    };
+    let diff = RealmFSDiff::compare(&image_path("old")?, &image_path("new")?)?;
+    if arg_matches.is_present("json") {
+        println!("{}", diff.to_json());
+    } else {
+        print!("{}", diff);
+    }
+    Ok(())
+}
+
+fn packages(arg_matches: &ArgMatches) -> Result<()> {
+    let img = realmfs_image(arg_matches)?;
+    let manifest = img.package_manifest()?;
+    if let Some(package) = arg_matches.value_of("package") {
+        match manifest.package_version(package) {
+            Some(version) => println!("{} {}", package, version),
+            None => bail!("Package {} is not installed in RealmFS '{}'", package, img.name()),
+        }
+    } else if arg_matches.is_present("json") {
+        println!("{}", manifest.to_json());
+    } else {
+        println!("{}", manifest);
+    }
+    Ok(())
+}
+
+fn import_signed(arg_matches: &ArgMatches) -> Result<()> {
+    let source = match arg_matches.value_of("source") {
+        Some(source) => source,
+        None => bail!("No source image argument"),
+    };
+    let img = libcitadel::import_signed_image(Path::new(source), arg_matches.value_of("publisher"))?;
+    info!("Imported RealmFS image {}", img.path().display());
+    Ok(())
+}
+
+fn publisher_list() -> Result<()> {
+    let store = PublisherTrustStore::load()?;
+    for (name, publisher) in store.publishers() {
+        println!("{:<20} {}  (added {})", name, publisher.public_key_hex(), publisher.added());
+    }
+    Ok(())
+}
+
+fn publisher_add(arg_matches: &ArgMatches) -> Result<()> {
+    let (name, key) = match (arg_matches.value_of("name"), arg_matches.value_of("key")) {
+        (Some(name), Some(key)) => (name, key),
+        _ => bail!("Publisher name and key arguments required"),
+    };
+    PublisherTrustStore::load()?.add(name, key)
+}
+
+fn publisher_remove(arg_matches: &ArgMatches) -> Result<()> {
+    let name = match arg_matches.value_of("name") {
+        Some(name) => name,
+        None => bail!("No publisher name argument"),
+    };
+    PublisherTrustStore::load()?.remove(name)
+}
+
+fn publisher_show_key() -> Result<()> {
+    let keys = KeyRing::get_kernel_keypair(RealmFS::USER_KEYNAME)?;
+    println!("{}", keys.public_key().to_hex());
+    Ok(())
+}
+
 fn update(arg_matches: &ArgMatches) -> Result<()> {
     if !is_euid_root() {
         bail!("RealmFS updates must be run as root");
     }
     let img = realmfs_image(arg_matches)?;
-    img.interactive_update(Some("icy"))?;
+    let script = if let Some(command) = arg_matches.value_of("run") {
+        UpdateScript::Command(command.to_string())
+    } else if let Some(path) = arg_matches.value_of("script") {
+        UpdateScript::File(PathBuf::from(path))
+    } else {
+        return img.interactive_update(Some("icy"));
+    };
+
+    let log_path = match arg_matches.value_of("log") {
+        Some(path) => PathBuf::from(path),
+        None => img.update_log_path(),
+    };
+    info!("Running update of {} with output logged to {}", img.name(), log_path.display());
+    img.scripted_update(&script, &log_path)?;
+    info!("Update of {} sealed and applied", img.name());
+    Ok(())
+}
+
+fn history(arg_matches: &ArgMatches) -> Result<()> {
+    let img = realmfs_image(arg_matches)?;
+    let history = img.history();
+    if history.is_empty() {
+        println!("No backup images of RealmFS '{}'", img.name());
+        return Ok(());
+    }
+    for backup in history {
+        let metainfo = backup.metainfo();
+        println!("[{}] {}", backup.index(), backup.path().display());
+        println!("    timestamp:   {}", backup.timestamp());
+        println!("    nblocks:     {}", backup.nblocks());
+        println!("    channel:     {}", metainfo.channel());
+        println!("    verity-root: {}", backup.verity_root());
+        println!("    signature:   {}", backup.signature_status());
+    }
+    Ok(())
+}
+
+fn rollback(arg_matches: &ArgMatches) -> Result<()> {
+    if !is_euid_root() {
+        bail!("RealmFS rollback must be run as root");
+    }
+    let name = arg_matches.value_of("image").unwrap();
+    let index = match arg_matches.value_of("backup") {
+        Some(s) => s.parse::<usize>().map_err(|_| format_err!("Invalid backup index '{}'", s))?,
+        None => 0,
+    };
+    // Load through the realm manager so that realms using the image are seen
+    let manager = RealmManager::load()?;
+    let img = match manager.realmfs_by_name(name) {
+        Some(img) => img,
+        None => bail!("No RealmFS image named '{}' found", name),
+    };
+    img.rollback(index)?;
+    info!("RealmFS image {} rolled back to backup {}", name, index);
     Ok(())
 }
 
@@ -1,6 +1,6 @@
 use std::path::{Path, PathBuf};
 
-use libcitadel::{Result, Partition, ResourceImage, ImageHeader, LogLevel, Logger, util};
+use libcitadel::{Result, Partition, ResourceImage, ImageHeader, LogLevel, Logger, AuditLog, util};
 use crate::update::kernel::{KernelInstaller, KernelVersion};
 use std::collections::HashSet;
 use std::fs::DirEntry;
@@ -76,12 +76,18 @@ fn install_image(path: &Path, flags: u32) -> Result<()> {
     detect_duplicates(&image)?;
     prepare_image(&image, flags)?;
 
+    let metainfo = image.metainfo();
+    let description = format!("{} image version {} from channel {} (sha256 {})",
+                              metainfo.image_type(), metainfo.version(), metainfo.channel(), metainfo.shasum());
+
     match image.metainfo().image_type() {
         "kernel" => install_kernel_image(&mut image),
         "extra" => install_extra_image(&image),
         "rootfs" => install_rootfs_image(&image, flags),
         image_type => bail!("Unknown image type: {}", image_type),
-    }
+    }?;
+    AuditLog::record("update", format!("installed {}", description));
+    Ok(())
 }
 
 // Prepare the image file for installation by decompressing and generating
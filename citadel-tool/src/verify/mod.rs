@@ -0,0 +1,78 @@
+use std::process::exit;
+use std::time::Duration;
+
+use clap::{App, Arg, ArgMatches};
+use clap::AppSettings::*;
+use dbus::blocking::Connection;
+
+use libcitadel::{IntegrityScan, Result};
+use libcitadel::util::is_euid_root;
+
+const BUS_NAME: &str = "com.subgraph.realms";
+const OBJECT_PATH: &str = "/com/subgraph/realms";
+const INTERFACE_NAME: &str = "com.subgraph.realms.Manager";
+
+const CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn main(args: Vec<String>) {
+    let app = App::new("citadel-verify-all")
+        .about("Verify signatures and hash trees of all rootfs, resource and RealmFS images")
+        .settings(&[ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder])
+
+        .arg(Arg::with_name("json")
+            .long("json")
+            .help("Print results as JSON"))
+        .arg(Arg::with_name("no-verity")
+            .long("no-verity")
+            .help("Do not verify dm-verity hash trees, which requires reading every image in full"))
+        .arg(Arg::with_name("notify")
+            .long("notify")
+            .help("Report failed checks to realmsd so that desktop clients are notified"));
+
+    let matches = app.get_matches_from(args);
+
+    let result = verify_all(&matches);
+    match result {
+        Ok(true) => {},
+        Ok(false) => exit(1),
+        Err(ref e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+    }
+}
+
+// Returns false if any image failed a check
+fn verify_all(matches: &ArgMatches) -> Result<bool> {
+    if !is_euid_root() {
+        bail!("Images must be verified as root");
+    }
+    let scan = IntegrityScan::new()
+        .verity(!matches.is_present("no-verity"))
+        .run()?;
+
+    if matches.is_present("json") {
+        println!("{}", scan.to_json());
+    } else {
+        println!("{}", scan);
+    }
+
+    if matches.is_present("notify") && scan.failed_count() > 0 {
+        notify_failures(&scan)?;
+    }
+    Ok(scan.failed_count() == 0)
+}
+
+fn notify_failures(scan: &IntegrityScan) -> Result<()> {
+    let failures = scan.checks().iter()
+        .flat_map(|check| check.failures().map(move |(name, reason)| {
+            (check.kind().to_string(), check.path().display().to_string(), format!("{} check failed: {}", name, reason))
+        }))
+        .collect::<Vec<_>>();
+
+    let connection = Connection::new_system()
+        .map_err(|e| format_err!("Failed to connect to DBUS system bus: {}", e))?;
+    connection.with_proxy(BUS_NAME, OBJECT_PATH, CALL_TIMEOUT)
+        .method_call::<(), _, _, _>(INTERFACE_NAME, "ReportIntegrityFailures", (failures,))
+        .map_err(|e| format_err!("ReportIntegrityFailures failed: {}", e.message().unwrap_or("unknown error")))
+}
@@ -0,0 +1,297 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sodiumoxide::crypto::hash::sha256;
+
+use crate::{FileLock, Result, util};
+
+const AUDIT_LOG_PATH: &str = "/storage/citadel-state/audit.log";
+const AUDIT_LOCK_PATH: &str = "/storage/citadel-state/audit.lock";
+
+/// Hash value which the first entry in the log is chained to.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+///
+/// Append-only log of privileged operations such as starting and stopping
+/// realms, writing realm configuration, sealing RealmFS images, installing
+/// resource images and loading keys.
+///
+/// Each entry is a single line of tab separated fields:
+///
+/// ```text
+/// seq  timestamp  uid  category  message  prev-hash  hash
+/// ```
+///
+/// where `hash` is the sha256 of the preceding fields and `prev-hash` is the
+/// `hash` field of the previous entry. Removing, reordering or modifying any
+/// entry breaks the chain for every later entry, which `verify()` detects.
+/// The chain does not prevent someone with write access from rebuilding the
+/// entire log, so the most recent hash should also be recorded elsewhere if
+/// that matters.
+///
+pub struct AuditLog {
+    path: PathBuf,
+    lock_path: PathBuf,
+}
+
+impl AuditLog {
+
+    pub fn new(path: impl AsRef<Path>, lock_path: impl AsRef<Path>) -> Self {
+        AuditLog {
+            path: path.as_ref().to_path_buf(),
+            lock_path: lock_path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn system() -> Self {
+        Self::new(AUDIT_LOG_PATH, AUDIT_LOCK_PATH)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append an entry to the system audit log.
+    ///
+    /// Failing to write the audit log never causes the operation being
+    /// audited to fail, so errors are logged together with the entry which
+    /// could not be written. Nothing is recorded if
+    /// /storage is not mounted, which is the case in the installer.
+    pub fn record(category: &str, message: impl AsRef<str>) {
+        let log = Self::system();
+        if !log.path.parent().map(|p| p.exists()).unwrap_or(false) {
+            verbose!("Audit log directory does not exist, not recording: {}", message.as_ref());
+            return;
+        }
+        if let Err(e) = log.append(category, message.as_ref()) {
+            warn!("Failed to write audit log entry [{}] {}: {}", category, message.as_ref(), e);
+        }
+    }
+
+    /// Append a new entry chained to the last entry in the log.
+    ///
+    /// Only the last entry is read, so this fails only if that entry is
+    /// malformed or does not match its own hash.
+    pub fn append(&self, category: &str, message: &str) -> Result<AuditEntry> {
+        let _lock = FileLock::acquire(&self.lock_path)?;
+        let (seq, prev_hash) = match self.last_line()? {
+            Some(line) => {
+                let last = AuditEntry::from_line(&line)
+                    .ok_or_else(|| format_err!("last entry in audit log {:?} is malformed: {:?}", self.path, line))?;
+                if last.hash != last.calculate_hash() {
+                    bail!("last entry in audit log {:?} has been modified, hash chain is broken", self.path);
+                }
+                (last.seq + 1, last.hash)
+            },
+            None => (1, GENESIS_HASH.to_string()),
+        };
+
+        let entry = AuditEntry::new(seq, category, message, prev_hash);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(context!("failed to open audit log {:?}", self.path))?;
+        writeln!(file, "{}", entry.to_line())
+            .map_err(context!("failed to write to audit log {:?}", self.path))?;
+        Ok(entry)
+    }
+
+    /// Read the last line of the log by reading backwards from the end of the file.
+    fn last_line(&self) -> Result<Option<String>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let mut file = File::open(&self.path)
+            .map_err(context!("failed to open audit log {:?}", self.path))?;
+        let mut end = file.seek(SeekFrom::End(0))
+            .map_err(context!("failed to seek in audit log {:?}", self.path))?;
+
+        let mut tail = Vec::new();
+        let mut chunk = vec![0u8; 4096];
+        while end > 0 {
+            let n = chunk.len().min(end as usize);
+            end -= n as u64;
+            file.seek(SeekFrom::Start(end))
+                .and_then(|_| file.read_exact(&mut chunk[..n]))
+                .map_err(context!("failed to read audit log {:?}", self.path))?;
+            tail.splice(0..0, chunk[..n].iter().cloned());
+
+            let content = tail.strip_suffix(b"\n").unwrap_or(&tail);
+            if content.contains(&b'\n') {
+                break;
+            }
+        }
+
+        let tail = String::from_utf8(tail)
+            .map_err(|_| format_err!("last entry in audit log {:?} is not valid UTF-8", self.path))?;
+        Ok(tail.lines().next_back().map(|s| s.to_string()))
+    }
+
+    /// Read every entry in the log without checking the hash chain.
+    pub fn entries(&self) -> Result<Vec<AuditEntry>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        util::read_to_string(&self.path)?
+            .lines()
+            .enumerate()
+            .map(|(n, line)| AuditEntry::from_line(line)
+                .ok_or_else(|| format_err!("malformed audit log entry on line {}", n + 1)))
+            .collect()
+    }
+
+    /// Check the hash chain of the entire log and return the number of entries.
+    pub fn verify(&self) -> Result<usize> {
+        let entries = self.entries()?;
+        let mut prev_hash = GENESIS_HASH;
+        let mut prev_seq = 0;
+        for entry in &entries {
+            if entry.seq != prev_seq + 1 {
+                bail!("audit log entry {} follows entry {}, entries are missing or out of order", entry.seq, prev_seq);
+            }
+            if entry.prev_hash != prev_hash {
+                bail!("audit log entry {} is not chained to the previous entry", entry.seq);
+            }
+            if entry.hash != entry.calculate_hash() {
+                bail!("audit log entry {} has been modified", entry.seq);
+            }
+            prev_hash = &entry.hash;
+            prev_seq = entry.seq;
+        }
+        Ok(entries.len())
+    }
+}
+
+#[derive(Clone,Debug)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub uid: u32,
+    pub category: String,
+    pub message: String,
+    prev_hash: String,
+    hash: String,
+}
+
+impl AuditEntry {
+    fn new(seq: u64, category: &str, message: &str, prev_hash: String) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let uid = unsafe { libc::getuid() };
+        let mut entry = AuditEntry {
+            seq, timestamp, uid,
+            category: escape_field(category),
+            message: escape_field(message),
+            prev_hash,
+            hash: String::new(),
+        };
+        entry.hash = entry.calculate_hash();
+        entry
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    fn hashed_fields(&self) -> String {
+        format!("{}\t{}\t{}\t{}\t{}\t{}",
+                self.seq, self.timestamp, self.uid, self.category, self.message, self.prev_hash)
+    }
+
+    fn calculate_hash(&self) -> String {
+        let digest = sha256::hash(self.hashed_fields().as_bytes());
+        hex::encode(digest.as_ref())
+    }
+
+    fn to_line(&self) -> String {
+        format!("{}\t{}", self.hashed_fields(), self.hash)
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let v = line.split('\t').collect::<Vec<_>>();
+        if v.len() != 7 {
+            return None;
+        }
+        Some(AuditEntry {
+            seq: v[0].parse().ok()?,
+            timestamp: v[1].parse().ok()?,
+            uid: v[2].parse().ok()?,
+            category: v[3].to_string(),
+            message: v[4].to_string(),
+            prev_hash: v[5].to_string(),
+            hash: v[6].to_string(),
+        })
+    }
+}
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>6} {} uid={} [{}] {}", self.seq, format_timestamp(self.timestamp), self.uid, self.category, self.message)
+    }
+}
+
+/// Format seconds since the epoch as a UTC date and time.
+pub(crate) fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let secs = timestamp % 86400;
+
+    // Convert days since 1970-01-01 to a civil date (proleptic Gregorian calendar)
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year, month, day, secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+fn escape_field(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+#[test]
+fn test_audit_chain() {
+    let dir = std::env::temp_dir().join(format!("citadel-audit-test-{}", std::process::id()));
+    util::create_dir(&dir).unwrap();
+    let log = AuditLog::new(dir.join("audit.log"), dir.join("audit.lock"));
+
+    log.append("realm", "started realm-main").unwrap();
+    log.append("config", "wrote config\twith tab").unwrap();
+    log.append("realm", "stopped realm-main").unwrap();
+    assert_eq!(log.verify().unwrap(), 3);
+
+    let content = util::read_to_string(log.path()).unwrap();
+    util::write_file(log.path(), content.replace("stopped", "started")).unwrap();
+    assert!(log.verify().is_err());
+
+    let lines = content.lines().collect::<Vec<_>>();
+    util::write_file(log.path(), format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+    assert!(log.verify().is_err());
+
+    // A modified entry earlier in the log does not prevent appending new entries
+    util::write_file(log.path(), content.replace("started", "stopped")).unwrap();
+    assert_eq!(log.append("realm", "started realm-main").unwrap().seq, 4);
+    assert!(log.verify().is_err());
+
+    // A malformed last entry is reported instead of starting a new chain
+    util::write_file(log.path(), format!("{}garbage\n", content)).unwrap();
+    assert!(log.append("realm", "started realm-main").is_err());
+
+    let _ = std::fs::remove_dir_all(&dir);
+
+    assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+    assert_eq!(format_timestamp(951_825_600), "2000-02-29 12:00:00");
+}
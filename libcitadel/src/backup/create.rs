@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use walkdir::WalkDir;
+
+use crate::backup::repository::BackupRepository;
+use crate::backup::snapshot::{BackupItem, EntryKind, FileEntry, ItemKind, Snapshot};
+use crate::realm::quota::{self, is_subvolume};
+use crate::{AuditLog, RealmFS, Result, util};
+
+const REALMS_PATH: &str = "/storage/realms";
+const KEYRING_PATH: &str = "/storage/keyring";
+
+/// Local read-only snapshots of btrfs subvolumes which are kept as the parent
+/// for the next incremental `btrfs send`.
+const LOCAL_SNAPSHOTS_PATH: &str = "/storage/realms/.backup-snapshots";
+
+const BTRFS_PATH: &str = "/usr/bin/btrfs";
+
+/// Maximum number of incremental send streams before a full stream is sent again,
+/// so that restoring a subvolume does not require receiving an unbounded number
+/// of streams.
+const MAX_STREAM_CHAIN: usize = 30;
+
+#[derive(Clone,Copy,PartialEq)]
+enum Scope {
+    /// A directory and everything below it on the same filesystem.
+    Tree,
+    /// Only the regular files and symlinks directly inside a directory.
+    TopLevel,
+    /// A single file.
+    File,
+}
+
+struct Source {
+    name: String,
+    path: PathBuf,
+    scope: Scope,
+}
+
+impl Source {
+    fn new(name: impl Into<String>, path: impl Into<PathBuf>, scope: Scope) -> Self {
+        Source { name: name.into(), path: path.into(), scope }
+    }
+}
+
+///
+/// A single run of backing up /storage/realms into a `BackupRepository`.
+///
+/// Each realm is stored as three items: the files in the realm directory
+/// (config, notes, an encrypted home image), the home directory, and the
+/// storage overlay if one exists. User RealmFS images, shared folders, the
+/// files in /storage/realms and the keyring are stored as separate items.
+///
+/// Home directories and overlays which are btrfs subvolumes are backed up with
+/// `btrfs send` from a read-only snapshot, incrementally against the snapshot
+/// taken for the previous backup. Everything else is backed up file by file
+/// and files which have the same size and modification time as in the previous
+/// backup are not read again.
+///
+pub struct Backup<'a> {
+    repo: &'a BackupRepository,
+    previous: Option<Snapshot>,
+    snapshot: Snapshot,
+}
+
+impl <'a> Backup<'a> {
+    pub fn new(repo: &'a BackupRepository) -> Result<Self> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let previous = repo.latest_snapshot()?;
+        Ok(Backup { repo, previous, snapshot: Snapshot::new(timestamp) })
+    }
+
+    pub fn run(mut self) -> Result<Snapshot> {
+        let _lock = self.repo.lock()?;
+        if self.repo.load_snapshot(self.snapshot.id()).is_ok() {
+            bail!("a backup snapshot with id {} already exists", self.snapshot.id());
+        }
+        info!("Creating backup snapshot {} in {}", self.snapshot.id(), self.repo.path().display());
+
+        for source in Self::sources()? {
+            let item = self.backup_source(&source)
+                .map_err(context!("failed to back up {}", source.name))?;
+            self.snapshot.add_item(item);
+        }
+
+        self.repo.save_snapshot(&self.snapshot)?;
+        AuditLog::record("backup", format!("created backup snapshot {} in {}", self.snapshot.id(), self.repo.path().display()));
+        Ok(self.snapshot)
+    }
+
+    fn sources() -> Result<Vec<Source>> {
+        let mut sources = vec![Source::new("realms", REALMS_PATH, Scope::TopLevel)];
+
+        let mut realm_dirs = Vec::new();
+        util::read_directory(REALMS_PATH, |dent| {
+            let name = dent.file_name().to_string_lossy().to_string();
+            if name.starts_with("realm-") && dent.path().is_dir() {
+                realm_dirs.push((name, dent.path()));
+            }
+            Ok(())
+        })?;
+        realm_dirs.sort();
+
+        for (name, dir) in realm_dirs {
+            sources.push(Source::new(name.as_str(), &dir, Scope::TopLevel));
+            // An encrypted home is backed up as the image file in the realm directory
+            if !dir.join("home.luks").exists() && dir.join("home").is_dir() {
+                sources.push(Source::new(format!("{}/home", name), dir.join("home"), Scope::Tree));
+            }
+            if dir.join("overlay").is_dir() {
+                sources.push(Source::new(format!("{}/overlay", name), dir.join("overlay"), Scope::Tree));
+            }
+        }
+
+        let shared = Path::new(REALMS_PATH).join("shared");
+        if shared.is_dir() {
+            sources.push(Source::new("shared", shared, Scope::Tree));
+        }
+
+        for realmfs in Self::user_realmfs_images()? {
+            sources.push(Source::new(format!("realmfs/{}", realmfs.name()), realmfs.path(), Scope::File));
+        }
+
+        if Path::new(KEYRING_PATH).exists() {
+            sources.push(Source::new("keyring", KEYRING_PATH, Scope::File));
+        }
+        Ok(sources)
+    }
+
+    /// RealmFS images signed with the user key. Images from the update channel
+    /// can be downloaded again and are not backed up.
+    fn user_realmfs_images() -> Result<Vec<RealmFS>> {
+        let mut images = Vec::new();
+        if !Path::new(RealmFS::BASE_PATH).exists() {
+            return Ok(images);
+        }
+        util::read_directory(RealmFS::BASE_PATH, |dent| {
+            if RealmFS::is_valid_realmfs_image(dent.path()) {
+                match RealmFS::load_from_path(dent.path()) {
+                    Ok(realmfs) if realmfs.is_user_realmfs() => images.push(realmfs),
+                    Ok(_) => {},
+                    Err(e) => warn!("Not backing up RealmFS image {}: {}", dent.path().display(), e),
+                }
+            }
+            Ok(())
+        })?;
+        images.sort_by(|a, b| a.name().cmp(b.name()));
+        Ok(images)
+    }
+
+    fn previous_item(&self, name: &str) -> Option<&BackupItem> {
+        self.previous.as_ref().and_then(|s| s.item(name))
+    }
+
+    fn backup_source(&self, source: &Source) -> Result<BackupItem> {
+        if source.scope == Scope::Tree && is_subvolume(&source.path) {
+            return self.backup_subvolume(source);
+        }
+        verbose!("Backing up {} from {}", source.name, source.path.display());
+
+        let previous = self.previous_item(&source.name)
+            .filter(|item| item.kind == ItemKind::Files)
+            .map(|item| item.files.iter().map(|f| (f.path.as_str(), f)).collect::<HashMap<_,_>>())
+            .unwrap_or_default();
+
+        let mut item = BackupItem::new(&source.name, &source.path, ItemKind::Files);
+        let walker = match source.scope {
+            Scope::Tree => WalkDir::new(&source.path),
+            Scope::TopLevel => WalkDir::new(&source.path).max_depth(1),
+            Scope::File => WalkDir::new(&source.path).max_depth(0),
+        };
+        for entry in walker.same_file_system(true).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+            let entry = entry.map_err(|e| format_err!("error reading directory tree: {}", e))?;
+            let relative = entry.path().strip_prefix(&source.path)
+                .map_err(|_| format_err!("failed to strip prefix from {:?}", entry.path()))?
+                .to_string_lossy()
+                .to_string();
+            if source.scope == Scope::TopLevel && !relative.is_empty() && entry.file_type().is_dir() {
+                continue;
+            }
+            if let Some(file) = self.backup_entry(entry.path(), relative, &previous)? {
+                item.files.push(file);
+            }
+        }
+        Ok(item)
+    }
+
+    fn backup_entry(&self, path: &Path, relative: String, previous: &HashMap<&str, &FileEntry>) -> Result<Option<FileEntry>> {
+        let meta = path.symlink_metadata()
+            .map_err(context!("failed to read metadata of {:?}", path))?;
+        let file_type = meta.file_type();
+        let kind = if file_type.is_dir() {
+            EntryKind::Dir
+        } else if file_type.is_symlink() {
+            EntryKind::Symlink
+        } else if file_type.is_file() {
+            EntryKind::File
+        } else {
+            // sockets, fifos and device nodes
+            return Ok(None);
+        };
+
+        let mut entry = FileEntry {
+            path: relative,
+            kind,
+            mode: meta.permissions().mode() & 0o7777,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            mtime: meta.mtime(),
+            size: if kind == EntryKind::File { meta.len() } else { 0 },
+            object: None,
+            target: None,
+        };
+
+        match kind {
+            EntryKind::File => entry.object = Some(self.backup_file(path, &entry, previous)?),
+            EntryKind::Symlink => {
+                let target = fs::read_link(path)
+                    .map_err(context!("failed to read symlink {:?}", path))?;
+                entry.target = Some(target.to_string_lossy().to_string());
+            },
+            EntryKind::Dir => {},
+        }
+        Ok(Some(entry))
+    }
+
+    fn backup_file(&self, path: &Path, entry: &FileEntry, previous: &HashMap<&str, &FileEntry>) -> Result<String> {
+        let unchanged = previous.get(entry.path.as_str())
+            .filter(|p| p.kind == EntryKind::File && p.size == entry.size && p.mtime == entry.mtime)
+            .and_then(|p| p.object.as_ref())
+            .filter(|id| self.repo.has_object(id));
+        if let Some(id) = unchanged {
+            return Ok(id.clone());
+        }
+
+        let file = File::open(path)
+            .map_err(context!("failed to open {:?}", path))?;
+        self.repo.store_object(BufReader::new(file))
+            .map_err(context!("failed to back up {:?}", path))
+    }
+
+    fn local_snapshot_dir(&self, source: &Source) -> PathBuf {
+        Path::new(LOCAL_SNAPSHOTS_PATH)
+            .join(self.repo.id())
+            .join(source.name.replace('/', "-"))
+    }
+
+    fn backup_subvolume(&self, source: &Source) -> Result<BackupItem> {
+        verbose!("Backing up {} from btrfs subvolume {}", source.name, source.path.display());
+        let dir = self.local_snapshot_dir(source);
+        util::create_dir(&dir)?;
+        let local = dir.join(self.snapshot.id());
+        cmd!(BTRFS_PATH, "subvolume snapshot -r {} {}", source.path.display(), local.display())?;
+
+        // Send incrementally if the snapshot taken for the previous backup still exists
+        let previous = self.previous_item(&source.name)
+            .filter(|item| item.kind == ItemKind::Btrfs && !item.streams.is_empty() && item.streams.len() < MAX_STREAM_CHAIN);
+        let parent = match (&self.previous, previous) {
+            (Some(snapshot), Some(_)) => Some(dir.join(snapshot.id())).filter(|p| p.exists()),
+            _ => None,
+        };
+
+        let stream = match self.send_subvolume(&local, parent.as_deref()) {
+            Ok(stream) => stream,
+            Err(e) => {
+                let _ = quota::delete_subvolume(&local);
+                return Err(e);
+            }
+        };
+
+        let mut item = BackupItem::new(&source.name, &source.path, ItemKind::Btrfs);
+        if let (Some(_), Some(previous)) = (&parent, previous) {
+            item.streams.extend(previous.streams.iter().cloned());
+        }
+        item.streams.push(stream);
+
+        Self::remove_old_local_snapshots(&dir, &local);
+        Ok(item)
+    }
+
+    fn send_subvolume(&self, subvolume: &Path, parent: Option<&Path>) -> Result<String> {
+        let mut cmd = Command::new(BTRFS_PATH);
+        cmd.arg("send").arg("-q");
+        if let Some(parent) = parent {
+            cmd.arg("-p").arg(parent);
+        }
+        let mut child = cmd.arg(subvolume)
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(context!("failed to execute {}", BTRFS_PATH))?;
+
+        let stdout = child.stdout.take().expect("btrfs send stdout");
+        let stored = self.repo.store_object(BufReader::new(stdout));
+        let status = child.wait()
+            .map_err(context!("error waiting for btrfs send to exit"))?;
+        let id = stored?;
+        if !status.success() {
+            bail!("btrfs send of {} failed", subvolume.display());
+        }
+        Ok(id)
+    }
+
+    fn remove_old_local_snapshots(dir: &Path, keep: &Path) {
+        let result = util::read_directory(dir, |dent| {
+            if dent.path() != keep {
+                quota::delete_subvolume(&dent.path())?;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            warn!("Failed to remove old local backup snapshots in {}: {}", dir.display(), e);
+        }
+    }
+}
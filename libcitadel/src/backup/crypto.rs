@@ -0,0 +1,187 @@
+use std::io::{self, Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use sodiumoxide::crypto::generichash;
+use sodiumoxide::crypto::pwhash::{self, Salt, SALTBYTES};
+use sodiumoxide::crypto::secretstream::{self, Header, Stream, Tag, ABYTES, HEADERBYTES};
+
+use crate::Result;
+
+/// Size of the plaintext chunks which are encrypted as individual messages of the stream.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+const HASH_KEYBYTES: usize = 32;
+const HASH_BYTES: usize = 32;
+
+///
+/// Keys derived from the backup passphrase.
+///
+/// Objects are encrypted with the xchacha20poly1305 secretstream construction so
+/// that files of any size can be encrypted and decrypted without holding them in
+/// memory. Objects are named by a keyed blake2b hash of their contents, which
+/// allows identical files to be stored once without revealing the plain hash of
+/// the contents to anyone who can read the backup directory.
+///
+pub(crate) struct BackupKey {
+    stream_key: secretstream::Key,
+    hash_key: [u8; HASH_KEYBYTES],
+}
+
+impl BackupKey {
+    pub fn new_salt() -> Salt {
+        pwhash::gen_salt()
+    }
+
+    pub fn salt_from_hex(hex: &str) -> Result<Salt> {
+        let bytes = hex::decode(hex)
+            .map_err(|_| format_err!("invalid salt in backup repository config"))?;
+        Salt::from_slice(&bytes)
+            .ok_or_else(|| format_err!("salt in backup repository config must be {} bytes", SALTBYTES))
+    }
+
+    pub fn derive(passphrase: &str, salt: &Salt) -> Result<Self> {
+        let mut keybuf = [0; secretstream::KEYBYTES + HASH_KEYBYTES];
+        pwhash::derive_key(&mut keybuf, passphrase.as_bytes(), salt, pwhash::OPSLIMIT_INTERACTIVE, pwhash::MEMLIMIT_INTERACTIVE)
+            .map_err(|_| format_err!("failed to derive backup key from passphrase"))?;
+
+        let (stream, hash) = keybuf.split_at(secretstream::KEYBYTES);
+        let stream_key = secretstream::Key::from_slice(stream)
+            .ok_or_else(|| format_err!("failed to create backup encryption key"))?;
+        let mut hash_key = [0; HASH_KEYBYTES];
+        hash_key.copy_from_slice(hash);
+        keybuf.iter_mut().for_each(|b| *b = 0);
+
+        Ok(BackupKey { stream_key, hash_key })
+    }
+
+    fn hasher(&self) -> Result<generichash::State> {
+        generichash::State::new(HASH_BYTES, Some(&self.hash_key))
+            .map_err(|_| format_err!("failed to initialize object hash"))
+    }
+
+    /// Encrypt everything read from `input` to `output` and return the object id
+    /// of the plaintext.
+    pub fn encrypt<R: Read, W: Write>(&self, mut input: R, mut output: W) -> Result<String> {
+        let (mut stream, header) = Stream::init_push(&self.stream_key)
+            .map_err(|_| format_err!("failed to initialize encryption stream"))?;
+        output.write_all(header.as_ref())
+            .map_err(context!("error writing encrypted object"))?;
+
+        let mut hasher = self.hasher()?;
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut ciphertext = Vec::with_capacity(CHUNK_SIZE + ABYTES);
+        loop {
+            let n = read_chunk(&mut input, &mut buffer)
+                .map_err(context!("error reading data to encrypt"))?;
+            let chunk = &buffer[..n];
+            hasher.update(chunk)
+                .map_err(|_| format_err!("failed to update object hash"))?;
+
+            let tag = if n < CHUNK_SIZE { Tag::Final } else { Tag::Message };
+            ciphertext.clear();
+            stream.push_to_vec(chunk, None, tag, &mut ciphertext)
+                .map_err(|_| format_err!("failed to encrypt backup data"))?;
+            output.write_u32::<LittleEndian>(ciphertext.len() as u32)
+                .and_then(|_| output.write_all(&ciphertext))
+                .map_err(context!("error writing encrypted object"))?;
+
+            if tag == Tag::Final {
+                break;
+            }
+        }
+        Self::finish_hash(hasher)
+    }
+
+    /// Decrypt an object read from `input` to `output` and return the object id of
+    /// the decrypted contents. Fails if any part of the object has been modified
+    /// or if the object is truncated.
+    pub fn decrypt<R: Read, W: Write>(&self, mut input: R, mut output: W) -> Result<String> {
+        let mut header = [0u8; HEADERBYTES];
+        input.read_exact(&mut header)
+            .map_err(context!("error reading encrypted object header"))?;
+        let header = Header::from_slice(&header)
+            .ok_or_else(|| format_err!("invalid encrypted object header"))?;
+        let mut stream = Stream::init_pull(&header, &self.stream_key)
+            .map_err(|_| format_err!("failed to initialize decryption stream"))?;
+
+        let mut hasher = self.hasher()?;
+        let mut ciphertext = Vec::with_capacity(CHUNK_SIZE + ABYTES);
+        let mut plaintext = Vec::with_capacity(CHUNK_SIZE);
+        while !stream.is_finalized() {
+            let len = input.read_u32::<LittleEndian>()
+                .map_err(context!("encrypted object is truncated"))? as usize;
+            if len > CHUNK_SIZE + ABYTES {
+                bail!("encrypted object has invalid chunk length {}", len);
+            }
+            ciphertext.resize(len, 0);
+            input.read_exact(&mut ciphertext)
+                .map_err(context!("encrypted object is truncated"))?;
+            plaintext.clear();
+            stream.pull_to_vec(&ciphertext, None, &mut plaintext)
+                .map_err(|_| format_err!("failed to decrypt backup data, wrong passphrase or corrupted object"))?;
+            hasher.update(&plaintext)
+                .map_err(|_| format_err!("failed to update object hash"))?;
+            output.write_all(&plaintext)
+                .map_err(context!("error writing decrypted data"))?;
+        }
+        Self::finish_hash(hasher)
+    }
+
+    /// Encrypt a small value held in memory.
+    pub fn seal(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.encrypt(data, &mut out)?;
+        Ok(out)
+    }
+
+    /// Decrypt a value encrypted with `seal()`.
+    pub fn open(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.decrypt(data, &mut out)?;
+        Ok(out)
+    }
+
+    fn finish_hash(hasher: generichash::State) -> Result<String> {
+        let digest = hasher.finalize()
+            .map_err(|_| format_err!("failed to finalize object hash"))?;
+        Ok(hex::encode(digest.as_ref()))
+    }
+}
+
+/// Fill `buffer` from `input`, returning fewer bytes than the size of the buffer only
+/// at end of input.
+fn read_chunk<R: Read>(input: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buffer.len() {
+        match input.read(&mut buffer[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(total)
+}
+
+#[test]
+fn test_encrypt_roundtrip() {
+    let salt = BackupKey::new_salt();
+    let key = BackupKey::derive("correct horse", &salt).unwrap();
+
+    let data = (0..CHUNK_SIZE * 2 + 17).map(|i| i as u8).collect::<Vec<_>>();
+    let mut encrypted = Vec::new();
+    let id = key.encrypt(&data[..], &mut encrypted).unwrap();
+
+    let mut decrypted = Vec::new();
+    assert_eq!(key.decrypt(&encrypted[..], &mut decrypted).unwrap(), id);
+    assert_eq!(decrypted, data);
+
+    let empty = key.seal(&[]).unwrap();
+    assert!(key.open(&empty).unwrap().is_empty());
+
+    let wrong = BackupKey::derive("wrong horse", &salt).unwrap();
+    assert!(wrong.decrypt(&encrypted[..], &mut Vec::new()).is_err());
+
+    encrypted.truncate(encrypted.len() - 10);
+    assert!(key.decrypt(&encrypted[..], &mut Vec::new()).is_err());
+}
@@ -0,0 +1,17 @@
+//!
+//! Encrypted incremental backups of realms and RealmFS images.
+//!
+//! A backup is written as a `Snapshot` into a `BackupRepository` by `Backup`,
+//! and items can be restored from any snapshot with `Restore`.
+//!
+
+mod crypto;
+mod create;
+mod repository;
+mod restore;
+mod snapshot;
+
+pub use self::create::Backup;
+pub use self::repository::{BackupRepository, VerifyReport};
+pub use self::restore::Restore;
+pub use self::snapshot::{Snapshot, BackupItem, ItemKind, FileEntry, EntryKind, Retention};
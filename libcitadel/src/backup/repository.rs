@@ -0,0 +1,313 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use sodiumoxide::randombytes::randombytes;
+
+use crate::backup::crypto::BackupKey;
+use crate::backup::snapshot::{Retention, Snapshot};
+use crate::{AuditLog, FileLock, Result, util};
+
+const REPOSITORY_VERSION: u32 = 1;
+
+const CONFIG_FILE: &str = "backup.toml";
+const KEY_CHECK_FILE: &str = "key-check";
+const LOCK_FILE: &str = "backup.lock";
+const OBJECTS_DIR: &str = "objects";
+const SNAPSHOTS_DIR: &str = "snapshots";
+
+#[derive(Serialize,Deserialize)]
+struct RepositoryConfig {
+    version: u32,
+    id: String,
+    salt: String,
+    #[serde(default)]
+    retention: Retention,
+}
+
+/// Result of verifying the objects of a backup repository.
+pub struct VerifyReport {
+    pub snapshots: usize,
+    pub objects: usize,
+    pub errors: Vec<String>,
+}
+
+///
+/// A directory on a local disk or mounted drive which stores encrypted backups.
+///
+/// ```text
+/// backup.toml              repository id, key salt and retention rules
+/// key-check                repository id encrypted with the backup key
+/// snapshots/ID             encrypted manifest of each backup
+/// objects/XX/XXXXXXXX...   encrypted file contents and btrfs send streams
+/// ```
+///
+/// Objects are shared between snapshots, so each backup only stores the files
+/// which have changed since the previous backup. Objects which are no longer
+/// referenced by any snapshot are removed when the repository is pruned.
+///
+pub struct BackupRepository {
+    path: PathBuf,
+    config: RepositoryConfig,
+    key: BackupKey,
+}
+
+impl BackupRepository {
+
+    /// Create a new empty repository in the directory `path` encrypted with `passphrase`.
+    pub fn init(path: impl AsRef<Path>, passphrase: &str, retention: Retention) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if path.join(CONFIG_FILE).exists() {
+            bail!("a backup repository already exists in {}", path.display());
+        }
+        if passphrase.is_empty() {
+            bail!("backup passphrase cannot be empty");
+        }
+        util::create_dir(path.join(OBJECTS_DIR))?;
+        util::create_dir(path.join(SNAPSHOTS_DIR))?;
+
+        let salt = BackupKey::new_salt();
+        let config = RepositoryConfig {
+            version: REPOSITORY_VERSION,
+            id: hex::encode(randombytes(8)),
+            salt: hex::encode(&salt.0[..]),
+            retention,
+        };
+        let key = BackupKey::derive(passphrase, &salt)?;
+        util::write_file(path.join(KEY_CHECK_FILE), key.seal(config.id.as_bytes())?)?;
+
+        let repo = BackupRepository { path, config, key };
+        repo.write_config()?;
+        AuditLog::record("backup", format!("created backup repository {}", repo.path.display()));
+        Ok(repo)
+    }
+
+    /// Open an existing repository, failing if `passphrase` is not correct.
+    pub fn open(path: impl AsRef<Path>, passphrase: &str) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let config_path = path.join(CONFIG_FILE);
+        if !config_path.exists() {
+            bail!("no backup repository found in {}", path.display());
+        }
+        let config = util::read_to_string(&config_path)?;
+        let config = toml::from_str::<RepositoryConfig>(&config)
+            .map_err(context!("failed to parse backup repository config {:?}", config_path))?;
+        if config.version != REPOSITORY_VERSION {
+            bail!("backup repository {} has unsupported version {}", path.display(), config.version);
+        }
+
+        let salt = BackupKey::salt_from_hex(&config.salt)?;
+        let key = BackupKey::derive(passphrase, &salt)?;
+        let check = fs::read(path.join(KEY_CHECK_FILE))
+            .map_err(context!("failed to read key check file in {:?}", path))?;
+        match key.open(&check) {
+            Ok(ref id) if id == config.id.as_bytes() => {},
+            _ => bail!("incorrect passphrase for backup repository {}", path.display()),
+        }
+        Ok(BackupRepository { path, config, key })
+    }
+
+    fn write_config(&self) -> Result<()> {
+        let config = toml::to_string(&self.config)
+            .map_err(context!("failed to serialize backup repository config"))?;
+        util::write_file(self.path.join(CONFIG_FILE), config)
+    }
+
+    /// Hold an exclusive lock on the repository while it is being modified.
+    pub fn lock(&self) -> Result<FileLock> {
+        match FileLock::nonblocking_acquire(self.path.join(LOCK_FILE))? {
+            Some(lock) => Ok(lock),
+            None => bail!("backup repository {} is in use by another process", self.path.display()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Random identifier of this repository.
+    pub fn id(&self) -> &str {
+        &self.config.id
+    }
+
+    pub fn retention(&self) -> Retention {
+        self.config.retention
+    }
+
+    pub fn set_retention(&mut self, retention: Retention) -> Result<()> {
+        self.config.retention = retention;
+        self.write_config()
+    }
+
+    fn object_path(&self, id: &str) -> PathBuf {
+        let prefix = id.get(..2).unwrap_or("xx");
+        self.path.join(OBJECTS_DIR).join(prefix).join(id)
+    }
+
+    pub fn has_object(&self, id: &str) -> bool {
+        self.object_path(id).exists()
+    }
+
+    /// Encrypt everything read from `input` into the repository and return the
+    /// object id. If an object with the same contents already exists the new copy
+    /// is discarded.
+    pub fn store_object<R: Read>(&self, input: R) -> Result<String> {
+        let tmp = self.path.join(OBJECTS_DIR).join(format!(".tmp-{}", hex::encode(randombytes(8))));
+        let result = File::create(&tmp)
+            .map_err(context!("failed to create object file {:?}", tmp))
+            .and_then(|file| {
+                let mut output = BufWriter::new(file);
+                let id = self.key.encrypt(input, &mut output)?;
+                output.flush().map_err(context!("error writing object file {:?}", tmp))?;
+                Ok(id)
+            });
+
+        let id = match result {
+            Ok(id) => id,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                return Err(e);
+            }
+        };
+
+        let path = self.object_path(&id);
+        if path.exists() {
+            util::remove_file(&tmp)?;
+        } else {
+            if let Some(parent) = path.parent() {
+                util::create_dir(parent)?;
+            }
+            util::rename(&tmp, &path)?;
+        }
+        Ok(id)
+    }
+
+    /// Decrypt object `id` to `output`, failing if the contents do not match the id.
+    pub fn read_object<W: Write>(&self, id: &str, output: W) -> Result<()> {
+        let path = self.object_path(id);
+        let file = File::open(&path)
+            .map_err(context!("failed to open backup object {}", id))?;
+        let actual = self.key.decrypt(BufReader::new(file), output)
+            .map_err(context!("failed to read backup object {}", id))?;
+        if actual != id {
+            bail!("backup object {} is corrupted", id);
+        }
+        Ok(())
+    }
+
+    fn snapshot_path(&self, id: &str) -> PathBuf {
+        self.path.join(SNAPSHOTS_DIR).join(id)
+    }
+
+    pub fn save_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        let manifest = toml::to_string(snapshot)
+            .map_err(context!("failed to serialize backup snapshot {}", snapshot.id()))?;
+        let sealed = self.key.seal(manifest.as_bytes())?;
+        util::write_file(self.snapshot_path(snapshot.id()), sealed)
+    }
+
+    pub fn load_snapshot(&self, id: &str) -> Result<Snapshot> {
+        let path = self.snapshot_path(id);
+        if !path.exists() {
+            bail!("no snapshot {} in backup repository {}", id, self.path.display());
+        }
+        let sealed = fs::read(&path)
+            .map_err(context!("failed to read backup snapshot {:?}", path))?;
+        let manifest = self.key.open(&sealed)
+            .map_err(context!("failed to decrypt backup snapshot {}", id))?;
+        toml::from_slice(&manifest)
+            .map_err(context!("failed to parse backup snapshot {}", id))
+    }
+
+    /// All snapshots in the repository from oldest to newest.
+    pub fn snapshots(&self) -> Result<Vec<Snapshot>> {
+        let mut ids = Vec::new();
+        util::read_directory(self.path.join(SNAPSHOTS_DIR), |dent| {
+            if let Some(name) = dent.file_name().to_str() {
+                ids.push(name.to_string());
+            }
+            Ok(())
+        })?;
+        let mut snapshots = ids.iter()
+            .map(|id| self.load_snapshot(id))
+            .collect::<Result<Vec<_>>>()?;
+        snapshots.sort_by_key(|s| s.timestamp());
+        Ok(snapshots)
+    }
+
+    pub fn latest_snapshot(&self) -> Result<Option<Snapshot>> {
+        Ok(self.snapshots()?.pop())
+    }
+
+    /// Remove the snapshots which are not kept by the retention rules and any
+    /// objects no longer used by the remaining snapshots. Returns the ids of the
+    /// removed snapshots.
+    pub fn prune(&self) -> Result<Vec<String>> {
+        let snapshots = self.snapshots()?;
+        let keep = self.config.retention.select(&snapshots);
+        let mut removed = Vec::new();
+        for snapshot in snapshots.iter().filter(|s| !keep.contains(s.id())) {
+            util::remove_file(self.snapshot_path(snapshot.id()))?;
+            removed.push(snapshot.id().to_string());
+        }
+        let objects = self.remove_unused_objects()?;
+        if !removed.is_empty() {
+            info!("Removed {} snapshots and {} objects from backup repository", removed.len(), objects);
+            AuditLog::record("backup", format!("pruned snapshots {} from {}", removed.join(", "), self.path.display()));
+        }
+        Ok(removed)
+    }
+
+    fn remove_unused_objects(&self) -> Result<usize> {
+        let used = self.snapshots()?.iter()
+            .flat_map(|s| s.items().iter())
+            .flat_map(|item| item.objects())
+            .map(|id| id.to_string())
+            .collect::<HashSet<_>>();
+
+        let mut count = 0;
+        util::read_directory(self.path.join(OBJECTS_DIR), |prefix| {
+            if !prefix.path().is_dir() {
+                // left behind by an interrupted backup
+                if prefix.file_name().to_string_lossy().starts_with(".tmp-") {
+                    util::remove_file(prefix.path())?;
+                }
+                return Ok(())
+            }
+            util::read_directory(prefix.path(), |dent| {
+                let name = dent.file_name();
+                if !used.contains(name.to_string_lossy().as_ref()) {
+                    util::remove_file(dent.path())?;
+                    count += 1;
+                }
+                Ok(())
+            })
+        })?;
+        Ok(count)
+    }
+
+    /// Decrypt every object used by snapshot `id`, or by every snapshot if `id` is
+    /// `None`, and check that the contents match the object ids.
+    pub fn verify(&self, id: Option<&str>) -> Result<VerifyReport> {
+        let snapshots = match id {
+            Some(id) => vec![self.load_snapshot(id)?],
+            None => self.snapshots()?,
+        };
+        let mut checked = HashSet::new();
+        let mut errors = Vec::new();
+        for snapshot in &snapshots {
+            for item in snapshot.items() {
+                for object in item.objects() {
+                    if !checked.insert(object.to_string()) {
+                        continue;
+                    }
+                    if let Err(e) = self.read_object(object, io::sink()) {
+                        errors.push(format!("{} ({}): {}", item.name, snapshot.id(), e));
+                    }
+                }
+            }
+        }
+        Ok(VerifyReport { snapshots: snapshots.len(), objects: checked.len(), errors })
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use nix::errno::Errno;
+use nix::fcntl::AtFlags;
+use nix::sys::stat::{fstatat, futimens, mkdirat, utimensat, Mode, SFlag, UtimensatFlags};
+use nix::sys::time::{TimeSpec, TimeValLike};
+use nix::unistd::{fchownat, symlinkat, FchownatFlags, Gid, Uid};
+use sodiumoxide::randombytes::randombytes;
+
+use crate::backup::repository::BackupRepository;
+use crate::backup::snapshot::{BackupItem, EntryKind, FileEntry, ItemKind};
+use crate::realm::quota::{self, is_btrfs};
+use crate::{AuditLog, Result, util};
+
+const BTRFS_PATH: &str = "/usr/bin/btrfs";
+
+///
+/// Restores a single item from a backup snapshot, or a single file or directory
+/// within the item.
+///
+/// Files are written over any existing files at the destination, but files at
+/// the destination which are not in the backup are left in place. Every file is
+/// created relative to a descriptor of its parent directory, and directories are
+/// opened one component at a time below the destination without following
+/// symlinks, so symlinks in the destination cannot redirect the restore to
+/// somewhere outside of it. Items stored
+/// as btrfs send streams are received into a temporary directory on the same
+/// filesystem as the destination and then copied into place with reflinks.
+///
+pub struct Restore<'a> {
+    repo: &'a BackupRepository,
+    item: BackupItem,
+    path: Option<String>,
+    target: PathBuf,
+}
+
+impl <'a> Restore<'a> {
+    pub fn new(repo: &'a BackupRepository, snapshot: &str, item: &str) -> Result<Self> {
+        let snapshot = repo.load_snapshot(snapshot)?;
+        let item = match snapshot.item(item) {
+            Some(item) => item.clone(),
+            None => bail!("snapshot {} does not contain an item named {}", snapshot.id(), item),
+        };
+        let target = item.source.clone();
+        Ok(Restore { repo, item, path: None, target })
+    }
+
+    /// Only restore the file or directory `path` relative to the item.
+    pub fn path(mut self, path: &str) -> Self {
+        let path = path.trim_matches('/');
+        if !path.is_empty() {
+            self.path = Some(path.to_string());
+        }
+        self
+    }
+
+    /// Restore to `target` instead of the location the item was backed up from.
+    pub fn target(mut self, target: impl Into<PathBuf>) -> Self {
+        self.target = target.into();
+        self
+    }
+
+    pub fn run(self) -> Result<()> {
+        info!("Restoring {} to {}", self.item.name, self.target.display());
+        match self.item.kind {
+            ItemKind::Files => self.restore_files()?,
+            ItemKind::Btrfs => self.restore_subvolume()?,
+        }
+        let what = match self.path {
+            Some(ref path) => format!("{}/{}", self.item.name, path),
+            None => self.item.name.clone(),
+        };
+        AuditLog::record("backup", format!("restored {} to {}", what, self.target.display()));
+        Ok(())
+    }
+
+    fn is_selected(&self, entry_path: &str) -> bool {
+        match self.path {
+            Some(ref path) => entry_path == path || entry_path.starts_with(&format!("{}/", path)),
+            None => true,
+        }
+    }
+
+    fn restore_files(&self) -> Result<()> {
+        let entries = self.item.files.iter()
+            .filter(|e| self.is_selected(&e.path))
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            bail!("{} is not in backup item {}", self.path.as_deref().unwrap_or(""), self.item.name);
+        }
+
+        util::create_dir(&self.target)?;
+        let root = util::open_directory(&self.target)?;
+        for entry in &entries {
+            self.restore_entry(&root, entry)?;
+        }
+        // Set directory times last since restoring files into a directory changes them
+        for entry in entries.iter().filter(|e| e.kind == EntryKind::Dir) {
+            let dir = util::open_directory_beneath(&root, Path::new(&entry.path), None)?;
+            Self::set_mtime(&dir, entry.mtime)
+                .map_err(context!("failed to set modification time of {:?}", self.destination(entry)))?;
+        }
+        Ok(())
+    }
+
+    fn destination(&self, entry: &FileEntry) -> PathBuf {
+        if entry.path.is_empty() {
+            self.target.clone()
+        } else {
+            self.target.join(&entry.path)
+        }
+    }
+
+    /// Open the directory below `root` which contains `entry`, creating any
+    /// missing directories, and return it with the file name of the entry. The
+    /// file name is `None` for the entry which describes `root` itself.
+    fn open_parent<'e>(root: &File, entry: &'e FileEntry) -> Result<(File, Option<&'e OsStr>)> {
+        let path = Path::new(&entry.path);
+        match path.file_name() {
+            Some(name) => {
+                let parent = path.parent().unwrap_or_else(|| Path::new(""));
+                let owner = (Uid::effective().as_raw(), Gid::effective().as_raw());
+                Ok((util::open_directory_beneath(root, parent, Some(owner))?, Some(name)))
+            },
+            None => {
+                let root = root.try_clone()
+                    .map_err(context!("failed to duplicate directory file descriptor"))?;
+                Ok((root, None))
+            },
+        }
+    }
+
+    fn restore_entry(&self, root: &File, entry: &FileEntry) -> Result<()> {
+        let dest = self.destination(entry);
+        let (parent, name) = Self::open_parent(root, entry)?;
+        let name = match name {
+            Some(name) => name,
+            None if entry.kind == EntryKind::Dir => return Self::set_attributes(&parent, entry, &dest),
+            None => bail!("backup entry for {} is not a directory", dest.display()),
+        };
+
+        let existing = fstatat(parent.as_raw_fd(), name, AtFlags::AT_SYMLINK_NOFOLLOW).ok()
+            .map(|st| SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT);
+        if existing == Some(SFlag::S_IFLNK) {
+            util::unlinkat(&parent, name)?;
+        }
+
+        match entry.kind {
+            EntryKind::Dir => {
+                match mkdirat(parent.as_raw_fd(), name, Mode::S_IRWXU) {
+                    Ok(()) | Err(nix::Error::Sys(Errno::EEXIST)) => {},
+                    Err(e) => bail!("failed to create directory {:?}: {}", dest, e),
+                }
+                let dir = util::openat(&parent, name, libc::O_RDONLY | libc::O_DIRECTORY, 0)
+                    .map_err(context!("failed to open directory {:?}", dest))?;
+                Self::set_attributes(&dir, entry, &dest)
+            },
+            EntryKind::File => {
+                let object = entry.object.as_ref()
+                    .ok_or_else(|| format_err!("backup entry for {} has no contents", entry.path))?;
+                let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC;
+                let file = util::openat(&parent, name, flags, 0o600)
+                    .map_err(context!("failed to create {:?}", dest))?;
+                let mut output = BufWriter::new(&file);
+                self.repo.read_object(object, &mut output)?;
+                output.flush().map_err(context!("error writing {:?}", dest))?;
+                drop(output);
+                Self::set_attributes(&file, entry, &dest)
+            },
+            EntryKind::Symlink => {
+                let target = entry.target.as_ref()
+                    .ok_or_else(|| format_err!("backup entry for {} has no symlink target", entry.path))?;
+                if existing.is_some() && existing != Some(SFlag::S_IFLNK) {
+                    bail!("cannot restore symlink {} over existing file", dest.display());
+                }
+                let fd = Some(parent.as_raw_fd());
+                symlinkat(target.as_str(), fd, name)
+                    .map_err(context!("failed to create symlink {:?}", dest))?;
+                fchownat(fd, name, Some(Uid::from_raw(entry.uid)), Some(Gid::from_raw(entry.gid)), FchownatFlags::NoFollowSymlink)
+                    .map_err(context!("failed to set owner of symlink {:?}", dest))?;
+                let time = TimeSpec::seconds(entry.mtime);
+                utimensat(fd, name, &time, &time, UtimensatFlags::NoFollowSymlink)
+                    .map_err(context!("failed to set modification time of {:?}", dest))
+            },
+        }
+    }
+
+    fn set_attributes(file: &File, entry: &FileEntry, dest: &Path) -> Result<()> {
+        util::fchown(file, entry.uid, entry.gid)?;
+        file.set_permissions(fs::Permissions::from_mode(entry.mode))
+            .map_err(context!("failed to set permissions of {:?}", dest))?;
+        Self::set_mtime(file, entry.mtime)
+            .map_err(context!("failed to set modification time of {:?}", dest))
+    }
+
+    fn set_mtime(file: &File, mtime: i64) -> nix::Result<()> {
+        let time = TimeSpec::seconds(mtime);
+        futimens(file.as_raw_fd(), &time, &time)
+    }
+
+    fn restore_subvolume(&self) -> Result<()> {
+        let parent = self.target.parent()
+            .ok_or_else(|| format_err!("invalid restore target {}", self.target.display()))?;
+        util::create_dir(parent)?;
+        if !is_btrfs(parent) {
+            bail!("{} was backed up as a btrfs subvolume and can only be restored to a btrfs filesystem", self.item.name);
+        }
+
+        let tmp = parent.join(format!(".restore-{}", hex::encode(randombytes(4))));
+        util::create_dir(&tmp)?;
+        let result = self.receive_streams(&tmp)
+            .and_then(|received| self.copy_received(&received));
+
+        let cleanup = util::read_directory(&tmp, |dent| quota::delete_subvolume(&dent.path()))
+            .and_then(|_| fs::remove_dir(&tmp).map_err(context!("failed to remove {:?}", tmp)));
+        if let Err(e) = cleanup {
+            warn!("Failed to remove temporary restore directory {}: {}", tmp.display(), e);
+        }
+        result
+    }
+
+    /// Receive the chain of send streams into `dir` and return the path of the
+    /// subvolume created by the last stream.
+    fn receive_streams(&self, dir: &Path) -> Result<PathBuf> {
+        let mut last = None;
+        for stream in &self.item.streams {
+            let before = Self::subvolume_names(dir)?;
+            let mut child = Command::new(BTRFS_PATH)
+                .arg("receive").arg("-q").arg(dir)
+                .stdin(Stdio::piped())
+                .spawn()
+                .map_err(context!("failed to execute {}", BTRFS_PATH))?;
+
+            let written = {
+                let stdin = child.stdin.take().expect("btrfs receive stdin");
+                self.repo.read_object(stream, BufWriter::new(stdin))
+            };
+            let status = child.wait()
+                .map_err(context!("error waiting for btrfs receive to exit"))?;
+            written?;
+            if !status.success() {
+                bail!("btrfs receive of backup stream {} failed", stream);
+            }
+
+            last = Self::subvolume_names(dir)?.difference(&before).next().cloned();
+        }
+        last.map(|name| dir.join(name))
+            .ok_or_else(|| format_err!("no subvolume was received for {}", self.item.name))
+    }
+
+    fn subvolume_names(dir: &Path) -> Result<HashSet<String>> {
+        let mut names = HashSet::new();
+        util::read_directory(dir, |dent| {
+            names.insert(dent.file_name().to_string_lossy().to_string());
+            Ok(())
+        })?;
+        Ok(names)
+    }
+
+    fn copy_received(&self, received: &Path) -> Result<()> {
+        if self.path.is_none() && !self.target.exists() {
+            return cmd!(BTRFS_PATH, "subvolume snapshot {} {}", received.display(), self.target.display());
+        }
+        let (from, to) = match self.path {
+            Some(ref path) => (received.join(path), self.target.join(path)),
+            None => (received.to_path_buf(), self.target.clone()),
+        };
+        if !from.exists() {
+            bail!("{} is not in backup item {}", self.path.as_deref().unwrap_or(""), self.item.name);
+        }
+        if from.is_dir() {
+            util::create_dir(&to)?;
+            cmd!("/usr/bin/cp", "-a --reflink=auto {}/. {}", from.display(), to.display())
+        } else {
+            if let Some(parent) = to.parent() {
+                util::create_dir(parent)?;
+            }
+            cmd!("/usr/bin/cp", "-a --reflink=auto {} {}", from.display(), to.display())
+        }
+    }
+}
+
+#[test]
+fn test_restore_through_symlinked_parent() {
+    use crate::backup::snapshot::Retention;
+
+    let base = std::env::temp_dir().join(format!("citadel-restore-test-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&base);
+    let (target, outside) = (base.join("target"), base.join("outside"));
+    util::create_dir(&target).unwrap();
+    util::create_dir(&outside).unwrap();
+
+    let repo = BackupRepository::init(base.join("repo"), "passphrase", Retention::default()).unwrap();
+    let object = repo.store_object(&b"restored"[..]).unwrap();
+    let (uid, gid) = (Uid::effective().as_raw(), Gid::effective().as_raw());
+    let entry = |path: &str, kind, object: Option<String>| FileEntry {
+        path: path.to_string(), kind, mode: 0o644, uid, gid, mtime: 0, size: 8, object, target: None,
+    };
+    let mut item = BackupItem::new("test", &target, ItemKind::Files);
+    item.files.push(entry("Documents/file.txt", EntryKind::File, Some(object)));
+    let restore = Restore { repo: &repo, item, path: None, target: target.clone() };
+
+    std::os::unix::fs::symlink(&outside, target.join("Documents")).unwrap();
+    assert!(restore.restore_files().is_err());
+    assert!(!outside.join("file.txt").exists());
+
+    fs::remove_file(target.join("Documents")).unwrap();
+    restore.restore_files().unwrap();
+    assert_eq!(util::read_to_string(target.join("Documents/file.txt")).unwrap(), "restored");
+
+    let _ = fs::remove_dir_all(&base);
+}
@@ -0,0 +1,208 @@
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::audit::format_timestamp;
+
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ItemKind {
+    /// Files stored individually, each regular file as one object.
+    Files,
+    /// A btrfs subvolume stored as a chain of `btrfs send` streams.
+    Btrfs,
+}
+
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// A file, directory or symlink in an item stored as `ItemKind::Files`.
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct FileEntry {
+    /// Path relative to the source directory of the item.
+    pub path: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub size: u64,
+    /// Object containing the contents of a regular file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub object: Option<String>,
+    /// Target of a symlink.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+/// One thing which has been backed up, such as the home directory of a realm
+/// or a RealmFS image.
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct BackupItem {
+    /// Name used to select the item to restore, for example `realm-main/home`.
+    pub name: String,
+    /// Location the item was backed up from and is restored to by default.
+    pub source: PathBuf,
+    pub kind: ItemKind,
+    /// For `ItemKind::Btrfs` the send streams which must be received in order
+    /// to recreate the subvolume, starting with a full stream followed by
+    /// incremental streams.
+    #[serde(default)]
+    pub streams: Vec<String>,
+    #[serde(default)]
+    pub files: Vec<FileEntry>,
+}
+
+impl BackupItem {
+    pub fn new(name: &str, source: impl Into<PathBuf>, kind: ItemKind) -> Self {
+        BackupItem {
+            name: name.to_string(),
+            source: source.into(),
+            kind,
+            streams: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Every object this item refers to.
+    pub fn objects(&self) -> impl Iterator<Item=&str> {
+        self.streams.iter()
+            .map(|s| s.as_str())
+            .chain(self.files.iter().filter_map(|f| f.object.as_deref()))
+    }
+
+    /// Total size of the files in this item. Not known for btrfs items.
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+}
+
+/// The manifest of a single backup run.
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct Snapshot {
+    id: String,
+    timestamp: u64,
+    #[serde(default)]
+    items: Vec<BackupItem>,
+}
+
+impl Snapshot {
+    pub fn new(timestamp: u64) -> Self {
+        // 2020-03-14 12:30:05 -> 20200314-123005
+        let id = format_timestamp(timestamp)
+            .replace([':', '-'], "")
+            .replace(' ', "-");
+        Snapshot { id, timestamp, items: Vec::new() }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn items(&self) -> &[BackupItem] {
+        &self.items
+    }
+
+    pub fn item(&self, name: &str) -> Option<&BackupItem> {
+        self.items.iter().find(|item| item.name == name)
+    }
+
+    pub fn add_item(&mut self, item: BackupItem) {
+        self.items.push(item);
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}  {}  {} items", self.id, format_timestamp(self.timestamp), self.items.len())
+    }
+}
+
+///
+/// Rules for which snapshots are kept when a backup repository is pruned.
+///
+/// The most recent `keep-last` snapshots are always kept. In addition the most
+/// recent snapshot of each of the last `keep-daily` days and `keep-weekly` weeks
+/// which have a snapshot is kept.
+///
+#[derive(Serialize,Deserialize,Clone,Copy,Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Retention {
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention { keep_last: 3, keep_daily: 7, keep_weekly: 4 }
+    }
+}
+
+impl Retention {
+    /// Return the ids of the snapshots in `snapshots` which should be kept.
+    pub fn select(&self, snapshots: &[Snapshot]) -> HashSet<String> {
+        let mut sorted = snapshots.iter().collect::<Vec<_>>();
+        sorted.sort_by_key(|s| Reverse(s.timestamp));
+
+        let mut keep = sorted.iter()
+            .take(self.keep_last)
+            .map(|s| s.id.clone())
+            .collect::<HashSet<_>>();
+
+        Self::keep_periods(&sorted, self.keep_daily, 86400, &mut keep);
+        Self::keep_periods(&sorted, self.keep_weekly, 7 * 86400, &mut keep);
+        keep
+    }
+
+    fn keep_periods(sorted: &[&Snapshot], count: usize, period: u64, keep: &mut HashSet<String>) {
+        let mut last_period = None;
+        let mut kept = 0;
+        for snapshot in sorted {
+            if kept == count {
+                break;
+            }
+            let p = snapshot.timestamp / period;
+            if last_period != Some(p) {
+                keep.insert(snapshot.id.clone());
+                last_period = Some(p);
+                kept += 1;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_retention() {
+    assert_eq!(Snapshot::new(1_584_189_005).id(), "20200314-123005");
+
+    let day = 86400;
+    let start = 1_584_144_000;
+    // Two snapshots a day for 30 days
+    let snapshots = (0..60)
+        .map(|i| Snapshot::new(start + i * (day / 2)))
+        .collect::<Vec<_>>();
+
+    let retention = Retention { keep_last: 3, keep_daily: 7, keep_weekly: 4 };
+    let keep = retention.select(&snapshots);
+    // The last 3, one more from each of 5 more days, and 2 weeks which are not
+    // already covered by the daily snapshots
+    assert!(keep.contains(snapshots[59].id()));
+    assert!(keep.contains(snapshots[57].id()));
+    assert!(!keep.contains(snapshots[56].id()));
+    assert!(keep.contains(snapshots[23].id()));
+    assert_eq!(keep.len(), 3 + 5 + 2);
+
+    let none = Retention { keep_last: 0, keep_daily: 0, keep_weekly: 0 };
+    assert!(none.select(&snapshots).is_empty());
+}
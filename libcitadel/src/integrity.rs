@@ -0,0 +1,312 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+use walkdir::WalkDir;
+
+use crate::{ImageHeader, Partition, RealmFS, ResourceImage, Result, SignatureStatus, util};
+use crate::verity::Verity;
+
+const RESOURCES_PATH: &str = "/storage/resources";
+
+/// Channels of RealmFS images which are sealed locally rather than signed by a
+/// channel key. An image on one of these channels which is unsealed or whose
+/// sealing key is not available is skipped rather than reported as failed.
+const UNSEALED_CHANNELS: &[&str] = &[RealmFS::USER_KEYNAME];
+
+/// Outcome of one check on an image.
+#[derive(Clone,Debug,PartialEq)]
+pub enum CheckResult {
+    Passed,
+    Failed(String),
+    /// The check does not apply to the image or could not be performed
+    Skipped(String),
+}
+
+impl CheckResult {
+    fn from_bool(ok: bool, failure: &str) -> Self {
+        if ok {
+            CheckResult::Passed
+        } else {
+            CheckResult::Failed(failure.to_string())
+        }
+    }
+
+    fn skipped(reason: &str) -> Self {
+        CheckResult::Skipped(reason.to_string())
+    }
+
+    pub fn is_failed(&self) -> bool {
+        matches!(self, CheckResult::Failed(_))
+    }
+
+    fn status(&self) -> &'static str {
+        match self {
+            CheckResult::Passed => "ok",
+            CheckResult::Failed(_) => "FAILED",
+            CheckResult::Skipped(_) => "-",
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let (status, reason) = match self {
+            CheckResult::Passed => ("passed", None),
+            CheckResult::Failed(reason) => ("failed", Some(reason)),
+            CheckResult::Skipped(reason) => ("skipped", Some(reason)),
+        };
+        json!({
+            "status": status,
+            "reason": reason,
+        })
+    }
+}
+
+/// Results of checking a single rootfs partition, resource image or RealmFS image.
+pub struct ImageCheck {
+    kind: &'static str,
+    path: PathBuf,
+    signature: CheckResult,
+    shasum: CheckResult,
+    verity: CheckResult,
+}
+
+impl ImageCheck {
+    fn new(kind: &'static str, path: &Path) -> Self {
+        let not_checked = || CheckResult::skipped("not checked");
+        ImageCheck {
+            kind,
+            path: path.to_path_buf(),
+            signature: not_checked(),
+            shasum: not_checked(),
+            verity: not_checked(),
+        }
+    }
+
+    // An image which could not be loaded fails every check
+    fn load_failed(kind: &'static str, path: &Path, err: impl fmt::Display) -> Self {
+        let failed = || CheckResult::Failed(format!("failed to load image: {}", err));
+        ImageCheck { signature: failed(), shasum: failed(), verity: failed(), ..Self::new(kind, path) }
+    }
+
+    /// One of "rootfs", "resource" or "realmfs".
+    pub fn kind(&self) -> &str {
+        self.kind
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn signature(&self) -> &CheckResult {
+        &self.signature
+    }
+
+    pub fn shasum(&self) -> &CheckResult {
+        &self.shasum
+    }
+
+    pub fn verity(&self) -> &CheckResult {
+        &self.verity
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.signature.is_failed() || self.shasum.is_failed() || self.verity.is_failed()
+    }
+
+    /// Name and reason of each check which failed.
+    pub fn failures(&self) -> impl Iterator<Item=(&'static str, &str)> {
+        vec![("signature", &self.signature), ("sha256", &self.shasum), ("verity", &self.verity)]
+            .into_iter()
+            .filter_map(|(name, result)| match result {
+                CheckResult::Failed(reason) => Some((name, reason.as_str())),
+                _ => None,
+            })
+    }
+}
+
+///
+/// Checks the integrity of every image on the system: the header signatures of
+/// the rootfs partitions, the signature, sha256 and dm-verity hash tree of each
+/// resource image in /storage/resources, and the signature and dm-verity hash
+/// tree of each RealmFS image.
+///
+/// Verifying hash trees reads every block of each image, so it can take a long
+/// time and may be disabled with `verity(false)`. Compressed resource images are
+/// not decompressed, and only have their header signature checked.
+///
+pub struct IntegrityScan {
+    check_verity: bool,
+    checks: Vec<ImageCheck>,
+}
+
+impl IntegrityScan {
+    pub fn new() -> Self {
+        IntegrityScan { check_verity: true, checks: Vec::new() }
+    }
+
+    /// Whether to verify dm-verity hash trees, default `true`.
+    pub fn verity(mut self, check_verity: bool) -> Self {
+        self.check_verity = check_verity;
+        self
+    }
+
+    pub fn run(mut self) -> Result<Self> {
+        self.scan_partitions()?;
+        self.scan_resources();
+        self.scan_realmfs()?;
+        Ok(self)
+    }
+
+    pub fn checks(&self) -> &[ImageCheck] {
+        &self.checks
+    }
+
+    pub fn failed_count(&self) -> usize {
+        self.checks.iter().filter(|c| c.is_failed()).count()
+    }
+
+    fn scan_partitions(&mut self) -> Result<()> {
+        for partition in Partition::rootfs_partitions()? {
+            let mut check = ImageCheck::new("rootfs", partition.path());
+            check.signature = if !partition.is_initialized() {
+                CheckResult::skipped("partition is not initialized")
+            } else if !partition.has_public_key() {
+                CheckResult::Failed("no public key for channel".to_string())
+            } else {
+                CheckResult::from_bool(partition.is_signature_valid(), "header signature is not valid")
+            };
+            check.shasum = CheckResult::skipped("not checked for partitions");
+            check.verity = CheckResult::skipped("not checked for partitions");
+            self.checks.push(check);
+        }
+        Ok(())
+    }
+
+    fn scan_resources(&mut self) {
+        let images = WalkDir::new(RESOURCES_PATH)
+            .max_depth(2)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file() && e.path().extension().map_or(false, |ext| ext == "img"))
+            .map(|e| e.into_path());
+
+        for path in images {
+            let check = match ResourceImage::from_path(&path) {
+                Ok(image) => self.check_resource(&image),
+                Err(err) => ImageCheck::load_failed("resource", &path, err),
+            };
+            self.checks.push(check);
+        }
+    }
+
+    fn check_resource(&self, image: &ResourceImage) -> ImageCheck {
+        let mut check = ImageCheck::new("resource", image.path());
+        check.signature = Self::check_header_signature(image.header());
+        check.shasum = if image.is_compressed() {
+            CheckResult::skipped("image is compressed")
+        } else {
+            match image.generate_shasum() {
+                Ok(shasum) => CheckResult::from_bool(shasum == image.metainfo().shasum(), "sha256 does not match header"),
+                Err(err) => CheckResult::Failed(err.to_string()),
+            }
+        };
+        check.verity = self.check_hashtree(image.path(), image.header(), image.is_compressed());
+        check
+    }
+
+    fn check_header_signature(header: &ImageHeader) -> CheckResult {
+        if !header.has_signature() {
+            return CheckResult::Failed("image is not signed".to_string());
+        }
+        match header.public_key() {
+            Ok(Some(pubkey)) => CheckResult::from_bool(header.verify_signature(pubkey), "header signature is not valid"),
+            Ok(None) => CheckResult::Failed("no public key for channel".to_string()),
+            Err(err) => CheckResult::Failed(err.to_string()),
+        }
+    }
+
+    fn check_hashtree(&self, path: &Path, header: &ImageHeader, is_compressed: bool) -> CheckResult {
+        if !self.check_verity {
+            CheckResult::skipped("verity checks disabled")
+        } else if is_compressed {
+            CheckResult::skipped("image is compressed")
+        } else if !header.has_flag(ImageHeader::FLAG_HASH_TREE) {
+            CheckResult::skipped("image has no hash tree")
+        } else {
+            match Verity::new(path).and_then(|verity| verity.verify()) {
+                Ok(ok) => CheckResult::from_bool(ok, "hash tree does not match image"),
+                Err(err) => CheckResult::Failed(err.to_string()),
+            }
+        }
+    }
+
+    fn scan_realmfs(&mut self) -> Result<()> {
+        let mut paths = Vec::new();
+        util::read_directory(RealmFS::BASE_PATH, |dent| {
+            let path = dent.path();
+            if path.file_name().and_then(|n| n.to_str()).map_or(false, |n| n.ends_with("-realmfs.img")) {
+                paths.push(path);
+            }
+            Ok(())
+        })?;
+        paths.sort();
+
+        for path in paths {
+            let check = match RealmFS::load_from_path(&path) {
+                Ok(realmfs) => self.check_realmfs(&realmfs),
+                Err(err) => ImageCheck::load_failed("realmfs", &path, err),
+            };
+            self.checks.push(check);
+        }
+        Ok(())
+    }
+
+    fn check_realmfs(&self, realmfs: &RealmFS) -> ImageCheck {
+        let mut check = ImageCheck::new("realmfs", realmfs.path());
+        let unsealed_channel = UNSEALED_CHANNELS.contains(&realmfs.metainfo().channel());
+        check.signature = match realmfs.signature_status() {
+            SignatureStatus::Valid => CheckResult::Passed,
+            SignatureStatus::Unsigned if unsealed_channel => CheckResult::skipped("user image is not sealed"),
+            SignatureStatus::NoKey if unsealed_channel => CheckResult::skipped("no sealing key for user image"),
+            SignatureStatus::NoKey => CheckResult::Failed("no public key for channel".to_string()),
+            status => CheckResult::Failed(format!("header signature is {}", status)),
+        };
+        check.shasum = CheckResult::skipped("not checked for RealmFS images");
+        check.verity = self.check_hashtree(realmfs.path(), realmfs.header(), false);
+        check
+    }
+
+    pub fn to_json(&self) -> String {
+        let checks = self.checks.iter().map(|c| json!({
+            "kind": c.kind(),
+            "path": c.path().to_string_lossy(),
+            "signature": c.signature.to_json(),
+            "sha256": c.shasum.to_json(),
+            "verity": c.verity.to_json(),
+        })).collect();
+        Value::Array(checks).to_string()
+    }
+}
+
+impl Default for IntegrityScan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for IntegrityScan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<10} {:<56} {:<10} {:<10} {:<10}", "KIND", "IMAGE", "SIGNATURE", "SHA256", "VERITY")?;
+        for c in &self.checks {
+            writeln!(f, "{:<10} {:<56} {:<10} {:<10} {:<10}", c.kind, c.path.display(),
+                     c.signature.status(), c.shasum.status(), c.verity.status())?;
+        }
+        writeln!(f)?;
+        for c in &self.checks {
+            for (name, reason) in c.failures() {
+                writeln!(f, "{}: {} check failed: {}", c.path.display(), name, reason)?;
+            }
+        }
+        write!(f, "{} images checked, {} failed", self.checks.len(), self.failed_count())
+    }
+}
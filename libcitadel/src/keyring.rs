@@ -21,7 +21,7 @@ use sodiumoxide::crypto::{
     },
 };
 
-use crate::{Result, Error, KeyPair};
+use crate::{Result, Error, KeyPair, AuditLog};
 
 #[derive(Serialize,Deserialize,Debug)]
 pub struct KeyRing {
@@ -84,6 +84,8 @@ impl KeyRing {
             let key = KernelKey::add_key("user", k.as_str(), &bytes, KEY_SPEC_USER_KEYRING)?;
             key.set_perm(0x3f03_0000)?;
         }
+        let names = self.keypairs.keys().map(|s| s.as_str()).collect::<Vec<_>>();
+        AuditLog::record("keys", format!("added keys to kernel keyring: {}", names.join(", ")));
         Ok(())
     }
 
@@ -102,7 +104,9 @@ impl KeyRing {
         let ciphertext = secretbox::seal(&bytes, &nonce, &key);
 
         Self::write_keyring(path.as_ref(), &salt.0, &nonce.0, &ciphertext)
-            .map_err(context!("error writing keyring file {:?}", path.as_ref()))
+            .map_err(context!("error writing keyring file {:?}", path.as_ref()))?;
+        AuditLog::record("keys", format!("wrote keyring file {}", path.as_ref().display()));
+        Ok(())
     }
 
     fn write_keyring(path: &Path, salt: &[u8], nonce: &[u8], ciphertext: &[u8]) -> io::Result<()> {
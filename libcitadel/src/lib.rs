@@ -20,6 +20,9 @@ pub mod symlink;
 mod realm;
 pub mod terminal;
 mod system;
+mod audit;
+mod backup;
+mod integrity;
 
 pub use crate::config::OsRelease;
 pub use crate::blockdev::BlockDev;
@@ -28,16 +31,24 @@ pub use crate::header::{ImageHeader,MetaInfo};
 pub use crate::partition::Partition;
 pub use crate::resource::ResourceImage;
 pub use crate::keys::{KeyPair,PublicKey,Signature};
-pub use crate::realmfs::{RealmFS,Mountpoint};
+pub use crate::realmfs::{RealmFS,Mountpoint,RealmFSBackup,SignatureStatus,UpdateScript,RealmFSImport,ImportSource,RealmFSExport,ExportFormat,RealmFSDiff,FileChange,PackageChange,PublisherTrustStore,Publisher,Provenance,import_signed_image,PackageManifest};
 pub use crate::keyring::{KeyRing,KernelKey};
 pub use crate::exec::{Exec,FileRange};
-pub use crate::realmfs::resizer::ResizeSize;
+pub use crate::realmfs::resizer::{ResizeSize,AutoResizePolicy,ResizeDecision};
 pub use crate::realm::overlay::RealmOverlay;
 pub use crate::realm::realm::Realm;
-pub use crate::realm::config::{RealmConfig,OverlayType,GLOBAL_CONFIG};
+pub use crate::realm::config::{RealmConfig,OverlayType,SoundBackend,GLOBAL_CONFIG};
 pub use crate::realm::events::RealmEvent;
 pub use crate::realm::realms::Realms;
 pub use crate::realm::manager::RealmManager;
+pub use crate::realm::transfer::{FileTransfer,TransferRecord};
+pub use crate::realm::forward::PortForward;
+pub use crate::realm::shared::{SharedFolder,SharedFolderAccess};
+pub use crate::realm::quota::{RealmQuota,DiskUsage};
+pub use crate::realm::encrypted::EncryptedHome;
+pub use crate::audit::{AuditLog,AuditEntry};
+pub use crate::backup::{Backup,BackupRepository,BackupItem,ItemKind,FileEntry,EntryKind,Restore,Retention,Snapshot,VerifyReport};
+pub use crate::integrity::{IntegrityScan,ImageCheck,CheckResult};
 pub use crate::log::{LogLevel,Logger,DefaultLogOutput,LogOutput};
 
 pub use crate::system::{FileLock,Mounts,LoopDevice,UtsName};
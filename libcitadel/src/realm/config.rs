@@ -2,15 +2,16 @@ use std::path::{Path, PathBuf};
 use std::fs;
 use std::os::unix::fs::MetadataExt;
 use toml;
-use crate::{Result, Realms, util};
+use crate::{Result, Realms, AuditLog, util};
 
 lazy_static! {
     pub static ref GLOBAL_CONFIG: RealmConfig = RealmConfig::load_global_config();
 }
 
-const DEFAULT_ZONE: &str = "clear";
+pub(crate) const DEFAULT_ZONE: &str = "clear";
 const DEFAULT_REALMFS: &str = "base";
 const DEFAULT_OVERLAY: &str = "storage";
+const DEFAULT_SOUND_BACKEND: &str = "pulseaudio";
 
 /// Type of rootfs overlay a Realm is configured to use
 #[derive(PartialEq,Debug,Copy,Clone)]
@@ -44,6 +45,39 @@ impl OverlayType {
     }
 }
 
+/// Sound server a realm connects to when sound is enabled
+#[derive(PartialEq,Debug,Copy,Clone)]
+pub enum SoundBackend {
+    /// The PulseAudio socket of the desktop session
+    PulseAudio,
+    /// The native PipeWire socket of the desktop session
+    PipeWire,
+    /// The native PipeWire socket and the PulseAudio socket served by pipewire-pulse
+    PipeWirePulse,
+}
+
+impl SoundBackend {
+    pub fn from_str_value(value: &str) -> Self {
+        match value {
+            "pulseaudio" => SoundBackend::PulseAudio,
+            "pipewire" => SoundBackend::PipeWire,
+            "pipewire-pulse" => SoundBackend::PipeWirePulse,
+            _ => {
+                warn!("Invalid sound backend: '{}'", value);
+                SoundBackend::PulseAudio
+            }
+        }
+    }
+
+    pub fn to_str_value(self) -> &'static str {
+        match self {
+            SoundBackend::PulseAudio => "pulseaudio",
+            SoundBackend::PipeWire => "pipewire",
+            SoundBackend::PipeWirePulse => "pipewire-pulse",
+        }
+    }
+}
+
 /// Content of a Realm configuration file
 #[derive (Serialize,Deserialize,Clone)]
 pub struct RealmConfig {
@@ -59,6 +93,12 @@ pub struct RealmConfig {
     #[serde(rename="use-sound")]
     pub use_sound: Option<bool>,
 
+    #[serde(rename="sound-backend")]
+    pub sound_backend: Option<String>,
+
+    #[serde(rename="use-microphone")]
+    pub use_microphone: Option<bool>,
+
     #[serde(rename="use-x11")]
     pub use_x11: Option<bool>,
 
@@ -86,6 +126,33 @@ pub struct RealmConfig {
     #[serde(rename="reserved-ip")]
     pub reserved_ip: Option<u32>,
 
+    #[serde(rename="encrypted-home")]
+    pub encrypted_home: Option<bool>,
+
+    #[serde(rename="home-quota")]
+    pub home_quota: Option<String>,
+
+    #[serde(rename="overlay-quota")]
+    pub overlay_quota: Option<String>,
+
+    #[serde(rename="shared-folders")]
+    pub shared_folders: Option<Vec<String>>,
+
+    #[serde(rename="port-forwards")]
+    pub port_forwards: Option<Vec<String>>,
+
+    #[serde(rename="gateway-zone")]
+    pub gateway_zone: Option<String>,
+
+    #[serde(rename="dns-servers")]
+    pub dns_servers: Option<Vec<String>>,
+
+    #[serde(rename="dns-search")]
+    pub dns_search: Option<Vec<String>>,
+
+    #[serde(rename="hosts-entries")]
+    pub hosts_entries: Option<Vec<String>>,
+
     #[serde(rename="system-realm")]
     pub system_realm: Option<bool>,
 
@@ -109,6 +176,11 @@ pub struct RealmConfig {
 
     pub netns: Option<String>,
 
+    #[serde(rename="confirm-transfers")]
+    pub confirm_transfers: Option<bool>,
+
+    pub disposable: Option<bool>,
+
     #[serde(skip)]
     pub parent: Option<Box<RealmConfig>>,
 
@@ -153,7 +225,9 @@ impl RealmConfig {
     pub fn write_to<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         let serialized = toml::to_string(self)
             .map_err(context!("failed to serialize realm config"))?;
-        util::write_file(path, serialized)
+        util::write_file(path.as_ref(), &serialized)?;
+        AuditLog::record("config", format!("wrote {}: {}", path.as_ref().display(), serialized.trim().replace('\n', ", ")));
+        Ok(())
     }
 
     pub fn write(&self) -> Result<()> {
@@ -189,6 +263,8 @@ impl RealmConfig {
             use_shared_dir: Some(true),
             use_ephemeral_home: Some(false),
             use_sound: Some(true),
+            sound_backend: Some(DEFAULT_SOUND_BACKEND.into()),
+            use_microphone: Some(true),
             use_x11: Some(true),
             use_wayland: Some(true),
             wayland_socket: Some("wayland-0".to_string()),
@@ -199,6 +275,15 @@ impl RealmConfig {
             ephemeral_persistent_dirs: Some(vec!["Documents".to_string()]),
             network_zone: Some(DEFAULT_ZONE.into()),
             reserved_ip: None,
+            encrypted_home: Some(false),
+            home_quota: None,
+            overlay_quota: None,
+            shared_folders: None,
+            port_forwards: None,
+            gateway_zone: None,
+            dns_servers: None,
+            dns_search: None,
+            hosts_entries: None,
             system_realm: Some(false),
             autostart: Some(false),
             extra_bindmounts: None,
@@ -208,6 +293,8 @@ impl RealmConfig {
             overlay: Some(DEFAULT_OVERLAY.into()),
             terminal_scheme: None,
             netns: None,
+            confirm_transfers: Some(true),
+            disposable: Some(false),
             parent: None,
             loaded: None,
             path: PathBuf::new(),
@@ -219,6 +306,8 @@ impl RealmConfig {
             use_shared_dir: None,
             use_ephemeral_home: None,
             use_sound: None,
+            sound_backend: None,
+            use_microphone: None,
             use_x11: None,
             use_wayland: None,
             wayland_socket: None,
@@ -228,6 +317,15 @@ impl RealmConfig {
             use_network: None,
             network_zone: None,
             reserved_ip: None,
+            encrypted_home: None,
+            home_quota: None,
+            overlay_quota: None,
+            shared_folders: None,
+            port_forwards: None,
+            gateway_zone: None,
+            dns_servers: None,
+            dns_search: None,
+            hosts_entries: None,
             system_realm: None,
             autostart: None,
             extra_bindmounts: None,
@@ -238,6 +336,8 @@ impl RealmConfig {
             overlay: None,
             terminal_scheme: None,
             netns: None,
+            confirm_transfers: None,
+            disposable: None,
             parent: None,
             loaded: None,
             path: PathBuf::new(),
@@ -272,6 +372,18 @@ impl RealmConfig {
         self.bool_value(|c| c.use_shared_dir)
     }
 
+    /// If `true` files which another realm asks to transfer into this realm must
+    /// be approved by the user before they are copied.
+    pub fn confirm_transfers(&self) -> bool {
+        self.bool_value(|c| c.confirm_transfers)
+    }
+
+    /// If `true` this is a disposable realm which is deleted when it is stopped. Not
+    /// inherited from the global realm config.
+    pub fn disposable(&self) -> bool {
+        self.disposable.unwrap_or(false)
+    }
+
     /// If `true` the home directory of this realm will be set up in ephemeral mode.
     ///
     /// The ephemeral home directory is set up with the following steps:
@@ -298,16 +410,29 @@ impl RealmConfig {
         Vec::new()
     }
 
-    /// If `true` allows use of sound inside realm. The following items will be
-    /// added to realm:
-    ///
-    ///   /dev/snd
-    ///   /dev/shm
-    ///   /run/user/1000/pulse
+    /// If `true` allows use of sound inside realm. The sockets of the sound server
+    /// selected by `self.sound_backend()` will be added to the realm.
     pub fn sound(&self) -> bool {
         self.bool_value(|c| c.use_sound)
     }
 
+    /// The sound server a realm connects to if `self.sound()` is `true`:
+    ///
+    ///   pulseaudio        /run/user/1000/pulse
+    ///   pipewire          /run/user/1000/pipewire-0
+    ///   pipewire-pulse    both of the above, with pulse served by pipewire-pulse
+    ///
+    pub fn sound_backend(&self) -> SoundBackend {
+        self.str_value(|c| c.sound_backend.as_ref())
+            .map_or(SoundBackend::PulseAudio, SoundBackend::from_str_value)
+    }
+
+    /// If `false` the realm is only given sockets which permit audio playback and
+    /// cannot record from a microphone. This requires one of the PipeWire backends.
+    pub fn microphone(&self) -> bool {
+        self.bool_value(|c| c.use_microphone)
+    }
+
     /// If `true` access to the X11 server will be added to realm by bind mounting
     /// directory /tmp/.X11-unix
     pub fn x11(&self) -> bool {
@@ -340,6 +465,63 @@ impl RealmConfig {
     }
 
 
+    /// If `true` the realm home directory is kept in a separately encrypted image which
+    /// must be unlocked with its own passphrase before the realm can be started.
+    pub fn encrypted_home(&self) -> bool {
+        self.bool_value(|c| c.encrypted_home)
+    }
+
+    /// Maximum size of the realm home directory such as `20G`, enforced with a btrfs
+    /// qgroup limit on the home subvolume.
+    pub fn home_quota(&self) -> Option<&str> {
+        self.str_value(|c| c.home_quota.as_ref())
+    }
+
+    /// Maximum size of the storage overlay of the realm, if `self.overlay()` is
+    /// `OverlayType::Storage`.
+    pub fn overlay_quota(&self) -> Option<&str> {
+        self.str_value(|c| c.overlay_quota.as_ref())
+    }
+
+    /// Named shared folders to mount in this realm as `NAME:ro` or `NAME:rw`.
+    pub fn shared_folders(&self) -> Vec<&str> {
+        self.str_vec_value(|c| c.shared_folders.as_ref())
+    }
+
+    /// Ports in this realm which can be reached from the host or other realms, in the
+    /// form `PROTOCOL:PORT[:PEER,...]`. See `PortForward` for details. Not inherited
+    /// from the global realm config.
+    pub fn port_forwards(&self) -> Vec<&str> {
+        match self.port_forwards {
+            Some(ref forwards) => forwards.iter().map(|s| s.as_str()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// If set, this realm is the gateway for the named network zone and every realm
+    /// in that zone reaches the network only by routing through this realm.
+    ///
+    /// Unlike most options this is never inherited from the global realm config.
+    pub fn gateway_zone(&self) -> Option<&str> {
+        self.gateway_zone.as_deref()
+    }
+
+    /// Nameserver addresses to use in this realm instead of the servers configured
+    /// for the network zone or the system resolv.conf.
+    pub fn dns_servers(&self) -> Vec<&str> {
+        self.str_vec_value(|c| c.dns_servers.as_ref())
+    }
+
+    /// Domains to add to the search list of resolv.conf in this realm.
+    pub fn dns_search(&self) -> Vec<&str> {
+        self.str_vec_value(|c| c.dns_search.as_ref())
+    }
+
+    /// Extra lines in the form `ADDRESS HOSTNAME [ALIAS...]` to add to /etc/hosts in this realm.
+    pub fn hosts_entries(&self) -> Vec<&str> {
+        self.str_vec_value(|c| c.hosts_entries.as_ref())
+    }
+
     /// If configured, this realm uses a fixed IP address on the zone subnet. The last
     /// octet of the network address for this realm will be set to the provided value.
     pub fn reserved_ip(&self) -> Option<u8> {
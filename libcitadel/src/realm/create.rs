@@ -1,5 +1,6 @@
 use std::path::{PathBuf, Path};
 use crate::{Realms, Result, util};
+use crate::realm::quota;
 use std::fs;
 
 /// Creation and removal of a Realm
@@ -55,7 +56,13 @@ impl RealmCreateDestroy {
     fn create_home(&self) -> Result<()> {
         let home = self.temp_basepath().join("home");
 
-        util::create_dir(&home)?;
+        util::create_dir(self.temp_basepath())?;
+        if quota::is_btrfs(&self.temp_basepath()) {
+            // A subvolume so that disk usage of the home directory can be limited with a qgroup
+            quota::create_subvolume(&home)?;
+        } else {
+            util::create_dir(&home)?;
+        }
         util::chown(&home, 1000, 1000)?;
 
         let skel = Path::new(Realms::BASE_PATH).join("skel");
@@ -97,6 +104,10 @@ impl RealmCreateDestroy {
         }
 
         let realmdir = self.temp_basepath();
+        let home = realmdir.join("home");
+        if quota::is_subvolume(&home) {
+            quota::delete_subvolume(&home)?;
+        }
         info!("removing realm directory {:?}", realmdir);
         fs::remove_dir_all(&realmdir)
             .map_err(context!("error removing realm directory {:?}", realmdir))
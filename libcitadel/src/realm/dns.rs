@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use crate::{Realm, Result, util};
+
+/// The resolv.conf which is bound into realms that have no DNS configuration.
+const SYSTEM_RESOLV_CONF: &str = "/storage/citadel-state/resolv.conf";
+
+const ZONES_CONFIG: &str = "/storage/realms/zones.toml";
+
+/// DNS settings for a network zone as read from the zones file:
+///
+/// ```text
+/// [clear]
+/// dns-servers = ["9.9.9.9"]
+/// dns-search = ["example.com"]
+/// hosts-entries = ["10.0.0.5 printer"]
+/// ```
+///
+#[derive(Deserialize,Default,Clone)]
+pub struct ZoneDnsConfig {
+    #[serde(rename="dns-servers", default)]
+    dns_servers: Vec<String>,
+    #[serde(rename="dns-search", default)]
+    dns_search: Vec<String>,
+    #[serde(rename="hosts-entries", default)]
+    hosts_entries: Vec<String>,
+}
+
+impl ZoneDnsConfig {
+    pub fn load(zone: &str) -> ZoneDnsConfig {
+        match Self::load_zones() {
+            Ok(mut zones) => zones.remove(zone).unwrap_or_default(),
+            Err(e) => {
+                warn!("Failed to load network zone configuration: {}", e);
+                ZoneDnsConfig::default()
+            }
+        }
+    }
+
+    fn load_zones() -> Result<HashMap<String, ZoneDnsConfig>> {
+        let path = Path::new(ZONES_CONFIG);
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+        let s = util::read_to_string(path)?;
+        let zones = toml::from_str(&s)
+            .map_err(context!("failed to parse {}", ZONES_CONFIG))?;
+        Ok(zones)
+    }
+}
+
+///
+/// Generates the resolv.conf and hosts files for a realm.
+///
+/// Nameservers and search domains set in the realm configuration (including the
+/// global realm config) take precedence over the settings of the realm's network
+/// zone. Hosts entries from the zone and the realm are both added. If neither
+/// provides any settings the realm uses the system resolv.conf and the hosts file
+/// from its root filesystem as before.
+///
+pub struct RealmDns<'a> {
+    realm: &'a Realm,
+    servers: Vec<String>,
+    search: Vec<String>,
+    hosts: Vec<String>,
+}
+
+impl <'a> RealmDns<'a> {
+    pub fn new(realm: &'a Realm) -> Self {
+        let config = realm.config();
+        let zone = ZoneDnsConfig::load(config.network_zone());
+
+        let choose = |realm_values: Vec<&str>, zone_values: &[String]| {
+            if realm_values.is_empty() {
+                zone_values.to_vec()
+            } else {
+                realm_values.iter().map(|s| s.to_string()).collect()
+            }
+        };
+
+        let servers = choose(config.dns_servers(), &zone.dns_servers)
+            .into_iter()
+            .filter(|s| Self::check_value("DNS server", s, Self::is_valid_address))
+            .collect();
+
+        let search = choose(config.dns_search(), &zone.dns_search)
+            .into_iter()
+            .filter(|s| Self::check_value("DNS search domain", s, Self::is_valid_hostname))
+            .collect();
+
+        let hosts = zone.hosts_entries.iter()
+            .map(|s| s.as_str())
+            .chain(config.hosts_entries())
+            .filter(|s| Self::check_value("hosts entry", s, Self::is_valid_hosts_entry))
+            .map(|s| s.to_string())
+            .collect();
+
+        RealmDns { realm, servers, search, hosts }
+    }
+
+    fn check_value(kind: &str, value: &str, is_valid: fn(&str) -> bool) -> bool {
+        if is_valid(value) {
+            true
+        } else {
+            warn!("Ignoring invalid {} '{}'", kind, value);
+            false
+        }
+    }
+
+    fn is_valid_address(s: &str) -> bool {
+        s.parse::<IpAddr>().is_ok()
+    }
+
+    fn is_valid_hostname(s: &str) -> bool {
+        !s.is_empty() && s.len() <= 253 &&
+            s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+    }
+
+    fn is_valid_hosts_entry(s: &str) -> bool {
+        let mut fields = s.split_whitespace();
+        match fields.next() {
+            Some(addr) if Self::is_valid_address(addr) => {},
+            _ => return false,
+        }
+        let names = fields.collect::<Vec<_>>();
+        !names.is_empty() && names.iter().all(|name| Self::is_valid_hostname(name))
+    }
+
+    pub fn resolv_conf_path(&self) -> PathBuf {
+        self.realm.run_path_file("resolv.conf")
+    }
+
+    pub fn hosts_path(&self) -> PathBuf {
+        self.realm.run_path_file("hosts")
+    }
+
+    fn has_resolv_conf(&self) -> bool {
+        !(self.servers.is_empty() && self.search.is_empty())
+    }
+
+    fn has_hosts(&self) -> bool {
+        !self.hosts.is_empty()
+    }
+
+    /// Write the generated files and return the nspawn bind mount lines for them.
+    pub fn write_files(&self, rootfs: &Path) -> Result<String> {
+        let mut s = String::new();
+        if self.has_resolv_conf() {
+            util::create_dir(self.realm.run_path())?;
+            util::write_file(self.resolv_conf_path(), self.generate_resolv_conf()?)?;
+            writeln!(s, "BindReadOnly={}:/etc/resolv.conf", self.resolv_conf_path().display())?;
+        } else {
+            writeln!(s, "BindReadOnly={}:/etc/resolv.conf", SYSTEM_RESOLV_CONF)?;
+        }
+
+        if self.has_hosts() {
+            util::create_dir(self.realm.run_path())?;
+            util::write_file(self.hosts_path(), self.generate_hosts(rootfs)?)?;
+            writeln!(s, "BindReadOnly={}:/etc/hosts", self.hosts_path().display())?;
+        }
+        Ok(s)
+    }
+
+    /// Remove any files created by `write_files()`
+    pub fn remove_files(&self) -> Result<()> {
+        for path in &[self.resolv_conf_path(), self.hosts_path()] {
+            if path.exists() {
+                util::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn generate_resolv_conf(&self) -> Result<String> {
+        let mut s = String::new();
+        writeln!(s, "# Generated for realm-{}", self.realm.name())?;
+
+        let system_servers;
+        let servers = if self.servers.is_empty() {
+            system_servers = Self::system_nameservers();
+            &system_servers
+        } else {
+            &self.servers
+        };
+
+        for server in servers {
+            writeln!(s, "nameserver {}", server)?;
+        }
+        if !self.search.is_empty() {
+            writeln!(s, "search {}", self.search.join(" "))?;
+        }
+        Ok(s)
+    }
+
+    /// Nameservers listed in the system resolv.conf, used when only search domains are configured.
+    fn system_nameservers() -> Vec<String> {
+        let content = util::read_to_string(SYSTEM_RESOLV_CONF).unwrap_or_default();
+        content.lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                match (fields.next(), fields.next()) {
+                    (Some("nameserver"), Some(addr)) => Some(addr.to_string()),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    fn generate_hosts(&self, rootfs: &Path) -> Result<String> {
+        let base = rootfs.join("etc/hosts");
+        let mut s = if base.exists() {
+            util::read_to_string(&base)?
+        } else {
+            "127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost ip6-loopback\n".to_string()
+        };
+        if !s.ends_with('\n') {
+            s.push('\n');
+        }
+        writeln!(s)?;
+        writeln!(s, "# Added for realm-{}", self.realm.name())?;
+        for entry in &self.hosts {
+            writeln!(s, "{}", entry)?;
+        }
+        Ok(s)
+    }
+}
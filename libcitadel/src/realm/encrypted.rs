@@ -0,0 +1,244 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::{Realm, Result, AuditLog, Mounts, util};
+
+const CRYPTSETUP_PATH: &str = "/sbin/cryptsetup";
+const MKFS_EXT4_PATH: &str = "/sbin/mkfs.ext4";
+const E2FSCK_PATH: &str = "/sbin/e2fsck";
+
+const MIN_PASSPHRASE_LEN: usize = 8;
+
+///
+/// A realm home directory stored in a LUKS formatted image file under the realm
+/// directory and protected with its own passphrase.
+///
+/// The image is unlocked and mounted over /realms/realm-$NAME/home before the
+/// realm is started, and unmounted and locked again when the realm is stopped,
+/// so the contents of the home directory are only accessible while the realm is
+/// running even though /storage is unlocked.
+///
+pub struct EncryptedHome {
+    realm: String,
+    base_path: PathBuf,
+}
+
+impl EncryptedHome {
+    pub fn new(realm: &Realm) -> Self {
+        EncryptedHome {
+            realm: realm.name().to_string(),
+            base_path: realm.base_path(),
+        }
+    }
+
+    /// Return the encrypted home of `realm` if it is configured with `encrypted-home`.
+    pub fn for_realm(realm: &Realm) -> Option<Self> {
+        if realm.config().encrypted_home() {
+            Some(Self::new(realm))
+        } else {
+            None
+        }
+    }
+
+    pub fn image_path(&self) -> PathBuf {
+        self.base_path.join("home.luks")
+    }
+
+    fn home_path(&self) -> PathBuf {
+        self.base_path.join("home")
+    }
+
+    fn mapper_name(&self) -> String {
+        format!("realm-{}-home", self.realm)
+    }
+
+    fn mapper_path(&self) -> PathBuf {
+        Path::new("/dev/mapper").join(self.mapper_name())
+    }
+
+    pub fn exists(&self) -> bool {
+        self.image_path().exists()
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.mapper_path().exists()
+    }
+
+    /// Create an encrypted image of `size_mb` megabytes and move the current contents
+    /// of the realm home directory into it.
+    ///
+    /// The unencrypted home directory is only removed once the contents have been
+    /// copied and the image has been locked and checked. If any step after the copy
+    /// fails both the image and the unencrypted copy are kept and the error names
+    /// the copy which holds the home directory contents.
+    pub fn create(&self, passphrase: &str, size_mb: u64) -> Result<()> {
+        if self.exists() {
+            bail!("encrypted home image {} already exists", self.image_path().display());
+        }
+        let plain_home = self.plain_home_path();
+        if plain_home.exists() {
+            bail!("unencrypted home directory {} from an earlier attempt already exists", plain_home.display());
+        }
+        if passphrase.len() < MIN_PASSPHRASE_LEN {
+            bail!("passphrase must be at least {} characters long", MIN_PASSPHRASE_LEN);
+        }
+        info!("Creating encrypted home directory image for realm-{}", self.realm);
+        if let Err(e) = self.format_image(passphrase, size_mb).and_then(|_| self.copy_home()) {
+            self.discard_image();
+            return Err(e);
+        }
+
+        if let Err(e) = self.lock().and_then(|_| self.verify_image(passphrase)) {
+            bail!("home directory of realm-{} was copied into {} but the image could not be locked and checked, the unencrypted home directory has been kept at {}: {}",
+                  self.realm, self.image_path().display(), plain_home.display(), e);
+        }
+        if plain_home.exists() {
+            fs::remove_dir_all(&plain_home)
+                .map_err(|e| format_err!("encrypted home of realm-{} was created in {} but removing the unencrypted copy at {} failed, the encrypted image holds the complete home directory: {}",
+                                         self.realm, self.image_path().display(), plain_home.display(), e))?;
+        }
+        AuditLog::record("realm", format!("created encrypted home for realm-{}", self.realm));
+        Ok(())
+    }
+
+    fn plain_home_path(&self) -> PathBuf {
+        self.base_path.join("home.plain")
+    }
+
+    fn format_image(&self, passphrase: &str, size_mb: u64) -> Result<()> {
+        let image = self.image_path();
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&image)
+            .map_err(context!("failed to create image file {:?}", image))?;
+        file.set_len(size_mb * 1024 * 1024)
+            .map_err(context!("failed to set size of image file {:?}", image))?;
+        drop(file);
+
+        Self::cryptsetup(&["-q", "luksFormat", "--key-file=-"], &image, &[], passphrase)?;
+        self.open_image(passphrase)?;
+        cmd!(MKFS_EXT4_PATH, "-q {}", self.mapper_path().display())
+    }
+
+    /// Move the home directory aside to home.plain, mount the unlocked image in its
+    /// place and copy the contents into it.
+    fn copy_home(&self) -> Result<()> {
+        let home = self.home_path();
+        let plain_home = self.plain_home_path();
+        if home.exists() {
+            util::rename(&home, &plain_home)?;
+        }
+        util::create_dir(&home)?;
+        util::mount(self.mapper_path().to_string_lossy(), &home, None)?;
+        util::chown_user(&home)?;
+
+        if plain_home.exists() {
+            cmd!("/usr/bin/cp", "-a {}/. {}", plain_home.display(), home.display())?;
+        }
+        Ok(())
+    }
+
+    /// Open the locked image again with `passphrase` and check the filesystem
+    /// without modifying it.
+    fn verify_image(&self, passphrase: &str) -> Result<()> {
+        self.open_image(passphrase)?;
+        let result = cmd!(E2FSCK_PATH, "-n -f {}", self.mapper_path().display());
+        Self::close_mapping(&self.mapper_name())?;
+        result
+    }
+
+    /// Remove a partially created image and put back the unencrypted home directory.
+    /// Called only before the contents of the home directory have been fully copied
+    /// into the image, so the unencrypted home directory is still complete.
+    fn discard_image(&self) {
+        if self.is_unlocked() {
+            if let Err(e) = self.lock() {
+                warn!("Failed to lock partially created encrypted home of realm-{}, keeping image {} and home directory contents in {}: {}",
+                      self.realm, self.image_path().display(), self.plain_home_path().display(), e);
+                return;
+            }
+        }
+        let _ = fs::remove_file(self.image_path());
+        let plain_home = self.plain_home_path();
+        if plain_home.exists() {
+            let _ = fs::remove_dir(self.home_path());
+            if let Err(e) = util::rename(&plain_home, self.home_path()) {
+                warn!("Failed to restore home directory of realm-{}, contents are in {}: {}", self.realm, plain_home.display(), e);
+            }
+        }
+    }
+
+    /// Unlock the image with `passphrase` and mount it on the realm home directory.
+    pub fn unlock(&self, passphrase: &str) -> Result<()> {
+        if !self.exists() {
+            bail!("realm-{} has no encrypted home image at {}", self.realm, self.image_path().display());
+        }
+        if self.is_unlocked() {
+            return Ok(());
+        }
+        self.open_image(passphrase)?;
+        let home = self.home_path();
+        util::create_dir(&home)?;
+        if let Err(e) = util::mount(self.mapper_path().to_string_lossy(), &home, None) {
+            let _ = Self::close_mapping(&self.mapper_name());
+            return Err(e);
+        }
+        info!("Unlocked encrypted home directory of realm-{}", self.realm);
+        AuditLog::record("realm", format!("unlocked encrypted home of realm-{}", self.realm));
+        Ok(())
+    }
+
+    /// Unmount the realm home directory and close the LUKS mapping. If the home
+    /// directory cannot be unmounted, for example because some process still has
+    /// a file open in it, the mapping is left open and an error is returned.
+    pub fn lock(&self) -> Result<()> {
+        if !self.is_unlocked() {
+            return Ok(());
+        }
+        let home = self.home_path();
+        if Mounts::is_target_mounted(&home)? {
+            util::umount(&home)
+                .map_err(|e| format_err!("encrypted home of realm-{} is still in use and was not locked: {}", self.realm, e))?;
+        }
+        Self::close_mapping(&self.mapper_name())?;
+        info!("Locked encrypted home directory of realm-{}", self.realm);
+        Ok(())
+    }
+
+    fn open_image(&self, passphrase: &str) -> Result<()> {
+        let name = self.mapper_name();
+        Self::cryptsetup(&["open", "--type", "luks", "--key-file=-"], &self.image_path(), &[&name], passphrase)
+            .map_err(|_| format_err!("failed to unlock encrypted home of realm-{}, incorrect passphrase?", self.realm))
+    }
+
+    fn close_mapping(name: &str) -> Result<()> {
+        cmd!(CRYPTSETUP_PATH, "close {}", name)
+    }
+
+    /// Run cryptsetup as `cryptsetup ARGS IMAGE TRAILING` and write `passphrase` to stdin.
+    fn cryptsetup(args: &[&str], image: &Path, trailing: &[&str], passphrase: &str) -> Result<()> {
+        let mut cmd = Command::new(CRYPTSETUP_PATH);
+        cmd.args(args).arg(image).args(trailing);
+
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(context!("failed to execute {}", CRYPTSETUP_PATH))?;
+
+        child.stdin.as_mut().unwrap()
+            .write_all(passphrase.as_bytes())
+            .map_err(context!("failed to write passphrase to {}", CRYPTSETUP_PATH))?;
+
+        let status = child.wait()
+            .map_err(context!("error waiting for {} to exit", CRYPTSETUP_PATH))?;
+        if !status.success() {
+            bail!("{} {} failed", CRYPTSETUP_PATH, args.join(" "));
+        }
+        Ok(())
+    }
+}
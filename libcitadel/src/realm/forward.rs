@@ -0,0 +1,269 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::{Exec, Realm, Result};
+use crate::realm::network::NetworkConfig;
+
+const IPTABLES_PATH: &str = "/usr/sbin/iptables";
+
+/// Chain in the nat table for DNAT rules, jumped to from PREROUTING and OUTPUT.
+const NAT_CHAIN: &str = "CITADEL-PF";
+/// Chain in the nat table for SNAT rules, jumped to from POSTROUTING.
+const NAT_POST_CHAIN: &str = "CITADEL-PF-POST";
+/// Chain in the filter table accepting forwarded connections, jumped to from FORWARD.
+const FILTER_CHAIN: &str = "CITADEL-PF";
+
+/// Peer name which allows connections from the host.
+const HOST_PEER: &str = "host";
+
+///
+/// A port in a realm which is reachable from the host or from other realms.
+///
+/// Port forwards are configured in the realm config as strings of the form
+///
+/// ```text
+/// PROTOCOL:PORT[:PEER,PEER...]
+/// ```
+///
+/// where `PROTOCOL` is `tcp` or `udp` and each `PEER` is the name of another
+/// realm or `host`. If no peers are listed the port is only reachable from
+/// the host. Peers connect to the port on the gateway address of the bridge
+/// (172.17.0.1 for the clear zone), and the connection is forwarded to the
+/// address allocated to the realm.
+///
+#[derive(Clone,Debug,PartialEq)]
+pub struct PortForward {
+    protocol: String,
+    port: u16,
+    peers: Vec<String>,
+}
+
+impl PortForward {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut fields = s.trim().splitn(3, ':');
+        let protocol = match fields.next() {
+            Some(p @ "tcp") | Some(p @ "udp") => p.to_string(),
+            _ => bail!("port forward '{}' does not start with tcp or udp", s),
+        };
+        let port = fields.next()
+            .and_then(|p| p.parse::<u16>().ok())
+            .filter(|p| *p != 0)
+            .ok_or_else(|| format_err!("port forward '{}' does not have a valid port number", s))?;
+
+        let peers = fields.next().unwrap_or("")
+            .split(',')
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+
+        for peer in &peers {
+            if peer != HOST_PEER && !Realm::is_valid_name(peer) {
+                bail!("port forward '{}' has invalid peer name '{}'", s, peer);
+            }
+        }
+        Ok(PortForward { protocol, port, peers })
+    }
+
+    /// Return the valid port forwards configured for `realm`, logging a warning
+    /// about any which cannot be parsed.
+    pub fn for_realm(realm: &Realm) -> Vec<Self> {
+        realm.config().port_forwards()
+            .iter()
+            .filter_map(|s| match Self::parse(s) {
+                Ok(forward) => Some(forward),
+                Err(e) => {
+                    warn!("Ignoring port forward for realm-{}: {}", realm.name(), e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Names of the realms allowed to connect to this port. If the list is empty
+    /// only the host may connect.
+    pub fn peers(&self) -> &[String] {
+        &self.peers
+    }
+
+    pub fn allows_host(&self) -> bool {
+        self.peers.is_empty() || self.peers.iter().any(|p| p == HOST_PEER)
+    }
+
+    fn peer_realms(&self) -> impl Iterator<Item=&str> {
+        self.peers.iter()
+            .map(|p| p.as_str())
+            .filter(|p| *p != HOST_PEER)
+    }
+}
+
+impl fmt::Display for PortForward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.protocol, self.port)?;
+        if self.peers.is_empty() {
+            write!(f, " from host")
+        } else {
+            write!(f, " from {}", self.peers.join(", "))
+        }
+    }
+}
+
+///
+/// Generates the iptables rules for the port forwards of every running realm.
+///
+/// Rules are kept in chains owned by citadel which are flushed and rebuilt each
+/// time a realm is started or stopped, since the addresses allocated to realms
+/// (and so the addresses allowed to connect) change as realms come and go.
+///
+/// Forwarded connections from realms are also SNATed to the bridge gateway
+/// address so that replies are routed back through the host rather than sent
+/// directly across the bridge.
+///
+pub(crate) struct PortForwarding<'a> {
+    network: &'a NetworkConfig,
+    addresses: HashMap<String, (String, String)>,
+    nat: Vec<String>,
+    nat_post: Vec<String>,
+    filter: Vec<String>,
+}
+
+impl <'a> PortForwarding<'a> {
+    pub fn new(network: &'a NetworkConfig) -> Self {
+        PortForwarding {
+            network,
+            addresses: HashMap::new(),
+            nat: Vec::new(),
+            nat_post: Vec::new(),
+            filter: Vec::new(),
+        }
+    }
+
+    /// Replace the current port forwarding rules with rules for the realms in `active`.
+    pub fn apply(mut self, active: &[Realm]) -> Result<()> {
+        for realm in active {
+            let zone = realm.config().network_zone().to_string();
+            if let (Some(addr), Ok(gw)) = (self.network.allocated_address(&zone, realm.name()), self.network.gateway(&zone)) {
+                self.addresses.insert(realm.name().to_string(), (addr, gw));
+            }
+        }
+
+        let mut used = HashSet::new();
+        for realm in active {
+            for forward in PortForward::for_realm(realm) {
+                if !used.insert((forward.protocol.clone(), forward.port)) {
+                    warn!("Port {} is already forwarded to another realm, not forwarding to realm-{}", forward, realm.name());
+                    continue;
+                }
+                self.add_forward(realm, &forward);
+            }
+        }
+
+        if self.nat.is_empty() && !Self::chain_exists("nat", NAT_CHAIN) {
+            return Ok(());
+        }
+
+        Self::ensure_chain("nat", NAT_CHAIN, &["PREROUTING", "OUTPUT"])?;
+        Self::ensure_chain("nat", NAT_POST_CHAIN, &["POSTROUTING"])?;
+        Self::ensure_chain("filter", FILTER_CHAIN, &["FORWARD"])?;
+
+        Self::replace_rules("nat", NAT_CHAIN, &self.nat)?;
+        Self::replace_rules("nat", NAT_POST_CHAIN, &self.nat_post)?;
+        Self::replace_rules("filter", FILTER_CHAIN, &self.filter)
+    }
+
+    fn add_forward(&mut self, realm: &Realm, forward: &PortForward) {
+        let (addr, gw) = match self.addresses.get(realm.name()) {
+            Some(addrs) => addrs.clone(),
+            None => {
+                verbose!("realm-{} has no bridge address, not forwarding port {}", realm.name(), forward);
+                return;
+            }
+        };
+        let proto = forward.protocol();
+        let port = forward.port();
+
+        if forward.allows_host() {
+            self.nat.push(format!("-m addrtype --src-type LOCAL -d {} -p {} --dport {} -j DNAT --to-destination {}:{}",
+                                  gw, proto, port, addr, port));
+        }
+
+        for peer in forward.peer_realms() {
+            let peer_addr = match self.addresses.get(peer) {
+                Some((peer_addr, peer_gw)) if *peer_gw == gw => peer_addr.clone(),
+                Some(_) => {
+                    warn!("realm-{} is not on the same network zone as realm-{}, not forwarding port {}", peer, realm.name(), forward);
+                    continue;
+                },
+                // Rules are rebuilt when the peer starts
+                None => continue,
+            };
+            self.nat.push(format!("-s {} -d {} -p {} --dport {} -j DNAT --to-destination {}:{}",
+                                  peer_addr, gw, proto, port, addr, port));
+            self.filter.push(format!("-s {} -d {} -p {} --dport {} -m conntrack --ctstate DNAT -j ACCEPT",
+                                     peer_addr, addr, proto, port));
+            self.nat_post.push(format!("-s {} -d {} -p {} --dport {} -m conntrack --ctstate DNAT -j SNAT --to-source {}",
+                                       peer_addr, addr, proto, port, gw));
+        }
+    }
+
+    fn chain_exists(table: &str, chain: &str) -> bool {
+        Self::iptables_ok(&format!("-t {} -n -L {}", table, chain))
+    }
+
+    fn ensure_chain(table: &str, chain: &str, hooks: &[&str]) -> Result<()> {
+        if !Self::chain_exists(table, chain) {
+            Self::iptables(&format!("-t {} -N {}", table, chain))?;
+        }
+        for hook in hooks {
+            if !Self::iptables_ok(&format!("-t {} -C {} -j {}", table, hook, chain)) {
+                Self::iptables(&format!("-t {} -I {} -j {}", table, hook, chain))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn replace_rules(table: &str, chain: &str, rules: &[String]) -> Result<()> {
+        Self::iptables(&format!("-t {} -F {}", table, chain))?;
+        for rule in rules {
+            Self::iptables(&format!("-t {} -A {} {}", table, chain, rule))?;
+        }
+        Ok(())
+    }
+
+    fn iptables(args: &str) -> Result<()> {
+        cmd!(IPTABLES_PATH, "-w {}", args)
+    }
+
+    fn iptables_ok(args: &str) -> bool {
+        Exec::new(IPTABLES_PATH)
+            .quiet()
+            .run_ok(format!("-w {}", args))
+            .unwrap_or(false)
+    }
+}
+
+#[test]
+fn test_parse_port_forward() {
+    let fwd = PortForward::parse("tcp:8080").unwrap();
+    assert_eq!(fwd.port(), 8080);
+    assert!(fwd.allows_host());
+
+    let fwd = PortForward::parse("udp:53:work, dev").unwrap();
+    assert_eq!(fwd.protocol(), "udp");
+    assert_eq!(fwd.peers(), &["work".to_string(), "dev".to_string()]);
+    assert!(!fwd.allows_host());
+    assert!(PortForward::parse("tcp:22:host,work").unwrap().allows_host());
+
+    assert!(PortForward::parse("sctp:80").is_err());
+    assert!(PortForward::parse("tcp:0").is_err());
+    assert!(PortForward::parse("tcp:70000").is_err());
+    assert!(PortForward::parse("tcp:80:bad name").is_err());
+}
@@ -0,0 +1,358 @@
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+
+use crate::{FileLock, Realm, Result, util};
+use crate::realm::config::DEFAULT_ZONE;
+
+const IP_PATH: &str = "/usr/sbin/ip";
+const SYSCTL_PATH: &str = "/usr/sbin/sysctl";
+const NETNS_RUN_PATH: &str = "/run/netns";
+const STATE_RUN_PATH: &str = "/run/citadel/realms";
+const STATE_LOCK: &str = "gateway.lock";
+const ZONES_STATE: &str = "gateway-zones";
+
+/// Each gateway zone is allocated a /24 network from this /16 network.
+const GATEWAY_NETWORKS: [u8; 2] = [10, 253];
+const ZONE_MASK: usize = 24;
+const LINK_MASK: usize = 30;
+
+/// The zone network is divided into /30 links. The first links the zone
+/// router to the gateway realm and each of the others links the router to
+/// one realm in the zone.
+const MAX_LINKS: u8 = 64;
+const UPLINK: u8 = 0;
+
+/// Interface names are limited to 15 characters and carry a 3 character prefix.
+const MAX_ZONE_NAME: usize = 12;
+
+/// Priority of the rule in the router namespace which allows every realm to reach the gateway realm.
+const GATEWAY_RULE_PRIORITY: u32 = 100;
+/// Priority of the rules in the router namespace which prevent realms from reaching each other.
+const ISOLATE_RULE_PRIORITY: u32 = 200;
+
+///
+/// Network plumbing for a zone which reaches the network through a gateway realm.
+///
+/// While the gateway realm is running a router network namespace `gw-$ZONE`
+/// exists for the zone. Each zone is allocated its own /24 network, which is
+/// divided into /30 links. The first link is a veth pair between the router
+/// namespace and the gateway realm, where the gateway end `vg-$ZONE` is moved
+/// into the gateway realm. The gateway realm is passed the interface name and
+/// addresses in the `GATEWAY_IFACE`, `GATEWAY_IP`, `GATEWAY_ROUTER` and
+/// `GATEWAY_NET` environment variables and is responsible for configuring the
+/// interface, adding a route to `GATEWAY_NET` via `GATEWAY_ROUTER`, and
+/// forwarding traffic, for example through a VPN or Tor.
+///
+/// Every other realm in the zone is launched in a network namespace of its own,
+/// `gw-$ZONE.$REALM`, which is connected to the router namespace by another
+/// link. The router forwards traffic between these links and the gateway realm
+/// but refuses to forward traffic from one realm in the zone to another.
+///
+/// Realms in the zone have no other route to the network, so when the gateway
+/// realm is not running they have no network access at all.
+///
+#[derive(Clone)]
+pub struct Gateway {
+    zone: String,
+}
+
+impl Gateway {
+    pub fn new(zone: &str) -> Result<Self> {
+        if !util::is_valid_name(zone, MAX_ZONE_NAME) {
+            bail!("'{}' is not a valid name for a gateway network zone", zone);
+        }
+        if zone == DEFAULT_ZONE {
+            bail!("The {} network zone cannot have a gateway realm", DEFAULT_ZONE);
+        }
+        Ok(Gateway { zone: zone.to_string() })
+    }
+
+    /// Return the gateway for the zone `realm` is configured to provide networking
+    /// for, or `None` if `realm` is not a gateway realm.
+    pub fn for_realm(realm: &Realm) -> Result<Option<Self>> {
+        match realm.config().gateway_zone() {
+            Some(zone) => Ok(Some(Self::new(zone)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn zone(&self) -> &str {
+        &self.zone
+    }
+
+    /// Name of the network namespace which routes traffic for this zone.
+    pub fn netns_name(&self) -> String {
+        format!("gw-{}", self.zone)
+    }
+
+    pub fn netns_path(&self) -> PathBuf {
+        Path::new(NETNS_RUN_PATH).join(self.netns_name())
+    }
+
+    /// Name of the network namespace `realm` is launched in. Neither zone nor
+    /// realm names can contain '.' so these never collide.
+    fn realm_netns_name(&self, realm: &str) -> String {
+        format!("gw-{}.{}", self.zone, realm)
+    }
+
+    pub fn realm_netns_path(&self, realm: &Realm) -> PathBuf {
+        Path::new(NETNS_RUN_PATH).join(self.realm_netns_name(realm.name()))
+    }
+
+    /// Name of the interface which is moved into the gateway realm.
+    pub fn gateway_interface(&self) -> String {
+        format!("vg-{}", self.zone)
+    }
+
+    /// The network allocated to this zone, which the gateway realm must route back
+    /// to the router namespace.
+    pub fn zone_network(&self) -> Result<String> {
+        Ok(format!("{}/{}", self.link_address(UPLINK, 0)?, ZONE_MASK))
+    }
+
+    /// Address of the gateway realm on the link to the router namespace.
+    pub fn gateway_ip(&self) -> Result<String> {
+        Ok(format!("{}/{}", self.link_address(UPLINK, 1)?, LINK_MASK))
+    }
+
+    /// Address of the router namespace on the link to the gateway realm.
+    pub fn router_ip(&self) -> Result<String> {
+        Ok(self.link_address(UPLINK, 2)?.to_string())
+    }
+
+    fn link_address(&self, link: u8, host: u8) -> Result<Ipv4Addr> {
+        let index = match ZoneState::zones().get(&self.zone)? {
+            Some(index) => index,
+            None => bail!("No network has been allocated for gateway zone {}", self.zone),
+        };
+        Ok(Ipv4Addr::new(GATEWAY_NETWORKS[0], GATEWAY_NETWORKS[1], index, link * 4 + host))
+    }
+
+    fn link_interface(link: u8) -> String {
+        format!("vr-{}", link)
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.netns_path().exists()
+    }
+
+    /// Create the router network namespace and the veth pair linking it to the gateway realm.
+    pub fn setup(&self) -> Result<()> {
+        if self.is_up() {
+            // Left behind if realmsd or the gateway realm did not shut down cleanly
+            warn!("Network namespace {} already exists, recreating it", self.netns_name());
+            self.teardown()?;
+        }
+        {
+            let _lock = ZoneState::lock()?;
+            ZoneState::zones().allocate(&self.zone, 0..=u8::MAX)?;
+        }
+        info!("Creating network namespace {} for gateway zone {}", self.netns_name(), self.zone);
+        let netns = self.netns_name();
+        if let Err(e) = cmd!(IP_PATH, "netns add {}", netns).and_then(|_| self.setup_router(&netns)) {
+            let _ = self.teardown();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn setup_router(&self, netns: &str) -> Result<()> {
+        let gateway_address = self.link_address(UPLINK, 1)?;
+        let router_ip = format!("{}/{}", self.router_ip()?, LINK_MASK);
+        cmd!(IP_PATH, "link add {} type veth peer name eth0 netns {}", self.gateway_interface(), netns)?;
+        cmd!(IP_PATH, "-n {} link set lo up", netns)?;
+        cmd!(IP_PATH, "-n {} addr add {} dev eth0", netns, router_ip)?;
+        cmd!(IP_PATH, "-n {} link set eth0 up", netns)?;
+        cmd!(IP_PATH, "-n {} route add default via {}", netns, gateway_address)?;
+        cmd!(IP_PATH, "-n {} rule add to {}/32 lookup main priority {}", netns, gateway_address, GATEWAY_RULE_PRIORITY)?;
+        cmd!(IP_PATH, "netns exec {} {} -q -w net.ipv4.ip_forward=1", netns, SYSCTL_PATH)
+    }
+
+    /// Create a network namespace for `realm` with a link to the router namespace
+    /// of this zone. The realm is launched in this namespace.
+    pub fn attach(&self, realm: &Realm) -> Result<()> {
+        if !self.is_up() {
+            bail!("Network namespace {} for gateway zone {} does not exist", self.netns_name(), self.zone);
+        }
+        if self.realm_netns_path(realm).exists() {
+            warn!("Network namespace {} already exists, recreating it", self.realm_netns_name(realm.name()));
+            self.detach(realm)?;
+        }
+        let link = {
+            let _lock = ZoneState::lock()?;
+            ZoneState::links(&self.zone).allocate(realm.name(), 1..=MAX_LINKS - 1)?
+        };
+        info!("Creating network namespace {} for realm-{} in gateway zone {}",
+              self.realm_netns_name(realm.name()), realm.name(), self.zone);
+        if let Err(e) = self.setup_realm_link(realm.name(), link) {
+            let _ = self.detach(realm);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn setup_realm_link(&self, realm: &str, link: u8) -> Result<()> {
+        let router = self.netns_name();
+        let netns = self.realm_netns_name(realm);
+        let iface = Self::link_interface(link);
+        let router_address = self.link_address(link, 1)?;
+        let realm_address = self.link_address(link, 2)?;
+
+        cmd!(IP_PATH, "netns add {}", netns)?;
+        cmd!(IP_PATH, "-n {} link add {} type veth peer name eth0 netns {}", router, iface, netns)?;
+        cmd!(IP_PATH, "-n {} addr add {}/{} dev {}", router, router_address, LINK_MASK, iface)?;
+        cmd!(IP_PATH, "-n {} link set {} up", router, iface)?;
+        cmd!(IP_PATH, "-n {} rule add iif {} to {} prohibit priority {}", router, iface, self.zone_network()?, ISOLATE_RULE_PRIORITY)?;
+        cmd!(IP_PATH, "-n {} link set lo up", netns)?;
+        cmd!(IP_PATH, "-n {} addr add {}/{} dev eth0", netns, realm_address, LINK_MASK)?;
+        cmd!(IP_PATH, "-n {} link set eth0 up", netns)?;
+        cmd!(IP_PATH, "-n {} route add default via {}", netns, router_address)
+    }
+
+    /// Remove the network namespace of `realm` and its link to the router namespace.
+    pub fn detach(&self, realm: &Realm) -> Result<()> {
+        self.remove_realm_link(realm.name())
+    }
+
+    fn remove_realm_link(&self, realm: &str) -> Result<()> {
+        let _lock = ZoneState::lock()?;
+        let links = ZoneState::links(&self.zone);
+        if let Some(link) = links.get(realm)? {
+            if self.is_up() {
+                // The rule refers to the interface by name and outlives it
+                let _ = cmd!(IP_PATH, "-n {} rule del iif {} priority {}", self.netns_name(), Self::link_interface(link), ISOLATE_RULE_PRIORITY);
+            }
+            links.free(realm)?;
+        }
+        let netns = self.realm_netns_name(realm);
+        if Path::new(NETNS_RUN_PATH).join(&netns).exists() {
+            info!("Removing network namespace {} for realm-{}", netns, realm);
+            // Deleting the namespace also destroys the veth pair
+            cmd!(IP_PATH, "netns delete {}", netns)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the router network namespace and the namespaces of any realms still
+    /// attached to it. Deleting the router namespace also destroys the veth pair,
+    /// so the gateway end disappears from the gateway realm.
+    pub fn teardown(&self) -> Result<()> {
+        let attached = {
+            let _lock = ZoneState::lock()?;
+            ZoneState::links(&self.zone).names()?
+        };
+        for realm in attached {
+            self.remove_realm_link(&realm)?;
+        }
+        if self.is_up() {
+            info!("Removing network namespace {} for gateway zone {}", self.netns_name(), self.zone);
+            cmd!(IP_PATH, "netns delete {}", self.netns_name())?;
+        }
+        let _lock = ZoneState::lock()?;
+        ZoneState::links(&self.zone).remove()?;
+        ZoneState::zones().free(&self.zone)
+    }
+}
+
+///
+/// Allocation of small integers to names, stored in a state file below
+/// /run/citadel/realms as colon ':' separated pairs of name and value.
+/// This records the network index allocated to each gateway zone and the
+/// link index allocated to each realm attached to a zone.
+///
+struct ZoneState {
+    path: PathBuf,
+}
+
+impl ZoneState {
+    fn lock() -> Result<FileLock> {
+        util::create_dir(STATE_RUN_PATH)?;
+        FileLock::acquire(Path::new(STATE_RUN_PATH).join(STATE_LOCK))
+    }
+
+    fn zones() -> Self {
+        ZoneState { path: Path::new(STATE_RUN_PATH).join(ZONES_STATE) }
+    }
+
+    fn links(zone: &str) -> Self {
+        ZoneState { path: Path::new(STATE_RUN_PATH).join(format!("gateway-{}", zone)) }
+    }
+
+    fn load(&self) -> Result<BTreeMap<String, u8>> {
+        let mut map = BTreeMap::new();
+        if !self.path.exists() {
+            return Ok(map);
+        }
+        for line in util::read_to_string(&self.path)?.lines() {
+            let (name, value) = match line.find(':') {
+                Some(idx) => (&line[..idx], &line[idx + 1..]),
+                None => bail!("Could not parse line from gateway state file {:?}: {}", self.path, line),
+            };
+            let value = value.parse()
+                .map_err(|_| format_err!("Could not parse line from gateway state file {:?}: {}", self.path, line))?;
+            map.insert(name.to_string(), value);
+        }
+        Ok(map)
+    }
+
+    fn store(&self, map: &BTreeMap<String, u8>) -> Result<()> {
+        let s = map.iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect::<String>();
+        util::write_file(&self.path, s)
+    }
+
+    fn get(&self, name: &str) -> Result<Option<u8>> {
+        Ok(self.load()?.get(name).cloned())
+    }
+
+    fn names(&self) -> Result<Vec<String>> {
+        Ok(self.load()?.keys().cloned().collect())
+    }
+
+    /// Return the value allocated to `name`, allocating the lowest free value in
+    /// `range` if there is none.
+    fn allocate(&self, name: &str, range: std::ops::RangeInclusive<u8>) -> Result<u8> {
+        let mut map = self.load()?;
+        if let Some(value) = map.get(name) {
+            return Ok(*value);
+        }
+        let value = match range.into_iter().find(|v| !map.values().any(|x| x == v)) {
+            Some(value) => value,
+            None => bail!("No free network address could be allocated for {}", name),
+        };
+        map.insert(name.to_string(), value);
+        self.store(&map)?;
+        Ok(value)
+    }
+
+    fn free(&self, name: &str) -> Result<()> {
+        let mut map = self.load()?;
+        if map.remove(name).is_some() {
+            self.store(&map)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&self) -> Result<()> {
+        util::remove_file(&self.path)
+    }
+}
+
+#[test]
+fn test_zone_state_allocation() {
+    let dir = std::env::temp_dir().join(format!("citadel-gateway-test-{}", std::process::id()));
+    util::create_dir(&dir).unwrap();
+    let state = ZoneState { path: dir.join("gateway-zones") };
+
+    assert_eq!(state.allocate("vpn", 0..=u8::MAX).unwrap(), 0);
+    assert_eq!(state.allocate("tor", 0..=u8::MAX).unwrap(), 1);
+    assert_eq!(state.allocate("vpn", 0..=u8::MAX).unwrap(), 0);
+    state.free("vpn").unwrap();
+    assert_eq!(state.allocate("work", 0..=u8::MAX).unwrap(), 0);
+    assert_eq!(state.names().unwrap(), vec!["tor".to_string(), "work".to_string()]);
+    assert!(state.allocate("other", 0..=1).is_err());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -1,7 +1,10 @@
 use std::fmt::{self,Write};
 use std::path::{Path, PathBuf};
 
-use crate::{Realm, Result, util, realm::network::NetworkConfig};
+use crate::{Realm, Result, SharedFolderAccess, util, realm::network::NetworkConfig};
+use crate::realm::dns::RealmDns;
+use crate::realm::gateway::Gateway;
+use crate::realm::sound::RealmSound;
 
 const NSPAWN_FILE_TEMPLATE: &str = "\
 [Exec]
@@ -10,7 +13,7 @@ $NETWORK_CONFIG
 
 [Files]
 BindReadOnly=/opt/share
-BindReadOnly=/storage/citadel-state/resolv.conf:/etc/resolv.conf
+$DNS_BIND_MOUNTS
 
 $EXTRA_BIND_MOUNTS
 
@@ -43,6 +46,7 @@ pub struct RealmLauncher<'a> {
     realm: &'a Realm,
     service: String,
     devices: Vec<String>,
+    uplink: Option<Gateway>,
 }
 
 impl <'a> RealmLauncher <'a> {
@@ -51,9 +55,16 @@ impl <'a> RealmLauncher <'a> {
         RealmLauncher {
             realm, service,
             devices: Vec::new(),
+            uplink: None,
         }
     }
 
+    /// Launch the realm in its own network namespace routed through the gateway
+    /// of its zone rather than connecting it to a bridge.
+    pub fn set_uplink(&mut self, gateway: Gateway) {
+        self.uplink = Some(gateway);
+    }
+
     fn add_devices(&mut self) {
         let config = self.realm.config();
 
@@ -76,7 +87,8 @@ impl <'a> RealmLauncher <'a> {
 
     pub fn remove_launch_config_files(&self) -> Result<()> {
         util::remove_file(self.realm_nspawn_path())?;
-        util::remove_file(self.realm_service_path())
+        util::remove_file(self.realm_service_path())?;
+        RealmDns::new(self.realm).remove_files()
     }
 
     pub fn write_launch_config_files(&mut self, rootfs: &Path, netconfig: &mut NetworkConfig) -> Result<()> {
@@ -84,7 +96,7 @@ impl <'a> RealmLauncher <'a> {
             self.add_devices();
         }
         let nspawn_path = self.realm_nspawn_path();
-        let nspawn_content = self.generate_nspawn_file(netconfig)?;
+        let nspawn_content = self.generate_nspawn_file(rootfs, netconfig)?;
         self.write_launch_config_file(&nspawn_path, &nspawn_content)?;
 
         let service_path = self.realm_service_path();
@@ -106,8 +118,9 @@ impl <'a> RealmLauncher <'a> {
         util::write_file(path, content)
     }
 
-    fn generate_nspawn_file(&mut self, netconfig: &mut NetworkConfig) -> Result<String> {
+    fn generate_nspawn_file(&mut self, rootfs: &Path, netconfig: &mut NetworkConfig) -> Result<String> {
         Ok(NSPAWN_FILE_TEMPLATE
+            .replace("$DNS_BIND_MOUNTS", &RealmDns::new(self.realm).write_files(rootfs)?)
             .replace("$EXTRA_BIND_MOUNTS", &self.generate_extra_bind_mounts()?)
             .replace("$EXTRA_FILE_OPTIONS", &self.generate_extra_file_options()?)
             .replace("$NETWORK_CONFIG", &self.generate_network_config(netconfig)?))
@@ -127,13 +140,21 @@ impl <'a> RealmLauncher <'a> {
             writeln!(s, "Bind=/realms/Shared:/home/user/Shared")?;
         }
 
+        for access in SharedFolderAccess::for_realm(self.realm) {
+            let folder = access.folder();
+            if !folder.exists() {
+                warn!("Shared folder {} for realm-{} does not exist", folder.name(), self.realm.name());
+                continue;
+            }
+            let bind = if access.is_read_only() { "BindReadOnly" } else { "Bind" };
+            writeln!(s, "{}={}:{}", bind, folder.path().display(), folder.mount_path().display())?;
+        }
+
         for dev in &self.devices {
             writeln!(s, "Bind={}", dev)?;
         }
 
-        if config.sound() {
-            writeln!(s, "BindReadOnly=/run/user/1000/pulse:/run/user/host/pulse")?;
-        }
+        s.push_str(&RealmSound::new(self.realm).bind_mounts()?);
 
         if config.x11() {
             writeln!(s, "BindReadOnly=/tmp/.X11-unix")?;
@@ -174,9 +195,16 @@ impl <'a> RealmLauncher <'a> {
 
     fn generate_network_config(&mut self, netconfig: &mut NetworkConfig) -> Result<String> {
         let config = self.realm.config();
+        let gateway = Gateway::for_realm(self.realm)?;
         let mut s = String::new();
+        if let Some(ref gw) = gateway {
+            writeln!(s, "Environment=GATEWAY_IFACE={}", gw.gateway_interface())?;
+            writeln!(s, "Environment=GATEWAY_IP={}", gw.gateway_ip()?)?;
+            writeln!(s, "Environment=GATEWAY_ROUTER={}", gw.router_ip()?)?;
+            writeln!(s, "Environment=GATEWAY_NET={}", gw.zone_network()?)?;
+        }
         if config.network() {
-            if config.has_netns() {
+            if config.has_netns() || self.uplink.is_some() {
                 return Ok(s);
             }
             let zone = config.network_zone();
@@ -194,14 +222,18 @@ impl <'a> RealmLauncher <'a> {
             writeln!(s, "[Network]")?;
             writeln!(s, "Private=true")?;
         }
+        if let Some(gw) = gateway {
+            writeln!(s, "Interface={}", gw.gateway_interface())?;
+        }
         Ok(s)
     }
 
     fn generate_service_file(&self, rootfs: &Path) -> String {
         let rootfs = rootfs.display().to_string();
-        let netns_arg = match self.realm.config().netns() {
-            Some(netns) => format!("--network-namespace-path=/run/netns/{}", netns),
-            None => "".into(),
+        let netns_arg = match (&self.uplink, self.realm.config().netns()) {
+            (Some(gw), _) => format!("--network-namespace-path={}", gw.realm_netns_path(self.realm).display()),
+            (None, Some(netns)) => format!("--network-namespace-path=/run/netns/{}", netns),
+            (None, None) => "".into(),
         };
 
         let mut s = String::new();
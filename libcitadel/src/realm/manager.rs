@@ -2,13 +2,17 @@ use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use crate::{Mountpoint, Result, Realms, RealmFS, Realm, util};
+use crate::{Mountpoint, Result, Realms, RealmFS, Realm, FileTransfer, TransferRecord, AuditLog, RealmQuota, EncryptedHome, util};
 use crate::realmfs::realmfs_set::RealmFSSet;
 
 use super::systemd::Systemd;
 use super::network::NetworkConfig;
 use super::events::{RealmEventListener, RealmEvent};
 use crate::realm::realms::HasCurrentChanged;
+use crate::realm::config::OverlayType;
+use crate::realm::gateway::Gateway;
+
+const DISPOSABLE_PREFIX: &str = "disposable-";
 
 pub struct RealmManager {
     inner: RwLock<Inner>,
@@ -116,6 +120,21 @@ impl RealmManager {
         self.systemd.machinectl_copy_to(realm, from.as_ref(), to.as_ref())
     }
 
+    /// Copy the file at `path` in the home directory of realm `source` into the
+    /// `Inbox` directory of realm `target` and record the transfer in the transfer log.
+    pub fn transfer_file(&self, source: &Realm, target: &Realm, path: impl AsRef<Path>) -> Result<TransferRecord> {
+        FileTransfer::new(source, target, path)?.run()
+    }
+
+    /// Return the IP address allocated to `realm` on its network zone,
+    /// or `None` if the realm is not running with network access.
+    pub fn realm_address(&self, realm: &Realm) -> Option<String> {
+        if !realm.is_active() {
+            return None;
+        }
+        self.systemd.realm_address(realm)
+    }
+
     pub fn realm_list(&self) -> Vec<Realm> {
         self.inner_mut().realms.sorted()
     }
@@ -189,6 +208,8 @@ impl RealmManager {
         }
         info!("Starting realm {}", realm.name());
         self._start_realm(realm, &mut HashSet::new())?;
+        AuditLog::record("realm", format!("started realm-{} with realmfs {}", realm.name(), realm.config().realmfs()));
+        self.update_port_forwards();
 
         if !Realms::is_some_realm_current() {
             self.inner_mut().realms.set_realm_current(realm)
@@ -201,6 +222,15 @@ impl RealmManager {
 
         self.start_realm_dependencies(realm, starting)?;
 
+        if let Some(encrypted) = EncryptedHome::for_realm(realm) {
+            if !encrypted.exists() {
+                bail!("Realm-{} is configured with an encrypted home but {} does not exist", realm.name(), encrypted.image_path().display());
+            }
+            if !encrypted.is_unlocked() {
+                bail!("The encrypted home directory of realm-{} must be unlocked before starting the realm", realm.name());
+            }
+        }
+
         let home = realm.base_path_file("home");
         if !home.exists() {
             warn!("No home directory exists at {}, creating an empty directory", home.display());
@@ -208,11 +238,34 @@ impl RealmManager {
             util::chown_user(&home)?;
         }
 
+        let gateway = self.check_gateway(realm)?;
+        let uplink = self.zone_uplink(realm)?;
+
         let rootfs = realm.setup_rootfs()?;
 
+        RealmQuota::new(realm).apply()
+            .unwrap_or_else(|e| warn!("Failed to apply disk quotas to realm-{}: {}", realm.name(), e));
+
         realm.update_timestamp()?;
 
-        self.systemd.start_realm(realm, &rootfs)?;
+        if let Some(ref gateway) = gateway {
+            gateway.setup()?;
+        }
+        if let Some(ref uplink) = uplink {
+            uplink.attach(realm)?;
+        }
+
+        if let Err(e) = self.systemd.start_realm(realm, &rootfs, uplink.as_ref()) {
+            if let Some(gateway) = gateway {
+                gateway.teardown()
+                    .unwrap_or_else(|e| warn!("Error removing gateway network namespace: {}", e));
+            }
+            if let Some(uplink) = uplink {
+                uplink.detach(realm)
+                    .unwrap_or_else(|e| warn!("Error removing network namespace of realm-{}: {}", realm.name(), e));
+            }
+            return Err(e);
+        }
 
         self.create_realm_namefile(realm)?;
 
@@ -223,6 +276,108 @@ impl RealmManager {
         Ok(())
     }
 
+    /// If `realm` is a gateway realm, make sure no other running realm is already
+    /// the gateway for the same zone.
+    fn check_gateway(&self, realm: &Realm) -> Result<Option<Gateway>> {
+        let gateway = match Gateway::for_realm(realm)? {
+            Some(gateway) => gateway,
+            None => return Ok(None),
+        };
+        if realm.config().has_netns() {
+            bail!("Gateway realm-{} cannot also be configured with a network namespace", realm.name());
+        }
+        if let Some(other) = self.gateway_realm(gateway.zone()).filter(|r| r.name() != realm.name() && r.is_active()) {
+            bail!("Realm-{} is already running as the gateway for network zone {}", other.name(), gateway.zone());
+        }
+        Ok(Some(gateway))
+    }
+
+    /// If the network zone of `realm` has a gateway realm, return the gateway to route
+    /// through. Starting a realm in a gateway zone fails if the gateway realm is down.
+    fn zone_uplink(&self, realm: &Realm) -> Result<Option<Gateway>> {
+        let config = realm.config();
+        if !config.network() || config.gateway_zone().is_some() {
+            return Ok(None);
+        }
+        let zone = config.network_zone();
+        let gateway_realm = match self.gateway_realm(zone) {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let gateway = Gateway::new(zone)?;
+        if !gateway_realm.is_active() || !gateway.is_up() {
+            bail!("Cannot start realm-{} because gateway realm-{} for network zone {} is not running",
+                  realm.name(), gateway_realm.name(), zone);
+        }
+        Ok(Some(gateway))
+    }
+
+    /// If `realm` was launched in a network namespace routed through the gateway
+    /// of its zone, return that gateway.
+    fn attached_uplink(&self, realm: &Realm) -> Option<Gateway> {
+        let config = realm.config();
+        if config.gateway_zone().is_some() {
+            return None;
+        }
+        Gateway::new(config.network_zone()).ok()
+            .filter(|gw| gw.realm_netns_path(realm).exists())
+    }
+
+    /// Return the realm configured as the gateway for network zone `zone`
+    pub fn gateway_realm(&self, zone: &str) -> Option<Realm> {
+        self.realm_list()
+            .into_iter()
+            .find(|r| r.config().gateway_zone() == Some(zone))
+    }
+
+    /// Return the running realms which reach the network through `gateway`
+    fn gateway_dependents(&self, gateway: &Gateway) -> Vec<Realm> {
+        self.active_realms(false)
+            .into_iter()
+            .filter(|r| {
+                let config = r.config();
+                config.network() && config.gateway_zone().is_none() && config.network_zone() == gateway.zone()
+            })
+            .collect()
+    }
+
+    /// Return `true` if `realm` has an encrypted home directory which must be unlocked
+    /// with `unlock_home()` before the realm can be started.
+    pub fn home_needs_unlock(&self, realm: &Realm) -> bool {
+        EncryptedHome::for_realm(realm)
+            .map(|encrypted| encrypted.exists() && !encrypted.is_unlocked())
+            .unwrap_or(false)
+    }
+
+    /// Unlock the encrypted home directory of `realm` with `passphrase`.
+    pub fn unlock_home(&self, realm: &Realm, passphrase: &str) -> Result<()> {
+        match EncryptedHome::for_realm(realm) {
+            Some(encrypted) => encrypted.unlock(passphrase),
+            None => bail!("Realm-{} does not have an encrypted home directory", realm.name()),
+        }
+    }
+
+    /// Move the home directory of `realm` into a new encrypted image of `size_mb`
+    /// megabytes protected by `passphrase` and enable `encrypted-home` for the realm.
+    pub fn encrypt_home(&self, realm: &Realm, passphrase: &str, size_mb: u64) -> Result<()> {
+        if realm.is_active() {
+            bail!("Cannot encrypt home directory of realm-{} while it is running", realm.name());
+        }
+        EncryptedHome::new(realm).create(passphrase, size_mb)?;
+        realm.with_mut_config(|c| {
+            c.encrypted_home = Some(true);
+            c.write()
+        })
+    }
+
+    /// Rebuild port forwarding rules after the set of running realms has changed.
+    fn update_port_forwards(&self) {
+        let active = self.active_realms(false);
+        if let Err(e) = self.systemd.update_port_forwards(&active) {
+            warn!("Failed to update port forwarding rules: {}", e);
+        }
+    }
+
     fn create_realm_namefile(&self, realm: &Realm) -> Result<()> {
         let namefile = realm.run_path_file("realm-name");
         util::write_file(&namefile, realm.name())?;
@@ -258,16 +413,141 @@ impl RealmManager {
 
         info!("Stopping realm {}", realm.name());
 
+        let gateway = Gateway::for_realm(realm)?;
+        if let Some(ref gateway) = gateway {
+            for dependent in self.gateway_dependents(gateway) {
+                info!("Stopping realm-{} which uses gateway realm-{}", dependent.name(), realm.name());
+                self.stop_realm(&dependent)?;
+            }
+        }
+
         realm.set_active(false);
         self.systemd.stop_realm(realm)?;
         realm.cleanup_rootfs();
+        if let Some(encrypted) = EncryptedHome::for_realm(realm) {
+            encrypted.lock()
+                .unwrap_or_else(|e| warn!("Failed to lock encrypted home of realm-{}: {}", realm.name(), e));
+        }
+        if let Some(gateway) = gateway {
+            gateway.teardown()?;
+        }
+        if let Some(uplink) = self.attached_uplink(realm) {
+            uplink.detach(realm)?;
+        }
+        self.update_port_forwards();
+        AuditLog::record("realm", format!("stopped realm-{}", realm.name()));
 
         if realm.is_current() {
             self.choose_some_current_realm();
         }
+
+        if realm.config().disposable() {
+            self.remove_disposable_realm(realm)?;
+        }
+        Ok(())
+    }
+
+    /// Create a disposable realm using the configuration of `base` or the global
+    /// realm configuration if `base` is `None`.
+    ///
+    /// The new realm has a tmpfs rootfs overlay and an ephemeral home directory
+    /// with no persistent directories, so nothing written by the realm outlives
+    /// it. Shared folders, bind mounts and network namespaces of `base` are not
+    /// copied. A disposable realm is deleted as soon as it is stopped.
+    pub fn new_disposable_realm(&self, base: Option<&Realm>) -> Result<Realm> {
+        let name = self.disposable_realm_name();
+        let realm = self.new_realm(&name)?;
+
+        if let Some(base) = base {
+            base.config().write_to(realm.base_path_file("config"))?;
+        }
+
+        let result = realm.with_mut_config(|c| {
+            c.reload()?;
+            c.set_overlay(OverlayType::TmpFS);
+            c.use_ephemeral_home = Some(true);
+            c.ephemeral_persistent_dirs = Some(Vec::new());
+            c.disposable = Some(true);
+            c.autostart = Some(false);
+            c.system_realm = Some(false);
+            c.reserved_ip = None;
+            c.encrypted_home = Some(false);
+            c.gateway_zone = None;
+            c.port_forwards = None;
+            c.use_shared_dir = Some(false);
+            c.shared_folders = Some(Vec::new());
+            c.extra_bindmounts = Some(Vec::new());
+            c.extra_bindmounts_ro = Some(Vec::new());
+            c.netns = None;
+            c.write()
+        });
+
+        if let Err(e) = result {
+            let _ = self.delete_realm(&realm, false);
+            return Err(e);
+        }
+        info!("Created disposable realm-{}", name);
+        Ok(realm)
+    }
+
+    fn disposable_realm_name(&self) -> String {
+        let mut n = 1;
+        loop {
+            let name = format!("{}{}", DISPOSABLE_PREFIX, n);
+            if self.realm_by_name(&name).is_none() {
+                return name;
+            }
+            n += 1;
+        }
+    }
+
+    /// Run `args` in a newly created disposable realm and wait for the command to
+    /// exit. The realm is then stopped, which also deletes it.
+    pub fn run_disposable<S: AsRef<str>>(&self, base: Option<&Realm>, args: &[S]) -> Result<()> {
+        let realm = self.new_disposable_realm(base)?;
+        let result = self.start_realm(&realm)
+            .and_then(|_| self.run_in_realm(&realm, args, false));
+
+        if realm.is_active() {
+            self.stop_realm(&realm)?;
+        } else {
+            self.remove_disposable_realm(&realm)?;
+        }
+        result
+    }
+
+    /// Delete a disposable realm which is not running along with any network
+    /// allocation or run state left behind if it did not start or stop cleanly.
+    fn remove_disposable_realm(&self, realm: &Realm) -> Result<()> {
+        if !realm.name().starts_with(DISPOSABLE_PREFIX) {
+            bail!("Refusing to remove realm-{} as a disposable realm", realm.name());
+        }
+        info!("Removing disposable realm-{}", realm.name());
+        if self.systemd.realm_address(realm).is_some() {
+            self.systemd.free_network_allocation(realm)?;
+        }
+        if realm.run_path().exists() {
+            realm.cleanup_rootfs();
+        }
+        self.inner_mut().realms.delete_realm(realm.name(), false)?;
+        AuditLog::record("realm", format!("deleted disposable realm-{}", realm.name()));
         Ok(())
     }
 
+    /// Delete disposable realms which are not running. These are left behind if
+    /// the system was shut down or realmsd exited while a disposable realm was running.
+    pub fn remove_stale_disposable_realms(&self) {
+        let stale = self.realm_list()
+            .into_iter()
+            .filter(|r| !r.is_active() && r.config().disposable());
+
+        for realm in stale {
+            if let Err(e) = self.remove_disposable_realm(&realm) {
+                warn!("Failed to remove disposable realm-{}: {}", realm.name(), e);
+            }
+        }
+    }
+
     fn inner(&self) -> RwLockReadGuard<Inner> {
         self.inner.read().unwrap()
     }
@@ -283,6 +563,7 @@ impl RealmManager {
 
         // XXX do something to detect realmfs/overlay that is not cleaned up
         realm.set_active(false);
+        self.update_port_forwards();
 
         if realm.is_current() {
             self.choose_some_current_realm();
@@ -356,14 +637,22 @@ impl RealmManager {
     }
 
     pub fn new_realm(&self, name: &str) -> Result<Realm> {
-        self.inner_mut().realms.create_realm(name)
+        let realm = self.inner_mut().realms.create_realm(name)?;
+        AuditLog::record("realm", format!("created realm-{}", name));
+        Ok(realm)
     }
 
     pub fn delete_realm(&self, realm: &Realm, save_home: bool) -> Result<()> {
         if realm.is_active() {
             self.stop_realm(realm)?;
+            if realm.config().disposable() {
+                // Stopping a disposable realm has already removed it
+                return Ok(());
+            }
         }
-        self.inner_mut().realms.delete_realm(realm.name(), save_home)
+        self.inner_mut().realms.delete_realm(realm.name(), save_home)?;
+        AuditLog::record("realm", format!("deleted realm-{} (save home: {})", realm.name(), save_home));
+        Ok(())
     }
 
     pub fn realmfs_added(&self, realmfs: &RealmFS) {
@@ -380,6 +669,8 @@ impl RealmManager {
         }
         self.inner_mut().realmfs_set.remove(realmfs.name());
         info!("Removing RealmFS image file {}", realmfs.path().display());
-        util::remove_file(realmfs.path())
+        util::remove_file(realmfs.path())?;
+        AuditLog::record("realmfs", format!("deleted {}", realmfs.path().display()));
+        Ok(())
     }
 }
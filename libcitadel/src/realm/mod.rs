@@ -8,6 +8,14 @@ pub(crate) mod realm;
 pub (crate) mod network;
 pub(crate) mod create;
 pub(crate) mod events;
+pub(crate) mod transfer;
+pub(crate) mod dns;
+pub(crate) mod gateway;
+pub(crate) mod forward;
+pub(crate) mod shared;
+pub(crate) mod quota;
+pub(crate) mod encrypted;
+pub(crate) mod sound;
 mod systemd;
 mod launcher;
 
@@ -54,6 +54,11 @@ impl NetworkConfig {
         }
     }
 
+    pub fn allocated_address(&self, bridge: &str, realm_name: &str) -> Option<String> {
+        self.allocators.get(bridge)
+            .and_then(|allocator| allocator.allocated_address(realm_name))
+    }
+
     pub fn allocate_reserved(&mut self, bridge: &str, realm_name: &str, octet: u8) -> Result<String> {
         match self.allocators.get_mut(bridge) {
             Some(allocator) => allocator.allocate_reserved(realm_name, octet),
@@ -179,6 +184,11 @@ impl BridgeAllocator {
         Ok(s)
     }
 
+    /// Return the address currently allocated to `realm_name` on this bridge, if any.
+    pub fn allocated_address(&self, realm_name: &str) -> Option<String> {
+        self.allocations.get(realm_name).map(|addr| addr.to_string())
+    }
+
     pub fn free_allocation_for(&mut self, realm_name: &str) -> Result<()> {
         match self.allocations.remove(realm_name) {
             Some(ip) =>  {
@@ -0,0 +1,271 @@
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::{Exec, Realm, Result, util};
+
+const BTRFS_PATH: &str = "/usr/bin/btrfs";
+const BTRFS_SUPER_MAGIC: i64 = 0x9123_683E;
+
+/// The root directory of every btrfs subvolume has this inode number.
+const BTRFS_SUBVOLUME_INODE: u64 = 256;
+
+/// Return `true` if `path` is on a btrfs filesystem.
+pub fn is_btrfs(path: &Path) -> bool {
+    let cpath = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(cpath) => cpath,
+        Err(_) => return false,
+    };
+    unsafe {
+        let mut buf: libc::statfs = mem::zeroed();
+        libc::statfs(cpath.as_ptr(), &mut buf) == 0 && buf.f_type as i64 == BTRFS_SUPER_MAGIC
+    }
+}
+
+/// Return `true` if `path` is the root directory of a btrfs subvolume.
+pub fn is_subvolume(path: &Path) -> bool {
+    match path.symlink_metadata() {
+        Ok(meta) => meta.is_dir() && meta.ino() == BTRFS_SUBVOLUME_INODE && is_btrfs(path),
+        Err(_) => false,
+    }
+}
+
+pub fn create_subvolume(path: &Path) -> Result<()> {
+    Exec::new(BTRFS_PATH).quiet().run(format!("subvolume create {}", path.display()))
+        .map_err(context!("failed to create btrfs subvolume {:?}", path))
+}
+
+pub fn delete_subvolume(path: &Path) -> Result<()> {
+    Exec::new(BTRFS_PATH).quiet().run(format!("subvolume delete {}", path.display()))
+        .map_err(context!("failed to delete btrfs subvolume {:?}", path))
+}
+
+/// Space used by a btrfs subvolume as reported by its qgroup and the size limit
+/// of the qgroup if one has been set.
+#[derive(Copy,Clone,Debug)]
+pub struct DiskUsage {
+    pub used: u64,
+    pub limit: Option<u64>,
+}
+
+impl DiskUsage {
+    fn load(subvolume: &Path) -> Option<Self> {
+        // Not using Exec because errors (such as quotas not being enabled) should not be
+        // written to stderr when this is called from citadel-realms
+        let output = Command::new(BTRFS_PATH)
+            .args(["qgroup", "show", "--raw", "-r", "-f"])
+            .arg(subvolume)
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+        String::from_utf8_lossy(&output.stdout).lines()
+            .find(|line| line.starts_with("0/"))
+            .and_then(Self::parse_line)
+    }
+
+    ///   qgroupid   rfer   excl   max_rfer
+    fn parse_line(line: &str) -> Option<Self> {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.len() < 4 {
+            return None;
+        }
+        let used = fields[1].parse().ok()?;
+        let limit = fields[3].parse().ok();
+        Some(DiskUsage { used, limit })
+    }
+}
+
+impl fmt::Display for DiskUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.limit {
+            Some(limit) => write!(f, "{} of {}", format_size(self.used), format_size(limit)),
+            None => write!(f, "{}", format_size(self.used)),
+        }
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Return `true` if `size` is a size accepted by `btrfs qgroup limit` such as `500M` or `20G`.
+pub fn is_valid_quota(size: &str) -> bool {
+    let digits = size.trim_end_matches(|c| "KMGTkmgt".contains(c));
+    size.len() - digits.len() <= 1 && !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+///
+/// Disk quotas for the home directory and storage overlay of a realm.
+///
+/// Quotas are implemented as btrfs qgroup limits, so they only apply when the
+/// home directory or overlay is a btrfs subvolume. New realms are created with
+/// home directories in a subvolume when /realms is on btrfs. Existing realms
+/// with a plain home directory are migrated to a subvolume the next time they
+/// are started with a `home-quota` configured.
+///
+pub struct RealmQuota<'a> {
+    realm: &'a Realm,
+}
+
+impl <'a> RealmQuota<'a> {
+    pub fn new(realm: &'a Realm) -> Self {
+        RealmQuota { realm }
+    }
+
+    fn home(&self) -> PathBuf {
+        self.realm.base_path_file("home")
+    }
+
+    fn overlay(&self) -> PathBuf {
+        self.realm.base_path_file("overlay")
+    }
+
+    /// Space used by the realm home directory, if it is a subvolume.
+    pub fn home_usage(&self) -> Option<DiskUsage> {
+        let home = self.home();
+        if is_subvolume(&home) {
+            DiskUsage::load(&home)
+        } else {
+            None
+        }
+    }
+
+    /// Space used by the storage overlay of the realm, if it currently exists.
+    pub fn overlay_usage(&self) -> Option<DiskUsage> {
+        let overlay = self.overlay();
+        if is_subvolume(&overlay) {
+            DiskUsage::load(&overlay)
+        } else {
+            None
+        }
+    }
+
+    /// Set the qgroup limits of the home and overlay subvolumes to the sizes in the
+    /// realm config, migrating the home directory to a subvolume first if needed.
+    pub fn apply(&self) -> Result<()> {
+        let config = self.realm.config();
+        let home = self.home();
+        if config.encrypted_home() {
+            // The size of an encrypted home is fixed by the size of its image
+        } else if let Some(quota) = config.home_quota() {
+            if !is_subvolume(&home) {
+                self.migrate_home()?;
+            }
+            Self::set_limit(&home, quota)?;
+        } else if is_subvolume(&home) {
+            Self::clear_limit(&home)?;
+        }
+
+        let overlay = self.overlay();
+        if is_subvolume(&overlay) {
+            match config.overlay_quota() {
+                Some(quota) => Self::set_limit(&overlay, quota)?,
+                None => Self::clear_limit(&overlay)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Convert a plain home directory into a btrfs subvolume. Files are copied
+    /// with reflinks so no additional space is used.
+    pub fn migrate_home(&self) -> Result<()> {
+        let home = self.home();
+        if is_subvolume(&home) {
+            return Ok(());
+        }
+        if !is_btrfs(&home) {
+            bail!("cannot migrate home directory of realm-{} to a subvolume because {} is not on btrfs", self.realm.name(), home.display());
+        }
+        if self.realm.is_active() {
+            bail!("cannot migrate home directory of realm-{} while realm is running", self.realm.name());
+        }
+
+        info!("Migrating home directory of realm-{} to a btrfs subvolume", self.realm.name());
+        let new_home = self.realm.base_path_file("home.new");
+        let old_home = self.realm.base_path_file("home.old");
+        if new_home.exists() {
+            delete_subvolume(&new_home)?;
+        }
+        create_subvolume(&new_home)?;
+
+        let copied = cmd!("/usr/bin/cp", "-a --reflink=auto {}/. {}", home.display(), new_home.display())
+            .and_then(|_| {
+                let meta = home.metadata().map_err(context!("failed to read metadata of {:?}", home))?;
+                util::chown(&new_home, meta.uid(), meta.gid())
+            });
+        if let Err(e) = copied {
+            let _ = delete_subvolume(&new_home);
+            return Err(e);
+        }
+
+        util::rename(&home, &old_home)?;
+        util::rename(&new_home, &home)?;
+        fs::remove_dir_all(&old_home)
+            .map_err(context!("failed to remove old home directory {:?}", old_home))
+    }
+
+    /// Remove a limit left from an earlier quota configuration. Quotas are not
+    /// enabled on the filesystem if they are not already.
+    fn clear_limit(subvolume: &Path) -> Result<()> {
+        let has_limit = DiskUsage::load(subvolume)
+            .map(|usage| usage.limit.is_some())
+            .unwrap_or(false);
+        if has_limit {
+            cmd!(BTRFS_PATH, "qgroup limit none {}", subvolume.display())
+                .map_err(context!("failed to clear qgroup limit on {:?}", subvolume))?;
+        }
+        Ok(())
+    }
+
+    fn set_limit(subvolume: &Path, size: &str) -> Result<()> {
+        if !is_valid_quota(size) {
+            bail!("invalid quota size '{}'", size);
+        }
+        Self::ensure_quotas_enabled(subvolume)?;
+        cmd!(BTRFS_PATH, "qgroup limit {} {}", size, subvolume.display())
+            .map_err(context!("failed to set qgroup limit on {:?}", subvolume))
+    }
+
+    fn ensure_quotas_enabled(subvolume: &Path) -> Result<()> {
+        let enabled = Exec::new(BTRFS_PATH).quiet()
+            .run_ok(format!("qgroup show {}", subvolume.display()))?;
+        if !enabled {
+            info!("Enabling btrfs quotas on filesystem containing {}", subvolume.display());
+            cmd!(BTRFS_PATH, "quota enable {}", subvolume.display())?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_quota_parsing() {
+    assert!(is_valid_quota("20G"));
+    assert!(is_valid_quota("512m"));
+    assert!(is_valid_quota("1048576"));
+    assert!(!is_valid_quota("G"));
+    assert!(!is_valid_quota("20GB"));
+    assert!(!is_valid_quota("-5G"));
+
+    let usage = DiskUsage::parse_line("0/258   1073741824   16384   21474836480").unwrap();
+    assert_eq!(usage.to_string(), "1.0 GiB of 20.0 GiB");
+    let usage = DiskUsage::parse_line("0/259   512   512   none").unwrap();
+    assert_eq!(usage.limit, None);
+    assert_eq!(usage.to_string(), "512 B");
+}
@@ -0,0 +1,167 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{Realm, Result, AuditLog, util};
+
+const SHARED_FOLDERS_PATH: &str = "/storage/realms/shared";
+
+/// Directory in the realm home where shared folders are mounted.
+const SHARED_FOLDERS_MOUNT: &str = "/home/user/Folders";
+
+const MAX_FOLDER_NAME_LEN: usize = 64;
+
+///
+/// A named directory under /storage/realms/shared which can be mounted into
+/// selected realms.
+///
+/// Unlike the /realms/Shared directory which is mounted into every realm with
+/// `use-shared-dir`, a shared folder is only visible to realms which list it
+/// in their `shared-folders` configuration.
+///
+#[derive(Clone,Debug)]
+pub struct SharedFolder {
+    name: String,
+}
+
+impl SharedFolder {
+    pub fn new(name: &str) -> Result<Self> {
+        if !Self::is_valid_name(name) {
+            bail!("'{}' is not a valid shared folder name", name);
+        }
+        Ok(SharedFolder { name: name.to_string() })
+    }
+
+    pub fn is_valid_name(name: &str) -> bool {
+        util::is_valid_name(name, MAX_FOLDER_NAME_LEN)
+    }
+
+    /// All shared folders which currently exist, sorted by name.
+    pub fn list() -> Vec<Self> {
+        let mut folders = Vec::new();
+        if !Path::new(SHARED_FOLDERS_PATH).exists() {
+            return folders;
+        }
+        let result = util::read_directory(SHARED_FOLDERS_PATH, |dent| {
+            let name = dent.file_name();
+            if let Some(name) = name.to_str() {
+                if dent.path().is_dir() && Self::is_valid_name(name) {
+                    folders.push(SharedFolder { name: name.to_string() });
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            warn!("Error reading shared folders directory: {}", e);
+        }
+        folders.sort_by(|a, b| a.name.cmp(&b.name));
+        folders
+    }
+
+    /// Create a new empty shared folder owned by the realm user.
+    pub fn create(name: &str) -> Result<Self> {
+        let folder = Self::new(name)?;
+        if folder.exists() {
+            bail!("shared folder '{}' already exists", name);
+        }
+        util::create_dir(folder.path())?;
+        util::chown_user(folder.path())?;
+        AuditLog::record("config", format!("created shared folder {}", name));
+        Ok(folder)
+    }
+
+    /// Remove this shared folder. Only empty folders can be removed.
+    pub fn remove(&self) -> Result<()> {
+        fs::remove_dir(self.path())
+            .map_err(context!("failed to remove shared folder {:?}", self.path()))?;
+        AuditLog::record("config", format!("removed shared folder {}", self.name));
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> PathBuf {
+        Path::new(SHARED_FOLDERS_PATH).join(&self.name)
+    }
+
+    /// Path at which this folder appears inside of a realm.
+    pub fn mount_path(&self) -> PathBuf {
+        Path::new(SHARED_FOLDERS_MOUNT).join(&self.name)
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path().is_dir()
+    }
+}
+
+///
+/// Membership of a realm in a shared folder as configured in the realm config
+/// with entries of the form `NAME` or `NAME:rw` for read-write access and
+/// `NAME:ro` for read-only access.
+///
+#[derive(Clone,Debug)]
+pub struct SharedFolderAccess {
+    folder: SharedFolder,
+    read_only: bool,
+}
+
+impl SharedFolderAccess {
+    pub fn new(folder: SharedFolder, read_only: bool) -> Self {
+        SharedFolderAccess { folder, read_only }
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let (name, read_only) = match s.rfind(':') {
+            Some(idx) => match &s[idx + 1..] {
+                "ro" => (&s[..idx], true),
+                "rw" => (&s[..idx], false),
+                mode => bail!("invalid access mode '{}' for shared folder {}", mode, &s[..idx]),
+            },
+            None => (s, false),
+        };
+        Ok(Self::new(SharedFolder::new(name)?, read_only))
+    }
+
+    /// The shared folders configured for `realm`, logging a warning about any
+    /// entries which cannot be parsed.
+    pub fn for_realm(realm: &Realm) -> Vec<Self> {
+        realm.config().shared_folders()
+            .iter()
+            .filter_map(|s| match Self::parse(s) {
+                Ok(access) => Some(access),
+                Err(e) => {
+                    warn!("Ignoring shared folder for realm-{}: {}", realm.name(), e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn folder(&self) -> &SharedFolder {
+        &self.folder
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+impl fmt::Display for SharedFolderAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = if self.read_only { "ro" } else { "rw" };
+        write!(f, "{}:{}", self.folder.name(), mode)
+    }
+}
+
+#[test]
+fn test_parse_shared_folder_access() {
+    let access = SharedFolderAccess::parse("photos").unwrap();
+    assert_eq!(access.folder().name(), "photos");
+    assert!(!access.is_read_only());
+    assert!(SharedFolderAccess::parse("photos:ro").unwrap().is_read_only());
+    assert_eq!(SharedFolderAccess::parse("work-docs:rw").unwrap().to_string(), "work-docs:rw");
+    assert!(SharedFolderAccess::parse("photos:rx").is_err());
+    assert!(SharedFolderAccess::parse("../etc:ro").is_err());
+}
@@ -0,0 +1,97 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::{Realm, Result, SoundBackend};
+
+/// Directory of the PulseAudio socket of the desktop session, served either by
+/// PulseAudio itself or by pipewire-pulse.
+const PULSE_DIR: &str = "/run/user/1000/pulse";
+
+/// Directory of a second pipewire-pulse socket which the host configures with
+/// `client.access = "restricted"` so that clients may only create playback streams.
+/// See `data/pipewire` for the configuration which creates it.
+const PULSE_PLAYBACK_DIR: &str = "/run/user/1000/pulse-playback";
+
+/// Native PipeWire socket of the desktop session.
+const PIPEWIRE_SOCKET: &str = "/run/user/1000/pipewire-0";
+
+/// Additional PipeWire socket which the host access policy restricts to playback.
+/// Created by the fragment in `data/pipewire/pipewire.conf.d`.
+const PIPEWIRE_PLAYBACK_SOCKET: &str = "/run/user/1000/pipewire-0-playback";
+
+///
+/// Generates the bind mounts which give a realm access to the sound server of
+/// the desktop session.
+///
+/// Sockets are always mounted at the same location in the realm so that the
+/// realm does not need to know which backend is in use or whether microphone
+/// access has been restricted:
+///
+/// ```text
+/// /run/user/host/pulse/native
+/// /run/user/host/pipewire-0
+/// ```
+///
+/// Microphone access cannot be removed from a plain PulseAudio socket, so a realm
+/// configured with `use-microphone = false` and the `pulseaudio` backend gets no
+/// sound at all rather than a socket which can record. A realm configured with
+/// `use-microphone = false` and a PipeWire backend fails to start if the host
+/// does not provide the playback-only sockets.
+///
+pub struct RealmSound<'a> {
+    realm: &'a Realm,
+}
+
+impl <'a> RealmSound<'a> {
+    pub fn new(realm: &'a Realm) -> Self {
+        RealmSound { realm }
+    }
+
+    /// Return the nspawn `[Files]` options for sound in this realm.
+    pub fn bind_mounts(&self) -> Result<String> {
+        let config = self.realm.config();
+        let mut s = String::new();
+        if !config.sound() {
+            return Ok(s);
+        }
+
+        let microphone = config.microphone();
+        match config.sound_backend() {
+            SoundBackend::PulseAudio if !microphone => {
+                warn!("Sound disabled in realm-{}: microphone access can only be restricted with the pipewire or pipewire-pulse sound backends", self.realm.name());
+            },
+            SoundBackend::PulseAudio => {
+                self.bind(&mut s, PULSE_DIR, "pulse")?;
+            },
+            SoundBackend::PipeWire => {
+                self.bind_pipewire(&mut s, microphone)?;
+            },
+            SoundBackend::PipeWirePulse => {
+                self.bind_pipewire(&mut s, microphone)?;
+                let dir = if microphone { PULSE_DIR } else { PULSE_PLAYBACK_DIR };
+                self.bind(&mut s, dir, "pulse")?;
+            },
+        }
+        Ok(s)
+    }
+
+    fn bind_pipewire(&self, s: &mut String, microphone: bool) -> Result<()> {
+        let socket = if microphone { PIPEWIRE_SOCKET } else { PIPEWIRE_PLAYBACK_SOCKET };
+        self.bind(s, socket, "pipewire-0")
+    }
+
+    // A missing playback-only socket must not fall back to the unrestricted one
+    // and means the host has not been configured for it, so the realm is not started.
+    fn bind(&self, s: &mut String, source: &str, name: &str) -> Result<()> {
+        if !Path::new(source).exists() {
+            if source == PULSE_PLAYBACK_DIR || source == PIPEWIRE_PLAYBACK_SOCKET {
+                bail!("Playback-only sound socket {} for realm-{} does not exist. Configure the host sound server to provide it or set use-microphone = true",
+                      source, self.realm.name());
+            }
+            warn!("Sound socket {} for realm-{} does not exist", source, self.realm.name());
+            return Ok(());
+        }
+        writeln!(s, "BindReadOnly={}:/run/user/host/{}", source, name)?;
+        Ok(())
+    }
+}
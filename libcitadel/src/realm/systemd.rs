@@ -6,7 +6,9 @@ use std::sync::Mutex;
 use crate::{Result,Realm};
 use crate::realm::{
     launcher::RealmLauncher,
-    network::NetworkConfig
+    network::NetworkConfig,
+    gateway::Gateway,
+    forward::PortForwarding,
 };
 
 const SYSTEMCTL_PATH: &str = "/usr/bin/systemctl";
@@ -23,9 +25,12 @@ impl Systemd {
         Systemd { network }
     }
 
-    pub fn start_realm(&self, realm: &Realm, rootfs: &Path) -> Result<()> {
+    pub fn start_realm(&self, realm: &Realm, rootfs: &Path, uplink: Option<&Gateway>) -> Result<()> {
         let mut lock = self.network.lock().unwrap();
         let mut launcher = RealmLauncher::new(realm);
+        if let Some(gateway) = uplink {
+            launcher.set_uplink(gateway.clone());
+        }
         launcher.write_launch_config_files(rootfs, &mut lock)?;
         self.systemctl_start(&launcher.realm_service_name())?;
         if realm.config().ephemeral_home() {
@@ -71,10 +76,26 @@ impl Systemd {
         let launcher = RealmLauncher::new(realm);
         self.systemctl_stop(&launcher.realm_service_name())?;
         launcher.remove_launch_config_files()?;
+        if self.realm_address(realm).is_some() {
+            self.free_network_allocation(realm)?;
+        }
+        Ok(())
+    }
 
+    pub fn free_network_allocation(&self, realm: &Realm) -> Result<()> {
         let mut network = self.network.lock().unwrap();
-        network.free_allocation_for(realm.config().network_zone(), realm.name())?;
-        Ok(())
+        network.free_allocation_for(realm.config().network_zone(), realm.name())
+    }
+
+    /// Rebuild the port forwarding rules for the realms in `active`.
+    pub fn update_port_forwards(&self, active: &[Realm]) -> Result<()> {
+        let network = self.network.lock().unwrap();
+        PortForwarding::new(&network).apply(active)
+    }
+
+    pub fn realm_address(&self, realm: &Realm) -> Option<String> {
+        let network = self.network.lock().unwrap();
+        network.allocated_address(realm.config().network_zone(), realm.name())
     }
 
     fn systemctl_start(&self, name: &str) -> Result<bool> {
@@ -0,0 +1,377 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sodiumoxide::crypto::hash::sha256;
+
+use crate::{AuditLog, Realm, Realms, Result, util};
+
+const INBOX_DIR: &str = "Inbox";
+const TRANSFER_LOG: &str = "transfers.log";
+const MAX_NAME_ATTEMPTS: usize = 100;
+// uid and gid of the user account inside a realm
+const USER_ID: u32 = 1000;
+
+///
+/// Copies a single file from the home directory of one realm into the
+/// `Inbox` directory in the home directory of another realm.
+///
+/// The source path must name a regular file inside the home directory of the
+/// source realm. When the transfer runs, both the source file and the inbox
+/// are opened one path component at a time relative to a file descriptor on
+/// the realm home directory without following symlinks, so the contents of a
+/// realm cannot redirect the copy to a file outside of its home directory. An
+/// existing file in the inbox is never replaced. Every completed transfer is
+/// appended to a log file in `/realms` recording the realms, paths, size and
+/// sha256 of the file.
+///
+pub struct FileTransfer {
+    source: String,
+    target: String,
+    source_home: PathBuf,
+    target_home: PathBuf,
+    // path of the source file relative to `source_home` with all symlinks resolved
+    source_relative: PathBuf,
+    relative_path: PathBuf,
+}
+
+impl FileTransfer {
+
+    /// Prepare a transfer of `path` from realm `source` to realm `target`.
+    ///
+    /// `path` is interpreted relative to the home directory of `source`. An
+    /// absolute path beginning with `/home/user` is also accepted since that is
+    /// how the file appears from inside the realm.
+    pub fn new(source: &Realm, target: &Realm, path: impl AsRef<Path>) -> Result<Self> {
+        if source.name() == target.name() {
+            bail!("cannot transfer a file from realm-{} to itself", source.name());
+        }
+        Self::prepare(source.name(), Self::home_directory(source),
+                      target.name(), Self::home_directory(target),
+                      path.as_ref())
+    }
+
+    fn prepare(source: &str, source_home: PathBuf, target: &str, target_home: PathBuf, path: &Path) -> Result<Self> {
+        let relative_path = path.strip_prefix("/home/user")
+            .unwrap_or(path)
+            .to_path_buf();
+
+        if relative_path.is_absolute() {
+            bail!("transfer path {} is not inside the realm home directory", path.display());
+        }
+
+        let home = source_home.canonicalize()
+            .map_err(context!("failed to resolve home directory {:?}", source_home))?;
+
+        let joined = home.join(&relative_path);
+        let source_path = joined.canonicalize()
+            .map_err(context!("failed to resolve path {:?} in realm-{}", relative_path, source))?;
+
+        let source_relative = match source_path.strip_prefix(&home) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => bail!("transfer path {} resolves outside of home directory of realm-{}", path.display(), source),
+        };
+
+        let meta = source_path.symlink_metadata()
+            .map_err(context!("failed to read metadata for {:?}", source_path))?;
+        if !meta.file_type().is_file() {
+            bail!("transfer path {} is not a regular file", path.display());
+        }
+
+        Self::check_filename(&source_path)?;
+
+        Ok(FileTransfer {
+            source: source.to_string(),
+            target: target.to_string(),
+            source_home,
+            target_home,
+            source_relative,
+            relative_path,
+        })
+    }
+
+    fn home_directory(realm: &Realm) -> PathBuf {
+        realm.base_path().join("home")
+    }
+
+    fn check_filename(path: &Path) -> Result<()> {
+        let name = path.file_name()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| format_err!("transfer path {} does not have a valid filename", path.display()))?;
+
+        if name.starts_with('.') || name.chars().any(|c| c.is_control()) {
+            bail!("refusing to transfer file with filename {:?}", name);
+        }
+        Ok(())
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// The path of the file being transferred relative to the source realm home directory.
+    pub fn path(&self) -> &Path {
+        &self.relative_path
+    }
+
+    /// Perform the transfer and record it in the transfer log.
+    pub fn run(&self) -> Result<TransferRecord> {
+        let (name, size, digest) = self.copy()?;
+
+        let record = TransferRecord {
+            timestamp: now(),
+            source: self.source.clone(),
+            source_path: self.relative_path.to_string_lossy().to_string(),
+            target: self.target.clone(),
+            target_path: Path::new(INBOX_DIR).join(name).to_string_lossy().to_string(),
+            size,
+            sha256: digest,
+        };
+
+        record.append_to_log()?;
+        AuditLog::record("transfer", record.to_string());
+        info!("Transferred file: {}", record);
+        Ok(record)
+    }
+
+    /// Copy the source file into the inbox of the target realm and return the
+    /// name of the new file in the inbox with the size and sha256 of the contents.
+    fn copy(&self) -> Result<(OsString, u64, String)> {
+        let mut source = self.open_source()?;
+
+        let target_home = util::open_directory(&self.target_home)?;
+        let inbox = util::open_directory_beneath(&target_home, Path::new(INBOX_DIR), Some((USER_ID, USER_ID)))?;
+
+        let (name, mut target) = self.create_target_file(&inbox)?;
+        let result = Self::copy_contents(&mut source, &mut target)
+            .and_then(|v| util::fchown(&target, USER_ID, USER_ID).map(|_| v));
+        drop(target);
+
+        match result {
+            Ok((size, digest)) => Ok((name, size, digest)),
+            Err(e) => {
+                let _ = util::unlinkat(&inbox, &name);
+                Err(e)
+            }
+        }
+    }
+
+    fn open_source(&self) -> Result<File> {
+        let filename = self.source_relative.file_name()
+            .ok_or_else(|| format_err!("transfer path has no filename"))?;
+        let parent = self.source_relative.parent().unwrap_or_else(|| Path::new(""));
+
+        let home = util::open_directory(&self.source_home)?;
+        let dir = util::open_directory_beneath(&home, parent, None)?;
+        let file = util::openat(&dir, filename, libc::O_RDONLY | libc::O_NONBLOCK, 0)
+            .map_err(context!("failed to open {:?} in realm-{}", self.source_relative, self.source))?;
+
+        let meta = file.metadata()
+            .map_err(context!("failed to read metadata for {:?}", self.source_relative))?;
+        if !meta.file_type().is_file() {
+            bail!("transfer path {} is not a regular file", self.relative_path.display());
+        }
+        Ok(file)
+    }
+
+    /// Create a new file in `inbox` without following symlinks or replacing an
+    /// existing file. If the name is already taken a numeric suffix is added.
+    fn create_target_file(&self, inbox: &File) -> Result<(OsString, File)> {
+        let name = self.source_relative.file_name()
+            .ok_or_else(|| format_err!("transfer path has no filename"))?
+            .to_string_lossy()
+            .to_string();
+
+        for n in 0..MAX_NAME_ATTEMPTS {
+            let candidate = if n == 0 {
+                OsString::from(&name)
+            } else {
+                OsString::from(Self::numbered_name(&name, n))
+            };
+            let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL;
+            match util::openat(inbox, &candidate, flags, 0o644) {
+                Ok(file) => return Ok((candidate, file)),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => bail!("failed to create file {:?} in inbox of realm-{}: {}", candidate, self.target, e),
+            }
+        }
+        bail!("could not find a free filename for {} in inbox of realm-{}", name, self.target)
+    }
+
+    fn numbered_name(name: &str, n: usize) -> String {
+        match name.rfind('.') {
+            Some(idx) if idx > 0 => format!("{} ({}){}", &name[..idx], n, &name[idx..]),
+            _ => format!("{} ({})", name, n),
+        }
+    }
+
+    fn copy_contents(source: &mut File, target: &mut File) -> Result<(u64, String)> {
+        let mut state = sha256::State::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut size = 0u64;
+        loop {
+            let n = source.read(&mut buffer)
+                .map_err(context!("error reading transferred file"))?;
+            if n == 0 {
+                break;
+            }
+            state.update(&buffer[..n]);
+            target.write_all(&buffer[..n])
+                .map_err(context!("error writing transferred file"))?;
+            size += n as u64;
+        }
+        let digest = hex::encode(state.finalize().as_ref());
+        Ok((size, digest))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// A completed file transfer as recorded in the transfer log.
+#[derive(Clone,Debug)]
+pub struct TransferRecord {
+    pub timestamp: u64,
+    pub source: String,
+    pub source_path: String,
+    pub target: String,
+    pub target_path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl TransferRecord {
+
+    fn log_path() -> PathBuf {
+        Path::new(Realms::BASE_PATH).join(TRANSFER_LOG)
+    }
+
+    fn append_to_log(&self) -> Result<()> {
+        let path = Self::log_path();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(context!("failed to open transfer log {:?}", path))?;
+        writeln!(file, "{}", self.to_log_line())
+            .map_err(context!("failed to write to transfer log {:?}", path))?;
+        Ok(())
+    }
+
+    fn to_log_line(&self) -> String {
+        format!("{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                self.timestamp, self.source, escape_field(&self.source_path),
+                self.target, escape_field(&self.target_path), self.size, self.sha256)
+    }
+
+    fn from_log_line(line: &str) -> Option<TransferRecord> {
+        let v = line.split('\t').collect::<Vec<_>>();
+        if v.len() != 7 {
+            return None;
+        }
+        Some(TransferRecord {
+            timestamp: v[0].parse().ok()?,
+            source: v[1].to_string(),
+            source_path: v[2].to_string(),
+            target: v[3].to_string(),
+            target_path: v[4].to_string(),
+            size: v[5].parse().ok()?,
+            sha256: v[6].to_string(),
+        })
+    }
+
+    /// Read all records from the transfer log, oldest first.
+    pub fn load_log() -> Result<Vec<TransferRecord>> {
+        let path = Self::log_path();
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let records = util::read_to_string(&path)?
+            .lines()
+            .flat_map(Self::from_log_line)
+            .collect();
+        Ok(records)
+    }
+}
+
+impl fmt::Display for TransferRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "realm-{}:{} -> realm-{}:{} ({} bytes, sha256 {})",
+               self.source, self.source_path, self.target, self.target_path, self.size, self.sha256)
+    }
+}
+
+/// Paths may legally contain tabs and newlines which would corrupt the log format.
+fn escape_field(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+fn test_homes(label: &str) -> (PathBuf, PathBuf, PathBuf) {
+    let base = std::env::temp_dir().join(format!("citadel-transfer-test-{}-{}", label, std::process::id()));
+    let _ = std::fs::remove_dir_all(&base);
+    let homes = (base.join("source"), base.join("target"), base.join("outside"));
+    for dir in &[&homes.0, &homes.1, &homes.2] {
+        std::fs::create_dir_all(dir.join("Documents")).unwrap();
+    }
+    (base, homes.0, homes.1)
+}
+
+#[test]
+fn test_transfer_copy() {
+    let (base, source, target) = test_homes("copy");
+    std::fs::write(source.join("Documents/report.txt"), "contents").unwrap();
+    std::fs::create_dir(target.join(INBOX_DIR)).unwrap();
+    std::fs::write(target.join(INBOX_DIR).join("report.txt"), "existing").unwrap();
+
+    let transfer = FileTransfer::prepare("a", source, "b", target.clone(), Path::new("/home/user/Documents/report.txt")).unwrap();
+    let (name, size, _) = transfer.copy().unwrap();
+    assert_eq!(name, "report (1).txt");
+    assert_eq!(size, 8);
+    assert_eq!(std::fs::read_to_string(target.join(INBOX_DIR).join("report (1).txt")).unwrap(), "contents");
+    assert_eq!(std::fs::read_to_string(target.join(INBOX_DIR).join("report.txt")).unwrap(), "existing");
+    std::fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_transfer_source_parent_swapped_for_symlink() {
+    let (base, source, target) = test_homes("source");
+    std::fs::write(source.join("Documents/report.txt"), "contents").unwrap();
+    std::fs::write(base.join("outside/Documents/report.txt"), "secret").unwrap();
+
+    let transfer = FileTransfer::prepare("a", source.clone(), "b", target.clone(), Path::new("Documents/report.txt")).unwrap();
+
+    std::fs::rename(source.join("Documents"), source.join("Documents.orig")).unwrap();
+    std::os::unix::fs::symlink(base.join("outside/Documents"), source.join("Documents")).unwrap();
+
+    assert!(transfer.copy().is_err());
+    assert!(!target.join(INBOX_DIR).join("report.txt").exists());
+    std::fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_transfer_inbox_swapped_for_symlink() {
+    let (base, source, target) = test_homes("inbox");
+    std::fs::write(source.join("Documents/report.txt"), "contents").unwrap();
+
+    let transfer = FileTransfer::prepare("a", source, "b", target.clone(), Path::new("Documents/report.txt")).unwrap();
+
+    std::os::unix::fs::symlink(base.join("outside/Documents"), target.join(INBOX_DIR)).unwrap();
+
+    assert!(transfer.copy().is_err());
+    assert!(!base.join("outside/Documents/report.txt").exists());
+    std::fs::remove_dir_all(base).unwrap();
+}
@@ -0,0 +1,355 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, Metadata};
+use std::io::{BufReader, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use serde_json::json;
+use walkdir::WalkDir;
+
+use crate::{LoopDevice, RealmFS, Result, util};
+use crate::util::is_euid_root;
+
+pub(super) const DPKG_STATUS: &str = "var/lib/dpkg/status";
+
+/// A file which differs between two RealmFS images.
+#[derive(Clone,Debug,PartialEq)]
+pub enum FileChange {
+    Added(PathBuf),
+    Removed(PathBuf),
+    Modified(PathBuf),
+}
+
+impl FileChange {
+    pub fn path(&self) -> &Path {
+        match self {
+            FileChange::Added(path) | FileChange::Removed(path) | FileChange::Modified(path) => path,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            FileChange::Added(_) => "added",
+            FileChange::Removed(_) => "removed",
+            FileChange::Modified(_) => "modified",
+        }
+    }
+
+    fn symbol(&self) -> char {
+        match self {
+            FileChange::Added(_) => '+',
+            FileChange::Removed(_) => '-',
+            FileChange::Modified(_) => 'M',
+        }
+    }
+}
+
+/// A Debian package which was installed, removed, or changed version between two RealmFS images.
+#[derive(Clone,Debug,PartialEq)]
+pub struct PackageChange {
+    name: String,
+    old_version: Option<String>,
+    new_version: Option<String>,
+}
+
+impl PackageChange {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Version in the first image, or `None` if the package was installed in the second image.
+    pub fn old_version(&self) -> Option<&str> {
+        self.old_version.as_deref()
+    }
+
+    /// Version in the second image, or `None` if the package was removed.
+    pub fn new_version(&self) -> Option<&str> {
+        self.new_version.as_deref()
+    }
+}
+
+impl fmt::Display for PackageChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.old_version, &self.new_version) {
+            (None, Some(new)) => write!(f, "+ {} {}", self.name, new),
+            (Some(old), None) => write!(f, "- {} {}", self.name, old),
+            (Some(old), Some(new)) => write!(f, "U {} {} -> {}", self.name, old, new),
+            (None, None) => write!(f, "? {}", self.name),
+        }
+    }
+}
+
+///
+/// Differences between the files and installed packages of two RealmFS images.
+///
+/// Both images are attached to read-only loop devices and mounted below
+/// `RealmFS::RUN_DIRECTORY` while they are compared, so backup copies and
+/// images which are not sealed or signed can be compared as well.
+///
+/// Files of the same type, ownership, permissions and size are compared by
+/// content only if their modification times differ. Packages are compared if
+/// both images contain a dpkg status database.
+///
+pub struct RealmFSDiff {
+    files: Vec<FileChange>,
+    packages: Option<Vec<PackageChange>>,
+}
+
+impl RealmFSDiff {
+
+    /// Find the image file for a `spec` which is either a path to an image file,
+    /// the name of a RealmFS, or `NAME@N` to select backup copy `N` of a RealmFS
+    /// as listed by `RealmFS::history()`.
+    pub fn image_path(spec: &str) -> Result<PathBuf> {
+        let path = if spec.contains('/') {
+            PathBuf::from(spec)
+        } else if let Some((name, n)) = spec.split_once('@') {
+            let n = n.parse::<usize>()
+                .map_err(|_| format_err!("Invalid backup index in '{}'", spec))?;
+            RealmFS::load_by_name(name)?.backup_path(n)
+        } else {
+            RealmFS::image_path(spec)
+        };
+        if !RealmFS::is_valid_realmfs_image(&path) {
+            bail!("{} is not a RealmFS image", path.display());
+        }
+        Ok(path)
+    }
+
+    /// Compare the RealmFS image files at `old` and `new`.
+    pub fn compare(old: &Path, new: &Path) -> Result<Self> {
+        if !is_euid_root() {
+            bail!("RealmFS images must be compared as root");
+        }
+        with_mounted(old, "a", |old_root| {
+            with_mounted(new, "b", |new_root| {
+                let files = Self::compare_files(old_root, new_root)?;
+                let packages = Self::compare_packages(old_root, new_root)?;
+                Ok(RealmFSDiff { files, packages })
+            })
+        })
+    }
+
+    pub fn files(&self) -> &[FileChange] {
+        &self.files
+    }
+
+    /// Package changes, or `None` if either image has no dpkg status database.
+    pub fn packages(&self) -> Option<&[PackageChange]> {
+        self.packages.as_deref()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.packages.as_ref().map_or(true, |p| p.is_empty())
+    }
+
+    fn compare_files(old_root: &Path, new_root: &Path) -> Result<Vec<FileChange>> {
+        let old = Self::scan_tree(old_root)?;
+        let mut new = Self::scan_tree(new_root)?;
+        let mut changes = Vec::new();
+
+        for (path, old_meta) in old {
+            match new.remove(&path) {
+                None => changes.push(FileChange::Removed(path)),
+                Some(new_meta) => if Self::is_modified(old_root, new_root, &path, &old_meta, &new_meta)? {
+                    changes.push(FileChange::Modified(path));
+                },
+            }
+        }
+        changes.extend(new.into_keys().map(FileChange::Added));
+        changes.sort_by(|a, b| a.path().cmp(b.path()));
+        Ok(changes)
+    }
+
+    // Map of all paths below `root`, relative to `root` and starting with '/'
+    fn scan_tree(root: &Path) -> Result<BTreeMap<PathBuf, Metadata>> {
+        let mut map = BTreeMap::new();
+        for entry in WalkDir::new(root).min_depth(1) {
+            let entry = entry.map_err(|e| format_err!("Error walking directory tree: {}", e))?;
+            let meta = entry.metadata()
+                .map_err(|e| format_err!("Error reading metadata of {:?}: {}", entry.path(), e))?;
+            let relative = entry.path().strip_prefix(root)
+                .map_err(|_| format_err!("Failed to strip prefix from {:?}", entry.path()))?;
+            map.insert(Path::new("/").join(relative), meta);
+        }
+        Ok(map)
+    }
+
+    fn is_modified(old_root: &Path, new_root: &Path, path: &Path, old: &Metadata, new: &Metadata) -> Result<bool> {
+        if old.mode() != new.mode() || old.uid() != new.uid() || old.gid() != new.gid() {
+            return Ok(true);
+        }
+        let old_path = old_root.join(path.strip_prefix("/").unwrap_or(path));
+        let new_path = new_root.join(path.strip_prefix("/").unwrap_or(path));
+        let file_type = old.file_type();
+        if file_type.is_symlink() {
+            let old_target = fs::read_link(&old_path)
+                .map_err(context!("failed to read symlink {:?}", old_path))?;
+            let new_target = fs::read_link(&new_path)
+                .map_err(context!("failed to read symlink {:?}", new_path))?;
+            Ok(old_target != new_target)
+        } else if file_type.is_file() {
+            if old.len() != new.len() {
+                Ok(true)
+            } else if old.mtime() == new.mtime() && old.mtime_nsec() == new.mtime_nsec() {
+                Ok(false)
+            } else {
+                Ok(!Self::same_contents(&old_path, &new_path)?)
+            }
+        } else {
+            Ok(old.rdev() != new.rdev())
+        }
+    }
+
+    fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+        let open = |path: &Path| File::open(path)
+            .map(BufReader::new)
+            .map_err(context!("failed to open {:?}", path));
+        let mut a_reader = open(a)?;
+        let mut b_reader = open(b)?;
+        let mut a_buf = vec![0u8; 65536];
+        let mut b_buf = vec![0u8; 65536];
+        loop {
+            let n = a_reader.read(&mut a_buf)
+                .map_err(context!("failed to read {:?}", a))?;
+            if n == 0 {
+                return Ok(true);
+            }
+            b_reader.read_exact(&mut b_buf[..n])
+                .map_err(context!("failed to read {:?}", b))?;
+            if a_buf[..n] != b_buf[..n] {
+                return Ok(false);
+            }
+        }
+    }
+
+    fn compare_packages(old_root: &Path, new_root: &Path) -> Result<Option<Vec<PackageChange>>> {
+        let old_status = old_root.join(DPKG_STATUS);
+        let new_status = new_root.join(DPKG_STATUS);
+        if !old_status.exists() || !new_status.exists() {
+            return Ok(None);
+        }
+        let old = parse_dpkg_status(&util::read_to_string(&old_status)?);
+        let mut new = parse_dpkg_status(&util::read_to_string(&new_status)?);
+        let mut changes = Vec::new();
+
+        for (name, old_version) in old {
+            match new.remove(&name) {
+                Some(ref new_version) if *new_version == old_version => {},
+                new_version => changes.push(PackageChange { name, old_version: Some(old_version), new_version }),
+            }
+        }
+        changes.extend(new.into_iter().map(|(name, version)| {
+            PackageChange { name, old_version: None, new_version: Some(version) }
+        }));
+        changes.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Some(changes))
+    }
+
+    pub fn to_json(&self) -> String {
+        let files = self.files.iter().map(|change| json!({
+            "change": change.kind(),
+            "path": change.path().to_string_lossy(),
+        })).collect::<Vec<_>>();
+        let packages = self.packages.as_ref().map(|packages| packages.iter().map(|p| json!({
+            "name": p.name(),
+            "old-version": p.old_version(),
+            "new-version": p.new_version(),
+        })).collect::<Vec<_>>());
+        json!({
+            "files": files,
+            "packages": packages,
+        }).to_string()
+    }
+}
+
+impl fmt::Display for RealmFSDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for change in &self.files {
+            writeln!(f, "{} {}", change.symbol(), change.path().display())?;
+        }
+        if let Some(ref packages) = self.packages {
+            if !packages.is_empty() {
+                writeln!(f, "\nPackages:")?;
+                for package in packages {
+                    writeln!(f, "{}", package)?;
+                }
+            }
+        }
+        let count = |kind| self.files.iter().filter(|c| c.kind() == kind).count();
+        write!(f, "\n{} added, {} removed, {} modified", count("added"), count("removed"), count("modified"))?;
+        if let Some(ref packages) = self.packages {
+            write!(f, ", {} package changes", packages.len())?;
+        }
+        writeln!(f)
+    }
+}
+
+/// Attach the image file at `image` to a read-only loop device, mount it on a
+/// directory below `RealmFS::RUN_DIRECTORY`, and call `f` with the mountpoint.
+pub(super) fn with_mounted<F,R>(image: &Path, label: &str, f: F) -> Result<R>
+    where F: FnOnce(&Path) -> Result<R>
+{
+    let mountpoint = Path::new(RealmFS::RUN_DIRECTORY)
+        .join(format!("image-{}-{}", std::process::id(), label));
+    LoopDevice::with_loop(image, Some(4096), true, |loopdev| {
+        util::create_dir(&mountpoint)?;
+        if let Err(err) = loopdev.mount_ro(&mountpoint) {
+            let _ = fs::remove_dir(&mountpoint);
+            return Err(err);
+        }
+        let result = f(&mountpoint);
+        if let Err(err) = util::umount(&mountpoint) {
+            warn!("{}", err);
+        }
+        let _ = fs::remove_dir(&mountpoint);
+        result
+    })
+}
+
+// Map of installed package names to versions from the contents of a dpkg status file.
+// Packages of a foreign architecture are named 'package:arch' as dpkg does.
+pub(super) fn parse_dpkg_status(content: &str) -> BTreeMap<String, String> {
+    let mut packages = BTreeMap::new();
+    for paragraph in content.split("\n\n") {
+        let field = |name: &str| paragraph.lines()
+            .find_map(|line| line.strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix(':'))
+                .map(str::trim));
+        let installed = field("Status").map_or(false, |s| s.ends_with(" installed"));
+        if let (true, Some(name), Some(version)) = (installed, field("Package"), field("Version")) {
+            let name = match field("Architecture") {
+                Some(arch) if arch != "all" && arch != "amd64" => format!("{}:{}", name, arch),
+                _ => name.to_string(),
+            };
+            packages.insert(name, version.to_string());
+        }
+    }
+    packages
+}
+
+#[test]
+fn test_parse_dpkg_status() {
+    let status = "\
+Package: bash
+Status: install ok installed
+Architecture: amd64
+Version: 5.2.15-2+b2
+Description: GNU Bourne Again SHell
+
+Package: removed-pkg
+Status: deinstall ok config-files
+Architecture: amd64
+Version: 1.0
+
+Package: libc6
+Status: install ok installed
+Architecture: i386
+Version: 2.36-9
+";
+    let packages = parse_dpkg_status(status);
+    assert_eq!(packages.len(), 2);
+    assert_eq!(packages.get("bash").map(String::as_str), Some("5.2.15-2+b2"));
+    assert_eq!(packages.get("libc6:i386").map(String::as_str), Some("2.36-9"));
+}
@@ -0,0 +1,152 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::{Map, Value};
+
+use crate::{AuditLog, RealmFS, Result, util};
+use crate::realmfs::oci::OciLayout;
+use crate::util::is_euid_root;
+
+const TAR: &str = "/usr/bin/tar";
+
+// Annotation on the exported OCI manifest which holds the RealmFS image metainfo
+const METAINFO_ANNOTATION: &str = "com.subgraph.citadel.realmfs.metainfo";
+const TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+
+/// Archive format written by `RealmFSExport`.
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum ExportFormat {
+    /// A tar archive, compressed according to the file extension of the target path
+    Tar,
+    /// An OCI image layout directory containing a single layer image
+    OciLayout,
+}
+
+impl ExportFormat {
+    pub fn from_str_value(value: &str) -> Option<Self> {
+        match value {
+            "tar" => Some(ExportFormat::Tar),
+            "oci" => Some(ExportFormat::OciLayout),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportFormat::Tar => write!(f, "tar archive"),
+            ExportFormat::OciLayout => write!(f, "OCI image layout"),
+        }
+    }
+}
+
+///
+/// Writes the contents of a RealmFS image to a tar archive or an OCI image layout.
+///
+/// The files are read from the mounted dm-verity device of the image, so the
+/// signature of the image is checked and every block exported is verified
+/// against the hash tree. Ownership is stored as numeric ids, and extended
+/// attributes and device nodes are included.
+///
+/// The metainfo of the image is stored as an annotation of the OCI manifest,
+/// or next to a tar archive in a file with `.metainfo` appended to the name.
+///
+pub struct RealmFSExport {
+    realmfs: RealmFS,
+    format: ExportFormat,
+    target: PathBuf,
+    reference: Option<String>,
+}
+
+impl RealmFSExport {
+    pub fn new(realmfs: &RealmFS, format: ExportFormat, target: impl AsRef<Path>) -> Self {
+        RealmFSExport {
+            realmfs: realmfs.clone(),
+            format,
+            target: target.as_ref().to_path_buf(),
+            reference: None,
+        }
+    }
+
+    /// Name given to the image in an OCI image layout. The default is the
+    /// name of the RealmFS.
+    pub fn reference(mut self, reference: &str) -> Self {
+        self.reference = Some(reference.to_string());
+        self
+    }
+
+    pub fn run(&self) -> Result<()> {
+        if !is_euid_root() {
+            bail!("RealmFS images must be exported as root");
+        }
+        if self.target.exists() {
+            bail!("Export target {} already exists", self.target.display());
+        }
+
+        let mountpoint = self.realmfs.mountpoint();
+        let was_activated = mountpoint.is_mounted();
+        if !was_activated {
+            self.realmfs.activate()?;
+        }
+
+        info!("Exporting RealmFS '{}' to {} {}", self.realmfs.name(), self.format, self.target.display());
+        let result = match self.format {
+            ExportFormat::Tar => self.export_tar(mountpoint.path()),
+            ExportFormat::OciLayout => self.export_oci(mountpoint.path()),
+        };
+
+        if !was_activated {
+            mountpoint.deactivate();
+        }
+        if result.is_err() {
+            self.remove_target();
+        }
+        result?;
+
+        AuditLog::record("realmfs", format!("exported {} to {} {}", self.realmfs.path().display(), self.format, self.target.display()));
+        Ok(())
+    }
+
+    fn export_tar(&self, root: &Path) -> Result<()> {
+        Self::create_tar(root, &self.target, true)?;
+        let metainfo_path = PathBuf::from(format!("{}.metainfo", self.target.display()));
+        util::write_file(&metainfo_path, self.realmfs.header().metainfo_bytes())
+    }
+
+    fn export_oci(&self, root: &Path) -> Result<()> {
+        util::create_dir(&self.target)?;
+        let layer_tar = self.target.join("layer.tar");
+        Self::create_tar(root, &layer_tar, false)?;
+
+        let metainfo = String::from_utf8_lossy(&self.realmfs.header().metainfo_bytes()).to_string();
+        let mut annotations = Map::new();
+        annotations.insert(TITLE_ANNOTATION.to_string(), Value::from(self.realmfs.name()));
+        annotations.insert(METAINFO_ANNOTATION.to_string(), Value::from(metainfo));
+        let reference = self.reference.as_deref().unwrap_or(self.realmfs.name());
+        OciLayout::create(&self.target, &layer_tar, reference, annotations)?;
+        Ok(())
+    }
+
+    fn create_tar(root: &Path, target: &Path, compress: bool) -> Result<()> {
+        // --auto-compress chooses a compression program from the extension of the target file
+        let compress = if compress { "--auto-compress " } else { "" };
+        cmd!(TAR, "{}--numeric-owner --xattrs --xattrs-include=* --exclude=./lost+found -cpf {} -C {} .",
+             compress, target.display(), root.display())
+    }
+
+    fn remove_target(&self) {
+        if !self.target.exists() {
+            return;
+        }
+        let result = if self.target.is_dir() {
+            fs::remove_dir_all(&self.target)
+        } else {
+            fs::remove_file(&self.target)
+        };
+        if let Err(err) = result {
+            warn!("Failed to remove incomplete export {}: {}", self.target.display(), err);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::{ImageHeader, MetaInfo, RealmFS, Result};
+use crate::audit::format_timestamp;
+
+/// Result of checking the header signature of a RealmFS backup image.
+#[derive(PartialEq,Debug,Copy,Clone)]
+pub enum SignatureStatus {
+    /// Signature verified with the key of the image channel
+    Valid,
+    /// Signature did not verify with the key of the image channel
+    Invalid,
+    /// Image header has no signature
+    Unsigned,
+    /// No public key is available for the image channel
+    NoKey,
+}
+
+impl fmt::Display for SignatureStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            SignatureStatus::Valid => "valid",
+            SignatureStatus::Invalid => "INVALID",
+            SignatureStatus::Unsigned => "unsigned",
+            SignatureStatus::NoKey => "no key",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Check the signature of `header`, which is either the header of `realmfs` or of
+/// a backup copy of it.
+pub(super) fn signature_status(realmfs: &RealmFS, header: &ImageHeader) -> SignatureStatus {
+    if !header.has_signature() {
+        return SignatureStatus::Unsigned;
+    }
+    match realmfs.public_key_for_header(header).ok() {
+        Some(pubkey) => if header.verify_signature(pubkey) {
+            SignatureStatus::Valid
+        } else {
+            SignatureStatus::Invalid
+        },
+        None => SignatureStatus::NoKey,
+    }
+}
+
+///
+/// A previous version of a RealmFS image which was kept when the image was updated.
+///
+/// Each time a RealmFS image is updated the old image file is rotated to
+/// `NAME-realmfs.img.0` and older copies are moved up by one index, so index 0 is
+/// always the most recent backup.
+///
+pub struct RealmFSBackup {
+    index: usize,
+    path: PathBuf,
+    header: ImageHeader,
+    mtime: i64,
+    signature: SignatureStatus,
+}
+
+impl RealmFSBackup {
+    pub(super) fn load(realmfs: &RealmFS, index: usize, path: PathBuf) -> Result<Self> {
+        let header = ImageHeader::from_file(&path)?;
+        if !header.is_magic_valid() {
+            bail!("Backup image {} does not have a valid header", path.display());
+        }
+        let metainfo = header.metainfo();
+        if metainfo.image_type() != "realmfs" || metainfo.realmfs_name() != Some(realmfs.name()) {
+            bail!("Backup image {} is not a copy of realmfs '{}'", path.display(), realmfs.name());
+        }
+        let mtime = path.metadata()
+            .map_err(context!("failed to read metadata from {:?}", path))?
+            .mtime();
+        let signature = signature_status(realmfs, &header);
+        Ok(RealmFSBackup { index, path, header, mtime, signature })
+    }
+
+    /// Position of this backup in the rotation, 0 is the most recent.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn metainfo(&self) -> Arc<MetaInfo> {
+        self.header.metainfo()
+    }
+
+    /// The timestamp field of the image header, or the time the image file was
+    /// last modified for user sealed images which do not have one.
+    pub fn timestamp(&self) -> String {
+        let metainfo = self.metainfo();
+        if metainfo.timestamp().is_empty() {
+            format_timestamp(self.mtime as u64)
+        } else {
+            metainfo.timestamp().to_string()
+        }
+    }
+
+    pub fn nblocks(&self) -> usize {
+        self.metainfo().nblocks()
+    }
+
+    pub fn verity_root(&self) -> String {
+        self.metainfo().verity_root().to_string()
+    }
+
+    pub fn signature_status(&self) -> SignatureStatus {
+        self.signature
+    }
+}
+
+impl fmt::Display for RealmFSBackup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let root = self.verity_root();
+        write!(f, "{}  {}  {} blocks  root {}  signature {}",
+               self.index, self.timestamp(), self.nblocks(), root.get(..16).unwrap_or(&root), self.signature)
+    }
+}
@@ -0,0 +1,270 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use walkdir::WalkDir;
+
+use crate::{AuditLog, ImageHeader, KeyPair, KeyRing, LoopDevice, RealmFS, ResizeSize, Result, util};
+use crate::realmfs::manifest::PackageManifest;
+use crate::realmfs::oci::OciLayout;
+use crate::realmfs::update::seal_image;
+use crate::util::is_euid_root;
+
+const BLOCK_SIZE: usize = 4096;
+
+// Default bytes-per-inode ratio of mkfs.ext4
+const BYTES_PER_INODE: usize = 16384;
+
+const TAR: &str = "/usr/bin/tar";
+const MKFS_EXT4: &str = "/sbin/mkfs.ext4";
+
+// Whiteout files in OCI image layers mark files from lower layers as deleted
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Where the root filesystem of an imported RealmFS image comes from.
+pub enum ImportSource {
+    /// A directory containing a root filesystem
+    Directory(PathBuf),
+    /// A tar archive of a root filesystem, which may be compressed
+    Tarball(PathBuf),
+    /// An OCI image layout directory
+    OciLayout(PathBuf),
+}
+
+impl ImportSource {
+    /// Choose the type of source according to what is found at `path`.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if OciLayout::is_oci_layout(&path) {
+            Ok(ImportSource::OciLayout(path))
+        } else if path.is_dir() {
+            Ok(ImportSource::Directory(path))
+        } else if path.is_file() {
+            Ok(ImportSource::Tarball(path))
+        } else {
+            bail!("import source {} does not exist", path.display());
+        }
+    }
+}
+
+impl fmt::Display for ImportSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportSource::Directory(path) => write!(f, "directory {}", path.display()),
+            ImportSource::Tarball(path) => write!(f, "tar archive {}", path.display()),
+            ImportSource::OciLayout(path) => write!(f, "OCI image layout {}", path.display()),
+        }
+    }
+}
+
+///
+/// Creates a new RealmFS image from a root filesystem directory, a tar archive,
+/// or an OCI image layout.
+///
+/// The filesystem is copied into a new ext4 image sized to fit the files plus
+/// `headroom` of free space, and the image is then sealed with dm-verity and
+/// signed with the user RealmFS key in the same way as an updated image.
+///
+/// Layers of an OCI image are extracted one at a time into a staging directory
+/// under `RealmFS::BASE_PATH` and merged from the bottom layer up. Whiteout
+/// files in a layer remove the matching files of the layers below it.
+///
+pub struct RealmFSImport {
+    name: String,
+    source: ImportSource,
+    headroom: ResizeSize,
+    reference: Option<String>,
+}
+
+impl RealmFSImport {
+    pub fn new(name: &str, source: ImportSource) -> Self {
+        RealmFSImport {
+            name: name.to_string(),
+            source,
+            headroom: ResizeSize::default_headroom(),
+            reference: None,
+        }
+    }
+
+    /// Free space to leave in the new image.
+    pub fn headroom(mut self, headroom: ResizeSize) -> Self {
+        self.headroom = headroom;
+        self
+    }
+
+    /// Name of the image to import from an OCI image layout which contains more
+    /// than one image.
+    pub fn reference(mut self, reference: &str) -> Self {
+        self.reference = Some(reference.to_string());
+        self
+    }
+
+    pub fn run(&self) -> Result<RealmFS> {
+        if !is_euid_root() {
+            bail!("RealmFS images must be imported as root");
+        }
+        if !RealmFS::is_valid_name(&self.name) {
+            bail!("Invalid RealmFS name '{}'", self.name);
+        }
+        let path = RealmFS::image_path(&self.name);
+        if path.exists() {
+            bail!("RealmFS image for name {} already exists", self.name);
+        }
+        let keys = match KeyRing::get_kernel_keypair(RealmFS::USER_KEYNAME) {
+            Ok(keys) => keys,
+            Err(err) => bail!("Cannot import realmfs image, no signing keys available: {}", err),
+        };
+
+        info!("Importing RealmFS '{}' from {}", self.name, self.source);
+        let workdir = Path::new(RealmFS::BASE_PATH).join(format!(".import-{}", self.name));
+        Self::remove_path(&workdir)?;
+        util::create_dir(&workdir)?;
+
+        let image = workdir.join(format!("{}-realmfs.img", self.name));
+        let result = self.build_image(&workdir, &image, &keys)
+            .and_then(|_| util::rename(&image, &path))
+            .and_then(|_| PackageManifest::rename(&image, &path));
+
+        if let Err(err) = Self::remove_path(&workdir) {
+            warn!("Failed to remove import directory {}: {}", workdir.display(), err);
+        }
+        result?;
+
+        AuditLog::record("realmfs", format!("imported {} from {}", path.display(), self.source));
+        RealmFS::load_from_path(&path)
+    }
+
+    fn build_image(&self, workdir: &Path, image: &Path, keys: &KeyPair) -> Result<()> {
+        let rootfs = match self.source {
+            ImportSource::Directory(ref dir) => dir.clone(),
+            ImportSource::Tarball(ref tarball) => {
+                let rootfs = workdir.join("rootfs");
+                util::create_dir(&rootfs)?;
+                Self::extract(tarball, &rootfs)?;
+                rootfs
+            },
+            ImportSource::OciLayout(ref layout) => {
+                let rootfs = workdir.join("rootfs");
+                self.flatten_layers(layout, workdir, &rootfs)?;
+                rootfs
+            },
+        };
+
+        let (nblocks, ninodes) = Self::estimate_size(&rootfs);
+        let nblocks = nblocks + self.headroom.nblocks();
+        let ninodes = ninodes.max(nblocks * BLOCK_SIZE / BYTES_PER_INODE);
+        info!("Creating image of {} blocks with {} inodes", nblocks, ninodes);
+
+        let file = File::create(image)
+            .map_err(context!("failed to create image file {:?}", image))?;
+        file.set_len(((nblocks + 1) * BLOCK_SIZE) as u64)
+            .map_err(context!("failed to set length of image file {:?}", image))?;
+
+        LoopDevice::with_loop(image, Some(BLOCK_SIZE), false, |loopdev| {
+            cmd!(MKFS_EXT4, "-q -F -b {} -N {} -d {} {}", BLOCK_SIZE, ninodes, rootfs.display(), loopdev.device().display())
+        })?;
+
+        // The sealing code reads the current header of the image, so start with an unsigned one
+        let header = ImageHeader::new();
+        header.set_metainfo_bytes(&RealmFS::generate_metainfo(&self.name, nblocks, "", ""))?;
+        header.write_header_to(image)?;
+
+        seal_image(image, &self.name, nblocks, keys)
+    }
+
+    // Estimate the number of blocks and inodes needed to hold the files in `rootfs`,
+    // adding an allowance for the journal and other ext4 metadata.
+    fn estimate_size(rootfs: &Path) -> (usize, usize) {
+        let mut blocks = 0;
+        let mut inodes = 0;
+        for entry in WalkDir::new(rootfs).into_iter().filter_map(|e| e.ok()) {
+            if let Ok(meta) = entry.metadata() {
+                inodes += 1;
+                blocks += if meta.is_file() {
+                    (meta.len() as usize + BLOCK_SIZE - 1) / BLOCK_SIZE
+                } else {
+                    1
+                };
+            }
+        }
+        let blocks = blocks + blocks / 10 + ResizeSize::megs(128).nblocks();
+        (blocks, inodes * 2)
+    }
+
+    fn extract(tarball: &Path, target: &Path) -> Result<()> {
+        info!("Extracting {}", tarball.display());
+        cmd!(TAR, "--numeric-owner --xattrs --xattrs-include=* -xpf {} -C {}", tarball.display(), target.display())
+    }
+
+    fn flatten_layers(&self, layout: &Path, workdir: &Path, rootfs: &Path) -> Result<()> {
+        let layers = OciLayout::open(layout)?.layers(self.reference.as_deref())?;
+        if layers.is_empty() {
+            bail!("OCI image has no layers");
+        }
+        util::create_dir(rootfs)?;
+        for (n, layer) in layers.iter().enumerate() {
+            // Each layer is extracted into an empty directory so that symlinks from
+            // lower layers are never followed while extracting.
+            let layer_dir = workdir.join(format!("layer-{}", n));
+            util::create_dir(&layer_dir)?;
+            Self::extract(layer, &layer_dir)?;
+            Self::merge_layer(&layer_dir, rootfs)?;
+            Self::remove_path(&layer_dir)?;
+        }
+        Ok(())
+    }
+
+    // Move the contents of the directory `layer` into `target` and apply whiteouts.
+    // Only directories which are not symlinks are descended into in `target`.
+    fn merge_layer(layer: &Path, target: &Path) -> Result<()> {
+        let mut names = Vec::new();
+        util::read_directory(layer, |dent| {
+            names.push(dent.file_name());
+            Ok(())
+        })?;
+
+        let is_whiteout = |name: &str| name.starts_with(WHITEOUT_PREFIX);
+        if names.iter().any(|n| n == OPAQUE_WHITEOUT) {
+            util::read_directory(target, |dent| Self::remove_path(&dent.path()))?;
+        }
+        for name in names.iter().map(|n| n.to_string_lossy()) {
+            match name.strip_prefix(WHITEOUT_PREFIX) {
+                Some(hidden) if !hidden.starts_with(WHITEOUT_PREFIX) && hidden != "." && hidden != ".." && !hidden.is_empty() => {
+                    Self::remove_path(&target.join(hidden))?;
+                },
+                _ => {},
+            }
+        }
+
+        for name in names.iter().filter(|n| !is_whiteout(&n.to_string_lossy())) {
+            let src = layer.join(name);
+            let dst = target.join(name);
+            let src_meta = src.symlink_metadata()
+                .map_err(context!("failed to read metadata of {:?}", src))?;
+            let dst_is_dir = dst.symlink_metadata().map(|m| m.is_dir()).unwrap_or(false);
+            if src_meta.is_dir() && dst_is_dir {
+                Self::merge_layer(&src, &dst)?;
+                util::chown(&dst, src_meta.uid(), src_meta.gid())?;
+                fs::set_permissions(&dst, fs::Permissions::from_mode(src_meta.mode()))
+                    .map_err(context!("failed to set permissions of {:?}", dst))?;
+            } else {
+                Self::remove_path(&dst)?;
+                util::rename(&src, &dst)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_path(path: &Path) -> Result<()> {
+        match path.symlink_metadata() {
+            Ok(meta) if meta.is_dir() => fs::remove_dir_all(path)
+                .map_err(context!("failed to remove directory {:?}", path)),
+            // util::remove_file() would skip a dangling symlink
+            Ok(_) => fs::remove_file(path)
+                .map_err(context!("failed to remove file {:?}", path)),
+            Err(_) => Ok(()),
+        }
+    }
+}
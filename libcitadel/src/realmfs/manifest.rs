@@ -0,0 +1,310 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::sign::SIGNATUREBYTES;
+
+use crate::{ImageHeader, KeyPair, PublicKey, Result, util};
+use crate::audit::format_timestamp;
+use crate::realmfs::diff::{self, DPKG_STATUS};
+
+// Files in the image which have their sha256 recorded in the manifest
+const KEY_FILES: &[&str] = &[
+    "etc/passwd",
+    "etc/group",
+    "etc/shadow",
+    "etc/sudoers",
+    "etc/ld.so.preload",
+    "etc/apt/sources.list",
+    "usr/lib/os-release",
+    DPKG_STATUS,
+];
+
+// Directories in the image with a sha256 recorded for each regular file they contain
+const KEY_DIRECTORIES: &[&str] = &[
+    "etc/sudoers.d",
+    "etc/apt/sources.list.d",
+    "etc/apt/trusted.gpg.d",
+    "etc/apt/keyrings",
+];
+
+///
+/// List of the installed packages and the sha256 of some important files of a
+/// RealmFS image, recorded when the image is sealed so that the contents of an
+/// image can be audited without mounting it.
+///
+/// The manifest is stored next to the image file in a file with `.manifest`
+/// appended to the name and is signed with the same key as the image header.
+/// It names the verity root of the image it describes, so a manifest left
+/// behind by an earlier version of the image is not mistaken for a current one.
+///
+#[derive(Serialize,Deserialize,Clone)]
+pub struct PackageManifest {
+    #[serde(rename = "realmfs-name")]
+    name: String,
+    #[serde(rename = "verity-root")]
+    verity_root: String,
+    created: String,
+    signature: String,
+    #[serde(default)]
+    packages: BTreeMap<String, String>,
+    #[serde(default)]
+    files: BTreeMap<String, String>,
+}
+
+impl PackageManifest {
+
+    /// Path of the manifest for the image file at `image`.
+    pub(super) fn path_for(image: &Path) -> PathBuf {
+        PathBuf::from(format!("{}.manifest", image.display()))
+    }
+
+    /// Mount the sealed image at `image` read-only and record its contents in a
+    /// new manifest signed with `keys`.
+    pub(super) fn generate(image: &Path, name: &str, verity_root: &str, keys: &KeyPair) -> Result<Self> {
+        let (packages, files) = diff::with_mounted(image, "manifest", |root| {
+            let status = root.join(DPKG_STATUS);
+            let packages = if status.exists() {
+                diff::parse_dpkg_status(&util::read_to_string(&status)?)
+            } else {
+                BTreeMap::new()
+            };
+            Ok((packages, Self::hash_key_files(root)?))
+        })?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut manifest = PackageManifest {
+            name: name.to_string(),
+            verity_root: verity_root.to_string(),
+            created: format_timestamp(now),
+            signature: String::new(),
+            packages,
+            files,
+        };
+        manifest.sign(keys);
+        Ok(manifest)
+    }
+
+    fn hash_key_files(root: &Path) -> Result<BTreeMap<String, String>> {
+        let mut paths = KEY_FILES.iter().map(|p| root.join(p)).collect::<Vec<_>>();
+        for dir in KEY_DIRECTORIES {
+            let dir = root.join(dir);
+            let is_dir = dir.symlink_metadata().map(|m| m.is_dir()).unwrap_or(false);
+            if is_dir {
+                util::read_directory(&dir, |dent| {
+                    paths.push(dent.path());
+                    Ok(())
+                })?;
+            }
+        }
+
+        let mut files = BTreeMap::new();
+        for path in paths {
+            // Symlinks are not followed since they would be resolved against the host filesystem
+            let is_file = path.symlink_metadata().map(|m| m.is_file()).unwrap_or(false);
+            if is_file {
+                let relative = path.strip_prefix(root)
+                    .map_err(|_| format_err!("Failed to strip prefix from {:?}", path))?;
+                files.insert(Path::new("/").join(relative).display().to_string(), Self::hash_file(&path)?);
+            }
+        }
+        Ok(files)
+    }
+
+    fn hash_file(path: &Path) -> Result<String> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)
+            .map_err(context!("failed to open {:?}", path))?;
+        let mut state = sha256::State::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let n = file.read(&mut buffer)
+                .map_err(context!("error reading {:?}", path))?;
+            if n == 0 {
+                break;
+            }
+            state.update(&buffer[..n]);
+        }
+        Ok(hex::encode(state.finalize().as_ref()))
+    }
+
+    // The signature covers this text rather than the TOML file so that it does
+    // not depend on how the file is formatted.
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        writeln!(v, "realmfs-name {}", self.name).unwrap();
+        writeln!(v, "verity-root {}", self.verity_root).unwrap();
+        writeln!(v, "created {}", self.created).unwrap();
+        for (name, version) in &self.packages {
+            writeln!(v, "package {} {}", name, version).unwrap();
+        }
+        for (path, sha256) in &self.files {
+            writeln!(v, "file {} {}", path, sha256).unwrap();
+        }
+        v
+    }
+
+    fn sign(&mut self, keys: &KeyPair) {
+        let sig = keys.sign(&self.signed_bytes());
+        self.signature = hex::encode(sig.to_bytes());
+    }
+
+    /// Return a copy of this manifest for a fork of the image named `name`.
+    pub(super) fn with_name(&self, name: &str, keys: &KeyPair) -> Self {
+        let mut manifest = self.clone();
+        manifest.name = name.to_string();
+        manifest.sign(keys);
+        manifest
+    }
+
+    /// Check that this manifest describes the image with header `header` and
+    /// that it is signed by `pubkey`.
+    pub(super) fn verify(&self, header: &ImageHeader, pubkey: PublicKey) -> Result<()> {
+        if header.metainfo().verity_root() != self.verity_root {
+            bail!("package manifest does not describe the current version of the image");
+        }
+        let sig = hex::decode(&self.signature)
+            .map_err(|_| format_err!("package manifest signature is not valid hex"))?;
+        if sig.len() != SIGNATUREBYTES || !pubkey.verify(&self.signed_bytes(), &sig) {
+            bail!("package manifest signature verification failed");
+        }
+        Ok(())
+    }
+
+    pub(super) fn load(path: &Path) -> Result<Self> {
+        let s = util::read_to_string(path)?;
+        toml::from_str(&s)
+            .map_err(|e| format_err!("failed to parse package manifest {}: {}", path.display(), e))
+    }
+
+    pub(super) fn save(&self, path: &Path) -> Result<()> {
+        let s = toml::to_string(self)
+            .map_err(|e| format_err!("failed to serialize package manifest: {}", e))?;
+        util::write_file(path, s)
+    }
+
+    /// Move the manifest of the image file `from` to go with the image file
+    /// `to`, removing any manifest of `to` if `from` has none.
+    pub(super) fn rename(from: &Path, to: &Path) -> Result<()> {
+        let from = Self::path_for(from);
+        let to = Self::path_for(to);
+        if from.exists() {
+            util::rename(&from, &to)
+        } else {
+            util::remove_file(&to)
+        }
+    }
+
+    /// Exchange the manifests of the image files `a` and `b`.
+    pub(super) fn exchange(a: &Path, b: &Path) -> Result<()> {
+        let (a_manifest, b_manifest) = (Self::path_for(a), Self::path_for(b));
+        match (a_manifest.exists(), b_manifest.exists()) {
+            (true, true) => util::exchange(&a_manifest, &b_manifest),
+            (true, false) => Self::rename(a, b),
+            (false, true) => Self::rename(b, a),
+            (false, false) => Ok(()),
+        }
+    }
+
+    /// Copy the manifest of the image file `from`, if it has one, to go with the image file `to`.
+    pub(super) fn copy(from: &Path, to: &Path) -> Result<()> {
+        let from = Self::path_for(from);
+        if from.exists() {
+            util::copy_file(&from, Self::path_for(to))?;
+        }
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn verity_root(&self) -> &str {
+        &self.verity_root
+    }
+
+    /// Time the image was sealed.
+    pub fn created(&self) -> &str {
+        &self.created
+    }
+
+    /// Installed packages and their versions. Packages of a foreign architecture
+    /// are named `package:arch`.
+    pub fn packages(&self) -> impl Iterator<Item=(&str, &str)> {
+        self.packages.iter().map(|(name, version)| (name.as_str(), version.as_str()))
+    }
+
+    /// Version of the package `name` if it is installed.
+    pub fn package_version(&self, name: &str) -> Option<&str> {
+        self.packages.get(name).map(String::as_str)
+    }
+
+    /// Absolute paths within the image of the files recorded in the manifest and their sha256.
+    pub fn files(&self) -> impl Iterator<Item=(&str, &str)> {
+        self.files.iter().map(|(path, sha256)| (path.as_str(), sha256.as_str()))
+    }
+
+    pub fn to_json(&self) -> String {
+        let packages = self.packages().map(|(name, version)| json!({
+            "name": name,
+            "version": version,
+        })).collect::<Vec<_>>();
+        let files = self.files().map(|(path, sha256)| json!({
+            "path": path,
+            "sha256": sha256,
+        })).collect::<Vec<_>>();
+        json!({
+            "realmfs-name": self.name(),
+            "verity-root": self.verity_root(),
+            "created": self.created(),
+            "packages": packages,
+            "files": files,
+        }).to_string()
+    }
+}
+
+impl fmt::Display for PackageManifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, version) in self.packages() {
+            writeln!(f, "{:<40} {}", name, version)?;
+        }
+        if !self.files.is_empty() {
+            writeln!(f, "\nFiles:")?;
+            for (path, sha256) in self.files() {
+                writeln!(f, "{}  {}", sha256, path)?;
+            }
+        }
+        write!(f, "\n{} packages, sealed {}", self.packages.len(), self.created)
+    }
+}
+
+#[test]
+fn test_manifest_signature() {
+    let keys = KeyPair::generate();
+    let mut manifest = PackageManifest {
+        name: "main".to_string(),
+        verity_root: "00ff".to_string(),
+        created: "2026-10-18 12:00:00".to_string(),
+        signature: String::new(),
+        packages: vec![("bash".to_string(), "5.2.15-2+b2".to_string())].into_iter().collect(),
+        files: BTreeMap::new(),
+    };
+    manifest.sign(&keys);
+    let sig = hex::decode(&manifest.signature).unwrap();
+    assert!(keys.verify(&manifest.signed_bytes(), &sig));
+
+    let parsed: PackageManifest = toml::from_str(&toml::to_string(&manifest).unwrap()).unwrap();
+    assert_eq!(parsed.package_version("bash"), Some("5.2.15-2+b2"));
+    assert!(keys.verify(&parsed.signed_bytes(), &sig));
+
+    manifest.packages.insert("openssl".to_string(), "3.0.11-1".to_string());
+    assert!(!keys.verify(&manifest.signed_bytes(), &sig));
+}
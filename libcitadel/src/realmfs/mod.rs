@@ -1,9 +1,23 @@
 pub(crate) mod resizer;
 mod mountpoint;
 mod update;
+mod history;
+pub(crate) mod oci;
+mod import;
+mod export;
+mod diff;
+mod trust;
+mod manifest;
 pub(crate) mod realmfs_set;
 #[allow(clippy::module_inception)]
 mod realmfs;
 
 pub use self::realmfs::RealmFS;
 pub use self::mountpoint::Mountpoint;
+pub use self::update::UpdateScript;
+pub use self::history::{RealmFSBackup,SignatureStatus};
+pub use self::import::{RealmFSImport,ImportSource};
+pub use self::export::{RealmFSExport,ExportFormat};
+pub use self::diff::{RealmFSDiff,FileChange,PackageChange};
+pub use self::trust::{PublisherTrustStore,Publisher,Provenance,import_signed_image};
+pub use self::manifest::PackageManifest;
@@ -0,0 +1,226 @@
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Map, Value};
+
+use crate::{Result, util};
+
+const INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+const LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+const DOCKER_LIST_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+///
+/// An OCI image layout directory as written by tools such as `skopeo copy` or
+/// `podman save --format oci-dir`:
+///
+/// ```text
+/// oci-layout
+/// index.json
+/// blobs/sha256/...
+/// ```
+///
+pub(super) struct OciLayout {
+    path: PathBuf,
+}
+
+impl OciLayout {
+    pub fn is_oci_layout(path: &Path) -> bool {
+        path.join("oci-layout").is_file() && path.join("index.json").is_file()
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        if !Self::is_oci_layout(path) {
+            bail!("{} is not an OCI image layout directory", path.display());
+        }
+        Ok(OciLayout { path: path.to_path_buf() })
+    }
+
+    /// Create a new OCI image layout at `path` containing a single image named
+    /// `reference` with one layer built from the uncompressed tar archive
+    /// `layer_tar`. The archive is compressed and moved into the layout.
+    pub fn create(path: &Path, layer_tar: &Path, reference: &str, annotations: Map<String, Value>) -> Result<Self> {
+        util::create_dir(path.join("blobs/sha256"))?;
+        let layout = OciLayout { path: path.to_path_buf() };
+
+        // The config lists the digest of the uncompressed layer, the manifest the compressed one
+        let diff_id = format!("sha256:{}", util::sha256(layer_tar)?);
+        cmd!("/usr/bin/gzip", "-n {}", layer_tar.display())?;
+        let layer_gz = PathBuf::from(format!("{}.gz", layer_tar.display()));
+        let layer = layout.add_blob(&layer_gz, LAYER_MEDIA_TYPE)?;
+
+        let config = json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "config": {},
+            "rootfs": {
+                "type": "layers",
+                "diff_ids": [diff_id],
+            },
+        });
+        let config = layout.add_json_blob(&config, CONFIG_MEDIA_TYPE)?;
+
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": MANIFEST_MEDIA_TYPE,
+            "config": config,
+            "layers": [layer],
+            "annotations": annotations,
+        });
+        let mut manifest = layout.add_json_blob(&manifest, MANIFEST_MEDIA_TYPE)?;
+        manifest["annotations"] = json!({ REF_NAME_ANNOTATION: reference });
+
+        let index = json!({
+            "schemaVersion": 2,
+            "manifests": [manifest],
+        });
+        util::write_file(path.join("index.json"), index.to_string())?;
+        util::write_file(path.join("oci-layout"), r#"{"imageLayoutVersion":"1.0.0"}"#)?;
+        Ok(layout)
+    }
+
+    // Move the file at `source` into the blob directory and return a descriptor for it
+    fn add_blob(&self, source: &Path, media_type: &str) -> Result<Value> {
+        let hash = util::sha256(source)?;
+        let size = source.metadata()
+            .map_err(context!("failed to read metadata from {:?}", source))?
+            .len();
+        util::rename(source, self.path.join("blobs/sha256").join(&hash))?;
+        Ok(json!({
+            "mediaType": media_type,
+            "digest": format!("sha256:{}", hash),
+            "size": size,
+        }))
+    }
+
+    fn add_json_blob(&self, json: &Value, media_type: &str) -> Result<Value> {
+        let tmp = self.path.join("blobs/sha256/.new-blob");
+        util::write_file(&tmp, json.to_string())?;
+        self.add_blob(&tmp, media_type)
+    }
+
+    /// Return the paths of the layer blobs of the image named `reference`, from
+    /// the base layer to the top layer. If `reference` is `None` the layout must
+    /// contain exactly one image.
+    pub fn layers(&self, reference: Option<&str>) -> Result<Vec<PathBuf>> {
+        let index = self.read_json(&self.path.join("index.json"))?;
+        let mut descriptor = Self::select_manifest(&index, reference)?.clone();
+
+        // A multi-platform image points to another index listing one manifest per platform
+        if Self::is_index(&descriptor) {
+            let platforms = self.read_blob_json(Self::digest(&descriptor)?)?;
+            descriptor = Self::select_platform(&platforms)?.clone();
+        }
+
+        let manifest = self.read_blob_json(Self::digest(&descriptor)?)?;
+        Self::array(&manifest, "layers")
+            .iter()
+            .map(|layer| self.verified_blob(Self::digest(layer)?))
+            .collect()
+    }
+
+    // The members of the array `key` of `json`, or an empty slice if there is no such array
+    fn array<'a>(json: &'a Value, key: &str) -> &'a [Value] {
+        json.get(key)
+            .and_then(Value::as_array)
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+
+    fn select_manifest<'a>(index: &'a Value, reference: Option<&str>) -> Result<&'a Value> {
+        let manifests = Self::array(index, "manifests");
+        fn ref_name(m: &Value) -> Option<&str> {
+            m.get("annotations")
+                .and_then(|a| a.get(REF_NAME_ANNOTATION))
+                .and_then(Value::as_str)
+        }
+
+        match reference {
+            Some(reference) => manifests.iter()
+                .find(|m| ref_name(m) == Some(reference))
+                .ok_or_else(|| format_err!("OCI image layout has no image named '{}'", reference)),
+            None if manifests.len() == 1 => Ok(&manifests[0]),
+            None if manifests.is_empty() => bail!("OCI image layout does not contain any images"),
+            None => {
+                let names = manifests.iter().filter_map(ref_name).collect::<Vec<_>>();
+                bail!("OCI image layout contains more than one image, choose one of: {}", names.join(", "));
+            }
+        }
+    }
+
+    fn select_platform(index: &Value) -> Result<&Value> {
+        Self::array(index, "manifests")
+            .iter()
+            .find(|m| {
+                let platform = |key| m.get("platform").and_then(|p| p.get(key)).and_then(Value::as_str);
+                platform("os") == Some("linux") && platform("architecture") == Some("amd64")
+            })
+            .ok_or_else(|| format_err!("OCI image does not have a linux/amd64 manifest"))
+    }
+
+    fn is_index(descriptor: &Value) -> bool {
+        match descriptor.get("mediaType").and_then(Value::as_str) {
+            Some(media_type) => media_type == INDEX_MEDIA_TYPE || media_type == DOCKER_LIST_MEDIA_TYPE,
+            None => false,
+        }
+    }
+
+    fn digest(descriptor: &Value) -> Result<&str> {
+        descriptor.get("digest")
+            .and_then(Value::as_str)
+            .ok_or_else(|| format_err!("OCI descriptor has no digest"))
+    }
+
+    fn read_blob_json(&self, digest: &str) -> Result<Value> {
+        let path = self.verified_blob(digest)?;
+        self.read_json(&path)
+    }
+
+    fn read_json(&self, path: &Path) -> Result<Value> {
+        let s = util::read_to_string(path)?;
+        serde_json::from_str(&s).map_err(context!("failed to parse {:?}", path))
+    }
+
+    // Return the path of the blob with `digest` after checking that the contents match
+    fn verified_blob(&self, digest: &str) -> Result<PathBuf> {
+        let hash = match digest.strip_prefix("sha256:") {
+            Some(hash) if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) => hash,
+            _ => bail!("unsupported OCI blob digest '{}'", digest),
+        };
+        let path = self.path.join("blobs/sha256").join(hash);
+        if !path.is_file() {
+            bail!("OCI blob {} is missing", digest);
+        }
+        if util::sha256(&path)? != hash {
+            bail!("OCI blob {} does not match its digest", digest);
+        }
+        Ok(path)
+    }
+}
+
+#[test]
+fn test_parse_oci_index() {
+    let index = r#"{
+        "schemaVersion": 2,
+        "manifests": [
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": "sha256:0123",
+                "size": 1024,
+                "annotations": { "org.opencontainers.image.ref.name": "bookworm" }
+            }
+        ],
+        "empty": [], "ok": true, "none": null
+    }"#;
+    let json: Value = serde_json::from_str(index).unwrap();
+    let manifest = &OciLayout::array(&json, "manifests")[0];
+    assert_eq!(OciLayout::digest(manifest).unwrap(), "sha256:0123");
+    assert_eq!(manifest["size"], 1024);
+    assert_eq!(OciLayout::select_manifest(&json, Some("bookworm")).unwrap(), manifest);
+    assert_eq!(OciLayout::select_manifest(&json, None).unwrap(), manifest);
+    assert!(OciLayout::select_manifest(&json, Some("sid")).is_err());
+    assert!(!OciLayout::is_index(manifest));
+    assert!(OciLayout::array(&json, "empty").is_empty());
+    assert!(OciLayout::array(&json, "none").is_empty());
+}
@@ -5,9 +5,12 @@ use std::os::unix::fs::MetadataExt;
 use std::path::{Path,PathBuf};
 use std::sync::{Arc, Weak, RwLock};
 
-use crate::{ImageHeader, MetaInfo, Result, KeyRing, KeyPair, util, RealmManager, PublicKey, ResizeSize};
+use crate::{ImageHeader, MetaInfo, Result, KeyRing, KeyPair, util, RealmManager, PublicKey, ResizeSize, FileLock, AuditLog};
 use crate::realmfs::resizer::Superblock;
-use crate::realmfs::update::Update;
+use crate::realmfs::update::{self, Update, UpdateScript};
+use crate::realmfs::history::{self, RealmFSBackup, SignatureStatus};
+use crate::realmfs::trust::Provenance;
+use crate::realmfs::manifest::PackageManifest;
 use super::mountpoint::Mountpoint;
 
 // Maximum length of a RealmFS name
@@ -154,7 +157,7 @@ impl RealmFS {
         Self::is_valid_realmfs_image(Self::image_path(name))
     }
 
-    fn image_path(name: &str) -> PathBuf {
+    pub(super) fn image_path(name: &str) -> PathBuf {
         Path::new(Self::BASE_PATH).join(format!("{}-realmfs.img", name))
     }
 
@@ -266,22 +269,130 @@ impl RealmFS {
         }
     }
 
+    /// Path of backup copy `n` of this image, created when the image is updated.
+    pub(super) fn backup_path(&self, n: usize) -> PathBuf {
+        Path::new(Self::BASE_PATH)
+            .join(format!("{}-realmfs.img.{}", self.name(), n))
+    }
+
+    /// Return the backup copies of previous versions of this image, most recent first.
+    pub fn history(&self) -> Vec<RealmFSBackup> {
+        (0..update::NUM_BACKUPS)
+            .map(|n| (n, self.backup_path(n)))
+            .filter(|(_, path)| path.exists())
+            .filter_map(|(n, path)| match RealmFSBackup::load(self, n, path) {
+                Ok(backup) => Some(backup),
+                Err(err) => {
+                    warn!("Ignoring backup image {} of realmfs '{}': {}", n, self.name(), err);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Replace this image with backup copy `index` from `self.history()`.
+    ///
+    /// The two image files are exchanged atomically so the current image becomes
+    /// backup `index` and rolling back to the same index again undoes the rollback.
+    pub fn rollback(&self, index: usize) -> Result<()> {
+        let backup = match self.history().into_iter().find(|b| b.index() == index) {
+            Some(backup) => backup,
+            None => bail!("RealmFS '{}' has no backup image {}", self.name(), index),
+        };
+        if backup.signature_status() != SignatureStatus::Valid {
+            bail!("Cannot roll back realmfs '{}' to backup {} because signature is {}", self.name(), index, backup.signature_status());
+        }
+
+        let _lock = FileLock::nonblocking_acquire(self.path.with_extension("lock"))?
+            .ok_or_else(|| format_err!("Unable to obtain file lock to roll back realmfs image: {}", self.name()))?;
+
+        if self.is_in_use() {
+            bail!("Cannot roll back realmfs '{}' because it is in use", self.name());
+        }
+        self.deactivate();
+        if self.is_activated() {
+            bail!("Cannot roll back realmfs '{}' because it could not be deactivated", self.name());
+        }
+
+        info!("Rolling back realmfs '{}' to backup image {}", self.name(), backup.path().display());
+        util::exchange(self.path(), backup.path())?;
+        PackageManifest::exchange(self.path(), backup.path())?;
+        self.header.reload_if_stale(self.path())?;
+        self.check_stale_header(true)?;
+        AuditLog::record("realmfs", format!("rolled back {}-realmfs.img to backup {} ({})", self.name(), index, backup.timestamp()));
+        Ok(())
+    }
+
     pub fn interactive_update(&self, scheme: Option<&str>) -> Result<()> {
         let mut update = Update::create(self)?;
         update.run_interactive_update(scheme)
     }
 
+    /// Update this image without user interaction by running `script` in an update
+    /// container. Output of the script is appended to the file `log_path`.
+    pub fn scripted_update(&self, script: &UpdateScript, log_path: &Path) -> Result<()> {
+        let mut update = Update::create(self)?;
+        update.run_scripted_update(script, log_path)
+    }
+
+    /// Default log file for output of scripted updates of this image.
+    pub fn update_log_path(&self) -> PathBuf {
+        self.path.with_extension("update.log")
+    }
+
     // Return the public key for verifying the signature on this image
     fn public_key(&self) -> Result<PublicKey> {
-        let pubkey = if self.metainfo().channel() == RealmFS::USER_KEYNAME {
-            self.sealing_keys()?.public_key()
-        } else {
-            match self.header().public_key()? {
-                Some(pubkey) => pubkey,
-                None => bail!("No public key available for channel {}", self.metainfo().channel()),
+        self.public_key_for_header(self.header())
+    }
+
+    /// Return the public key for verifying `header`, which is either the header of
+    /// this image or of a backup copy of it. Images imported from a trusted
+    /// publisher are verified with the key of the publisher rather than the local
+    /// sealing key.
+    pub(super) fn public_key_for_header(&self, header: &ImageHeader) -> Result<PublicKey> {
+        let channel = header.metainfo().channel().to_string();
+        if channel == RealmFS::USER_KEYNAME {
+            if let Some(key) = self.provenance().and_then(|p| p.public_key_for(header)) {
+                return key;
             }
-        };
-        Ok(pubkey)
+            return Ok(self.sealing_keys()?.public_key());
+        }
+        match header.public_key()? {
+            Some(pubkey) => Ok(pubkey),
+            None => bail!("No public key available for channel {}", channel),
+        }
+    }
+
+    pub(super) fn provenance_path(&self) -> PathBuf {
+        self.path_with_extension("provenance")
+    }
+
+    /// Return the record of where this image was imported from if it was imported
+    /// from a trusted publisher.
+    pub fn provenance(&self) -> Option<Provenance> {
+        let path = self.provenance_path();
+        if path.exists() {
+            Provenance::load(&path)
+        } else {
+            None
+        }
+    }
+
+    /// Return the package manifest recorded when this image was sealed, after
+    /// checking that it describes the current image and is correctly signed.
+    pub fn package_manifest(&self) -> Result<PackageManifest> {
+        let path = PackageManifest::path_for(self.path());
+        if !path.exists() {
+            bail!("RealmFS image '{}' has no package manifest", self.name());
+        }
+        let manifest = PackageManifest::load(&path)?;
+        manifest.verify(self.header(), self.public_key()?)?;
+        Ok(manifest)
+    }
+
+    /// Check the header signature of this image without failing if it is not valid.
+    pub fn signature_status(&self) -> SignatureStatus {
+        history::signature_status(self, self.header())
     }
 
     pub(super) fn verify_signature(&self) -> Result<()> {
@@ -331,6 +442,11 @@ impl RealmFS {
         forked.set_name(new_name);
         forked.header().update_metainfo(&metainfo_bytes, sig.to_bytes(), new_path)?;
         forked.check_stale_header(true)?;
+        // The fork has the same contents, so only the name in the manifest changes
+        if let Ok(manifest) = self.package_manifest() {
+            manifest.with_name(new_name, &keys)
+                .save(&PackageManifest::path_for(new_path))?;
+        }
         Ok(forked)
     }
 
@@ -338,8 +454,7 @@ impl RealmFS {
         if to.exists() {
             bail!("Cannot copy image file to {} because it already exists", to.display());
         }
-        cmd!("/usr/bin/cp", "--reflink=auto {} {}", self.path.display(), to.display())?;
-        Ok(())
+        util::clone_file(&*self.path, to)
     }
 
     fn fork_metainfo(&self, new_name: &str) -> Vec<u8> {
@@ -400,6 +515,23 @@ impl RealmFS {
         update.resize()
     }
 
+    /// Shrink this image to the smallest size which holds the filesystem plus
+    /// `headroom` of free space.
+    pub fn shrink(&self, headroom: ResizeSize) -> Result<()> {
+        info!("Shrinking to minimum size plus {} blocks", headroom.nblocks());
+        let mut update = Update::create(self)?;
+        update.shrink(headroom)
+    }
+
+    /// Zero the free space of this image and release the zeroed blocks from storage.
+    ///
+    /// The uncompacted image is kept as the most recent backup copy, so the space is
+    /// only released once the backup copies of the image are removed.
+    pub fn compact(&self) -> Result<()> {
+        let mut update = Update::create(self)?;
+        update.compact()
+    }
+
     pub fn free_size_blocks(&self) -> Result<usize> {
         let sb = Superblock::load(self.path(), 4096)?;
         Ok(sb.free_block_count() as usize)
@@ -411,6 +543,18 @@ impl RealmFS {
         Ok(meta.blocks() as usize / 8)
     }
 
+    /// Blocks of storage used by this image and its backup copies. Blocks which a
+    /// reflinked copy shares with another file are counted for each file.
+    pub fn total_allocated_size_blocks(&self) -> Result<usize> {
+        let mut total = self.allocated_size_blocks()?;
+        for path in (0..update::NUM_BACKUPS).map(|n| self.backup_path(n)).filter(|p| p.exists()) {
+            let meta = path.metadata()
+                .map_err(context!("failed to read metadata from realmfs backup file {:?}", path))?;
+            total += meta.blocks() as usize / 8;
+        }
+        Ok(total)
+    }
+
     /// Activate this RealmFS image if not yet activated.
     pub fn activate(&self) -> Result<()> {
         self.mountpoint().activate(self)
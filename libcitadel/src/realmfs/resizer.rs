@@ -1,20 +1,28 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io::{Read,Seek,SeekFrom};
 use std::path::Path;
 
 use byteorder::{ByteOrder,LittleEndian};
 
-use crate::{RealmFS,Result};
+use crate::{RealmFS,Result,util};
 
 const BLOCK_SIZE: usize  = 4096;
 const BLOCKS_PER_MEG: usize = (1024 * 1024) / BLOCK_SIZE;
 const BLOCKS_PER_GIG: usize = 1024 * BLOCKS_PER_MEG;
 
+const AUTO_RESIZE_POLICY_PATH: &str = "/storage/citadel-state/realmfs-autoresize.toml";
+
 // If less than 1gb remaining space
 const AUTO_RESIZE_MINIMUM_FREE: ResizeSize = ResizeSize(BLOCKS_PER_GIG);
 // ... add 4gb to size of image
 const AUTO_RESIZE_INCREASE_SIZE: ResizeSize = ResizeSize(4 * BLOCKS_PER_GIG);
 
+// Free space left after shrinking or importing an image. Must be larger than the
+// auto resize minimum or the image would be grown again on the next update.
+const DEFAULT_HEADROOM: ResizeSize = ResizeSize(2 * BLOCKS_PER_GIG);
+
 
 #[derive(Copy,Clone)]
 pub struct ResizeSize(usize);
@@ -33,6 +41,28 @@ impl ResizeSize {
         ResizeSize(n)
     }
 
+    /// Parse a size such as `4g` or `512m`, or a count of blocks if there is no
+    /// unit. A leading `+` is ignored.
+    pub fn parse(s: &str) -> Result<Self> {
+        let unit = s.chars().last().filter(|c| c.is_alphabetic());
+
+        let skip = if s.starts_with('+') { 1 } else { 0 };
+        let size = s.chars()
+            .skip(skip)
+            .take_while(|c| c.is_numeric())
+            .collect::<String>()
+            .parse::<usize>()
+            .map_err(|_| format_err!("Unable to parse size value '{}'",s))?;
+
+        let sz = match unit {
+            Some('g') | Some('G') => ResizeSize::gigs(size),
+            Some('m') | Some('M') => ResizeSize::megs(size),
+            Some(c) => bail!("Unknown size unit '{}'", c),
+            None => ResizeSize::blocks(size),
+        };
+        Ok(sz)
+    }
+
     pub fn nblocks(&self) -> usize {
         self.0
     }
@@ -45,28 +75,242 @@ impl ResizeSize {
         self.0 / BLOCKS_PER_MEG
     }
 
-    /// If the RealmFS needs to be resized to a larger size, returns the
-    /// recommended size.
+    /// Amount of free space to leave in an image when it is shrunk or imported
+    /// if no other size is requested.
+    pub fn default_headroom() -> Self {
+        DEFAULT_HEADROOM
+    }
+
+    /// If the RealmFS needs to be resized to a larger size according to the
+    /// auto resize policy for the image, returns the new size.
     pub fn auto_resize_size(realmfs: &RealmFS) -> Option<ResizeSize> {
-        let sb = match Superblock::load(realmfs.path(), 4096) {
-            Ok(sb) => sb,
+        let policy = match AutoResizePolicy::load(realmfs.name()) {
+            Ok(policy) => policy,
             Err(e) => {
-                warn!("Error reading superblock from {}: {}", realmfs.path().display(), e);
-                return None;
-            },
+                warn!("Error loading auto resize policy, using default policy: {}", e);
+                AutoResizePolicy::default()
+            }
         };
+        match policy.decide(realmfs) {
+            Ok(decision) => decision.grow_size(),
+            Err(e) => {
+                warn!("Error deciding whether to resize {}: {}", realmfs.path().display(), e);
+                None
+            }
+        }
+    }
+}
 
-        sb.free_block_count();
-        let free_blocks = sb.free_block_count() as usize;
-        if free_blocks < AUTO_RESIZE_MINIMUM_FREE.nblocks() {
-            let metainfo_nblocks = realmfs.metainfo().nblocks() + 1;
-            let increase_multiple = metainfo_nblocks / AUTO_RESIZE_INCREASE_SIZE.nblocks();
-            let grow_size = (increase_multiple + 1) * AUTO_RESIZE_INCREASE_SIZE.nblocks();
-            let mask = grow_size - 1;
-            let grow_blocks = (free_blocks + mask) & !mask;
-            Some(ResizeSize::blocks(grow_blocks))
+impl fmt::Display for ResizeSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 % BLOCKS_PER_GIG == 0 {
+            write!(f, "{}G", self.size_in_gb())
         } else {
-            None
+            write!(f, "{}M", self.size_in_mb())
+        }
+    }
+}
+
+// A table of the auto resize policy file. Fields which are not set are taken
+// from the [default] table, or from the built in policy.
+#[derive(Deserialize,Default,Clone)]
+struct PolicyTable {
+    enabled: Option<bool>,
+    #[serde(rename = "minimum-free")]
+    minimum_free: Option<String>,
+    #[serde(rename = "grow-by")]
+    grow_by: Option<String>,
+    #[serde(rename = "maximum-size")]
+    maximum_size: Option<String>,
+}
+
+#[derive(Deserialize,Default)]
+struct PolicyFile {
+    #[serde(default)]
+    default: PolicyTable,
+    #[serde(default)]
+    realmfs: HashMap<String, PolicyTable>,
+}
+
+///
+/// When and how much a RealmFS image is grown before it is updated.
+///
+/// The policy is read from `/storage/citadel-state/realmfs-autoresize.toml`,
+/// where a `[default]` table applies to every image and a `[realmfs.NAME]`
+/// table overrides it for a single image:
+///
+/// ```text
+/// [default]
+/// minimum-free = "1g"
+/// grow-by = "4g"
+/// maximum-size = "64g"
+///
+/// [realmfs.main]
+/// enabled = false
+/// ```
+///
+/// Without a policy file an image with less than 1G of free space is grown
+/// to the next multiple of 4G, with no maximum size.
+///
+#[derive(Copy,Clone)]
+pub struct AutoResizePolicy {
+    enabled: bool,
+    minimum_free: ResizeSize,
+    grow_by: ResizeSize,
+    maximum_size: Option<ResizeSize>,
+}
+
+impl AutoResizePolicy {
+
+    /// Load the policy for the RealmFS named `name`.
+    pub fn load(name: &str) -> Result<Self> {
+        let path = Path::new(AUTO_RESIZE_POLICY_PATH);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let s = util::read_to_string(path)?;
+        let mut file = toml::from_str::<PolicyFile>(&s)
+            .map_err(|e| format_err!("failed to parse auto resize policy {}: {}", path.display(), e))?;
+        let policy = Self::default().apply(&file.default)?;
+        match file.realmfs.remove(name) {
+            Some(ref table) => policy.apply(table),
+            None => Ok(policy),
+        }
+    }
+
+    fn apply(mut self, table: &PolicyTable) -> Result<Self> {
+        if let Some(enabled) = table.enabled {
+            self.enabled = enabled;
+        }
+        if let Some(ref size) = table.minimum_free {
+            self.minimum_free = ResizeSize::parse(size)?;
+        }
+        if let Some(ref size) = table.grow_by {
+            let grow_by = ResizeSize::parse(size)?;
+            if grow_by.nblocks() == 0 {
+                bail!("auto resize grow-by must be larger than zero");
+            }
+            self.grow_by = grow_by;
+        }
+        if let Some(ref size) = table.maximum_size {
+            self.maximum_size = Some(ResizeSize::parse(size)?);
+        }
+        Ok(self)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Images with less free space than this are grown.
+    pub fn minimum_free(&self) -> ResizeSize {
+        self.minimum_free
+    }
+
+    /// Images are grown to the next multiple of this size.
+    pub fn grow_by(&self) -> ResizeSize {
+        self.grow_by
+    }
+
+    /// Images are never grown beyond this size.
+    pub fn maximum_size(&self) -> Option<ResizeSize> {
+        self.maximum_size
+    }
+
+    /// Decide whether `realmfs` should be grown, and to what size.
+    pub fn decide(&self, realmfs: &RealmFS) -> Result<ResizeDecision> {
+        if !self.enabled {
+            return Ok(ResizeDecision::Disabled);
+        }
+        let free = ResizeSize::blocks(realmfs.free_size_blocks()?);
+        if free.nblocks() >= self.minimum_free.nblocks() {
+            return Ok(ResizeDecision::NotNeeded { free, minimum: self.minimum_free });
+        }
+
+        let current = realmfs.metainfo().nblocks();
+        let step = self.grow_by.nblocks();
+        let mut size = (current / step + 1) * step;
+        if let Some(maximum) = self.maximum_size {
+            size = size.min(maximum.nblocks());
+            if size <= current {
+                return Ok(ResizeDecision::AtMaximum { free, maximum });
+            }
+        }
+
+        // The image file is sparse, but growing it allows the filesystem to use
+        // this much more of /storage.
+        let needed = ((size - current) * BLOCK_SIZE) as u64;
+        let available = util::free_space(RealmFS::BASE_PATH)?;
+        let size = ResizeSize::blocks(size);
+        if available < needed {
+            return Ok(ResizeDecision::InsufficientStorage { size, needed, available });
+        }
+        Ok(ResizeDecision::Grow { free, from: ResizeSize::blocks(current), to: size })
+    }
+}
+
+impl Default for AutoResizePolicy {
+    fn default() -> Self {
+        AutoResizePolicy {
+            enabled: true,
+            minimum_free: AUTO_RESIZE_MINIMUM_FREE,
+            grow_by: AUTO_RESIZE_INCREASE_SIZE,
+            maximum_size: None,
+        }
+    }
+}
+
+impl fmt::Display for AutoResizePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.enabled {
+            return write!(f, "disabled");
+        }
+        write!(f, "grow by {} when less than {} free", self.grow_by, self.minimum_free)?;
+        if let Some(maximum) = self.maximum_size {
+            write!(f, ", up to {}", maximum)?;
+        }
+        Ok(())
+    }
+}
+
+/// Result of applying an `AutoResizePolicy` to a RealmFS image.
+pub enum ResizeDecision {
+    /// Automatic resizing is disabled for the image
+    Disabled,
+    /// The image has at least the minimum free space
+    NotNeeded { free: ResizeSize, minimum: ResizeSize },
+    /// The image needs more space but is already at the maximum size
+    AtMaximum { free: ResizeSize, maximum: ResizeSize },
+    /// Growing the image to `size` needs more space than /storage has available
+    InsufficientStorage { size: ResizeSize, needed: u64, available: u64 },
+    /// The image should be grown to `to`
+    Grow { free: ResizeSize, from: ResizeSize, to: ResizeSize },
+}
+
+impl ResizeDecision {
+    /// The size to grow the image to, if it should be grown.
+    pub fn grow_size(&self) -> Option<ResizeSize> {
+        match *self {
+            ResizeDecision::Grow { to, .. } => Some(to),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ResizeDecision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let megs = |bytes: u64| bytes / (1024 * 1024);
+        match self {
+            ResizeDecision::Disabled =>
+                write!(f, "automatic resizing is disabled"),
+            ResizeDecision::NotNeeded { free, minimum } =>
+                write!(f, "{} free is at least the minimum of {}, not resizing", free, minimum),
+            ResizeDecision::AtMaximum { free, maximum } =>
+                write!(f, "only {} free but image is already at maximum size of {}, not resizing", free, maximum),
+            ResizeDecision::InsufficientStorage { size, needed, available } =>
+                write!(f, "growing to {} needs {}M of storage but only {}M is available, not resizing", size, megs(*needed), megs(*available)),
+            ResizeDecision::Grow { free, from, to } =>
+                write!(f, "only {} free, growing from {} to {}", free, from, to),
         }
     }
 }
@@ -109,3 +353,27 @@ impl Superblock {
         &self.0[offset..]
     }
 }
+
+#[test]
+fn test_policy_override() {
+    let file: PolicyFile = toml::from_str(r#"
+[default]
+minimum-free = "512m"
+maximum-size = "32g"
+
+[realmfs.main]
+grow-by = "2g"
+"#).unwrap();
+    let default = AutoResizePolicy::default().apply(&file.default).unwrap();
+    let main = default.apply(&file.realmfs["main"]).unwrap();
+    assert_eq!(main.minimum_free().size_in_mb(), 512);
+    assert_eq!(main.grow_by().size_in_gb(), 2);
+    assert_eq!(main.maximum_size().map(|s| s.size_in_gb()), Some(32));
+    assert_eq!(default.grow_by().size_in_gb(), 4);
+
+    let large = PolicyTable { grow_by: Some("16g".to_string()), ..PolicyTable::default() };
+    assert_eq!(default.apply(&large).unwrap().grow_by().size_in_gb(), 16);
+
+    let zero = PolicyTable { grow_by: Some("0g".to_string()), ..PolicyTable::default() };
+    assert!(default.apply(&zero).is_err());
+}
@@ -0,0 +1,258 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{AuditLog, ImageHeader, PublicKey, RealmFS, Result, util};
+use crate::audit::format_timestamp;
+use crate::realmfs::manifest::PackageManifest;
+use crate::util::is_euid_root;
+
+const TRUST_STORE_PATH: &str = "/storage/citadel-state/realmfs-publishers.toml";
+
+fn now() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format_timestamp(secs)
+}
+
+/// A third party whose RealmFS images are trusted.
+#[derive(Serialize,Deserialize,Clone)]
+pub struct Publisher {
+    #[serde(rename = "public-key")]
+    public_key: String,
+    added: String,
+}
+
+impl Publisher {
+    pub fn public_key_hex(&self) -> &str {
+        &self.public_key
+    }
+
+    pub fn public_key(&self) -> Result<PublicKey> {
+        PublicKey::from_hex(&self.public_key)
+    }
+
+    /// Time the publisher was added to the trust store.
+    pub fn added(&self) -> &str {
+        &self.added
+    }
+}
+
+///
+/// Named public keys of publishers whose signed RealmFS images may be imported and
+/// used without being resealed with the local RealmFS key.
+///
+/// The trust store is a TOML file with one table for each publisher:
+///
+/// ```text
+/// [publishers.alice]
+/// public-key = "3f0c...."
+/// added = "2026-10-18 14:03:12"
+/// ```
+///
+/// Removing a publisher revokes trust in every image imported from them, since
+/// the signatures of those images can no longer be verified.
+///
+#[derive(Serialize,Deserialize,Default)]
+pub struct PublisherTrustStore {
+    #[serde(default)]
+    publishers: BTreeMap<String, Publisher>,
+}
+
+impl PublisherTrustStore {
+
+    pub fn load() -> Result<Self> {
+        let path = Path::new(TRUST_STORE_PATH);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let s = util::read_to_string(path)?;
+        toml::from_str(&s)
+            .map_err(|e| format_err!("failed to parse trust store {}: {}", path.display(), e))
+    }
+
+    fn save(&self) -> Result<()> {
+        let s = toml::to_string(self)
+            .map_err(|e| format_err!("failed to serialize trust store: {}", e))?;
+        util::write_file(TRUST_STORE_PATH, s)
+    }
+
+    pub fn publishers(&self) -> impl Iterator<Item=(&str, &Publisher)> {
+        self.publishers.iter().map(|(name, p)| (name.as_str(), p))
+    }
+
+    pub fn publisher(&self, name: &str) -> Option<&Publisher> {
+        self.publishers.get(name)
+    }
+
+    pub fn add(&mut self, name: &str, public_key: &str) -> Result<()> {
+        if !is_euid_root() {
+            bail!("RealmFS publishers must be added as root");
+        }
+        if !util::is_valid_name(name, 40) {
+            bail!("Invalid publisher name '{}'", name);
+        }
+        if self.publishers.contains_key(name) {
+            bail!("A publisher named '{}' already exists", name);
+        }
+        // Parse to check the key before writing it to the trust store
+        let public_key = PublicKey::from_hex(public_key)?.to_hex();
+        self.publishers.insert(name.to_string(), Publisher { public_key: public_key.clone(), added: now() });
+        self.save()?;
+        AuditLog::record("keys", format!("added trusted RealmFS publisher {} with key {}", name, public_key));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        if !is_euid_root() {
+            bail!("RealmFS publishers must be removed as root");
+        }
+        if self.publishers.remove(name).is_none() {
+            bail!("No publisher named '{}' in trust store", name);
+        }
+        self.save()?;
+        AuditLog::record("keys", format!("removed trusted RealmFS publisher {}", name));
+        Ok(())
+    }
+
+    /// Find a publisher with a key that verifies the signature of `header`.
+    pub fn find_signer(&self, header: &ImageHeader) -> Option<&str> {
+        self.publishers.iter()
+            .find(|(_, p)| p.public_key().map(|key| header.verify_signature(key)).unwrap_or(false))
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+///
+/// Record of where an imported RealmFS image came from, stored next to the image
+/// in a file with a `.provenance` extension.
+///
+/// The record applies only to the version of the image with the verity root
+/// it names. When the image is updated locally it is resealed with the local
+/// RealmFS key and the record no longer applies, but it is kept so that
+/// backup copies of the imported version can still be verified.
+///
+#[derive(Serialize,Deserialize,Clone)]
+pub struct Provenance {
+    publisher: String,
+    #[serde(rename = "verity-root")]
+    verity_root: String,
+    source: String,
+    imported: String,
+}
+
+impl Provenance {
+    pub(super) fn load(path: &Path) -> Option<Self> {
+        let s = util::read_to_string(path).ok()?;
+        match toml::from_str(&s) {
+            Ok(provenance) => Some(provenance),
+            Err(err) => {
+                warn!("Failed to parse provenance file {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let s = toml::to_string(self)
+            .map_err(|e| format_err!("failed to serialize provenance: {}", e))?;
+        util::write_file(path, s)
+    }
+
+    pub fn publisher(&self) -> &str {
+        &self.publisher
+    }
+
+    pub fn verity_root(&self) -> &str {
+        &self.verity_root
+    }
+
+    /// Path of the image file which was imported.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Time the image was imported.
+    pub fn imported(&self) -> &str {
+        &self.imported
+    }
+
+    /// Return the public key to verify `header` if this record applies to it.
+    pub(super) fn public_key_for(&self, header: &ImageHeader) -> Option<Result<PublicKey>> {
+        if header.metainfo().verity_root() != self.verity_root {
+            return None;
+        }
+        let key = PublisherTrustStore::load().and_then(|store| match store.publisher(&self.publisher) {
+            Some(publisher) => publisher.public_key(),
+            None => bail!("RealmFS publisher '{}' is not in the trust store", self.publisher),
+        });
+        Some(key)
+    }
+}
+
+///
+/// Copy a RealmFS image file which was sealed and signed on another machine into
+/// `RealmFS::BASE_PATH` after verifying that it is signed by a publisher in the
+/// trust store. If `publisher` is given, the image must be signed by that publisher.
+///
+/// The image keeps the name from its header and is not resealed. A package
+/// manifest next to `source` is imported with the image.
+///
+pub fn import_signed_image(source: &Path, publisher: Option<&str>) -> Result<RealmFS> {
+    if !is_euid_root() {
+        bail!("RealmFS images must be imported as root");
+    }
+    let header = ImageHeader::from_file(source)?;
+    if !header.is_magic_valid() || header.metainfo().image_type() != "realmfs" {
+        bail!("{} is not a RealmFS image", source.display());
+    }
+    if !header.has_signature() {
+        bail!("RealmFS image {} is not signed", source.display());
+    }
+    let metainfo = header.metainfo();
+    let name = match metainfo.realmfs_name() {
+        Some(name) if RealmFS::is_valid_name(name) => name,
+        _ => bail!("RealmFS image {} does not have a valid name", source.display()),
+    };
+    let path = RealmFS::image_path(name);
+    if path.exists() {
+        bail!("A RealmFS image named '{}' already exists", name);
+    }
+
+    let store = PublisherTrustStore::load()?;
+    let signer = match publisher {
+        Some(publisher) => match store.publisher(publisher) {
+            Some(p) if header.verify_signature(p.public_key()?) => publisher,
+            Some(_) => bail!("RealmFS image {} is not signed by publisher '{}'", source.display(), publisher),
+            None => bail!("No publisher named '{}' in trust store", publisher),
+        },
+        None => match store.find_signer(&header) {
+            Some(signer) => signer,
+            None => bail!("RealmFS image {} is not signed by any trusted publisher", source.display()),
+        },
+    };
+
+    info!("Importing RealmFS '{}' signed by publisher '{}'", name, signer);
+    let tmp = Path::new(RealmFS::BASE_PATH).join(format!(".import-{}-realmfs.img", name));
+    util::remove_file(&tmp)?;
+    let result = util::clone_file(source, &tmp)
+        .and_then(|_| util::rename(&tmp, &path));
+    if result.is_err() {
+        let _ = util::remove_file(&tmp);
+    }
+    result?;
+
+    let provenance = Provenance {
+        publisher: signer.to_string(),
+        verity_root: metainfo.verity_root().to_string(),
+        source: source.display().to_string(),
+        imported: now(),
+    };
+    let realmfs = RealmFS::load_from_path(&path)?;
+    provenance.save(&realmfs.provenance_path())?;
+    // A manifest published with the image is signed by the publisher as well
+    PackageManifest::copy(source, &path)?;
+    AuditLog::record("realmfs", format!("imported {} signed by publisher {} from {}", path.display(), signer, source.display()));
+    Ok(realmfs)
+}
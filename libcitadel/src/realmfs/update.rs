@@ -1,23 +1,61 @@
-use std::fs;
+use std::fmt;
+use std::fs::{self, File};
 use std::io::{self, Write};
 use std::path::{PathBuf, Path};
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use sodiumoxide::randombytes::randombytes;
 
-use crate::{Result, RealmFS, FileLock, ImageHeader, LoopDevice, ResizeSize, util, Error};
+use crate::{Result, RealmFS, FileLock, ImageHeader, KeyPair, LoopDevice, ResizeSize, util, Error, AuditLog};
 use crate::realm::BridgeAllocator;
 use crate::util::is_euid_root;
 use crate::terminal::TerminalRestorer;
 use crate::verity::Verity;
+use crate::audit::format_timestamp;
+use crate::realmfs::manifest::PackageManifest;
 
 const BLOCK_SIZE: usize  = 4096;
 
 // The maximum number of backup copies the rotate() method will create
-const NUM_BACKUPS: usize = 2;
+pub(super) const NUM_BACKUPS: usize = 2;
+
+// Location at which an update script file is mounted in the update container
+const UPDATE_SCRIPT_PATH: &str = "/run/realmfs-update-script";
 
 const E2FSCK: &str = "e2fsck";
 const RESIZE2FS: &str = "resize2fs";
+const FALLOCATE: &str = "fallocate";
+
+// Name of the file created to fill free space with zeros when compacting an image
+const ZERO_FILL_FILE: &str = ".realmfs-zero-fill";
+
+/// Commands to run without user interaction by `Update::run_scripted_update()`.
+pub enum UpdateScript {
+    /// A command line which is run with `/bin/bash -e -c`
+    Command(String),
+    /// Path of a script file on the host which is run with `/bin/bash`
+    File(PathBuf),
+}
+
+impl UpdateScript {
+    // The command line which runs this script inside the update container
+    fn container_command(&self) -> String {
+        match self {
+            UpdateScript::Command(command) => format!("/bin/bash -e -c '{}'", command.replace('\'', "'\\''")),
+            UpdateScript::File(_) => format!("/bin/bash {}", UPDATE_SCRIPT_PATH),
+        }
+    }
+}
+
+impl fmt::Display for UpdateScript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateScript::Command(command) => write!(f, "{}", command),
+            UpdateScript::File(path) => write!(f, "script {}", path.display()),
+        }
+    }
+}
 
 /// Manages the process of updating or resizing a `RealmFS` image file.
 ///
@@ -139,6 +177,98 @@ impl <'a> Update<'a> {
         Ok(())
     }
 
+    /// Shrink the filesystem and image file to the minimum size reported by
+    /// resize2fs plus `headroom`, then seal the smaller image and rotate it into
+    /// place. Does nothing if the image is already no larger than that.
+    pub fn shrink(&mut self, headroom: ResizeSize) -> Result<()> {
+        // the shrunk size is computed below, never grow at the same time
+        self.resize = None;
+        self.create_update_copy()?;
+
+        let current = self.metainfo_nblock_size() - 1;
+        let nblocks = LoopDevice::with_loop(self.target(), Some(BLOCK_SIZE), false, |loopdev| {
+            self.shrink_device(loopdev, headroom, current)
+        })?;
+        let nblocks = match nblocks {
+            Some(nblocks) => nblocks,
+            None => {
+                info!("RealmFS image {} cannot be made smaller, doing nothing", self.realmfs.name());
+                return Ok(());
+            }
+        };
+
+        self.set_target_len(nblocks + 1)?;
+        self.set_resize(nblocks);
+        self.seal()?;
+        self.rotate()?;
+        AuditLog::record("realmfs", format!("shrank {}-realmfs.img from {} to {} blocks", self.realmfs.name(), current, nblocks));
+        Ok(())
+    }
+
+    // Returns the new size of the filesystem in blocks, or None if shrinking
+    // would not reduce the size.
+    fn shrink_device(&self, loopdev: &LoopDevice, headroom: ResizeSize, current: usize) -> Result<Option<usize>> {
+        info!("Running e2fsck {:?}", loopdev);
+        cmd!(E2FSCK, "-f -p {}", loopdev.device().display())?;
+        let output = cmd_with_output!(RESIZE2FS, "-P {}", loopdev.device().display())?;
+        let nblocks = parse_minimum_size(&output)? + headroom.nblocks();
+        if nblocks >= current {
+            return Ok(None);
+        }
+        info!("Running resize2fs {:?} to {} blocks", loopdev, nblocks);
+        cmd!(RESIZE2FS, "{} {}", loopdev.device().display(), nblocks)?;
+        Ok(Some(nblocks))
+    }
+
+    /// Fill the free space of the filesystem with zeros and deallocate the zeroed
+    /// blocks of the image file so they no longer use space in storage, then seal
+    /// the compacted image and rotate it into place.
+    ///
+    /// The holes are dug in the update copy, and `rotate()` keeps the previous,
+    /// fully allocated image as backup `.img.0`. Storage is only returned once that
+    /// backup is rotated out by later updates or removed, and until then compacting
+    /// uses more storage rather than less, up to a full second copy of the image on
+    /// filesystems without reflinks.
+    pub fn compact(&mut self) -> Result<()> {
+        self.resize = None;
+        self.create_update_copy()?;
+        self.mount_update_image()?;
+        let filled = self.zero_free_space();
+        self.unmount_update_image();
+        filled?;
+
+        info!("Deallocating zeroed blocks of {}", self.target().display());
+        cmd!(FALLOCATE, "--dig-holes {}", self.target().display())?;
+        self.seal()?;
+        self.rotate()?;
+        AuditLog::record("realmfs", format!("compacted {}-realmfs.img", self.realmfs.name()));
+        Ok(())
+    }
+
+    fn zero_free_space(&self) -> Result<()> {
+        let path = self.mountpath.join(ZERO_FILL_FILE);
+        info!("Filling free space of {} with zeros", self.realmfs.name());
+        let result = Self::write_zeros_until_full(&path);
+        util::remove_file(&path)?;
+        result
+    }
+
+    fn write_zeros_until_full(path: &Path) -> Result<()> {
+        let mut file = File::create(path)
+            .map_err(context!("failed to create zero fill file {:?}", path))?;
+        let zeros = vec![0u8; 1024 * 1024];
+        loop {
+            match file.write(&zeros) {
+                Ok(0) => break,
+                Ok(_) => {},
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOSPC) => break,
+                Err(e) => return Err(Error::with_error(format!("error writing zero fill file {:?}", path), e)),
+            }
+        }
+        file.sync_all()
+            .map_err(context!("failed to sync zero fill file {:?}", path))
+    }
+
     pub fn grow_to(&mut self, size: ResizeSize) {
         let target_nblocks = size.nblocks();
         let current_nblocks = self.metainfo_nblock_size();
@@ -192,10 +322,6 @@ impl <'a> Update<'a> {
         if nblocks < self.metainfo_nblock_size() {
             bail!("Cannot shrink image")
         }
-        // This is an arbitrary restriction which is probably not needed
-        if (nblocks - self.metainfo_nblock_size()) > ResizeSize::gigs(8).nblocks() {
-            bail!("Can only increase size of RealmFS image by a maximum of 8gb at one time");
-        }
         self.set_target_len(nblocks)
     }
 
@@ -209,6 +335,9 @@ impl <'a> Update<'a> {
                 warn!("Failed to remove update image copy {:?}: {}", self.target(), err);
             }
         }
+        if let Err(err) = util::remove_file(PackageManifest::path_for(self.target())) {
+            warn!("Failed to remove package manifest of update image copy: {}", err);
+        }
 
         // If an IP address was allocated, free it
         if self.network_allocated {
@@ -226,36 +355,8 @@ impl <'a> Update<'a> {
             Some(rs) => rs.nblocks(),
             None => self.metainfo_nblock_size() - 1,
         };
-
-        let salt = hex::encode(randombytes(32));
-        let verity = Verity::new(&self.target)
-            .map_err(context!("failed to create verity context for realmfs update image {:?}", self.target()))?;
-        let output = verity.generate_image_hashtree_with_salt(&salt, nblocks)
-            .map_err(context!("failed to generate dm-verity hashtree for realmfs update image {:?}", self.target()))?;
-        // XXX passes metainfo for nblocks
-        //let output = Verity::new(&self.target).generate_image_hashtree_with_salt(&self.realmfs.metainfo(), &salt)?;
-        let root_hash = output.root_hash()
-            .ok_or_else(|| format_err!("no root hash returned from verity format operation"))?;
-        info!("root hash is {}", output.root_hash().unwrap());
-
-        /*
-        let nblocks = match self.resize {
-            Some(rs) => rs.nblocks(),
-            None => self.metainfo_nblock_size() - 1,
-        };
-
-         */
-
-        info!("Signing new image with user realmfs keys");
-        let metainfo_bytes = RealmFS::generate_metainfo(self.realmfs.name(), nblocks, salt.as_str(), root_hash);
         let keys = self.realmfs.sealing_keys().expect("No sealing keys");
-        let sig = keys.sign(&metainfo_bytes);
-        let header = ImageHeader::new();
-        header.set_flag(ImageHeader::FLAG_HASH_TREE);
-        header.update_metainfo(&metainfo_bytes, sig.to_bytes(), &self.target)
-            .map_err(context!("failed to write header to update image {:?}", self.target()))?;
-        Ok(())
-
+        seal_image(&self.target, self.realmfs.name(), nblocks, &keys)
     }
 
 
@@ -308,57 +409,186 @@ impl <'a> Update<'a> {
     }
 
     pub fn run_update_shell(&mut self, command: &str) -> Result<()> {
+        self.nspawn_command()?
+            .arg("/bin/bash")
+            .arg("-c")
+            .arg(command)
+            .status()
+            .map_err(|e| {
+                let _ = self.cleanup();
+                Error::with_error("failed to run systemd-nspawn", e)
+            })?;
+        Ok(())
+    }
+
+    /// Run `script` in the update container without a terminal, appending all output
+    /// to the file `log_path`. If the script succeeds the changes are sealed and the
+    /// updated image replaces the current image, otherwise the changes are discarded.
+    pub fn run_scripted_update(&mut self, script: &UpdateScript, log_path: &Path) -> Result<()> {
+        if !is_euid_root() {
+            bail!("RealmFS updates must be run as root");
+        }
+        if let UpdateScript::File(path) = script {
+            if !path.is_file() {
+                bail!("Update script {} does not exist", path.display());
+            }
+        }
+
+        let mut log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)
+            .map_err(context!("failed to open update log file {:?}", log_path))?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Self::log_line(&mut log, &format!("{} updating {}-realmfs.img: {}", format_timestamp(now), self.realmfs.name(), script))?;
+
+        let result = self.setup()
+            .and_then(|_| self.run_update_script(script, &log))
+            .and_then(|_| self.apply_update());
+
+        self.cleanup();
+
+        match result {
+            Ok(()) => Self::log_line(&mut log, "update sealed and applied"),
+            Err(err) => {
+                let _ = Self::log_line(&mut log, &format!("update failed, changes discarded: {}", err));
+                AuditLog::record("realmfs", format!("scripted update of {}-realmfs.img failed: {}", self.realmfs.name(), err));
+                Err(err)
+            }
+        }
+    }
+
+    fn log_line(log: &mut File, line: &str) -> Result<()> {
+        writeln!(log, "=== {}", line)
+            .map_err(context!("error writing to update log file"))
+    }
+
+    fn run_update_script(&mut self, script: &UpdateScript, log: &File) -> Result<()> {
+        let stdout = log.try_clone().map_err(context!("failed to clone update log file handle"))?;
+        let stderr = log.try_clone().map_err(context!("failed to clone update log file handle"))?;
 
+        let mut command = self.nspawn_command()?;
+        if let UpdateScript::File(path) = script {
+            command.arg(format!("--bind-ro={}:{}", path.display(), UPDATE_SCRIPT_PATH));
+        }
+        let status = command
+            .arg("--console=pipe")
+            .arg("--setenv=DEBIAN_FRONTEND=noninteractive")
+            .arg("/bin/bash")
+            .arg("-c")
+            .arg(format!("/usr/libexec/configure-host0.sh && exec {}", script.container_command()))
+            .stdin(Stdio::null())
+            .stdout(stdout)
+            .stderr(stderr)
+            .status()
+            .map_err(|e| Error::with_error("failed to run systemd-nspawn", e))?;
+
+        if !status.success() {
+            match status.code() {
+                Some(code) => bail!("update command exited with status {}", code),
+                None => bail!("update command was terminated by a signal"),
+            }
+        }
+        Ok(())
+    }
+
+    // Create the systemd-nspawn command which boots the update copy of the image
+    // with an address allocated on the default bridge.
+    fn nspawn_command(&mut self) -> Result<Command> {
         let mut alloc = BridgeAllocator::default_bridge()?;
         let addr = alloc.allocate_address_for(&self.name())?;
         let gw = alloc.gateway();
         self.network_allocated = true;
-        Command::new("/usr/bin/systemd-nspawn")
+        let mut command = Command::new("/usr/bin/systemd-nspawn");
+        command
             .arg(format!("--setenv=IFCONFIG_IP={}", addr))
             .arg(format!("--setenv=IFCONFIG_GW={}", gw))
             .arg("--quiet")
             .arg(format!("--machine={}", self.name()))
             .arg(format!("--directory={}", &self.mountpath.display()))
-            .arg("--network-zone=clear")
-            .arg("/bin/bash")
-            .arg("-c")
-            .arg(command)
-            .status()
-            .map_err(|e| {
-                let _ = self.cleanup();
-                Error::with_error("failed to run systemd-nspawn", e)
-            })?;
-        Ok(())
+            .arg("--network-zone=clear");
+        Ok(command)
     }
 
     fn apply_update(&mut self) -> Result<()> {
         self.unmount_update_image();
         self.seal()?;
         self.rotate()?;
+        AuditLog::record("realmfs", format!("updated and sealed {}-realmfs.img", self.realmfs.name()));
         Ok(())
     }
 
     fn rotate(&self) -> Result<()> {
-        let backup = |n: usize|
-            Path::new(RealmFS::BASE_PATH)
-                .join(format!("{}-realmfs.img.{}", self.realmfs.name(), n));
+        let backup = |n: usize| self.realmfs.backup_path(n);
 
         for i in (1..NUM_BACKUPS).rev() {
             let from = backup(i - 1);
             if from.exists() {
                 let to = backup(i);
                 util::rename(&from, &to)?;
+                PackageManifest::rename(&from, &to)?;
             }
         }
         let to = backup(0);
         util::rename(self.realmfs.path(), &to)?;
-        util::rename(self.target(), self.realmfs.path())
+        PackageManifest::rename(self.realmfs.path(), &to)?;
+        util::rename(self.target(), self.realmfs.path())?;
+        PackageManifest::rename(self.target(), self.realmfs.path())
     }
 }
 
+/// Generate the dm-verity hash tree for the first `nblocks` blocks of filesystem data
+/// in the image file at `path`, append it to the file, and write a new header signed
+/// with `keys` for a RealmFS named `name`. A package manifest of the sealed image
+/// is written next to it, also signed with `keys`.
+pub(super) fn seal_image(path: &Path, name: &str, nblocks: usize, keys: &KeyPair) -> Result<()> {
+    let salt = hex::encode(randombytes(32));
+    let verity = Verity::new(path)
+        .map_err(context!("failed to create verity context for realmfs image {:?}", path))?;
+    let output = verity.generate_image_hashtree_with_salt(&salt, nblocks)
+        .map_err(context!("failed to generate dm-verity hashtree for realmfs image {:?}", path))?;
+    let root_hash = output.root_hash()
+        .ok_or_else(|| format_err!("no root hash returned from verity format operation"))?;
+    info!("root hash is {}", root_hash);
+
+    info!("Signing new image with user realmfs keys");
+    let metainfo_bytes = RealmFS::generate_metainfo(name, nblocks, salt.as_str(), root_hash);
+    let sig = keys.sign(&metainfo_bytes);
+    let header = ImageHeader::new();
+    header.set_flag(ImageHeader::FLAG_HASH_TREE);
+    header.update_metainfo(&metainfo_bytes, sig.to_bytes(), path)
+        .map_err(context!("failed to write header to realmfs image {:?}", path))?;
+
+    // A missing manifest only means the image cannot be audited, so it does not fail the seal
+    let manifest_path = PackageManifest::path_for(path);
+    util::remove_file(&manifest_path)?;
+    info!("Recording package manifest of new image");
+    match PackageManifest::generate(path, name, root_hash, keys) {
+        Ok(manifest) => manifest.save(&manifest_path)?,
+        Err(err) => warn!("Failed to record package manifest of realmfs image {:?}: {}", path, err),
+    }
+    Ok(())
+}
+
+/// Parse the minimum size in blocks from the output of `resize2fs -P`
+fn parse_minimum_size(output: &str) -> Result<usize> {
+    output.lines()
+        .find_map(|line| line.trim().strip_prefix("Estimated minimum size of the filesystem:"))
+        .and_then(|size| size.trim().parse().ok())
+        .ok_or_else(|| format_err!("could not find minimum filesystem size in resize2fs output"))
+}
+
 impl <'a> Drop for Update<'a> {
     fn drop(&mut self) {
         self.cleanup();
     }
 }
 
+
+#[test]
+fn test_parse_minimum_size() {
+    let output = "Estimated minimum size of the filesystem: 1234567";
+    assert_eq!(parse_minimum_size(output).unwrap(), 1234567);
+    assert!(parse_minimum_size("resize2fs 1.45.5 (07-Jan-2020)").is_err());
+}
@@ -3,7 +3,7 @@ use std::ffi::OsStr;
 use std::io::{self,Seek,SeekFrom};
 use std::path::{Path, PathBuf};
 
-use crate::{Result, CommandLine, OsRelease, ImageHeader, MetaInfo, Partition, Mounts, util, LoopDevice};
+use crate::{Result, CommandLine, OsRelease, ImageHeader, MetaInfo, Partition, Mounts, util, LoopDevice, AuditLog};
 
 use std::sync::Arc;
 use crate::UtsName;
@@ -176,6 +176,7 @@ impl ResourceImage {
         self.header.set_status(ImageHeader::STATUS_NEW);
         self.header.write_partition(partition.path())?;
 
+        AuditLog::record("update", format!("wrote rootfs image version {} to {}", self.metainfo().version(), partition.path().display()));
         Ok(())
     }
 
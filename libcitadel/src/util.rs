@@ -1,11 +1,12 @@
-use std::path::{Path,PathBuf};
+use std::path::{Component,Path,PathBuf};
 use std::process::{Command,Stdio};
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
 use std::os::unix::fs as unixfs;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::env;
-use std::fs::{self, File, DirEntry};
-use std::ffi::CString;
+use std::fs::{self, File, DirEntry, OpenOptions};
+use std::ffi::{CString, OsStr};
 use std::io::{self, Seek, Read, BufReader, SeekFrom};
 
 use walkdir::WalkDir;
@@ -163,6 +164,95 @@ pub fn chown(path: &Path, uid: u32, gid: u32) -> Result<()> {
     Ok(())
 }
 
+pub fn fchown(file: &File, uid: u32, gid: u32) -> Result<()> {
+    if unsafe { libc::fchown(file.as_raw_fd(), uid, gid) } == -1 {
+        let err = io::Error::last_os_error();
+        bail!("failed to fchown({},{}): {}", uid, gid, err);
+    }
+    Ok(())
+}
+
+/// Open the directory at `path` without following a symlink at the final component.
+pub fn open_directory(path: impl AsRef<Path>) -> Result<File> {
+    let path = path.as_ref();
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC)
+        .open(path)
+        .map_err(context!("failed to open directory {:?}", path))
+}
+
+/// Open `name`, which must be a single path component, in the directory `dir`.
+/// `O_NOFOLLOW` and `O_CLOEXEC` are always added to `flags`.
+pub fn openat(dir: &File, name: &OsStr, flags: libc::c_int, mode: libc::mode_t) -> io::Result<File> {
+    let cstr = CString::new(name.as_bytes())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let flags = flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), cstr.as_ptr(), flags, libc::c_uint::from(mode)) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Remove the file `name` from the directory `dir`.
+pub fn unlinkat(dir: &File, name: &OsStr) -> Result<()> {
+    let cstr = CString::new(name.as_bytes())
+        .map_err(|_| format_err!("filename {:?} contains null byte", name))?;
+    if unsafe { libc::unlinkat(dir.as_raw_fd(), cstr.as_ptr(), 0) } == -1 {
+        let err = io::Error::last_os_error();
+        bail!("failed to remove {:?}: {}", name, err);
+    }
+    Ok(())
+}
+
+fn mkdirat(dir: &File, name: &OsStr, mode: libc::mode_t) -> io::Result<()> {
+    let cstr = CString::new(name.as_bytes())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    if unsafe { libc::mkdirat(dir.as_raw_fd(), cstr.as_ptr(), mode) } == -1 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::AlreadyExists {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+///
+/// Open the directory `relative` below the directory `dir` one component at a
+/// time without following symlinks, so the directory opened is always inside
+/// `dir` even if some component is replaced with a symlink while it is being
+/// resolved. Paths containing `..` are rejected.
+///
+/// If `create` is set, missing directories are created with mode 0755 and
+/// owned by the given uid and gid.
+///
+pub fn open_directory_beneath(dir: &File, relative: &Path, create: Option<(u32,u32)>) -> Result<File> {
+    let mut current = dir.try_clone()
+        .map_err(context!("failed to duplicate directory file descriptor"))?;
+    for component in relative.components() {
+        let name = match component {
+            Component::Normal(name) => name,
+            Component::CurDir => continue,
+            _ => bail!("path {:?} is not a relative path below the directory", relative),
+        };
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY;
+        current = match (openat(&current, name, flags, 0), create) {
+            (Ok(next), _) => next,
+            (Err(ref e), Some((uid, gid))) if e.kind() == io::ErrorKind::NotFound => {
+                mkdirat(&current, name, 0o755)
+                    .map_err(context!("failed to create directory {:?} of {:?}", name, relative))?;
+                let next = openat(&current, name, flags, 0)
+                    .map_err(context!("failed to open directory {:?} of {:?}", name, relative))?;
+                fchown(&next, uid, gid)?;
+                next
+            },
+            (Err(e), _) => bail!("failed to open directory {:?} of {:?}: {}", name, relative, e),
+        };
+    }
+    Ok(current)
+}
+
 /// Rename or move file at `from` to file path `to`
 ///
 /// A wrapper around `fs::rename()` which on failure returns an error indicating the source and
@@ -175,6 +265,29 @@ pub fn rename(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
         .map_err(context!("error renaming {:?} to {:?}", from, to))
 }
 
+/// Atomically exchange the files at paths `a` and `b`, both of which must exist
+/// on the same filesystem.
+///
+pub fn exchange(a: impl AsRef<Path>, b: impl AsRef<Path>) -> Result<()> {
+    let a = a.as_ref();
+    let b = b.as_ref();
+    let cstr_a = CString::new(a.as_os_str().as_bytes())
+        .expect("path contains null byte");
+    let cstr_b = CString::new(b.as_os_str().as_bytes())
+        .expect("path contains null byte");
+    unsafe {
+        let r = libc::syscall(libc::SYS_renameat2,
+                              libc::AT_FDCWD, cstr_a.as_ptr(),
+                              libc::AT_FDCWD, cstr_b.as_ptr(),
+                              libc::RENAME_EXCHANGE);
+        if r == -1 {
+            let err = io::Error::last_os_error();
+            bail!("failed to exchange {:?} and {:?}: {}", a, b, err);
+        }
+    }
+    Ok(())
+}
+
 /// Create a symlink at path `dst` which points to `src`
 ///
 /// A wrapper around `fs::symlink()` which on failure returns an error indicating the source and
@@ -202,6 +315,22 @@ where
     Ok(())
 }
 
+/// Return the number of bytes available to unprivileged users on the filesystem containing `path`.
+///
+/// Space reserved for root is not counted, so this is the amount of data which can
+/// actually be written by a realm or by an unprivileged tool.
+pub fn free_space(path: impl AsRef<Path>) -> Result<u64> {
+    let path = path.as_ref();
+    let cstr = CString::new(path.as_os_str().as_bytes())
+        .expect("path contains null byte");
+    let mut buf: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(cstr.as_ptr(), &mut buf) } == -1 {
+        let err = io::Error::last_os_error();
+        bail!("failed to read free space of filesystem containing {:?}: {}", path, err);
+    }
+    Ok(buf.f_bavail as u64 * buf.f_frsize as u64)
+}
+
 /// Remove file at `path` if it exists.
 ///
 /// A wrapper around `fs::remove_file()` which on failure returns an error indicating the path of
@@ -265,6 +394,79 @@ pub fn copy_file(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
     Ok(())
 }
 
+// _IOW(0x94, 9, int) from linux/fs.h
+const FICLONE: libc::c_ulong = 0x4004_9409;
+
+/// Copy file at path `from` to a new file at path `to`, sharing the data blocks of
+/// the source file if the filesystem supports it.
+///
+/// On filesystems with copy-on-write support such as btrfs the new file is created
+/// with the `FICLONE` ioctl, which takes the same time for any size of file. On other
+/// filesystems only the regions of the source file which contain data are copied, so
+/// holes in sparse files such as RealmFS images are preserved.
+///
+pub fn clone_file(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+    let from = from.as_ref();
+    let to = to.as_ref();
+    let source = File::open(from)
+        .map_err(context!("failed to open file {:?}", from))?;
+    let meta = source.metadata()
+        .map_err(context!("failed to read metadata from {:?}", from))?;
+    let target = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(meta.mode())
+        .open(to)
+        .map_err(context!("failed to create file {:?}", to))?;
+
+    let cloned = unsafe { libc::ioctl(target.as_raw_fd(), FICLONE, source.as_raw_fd()) } == 0;
+    let result = if cloned {
+        Ok(())
+    } else {
+        copy_sparse(&source, &target, meta.len())
+            .map_err(context!("failed to copy file {:?} to {:?}", from, to))
+    };
+    if result.is_err() {
+        let _ = fs::remove_file(to);
+    }
+    result
+}
+
+fn copy_sparse(source: &File, target: &File, len: u64) -> io::Result<()> {
+    target.set_len(len)?;
+    let seek = |offset: u64, whence| {
+        let r = unsafe { libc::lseek(source.as_raw_fd(), offset as libc::off_t, whence) };
+        if r == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(r as u64)
+        }
+    };
+
+    let mut buffer = vec![0u8; 1024 * 1024];
+    let mut offset = 0;
+    while offset < len {
+        let data = match seek(offset, libc::SEEK_DATA) {
+            Ok(data) => data,
+            // No more data after offset
+            Err(ref e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(e) => return Err(e),
+        };
+        let hole = seek(data, libc::SEEK_HOLE)?.min(len);
+        offset = data;
+        while offset < hole {
+            let n = buffer.len().min((hole - offset) as usize);
+            source.read_exact_at(&mut buffer[..n], offset)?;
+            // The target is already zero filled so blocks of zeros can be left as holes
+            if buffer[..n].iter().any(|&b| b != 0) {
+                target.write_all_at(&buffer[..n], offset)?;
+            }
+            offset += n as u64;
+        }
+    }
+    Ok(())
+}
+
 fn copy_path(from: &Path, to: &Path, chown_to: Option<(u32,u32)>) -> Result<()> {
     if to.exists() {
         bail!("destination path {} already exists which is not expected", to.display());
@@ -327,3 +529,38 @@ pub fn is_euid_root() -> bool {
         libc::geteuid() == 0
     }
 }
+
+#[test]
+fn test_copy_sparse() {
+    const LEN: u64 = 16 * 1024 * 1024;
+    let base = std::env::temp_dir().join(format!("citadel-sparse-test-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&base);
+    create_dir(&base).unwrap();
+    let source_path = base.join("source.img");
+
+    let source = File::create(&source_path).unwrap();
+    source.set_len(LEN).unwrap();
+    source.write_all_at(b"start of image", 0).unwrap();
+    source.write_all_at(b"middle of image", LEN / 2).unwrap();
+    source.sync_all().unwrap();
+    drop(source);
+
+    let check_copy = |path: &Path| {
+        let (from, to) = (fs::metadata(&source_path).unwrap(), fs::metadata(path).unwrap());
+        assert_eq!(fs::read(&source_path).unwrap(), fs::read(path).unwrap());
+        assert_eq!(to.len(), LEN);
+        assert!(to.blocks() <= from.blocks(), "copy of sparse file has {} blocks, source has {}", to.blocks(), from.blocks());
+    };
+
+    let copied = base.join("copied.img");
+    let target = OpenOptions::new().write(true).create_new(true).open(&copied).unwrap();
+    copy_sparse(&File::open(&source_path).unwrap(), &target, LEN).unwrap();
+    target.sync_all().unwrap();
+    check_copy(&copied);
+
+    let cloned = base.join("cloned.img");
+    clone_file(&source_path, &cloned).unwrap();
+    check_copy(&cloned);
+
+    fs::remove_dir_all(&base).unwrap();
+}
@@ -1,13 +1,21 @@
+use std::cell::RefCell;
 use std::fmt;
-use std::sync::Arc;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 use std::{result, thread};
 
 use dbus::tree::{self, Factory, MTFn, MethodResult, Tree, MethodErr};
 use dbus::blocking::LocalConnection;
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::MatchRule;
 use dbus::Message;
-use libcitadel::{Result, RealmManager, Realm, RealmEvent, OverlayType, RealmFS, terminal};
+use libcitadel::{Result, RealmManager, Realm, RealmEvent, OverlayType, RealmFS, PortForward, terminal};
 use std::time::Duration;
 
+use crate::objects::{self, ObjectData, ObjectTree};
+use crate::policy::{Caller, Policy};
+use crate::transfers::Transfers;
+
 type MethodInfo<'a> = tree::MethodInfo<'a, MTFn<TData>, TData>;
 
 // XXX
@@ -18,10 +26,12 @@ const STATUS_REALM_RUNNING: u8 = 1;
 const STATUS_REALM_CURRENT: u8 = 2;
 const STATUS_REALM_SYSTEM_REALM: u8  = 4;
 
-const OBJECT_PATH: &str = "/com/subgraph/realms";
-const INTERFACE_NAME: &str = "com.subgraph.realms.Manager";
+pub const OBJECT_PATH: &str = "/com/subgraph/realms";
+pub const INTERFACE_NAME: &str = "com.subgraph.realms.Manager";
 const BUS_NAME: &str = "com.subgraph.realms";
 
+const ACCESS_DENIED_ERROR: &str = "org.freedesktop.DBus.Error.AccessDenied";
+
 pub struct DbusServer {
     connection: Arc<LocalConnection>,
     manager: Arc<RealmManager>,
@@ -34,14 +44,15 @@ impl DbusServer {
         let connection = LocalConnection::new_system()
             .map_err(|e| format_err!("Failed to connect to DBUS system bus: {}", e))?;
         let connection = Arc::new(connection);
-        let events = EventHandler::new(connection.clone());
+        let events = EventHandler::new(connection.clone(), &manager);
         let server = DbusServer { events, connection, manager };
         Ok(server)
     }
 
-    fn build_tree(&self) -> Tree<MTFn<TData>, TData> {
-        let f = Factory::new_fn::<TData>();
-        let data = TreeData::new(self.manager.clone());
+    fn build_tree(&self, f: &Factory<MTFn<TData>, TData>) -> Tree<MTFn<TData>, TData> {
+        let sender = ConnectionSender::new(self.connection.clone());
+        let transfers = Transfers::new(sender.clone());
+        let data = TreeData::new(self.manager.clone(), transfers, sender);
         let interface = f.interface(INTERFACE_NAME, ())
             // Methods
             .add_m(f.method("SetCurrent", (), Self::do_set_current)
@@ -62,6 +73,15 @@ impl DbusServer {
             .add_m(f.method("Restart", (), Self::do_restart)
                 .in_arg(("name", "s")))
 
+            .add_m(f.method("UnlockRealmHome", (), Self::do_unlock_home)
+                .in_arg(("name", "s"))
+                .in_arg(("passphrase", "s")))
+
+            .add_m(f.method("EncryptRealmHome", (), Self::do_encrypt_home)
+                .in_arg(("name", "s"))
+                .in_arg(("passphrase", "s"))
+                .in_arg(("size_mb", "t")))
+
             .add_m(f.method("Terminal", (), Self::do_terminal)
                 .in_arg(("name", "s")))
 
@@ -69,6 +89,10 @@ impl DbusServer {
                 .in_arg(("name", "s"))
                 .in_arg(("args", "as")))
 
+            .add_m(f.method("RunDisposable", (), Self::do_run_disposable)
+                .in_arg(("base", "s"))
+                .in_arg(("args", "as")))
+
             .add_m(f.method("RealmFromCitadelPid", (), Self::do_pid_to_realm)
                 .in_arg(("pid", "u"))
                 .out_arg(("realm", "s")))
@@ -77,12 +101,37 @@ impl DbusServer {
                        .in_arg(("name", "s"))
                        .out_arg(("config", "a(ss)")))
 
+            .add_m(f.method("SetRealmConfig", (), Self::do_set_realm_config)
+                .in_arg(("name", "s"))
+                .in_arg(("key", "s"))
+                .in_arg(("value", "s")))
+
             .add_m(f.method("ListRealmFS", (), Self::do_list_realmfs)
                 .out_arg(("realmfs", "as")))
 
             .add_m(f.method("UpdateRealmFS", (), Self::do_update)
                 .in_arg(("name", "s")))
 
+            .add_m(f.method("RealmFSPackages", (), Self::do_realmfs_packages)
+                .in_arg(("name", "s"))
+                .out_arg(("packages", "a(ss)")))
+
+            .add_m(f.method("TransferFile", (), Self::do_transfer_file)
+                .in_arg(("source", "s"))
+                .in_arg(("path", "s"))
+                .in_arg(("target", "s"))
+                .out_arg(("id", "u")))
+
+            .add_m(f.method("ConfirmTransfer", (), Self::do_confirm_transfer)
+                .in_arg(("id", "u"))
+                .in_arg(("accept", "b")))
+
+            .add_m(f.method("ListPendingTransfers", (), Self::do_list_pending_transfers)
+                .out_arg(("transfers", "a(usss)")))
+
+            .add_m(f.method("ReportIntegrityFailures", (), Self::do_report_integrity_failures)
+                .in_arg(("failures", "a(sss)")))
+
             // Signals
             .add_s(f.signal("RealmStarted", ())
                 .arg(("realm", "s")))
@@ -94,10 +143,34 @@ impl DbusServer {
                 .arg(("realm","s")))
             .add_s(f.signal("RealmCurrent", ())
                 .arg(("realm", "s")))
-            .add_s(f.signal("ServiceStarted", ()));
+            .add_s(f.signal("ServiceStarted", ()))
+            .add_s(f.signal("TransferRequested", ())
+                .arg(("id", "u"))
+                .arg(("source", "s"))
+                .arg(("path", "s"))
+                .arg(("target", "s")))
+            .add_s(f.signal("TransferCompleted", ())
+                .arg(("id", "u"))
+                .arg(("path", "s"))
+                .arg(("sha256", "s")))
+            .add_s(f.signal("TransferFailed", ())
+                .arg(("id", "u"))
+                .arg(("reason", "s")))
+            .add_s(f.signal("IntegrityCheckFailed", ())
+                .arg(("kind", "s"))
+                .arg(("path", "s"))
+                .arg(("reason", "s")))
+            .add_s(f.signal("RealmHomeUnlocked", ())
+                .arg(("realm", "s")))
+            .add_s(f.signal("RealmHomeEncrypted", ())
+                .arg(("realm", "s")))
+            .add_s(f.signal("RealmHomeFailed", ())
+                .arg(("realm", "s"))
+                .arg(("reason", "s")));
 
-        let obpath = f.object_path(OBJECT_PATH, ())
+        let obpath = f.object_path(OBJECT_PATH, ObjectData::Manager)
             .introspectable()
+            .object_manager()
             .add(interface);
 
         f.tree(data).add(obpath)
@@ -167,6 +240,38 @@ impl DbusServer {
         Ok(vec![m.msg.method_return()])
     }
 
+    // Unlocking and encrypting a home directory run cryptsetup and may copy the whole
+    // home directory, so the result is reported with a RealmHomeUnlocked, RealmHomeEncrypted
+    // or RealmHomeFailed signal rather than in the method return.
+    fn do_unlock_home(m: &MethodInfo) -> MethodResult {
+        let (name, passphrase) = m.msg.read2::<&str, String>()?;
+        let data = m.tree.get_data().clone();
+        let realm = data.realm_by_name(name)?;
+        thread::spawn(move || {
+            match data.manager().unlock_home(&realm, &passphrase) {
+                Ok(()) => data.send_signal(Self::create_signal("RealmHomeUnlocked").append1(realm.name())),
+                Err(e) => data.send_home_failed(&realm, &e.to_string()),
+            }
+        });
+        Ok(vec![m.msg.method_return()])
+    }
+
+    fn do_encrypt_home(m: &MethodInfo) -> MethodResult {
+        let (name, passphrase, size_mb) = m.msg.read3::<&str, String, u64>()?;
+        let data = m.tree.get_data().clone();
+        let realm = data.realm_by_name(name)?;
+        thread::spawn(move || {
+            match data.manager().encrypt_home(&realm, &passphrase, size_mb) {
+                Ok(()) => {
+                    data.send_signal(objects::realm_properties_changed(&realm, &["Config"]));
+                    data.send_signal(Self::create_signal("RealmHomeEncrypted").append1(realm.name()));
+                },
+                Err(e) => data.send_home_failed(&realm, &e.to_string()),
+            }
+        });
+        Ok(vec![m.msg.method_return()])
+    }
+
     fn do_terminal(m: &MethodInfo) -> MethodResult {
         let name = m.msg.read1()?;
         let data = m.tree.get_data().clone();
@@ -196,6 +301,18 @@ impl DbusServer {
         Ok(vec![m.msg.method_return()])
     }
 
+    // Installed packages and versions from the package manifest of a RealmFS image
+    fn do_realmfs_packages(m: &MethodInfo) -> MethodResult {
+        let name = m.msg.read1()?;
+        let realmfs = m.tree.get_data().realmfs_by_name(name)?;
+        let manifest = realmfs.package_manifest()
+            .map_err(|e| MethodErr::failed(&e))?;
+        let list = manifest.packages()
+            .map(|(name, version)| (name.to_string(), version.to_string()))
+            .collect::<Vec<_>>();
+        Ok(vec![m.msg.method_return().append1(list)])
+    }
+
     fn do_run(m: &MethodInfo) -> MethodResult {
         let (name,args) = m.msg.read2::<&str, Vec<String>>()?;
         let data = m.tree.get_data().clone();
@@ -214,6 +331,25 @@ impl DbusServer {
         Ok(vec![m.msg.method_return()])
     }
 
+    fn do_run_disposable(m: &MethodInfo) -> MethodResult {
+        let (base, args) = m.msg.read2::<&str, Vec<String>>()?;
+        let data = m.tree.get_data().clone();
+        let base = if base.is_empty() {
+            None
+        } else {
+            Some(data.realm_by_name(base)?)
+        };
+        if args.is_empty() {
+            return Err(MethodErr::failed(&"No command given to run in disposable realm"));
+        }
+        thread::spawn(move || {
+            if let Err(err) = data.manager().run_disposable(base.as_ref(), &args) {
+                warn!("error running {:?} in disposable realm: {}", args, err);
+            }
+        });
+        Ok(vec![m.msg.method_return()])
+    }
+
     fn do_pid_to_realm(m: &MethodInfo) -> MethodResult {
         let pid = m.msg.read1::<u32>()?;
         let manager = m.tree.get_data().manager();
@@ -232,18 +368,62 @@ impl DbusServer {
         Ok(vec![m.msg.method_return().append1(config)])
     }
 
+    fn do_set_realm_config(m: &MethodInfo) -> MethodResult {
+        let (name, key, value) = m.msg.read3::<&str, &str, &str>()?;
+        let data = m.tree.get_data().clone();
+        data.set_realm_config(name, key, value)?;
+        let realm = data.realm_by_name(name)?;
+        let changed: &[&str] = if key == "realmfs" { &["Config", "RealmFS"] } else { &["Config"] };
+        Ok(vec![m.msg.method_return(), objects::realm_properties_changed(&realm, changed)])
+    }
+
+    fn do_transfer_file(m: &MethodInfo) -> MethodResult {
+        let (source, path, target) = m.msg.read3::<&str, &str, &str>()?;
+        let data = m.tree.get_data();
+        let id = data.transfers.request(data.manager(), source, path, target)?;
+        Ok(vec![m.msg.method_return().append1(id)])
+    }
+
+    fn do_confirm_transfer(m: &MethodInfo) -> MethodResult {
+        let (id, accept) = m.msg.read2::<u32, bool>()?;
+        m.tree.get_data().transfers.confirm(id, accept)?;
+        Ok(vec![m.msg.method_return()])
+    }
+
+    fn do_list_pending_transfers(m: &MethodInfo) -> MethodResult {
+        let list = m.tree.get_data().transfers.pending();
+        Ok(vec![m.msg.method_return().append1(list)])
+    }
+
+    // Called by 'citadel-tool verify-all --notify' to pass failed image checks on to
+    // desktop clients listening for the IntegrityCheckFailed signal.
+    fn do_report_integrity_failures(m: &MethodInfo) -> MethodResult {
+        let failures: Vec<(String, String, String)> = m.msg.read1()?;
+        let mut replies = vec![m.msg.method_return()];
+        for (kind, path, reason) in failures {
+            warn!("Integrity check failed on {} image {}: {}", kind, path, reason);
+            replies.push(Self::create_signal("IntegrityCheckFailed").append3(kind, path, reason));
+        }
+        Ok(replies)
+    }
+
     fn do_list_realmfs(m: &MethodInfo) -> MethodResult {
         let list = m.tree.get_data().realmfs_list();
         Ok(vec![m.msg.method_return().append1(list)])
     }
 
     pub fn start(&self) -> Result<()> {
-        let tree = self.build_tree();
+        let factory = Factory::new_fn::<TData>();
+        let mut objects = ObjectTree::new(factory.clone());
+        let mut tree = self.build_tree(&factory);
+        objects.sync(&mut tree, &self.manager);
+        let tree = Rc::new(RefCell::new(tree));
+
         if let Err(err) = self.connection.request_name(BUS_NAME, false, true, false) {
             bail!("failed to register DBUS name {}: {}", BUS_NAME, err);
         }
 
-        tree.start_receive(self.connection.as_ref());
+        self.start_receive(tree.clone());
 
         self.manager.add_event_handler({
             let events = self.events.clone();
@@ -260,6 +440,51 @@ impl DbusServer {
             self.connection
                 .process(Duration::from_millis(1000))
                 .map_err(context!("Error handling dbus messages"))?;
+            self.sync_objects(&mut objects, &tree);
+        }
+    }
+
+    /// Same as `Tree::start_receive()` except that the tree is shared with
+    /// the main loop so that realm and RealmFS objects can be added and
+    /// removed while the service is running.
+    ///
+    /// Each incoming method call is checked against the access control
+    /// policy before it is dispatched to the tree.
+    fn start_receive(&self, tree: Rc<RefCell<Tree<MTFn<TData>, TData>>>) {
+        let manager = self.manager.clone();
+        let policy = Policy::load();
+        self.connection.start_receive(MatchRule::new_method_call(), Box::new(move |msg, conn| {
+            if let Err(err) = Self::check_access(&policy, conn, &msg, &manager) {
+                let _ = conn.send(err.to_message(&msg));
+                return true;
+            }
+            if let Some(replies) = tree.borrow().handle(&msg) {
+                for r in replies {
+                    let _ = conn.send(r);
+                }
+            }
+            true
+        }));
+    }
+
+    fn check_access(policy: &Policy, conn: &LocalConnection, msg: &Message, manager: &RealmManager) -> result::Result<(), MethodErr> {
+        let caller = Caller::identify(conn, msg, manager).map_err(|e| {
+            warn!("Could not identify caller: {}", e);
+            MethodErr::from((ACCESS_DENIED_ERROR, "Could not identify caller"))
+        })?;
+        let (method, target) = Policy::method_and_target(msg);
+        if !policy.is_allowed(&caller, &method, target.as_deref()) {
+            return Err(MethodErr::from((ACCESS_DENIED_ERROR, "Access denied by realmsd policy")));
+        }
+        Ok(())
+    }
+
+    fn sync_objects(&self, objects: &mut ObjectTree, tree: &RefCell<Tree<MTFn<TData>, TData>>) {
+        let signals = objects.sync(&mut tree.borrow_mut(), &self.manager);
+        for signal in signals {
+            if self.connection.channel().send(signal).is_err() {
+                warn!("failed to send ObjectManager signal");
+            }
         }
     }
 
@@ -286,7 +511,7 @@ impl DbusServer {
 /// internally libdbus uses a mutex to control concurrent access
 /// to the dbus_connection_send() function.
 #[derive(Clone)]
-struct ConnectionSender(Arc<LocalConnection>);
+pub struct ConnectionSender(Arc<LocalConnection>);
 
 unsafe impl Send for ConnectionSender {}
 unsafe impl Sync for ConnectionSender {}
@@ -296,7 +521,7 @@ impl ConnectionSender {
         ConnectionSender(connection)
     }
 
-    fn send(&self, msg: Message) -> Result<()> {
+    pub fn send(&self, msg: Message) -> Result<()> {
         if let Err(()) = self.0.channel().send(msg) {
             bail!("failed to send DBUS message");
         }
@@ -307,12 +532,14 @@ impl ConnectionSender {
 #[derive(Clone)]
 struct EventHandler {
     sender: ConnectionSender,
+    current: Arc<Mutex<Option<Realm>>>,
 }
 
 impl EventHandler {
-    fn new(conn: Arc<LocalConnection>) -> EventHandler {
+    fn new(conn: Arc<LocalConnection>, manager: &RealmManager) -> EventHandler {
         EventHandler {
             sender: ConnectionSender::new(conn),
+            current: Arc::new(Mutex::new(manager.current_realm())),
         }
     }
 
@@ -328,10 +555,15 @@ impl EventHandler {
 
     fn on_started(&self, realm: &Realm) {
         self.send_realm_signal("RealmStarted", Some(realm));
+        // Configuration is reloaded when a realm starts so the config values may also have changed
+        self.send_properties_changed(objects::realm_properties_changed(realm, &["Running", "Address", "RealmFS", "Config"]));
+        self.send_realmfs_changed(realm);
     }
 
     fn on_stopped(&self, realm: &Realm) {
         self.send_realm_signal("RealmStopped", Some(realm));
+        self.send_properties_changed(objects::realm_properties_changed(realm, &["Running", "Address"]));
+        self.send_realmfs_changed(realm);
     }
 
     fn on_new(&self, realm: &Realm) {
@@ -344,6 +576,32 @@ impl EventHandler {
 
     fn on_current(&self, realm: Option<&Realm>) {
         self.send_realm_signal("RealmCurrent", realm);
+
+        let mut current = self.current.lock().unwrap();
+        if let Some(ref previous) = *current {
+            if Some(previous.name()) != realm.map(|r| r.name()) {
+                self.send_properties_changed(objects::realm_properties_changed(previous, &["Current"]));
+            }
+        }
+        if let Some(realm) = realm {
+            self.send_properties_changed(objects::realm_properties_changed(realm, &["Current"]));
+        }
+        *current = realm.cloned();
+    }
+
+    /// Starting or stopping a realm may activate, deactivate, or change the
+    /// in use status of the RealmFS image the realm is configured to use.
+    fn send_realmfs_changed(&self, realm: &Realm) {
+        let manager = realm.manager();
+        if let Some(realmfs) = manager.realmfs_by_name(realm.config().realmfs()) {
+            self.send_properties_changed(objects::realmfs_properties_changed(&realmfs, &["Activated", "InUse", "Mountpoint"]));
+        }
+    }
+
+    fn send_properties_changed(&self, msg: Message) {
+        if let Err(e) = self.sender.send(msg) {
+            warn!("Could not send PropertiesChanged signal: {}", e);
+        }
     }
 
     fn create_realm_signal(name: &str) -> Message {
@@ -369,22 +627,38 @@ impl EventHandler {
 }
 
 #[derive(Clone)]
-struct TreeData {
+pub struct TreeData {
     manager: Arc<RealmManager>,
+    transfers: Transfers,
+    sender: ConnectionSender,
 }
 
 impl TreeData {
-    fn new(manager: Arc<RealmManager>) -> TreeData {
+    fn new(manager: Arc<RealmManager>, transfers: Transfers, sender: ConnectionSender) -> TreeData {
         TreeData {
             manager,
+            transfers,
+            sender,
         }
     }
 
-    fn manager(&self) -> &RealmManager {
+    pub fn manager(&self) -> &RealmManager {
         &self.manager
     }
 
-    fn realm_by_name(&self, name: &str) -> result::Result<Realm, MethodErr> {
+    /// Send a signal from a method handler which completes on another thread.
+    fn send_signal(&self, msg: Message) {
+        if let Err(e) = self.sender.send(msg) {
+            warn!("Could not send signal: {}", e);
+        }
+    }
+
+    fn send_home_failed(&self, realm: &Realm, reason: &str) {
+        warn!("Encrypted home operation on realm-{} failed: {}", realm.name(), reason);
+        self.send_signal(DbusServer::create_signal("RealmHomeFailed").append2(realm.name(), reason));
+    }
+
+    pub fn realm_by_name(&self, name: &str) -> result::Result<Realm, MethodErr> {
         if let Some(realm) = self.manager.realm_by_name(name) {
             Ok(realm)
         } else {
@@ -392,7 +666,7 @@ impl TreeData {
         }
     }
 
-    fn realmfs_by_name(&self, name: &str) -> result::Result<RealmFS, MethodErr> {
+    pub fn realmfs_by_name(&self, name: &str) -> result::Result<RealmFS, MethodErr> {
         if let Some(realmfs) = self.manager.realmfs_by_name(name) {
             Ok(realmfs)
         } else {
@@ -407,16 +681,119 @@ impl TreeData {
 
     fn realm_config(&self, name: &str) -> result::Result<Vec<(String,String)>, MethodErr> {
         let realm = self.realm_by_name(name)?;
+        Ok(Self::config_list(&realm))
+    }
+
+    fn parse_config_flag(key: &str, value: &str) -> result::Result<Option<bool>, MethodErr> {
+        match value {
+            "true" => Ok(Some(true)),
+            "false" => Ok(Some(false)),
+            "" | "default" => Ok(None),
+            _ => Err(MethodErr::failed(&format!("Invalid value '{}' for {}, expecting true, false, or default", value, key))),
+        }
+    }
+
+    /// Change a single configuration value of realm `name` and save the realm config file.
+    /// Keys are the same names returned by `RealmConfig`.
+    fn set_realm_config(&self, name: &str, key: &str, value: &str) -> result::Result<(), MethodErr> {
+        let realm = self.realm_by_name(name)?;
+        if key == "realmfs" && !value.is_empty() {
+            self.realmfs_by_name(value)?;
+        }
+        realm.with_mut_config(|config| {
+            match key {
+                "use-gpu" => config.use_gpu = Self::parse_config_flag(key, value)?,
+                "use-wayland" => config.use_wayland = Self::parse_config_flag(key, value)?,
+                "use-x11" => config.use_x11 = Self::parse_config_flag(key, value)?,
+                "use-sound" => config.use_sound = Self::parse_config_flag(key, value)?,
+                "use-microphone" => config.use_microphone = Self::parse_config_flag(key, value)?,
+                "use-shared-dir" => config.use_shared_dir = Self::parse_config_flag(key, value)?,
+                "use-network" => config.use_network = Self::parse_config_flag(key, value)?,
+                "use-kvm" => config.use_kvm = Self::parse_config_flag(key, value)?,
+                "use-ephemeral-home" => config.use_ephemeral_home = Self::parse_config_flag(key, value)?,
+                "realmfs" => config.realmfs = Self::optional_string(value),
+                "terminal-scheme" => config.terminal_scheme = Self::optional_string(value),
+                "home-quota" => config.home_quota = Self::optional_string(value),
+                "overlay-quota" => config.overlay_quota = Self::optional_string(value),
+                "shared-folders" => config.shared_folders = Self::optional_list(value),
+                "port-forwards" => config.port_forwards = Self::parse_port_forwards(value)?,
+                "gateway-zone" => config.gateway_zone = Self::optional_string(value),
+                "dns-servers" => config.dns_servers = Self::optional_list(value),
+                "dns-search" => config.dns_search = Self::optional_list(value),
+                "hosts-entries" => config.hosts_entries = Self::optional_list(value),
+                "encrypted-home" => return Err(MethodErr::failed(&"encrypted-home cannot be changed with SetRealmConfig")),
+                "sound-backend" => match value {
+                    "pulseaudio" | "pipewire" | "pipewire-pulse" => config.sound_backend = Some(value.to_string()),
+                    _ => return Err(MethodErr::failed(&format!("Invalid sound backend '{}'", value))),
+                },
+                "overlay" => match value {
+                    "none" => config.set_overlay(OverlayType::None),
+                    "tmpfs" => config.set_overlay(OverlayType::TmpFS),
+                    "storage" => config.set_overlay(OverlayType::Storage),
+                    _ => return Err(MethodErr::failed(&format!("Invalid overlay type '{}'", value))),
+                },
+                _ => return Err(MethodErr::failed(&format!("Unknown realm config key '{}'", key))),
+            };
+            Ok(())
+        })?;
+
+        let path = realm.base_path_file("config");
+        if let Err(e) = realm.config().write_to(&path) {
+            warn!("Error writing config file {}: {}", path.display(), e);
+            return Err(MethodErr::failed(&format!("Failed to write config file for realm {}", name)));
+        }
+        Ok(())
+    }
+
+    fn optional_string(value: &str) -> Option<String> {
+        if value.is_empty() {
+            None
+        } else {
+            Some(value.to_string())
+        }
+    }
+
+    /// Port forwards are separated by spaces since the peer list of each forward is
+    /// separated by commas.
+    fn parse_port_forwards(value: &str) -> result::Result<Option<Vec<String>>, MethodErr> {
+        let forwards = value.split_whitespace()
+            .map(|s| PortForward::parse(s).map(|_| s.to_string()))
+            .collect::<Result<Vec<_>>>()
+            .map_err(|e| MethodErr::failed(&e))?;
+        if forwards.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(forwards))
+        }
+    }
+
+    /// Parse a comma separated list of values, an empty string clears the setting.
+    fn optional_list(value: &str) -> Option<Vec<String>> {
+        let list = value.split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        if list.is_empty() {
+            None
+        } else {
+            Some(list)
+        }
+    }
+
+    pub fn config_list(realm: &Realm) -> Vec<(String,String)> {
         let config = realm.config();
         let mut list = Vec::new();
         Self::append_config_flag(&mut list, config.gpu(), "use-gpu");
         Self::append_config_flag(&mut list, config.wayland(), "use-wayland");
         Self::append_config_flag(&mut list, config.x11(), "use-x11");
         Self::append_config_flag(&mut list, config.sound(), "use-sound");
+        Self::append_config_flag(&mut list, config.microphone(), "use-microphone");
         Self::append_config_flag(&mut list, config.shared_dir(), "use-shared-dir");
         Self::append_config_flag(&mut list, config.network(), "use-network");
         Self::append_config_flag(&mut list, config.kvm(), "use-kvm");
         Self::append_config_flag(&mut list, config.ephemeral_home(), "use-ephemeral-home");
+        Self::append_config_flag(&mut list, config.encrypted_home(), "encrypted-home");
         let overlay = match config.overlay() {
             OverlayType::None => "none",
             OverlayType::TmpFS => "tmpfs",
@@ -429,9 +806,18 @@ impl TreeData {
 
         list.push(("realmfs".to_string(), config.realmfs().to_string()));
         list.push(("overlay".to_string(), overlay.to_string()));
+        list.push(("sound-backend".to_string(), config.sound_backend().to_str_value().to_string()));
         list.push(("terminal-scheme".to_string(), scheme));
+        list.push(("home-quota".to_string(), config.home_quota().unwrap_or("").to_string()));
+        list.push(("overlay-quota".to_string(), config.overlay_quota().unwrap_or("").to_string()));
+        list.push(("shared-folders".to_string(), config.shared_folders().join(",")));
+        list.push(("port-forwards".to_string(), config.port_forwards().join(" ")));
+        list.push(("gateway-zone".to_string(), config.gateway_zone().unwrap_or("").to_string()));
+        list.push(("dns-servers".to_string(), config.dns_servers().join(",")));
+        list.push(("dns-search".to_string(), config.dns_search().join(",")));
+        list.push(("hosts-entries".to_string(), config.hosts_entries().join(",")));
 
-        Ok(list)
+        list
     }
 
     fn realm_element(realm: &Realm) -> (String, String, String, u8) {
@@ -484,11 +870,11 @@ impl fmt::Debug for TreeData {
 }
 
 #[derive(Copy, Clone, Default, Debug)]
-struct TData;
+pub struct TData;
 
 impl tree::DataType for TData {
     type Tree = TreeData;
-    type ObjectPath = ();
+    type ObjectPath = ObjectData;
     type Property = ();
     type Interface = ();
     type Method = ();
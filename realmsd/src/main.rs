@@ -1,8 +1,12 @@
 #[macro_use] extern crate libcitadel;
+#[macro_use] extern crate serde_derive;
 use libcitadel::{RealmManager, Result, Logger, LogLevel};
 
 mod dbus;
 mod devices;
+mod objects;
+mod policy;
+mod transfers;
 
 fn main() {
     if let Err(e) = run_dbus_server() {
@@ -13,6 +17,7 @@ fn main() {
 fn run_dbus_server() -> Result<()> {
     Logger::set_log_level(LogLevel::Verbose);
     let manager = RealmManager::load()?;
+    manager.remove_stale_disposable_realms();
     let server = dbus::DbusServer::connect(manager)?;
     server.start()?;
     Ok(())
@@ -0,0 +1,300 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use dbus::arg::{Arg, RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::{
+    ObjectManagerInterfacesAdded, ObjectManagerInterfacesRemoved, PropertiesPropertiesChanged
+};
+use dbus::message::SignalArgs;
+use dbus::tree::{Access, EmitsChangedSignal, Factory, Interface, MTFn, MethodErr, Property, Tree};
+use dbus::{Message, Path};
+
+use libcitadel::{Realm, RealmFS, RealmManager};
+
+use crate::dbus::{TData, TreeData, OBJECT_PATH};
+
+pub const REALM_INTERFACE_NAME: &str = "com.subgraph.realms.Realm";
+pub const REALMFS_INTERFACE_NAME: &str = "com.subgraph.realms.RealmFS";
+
+const REALM_PATH_PREFIX: &str = "/com/subgraph/realms/realm";
+const REALMFS_PATH_PREFIX: &str = "/com/subgraph/realms/realmfs";
+
+const PROPERTIES_INTERFACE_NAME: &str = "org.freedesktop.DBus.Properties";
+const INTROSPECTABLE_INTERFACE_NAME: &str = "org.freedesktop.DBus.Introspectable";
+
+type PropertyValue = Box<dyn RefArg>;
+type PropertyMap = HashMap<String, Variant<PropertyValue>>;
+
+const REALM_PROPERTIES: &[&str] = &[
+    "Name", "Description", "Running", "Current", "System", "RealmFS", "Address", "Config",
+];
+
+const REALMFS_PROPERTIES: &[&str] = &[
+    "Name", "Activated", "InUse", "User", "Channel", "Mountpoint",
+];
+
+/// Data attached to each object path in the tree identifying which
+/// realm or RealmFS image the object represents.
+#[derive(Debug)]
+pub enum ObjectData {
+    Manager,
+    Realm(String),
+    RealmFS(String),
+}
+
+/// Realm and RealmFS names may contain '-' characters which are not
+/// permitted in an object path element. Names can never contain '_'
+/// so substituting it for '-' is unambiguous.
+fn path_element(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+pub fn realm_path(name: &str) -> Path<'static> {
+    Path::new(format!("{}/{}", REALM_PATH_PREFIX, path_element(name)))
+        .expect("realm name produced invalid object path")
+}
+
+pub fn realmfs_path(name: &str) -> Path<'static> {
+    Path::new(format!("{}/{}", REALMFS_PATH_PREFIX, path_element(name)))
+        .expect("realmfs name produced invalid object path")
+}
+
+/// Return the realm or RealmFS name for an object path created with
+/// `realm_path()` or `realmfs_path()`.
+pub fn name_from_path(path: &str) -> Option<String> {
+    [REALM_PATH_PREFIX, REALMFS_PATH_PREFIX].iter()
+        .filter_map(|prefix| path.strip_prefix(prefix))
+        .filter_map(|rest| rest.strip_prefix('/'))
+        .next()
+        .map(|element| element.replace('_', "-"))
+}
+
+fn realm_property_value(name: &str, realm: &Realm, manager: &RealmManager) -> Option<PropertyValue> {
+    let value: PropertyValue = match name {
+        "Name" => Box::new(realm.name().to_string()),
+        "Description" => Box::new(realm.notes().unwrap_or_default()),
+        "Running" => Box::new(realm.is_active()),
+        "Current" => Box::new(realm.is_current()),
+        "System" => Box::new(realm.is_system()),
+        "RealmFS" => Box::new(realm.config().realmfs().to_string()),
+        "Address" => Box::new(manager.realm_address(realm).unwrap_or_default()),
+        "Config" => Box::new(TreeData::config_list(realm).into_iter().collect::<HashMap<_,_>>()),
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn realmfs_property_value(name: &str, realmfs: &RealmFS) -> Option<PropertyValue> {
+    let value: PropertyValue = match name {
+        "Name" => Box::new(realmfs.name().to_string()),
+        "Activated" => Box::new(realmfs.is_activated()),
+        "InUse" => Box::new(realmfs.is_in_use()),
+        "User" => Box::new(realmfs.is_user_realmfs()),
+        "Channel" => Box::new(realmfs.metainfo().channel().to_string()),
+        "Mountpoint" => if realmfs.is_activated() {
+            Box::new(realmfs.mountpoint().to_string())
+        } else {
+            Box::new(String::new())
+        },
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn realm_property_map(realm: &Realm, manager: &RealmManager, names: &[&str]) -> PropertyMap {
+    names.iter()
+        .filter_map(|&name| {
+            realm_property_value(name, realm, manager)
+                .map(|v| (name.to_string(), Variant(v)))
+        })
+        .collect()
+}
+
+fn realmfs_property_map(realmfs: &RealmFS, names: &[&str]) -> PropertyMap {
+    names.iter()
+        .filter_map(|&name| {
+            realmfs_property_value(name, realmfs)
+                .map(|v| (name.to_string(), Variant(v)))
+        })
+        .collect()
+}
+
+/// Create a `PropertiesChanged` signal for the object representing `realm`
+/// carrying the current values of the properties listed in `names`.
+pub fn realm_properties_changed(realm: &Realm, names: &[&str]) -> Message {
+    let manager = realm.manager();
+    let signal = PropertiesPropertiesChanged {
+        interface_name: REALM_INTERFACE_NAME.to_string(),
+        changed_properties: realm_property_map(realm, &manager, names),
+        invalidated_properties: Vec::new(),
+    };
+    signal.to_emit_message(&realm_path(realm.name()))
+}
+
+/// Create a `PropertiesChanged` signal for the object representing `realmfs`
+/// carrying the current values of the properties listed in `names`.
+pub fn realmfs_properties_changed(realmfs: &RealmFS, names: &[&str]) -> Message {
+    let signal = PropertiesPropertiesChanged {
+        interface_name: REALMFS_INTERFACE_NAME.to_string(),
+        changed_properties: realmfs_property_map(realmfs, names),
+        invalidated_properties: Vec::new(),
+    };
+    signal.to_emit_message(&realmfs_path(realmfs.name()))
+}
+
+/// Maintains the set of realm and RealmFS objects published in the
+/// dbus object tree below `OBJECT_PATH`.
+///
+/// Objects are discovered by clients with the ObjectManager interface
+/// on `OBJECT_PATH`. Since realms and RealmFS images can be created and
+/// removed while the service is running, `sync()` is called periodically
+/// to add and remove objects and to announce these changes with the
+/// `InterfacesAdded` and `InterfacesRemoved` signals.
+pub struct ObjectTree {
+    factory: Factory<MTFn<TData>, TData>,
+    realm_interface: Arc<Interface<MTFn<TData>, TData>>,
+    realmfs_interface: Arc<Interface<MTFn<TData>, TData>>,
+    realms: HashSet<String>,
+    realmfs: HashSet<String>,
+}
+
+impl ObjectTree {
+    pub fn new(factory: Factory<MTFn<TData>, TData>) -> Self {
+        let realm_interface = Arc::new(Self::realm_interface(&factory));
+        let realmfs_interface = Arc::new(Self::realmfs_interface(&factory));
+        ObjectTree {
+            factory, realm_interface, realmfs_interface,
+            realms: HashSet::new(),
+            realmfs: HashSet::new(),
+        }
+    }
+
+    fn realm_interface(f: &Factory<MTFn<TData>, TData>) -> Interface<MTFn<TData>, TData> {
+        f.interface(REALM_INTERFACE_NAME, ())
+            .add_p(Self::realm_property::<String>(f, "Name", EmitsChangedSignal::Const))
+            .add_p(Self::realm_property::<String>(f, "Description", EmitsChangedSignal::False))
+            .add_p(Self::realm_property::<bool>(f, "Running", EmitsChangedSignal::True))
+            .add_p(Self::realm_property::<bool>(f, "Current", EmitsChangedSignal::True))
+            .add_p(Self::realm_property::<bool>(f, "System", EmitsChangedSignal::False))
+            .add_p(Self::realm_property::<String>(f, "RealmFS", EmitsChangedSignal::True))
+            .add_p(Self::realm_property::<String>(f, "Address", EmitsChangedSignal::True))
+            .add_p(Self::realm_property::<HashMap<String,String>>(f, "Config", EmitsChangedSignal::True))
+    }
+
+    fn realmfs_interface(f: &Factory<MTFn<TData>, TData>) -> Interface<MTFn<TData>, TData> {
+        f.interface(REALMFS_INTERFACE_NAME, ())
+            .add_p(Self::realmfs_property::<String>(f, "Name", EmitsChangedSignal::Const))
+            .add_p(Self::realmfs_property::<bool>(f, "Activated", EmitsChangedSignal::True))
+            .add_p(Self::realmfs_property::<bool>(f, "InUse", EmitsChangedSignal::True))
+            .add_p(Self::realmfs_property::<bool>(f, "User", EmitsChangedSignal::Const))
+            .add_p(Self::realmfs_property::<String>(f, "Channel", EmitsChangedSignal::Const))
+            .add_p(Self::realmfs_property::<String>(f, "Mountpoint", EmitsChangedSignal::True))
+    }
+
+    fn realm_property<A: Arg>(f: &Factory<MTFn<TData>, TData>, name: &'static str, emits: EmitsChangedSignal) -> Property<MTFn<TData>, TData> {
+        f.property::<A,_>(name, ())
+            .access(Access::Read)
+            .emits_changed(emits)
+            .on_get(move |iter, info| {
+                let data = info.tree.get_data();
+                let realm = match info.path.get_data() {
+                    ObjectData::Realm(realm) => data.realm_by_name(realm)?,
+                    _ => return Err(MethodErr::no_property(&name)),
+                };
+                let value = realm_property_value(name, &realm, data.manager())
+                    .ok_or_else(|| MethodErr::no_property(&name))?;
+                value.append(iter);
+                Ok(())
+            })
+    }
+
+    fn realmfs_property<A: Arg>(f: &Factory<MTFn<TData>, TData>, name: &'static str, emits: EmitsChangedSignal) -> Property<MTFn<TData>, TData> {
+        f.property::<A,_>(name, ())
+            .access(Access::Read)
+            .emits_changed(emits)
+            .on_get(move |iter, info| {
+                let data = info.tree.get_data();
+                let realmfs = match info.path.get_data() {
+                    ObjectData::RealmFS(realmfs) => data.realmfs_by_name(realmfs)?,
+                    _ => return Err(MethodErr::no_property(&name)),
+                };
+                let value = realmfs_property_value(name, &realmfs)
+                    .ok_or_else(|| MethodErr::no_property(&name))?;
+                value.append(iter);
+                Ok(())
+            })
+    }
+
+    /// Add or remove objects from `tree` so that there is exactly one object for each
+    /// realm and RealmFS image known to `manager`.
+    ///
+    /// Returns `InterfacesAdded` and `InterfacesRemoved` signals describing the changes.
+    pub fn sync(&mut self, tree: &mut Tree<MTFn<TData>, TData>, manager: &RealmManager) -> Vec<Message> {
+        let mut signals = Vec::new();
+
+        let realms = manager.realm_list();
+        let realm_names: HashSet<String> = realms.iter().map(|r| r.name().to_string()).collect();
+
+        for name in self.realms.difference(&realm_names) {
+            let path = realm_path(name);
+            tree.remove(&path);
+            signals.push(Self::interfaces_removed(path, REALM_INTERFACE_NAME));
+        }
+
+        for realm in realms.iter().filter(|r| !self.realms.contains(r.name())) {
+            let path = realm_path(realm.name());
+            let object = self.factory.object_path(path.clone(), ObjectData::Realm(realm.name().to_string()))
+                .introspectable()
+                .add(self.realm_interface.clone());
+            tree.insert(object);
+            let props = realm_property_map(realm, manager, REALM_PROPERTIES);
+            signals.push(Self::interfaces_added(path, REALM_INTERFACE_NAME, props));
+        }
+        self.realms = realm_names;
+
+        let realmfs_list = manager.realmfs_list();
+        let realmfs_names: HashSet<String> = realmfs_list.iter().map(|r| r.name().to_string()).collect();
+
+        for name in self.realmfs.difference(&realmfs_names) {
+            let path = realmfs_path(name);
+            tree.remove(&path);
+            signals.push(Self::interfaces_removed(path, REALMFS_INTERFACE_NAME));
+        }
+
+        for realmfs in realmfs_list.iter().filter(|r| !self.realmfs.contains(r.name())) {
+            let path = realmfs_path(realmfs.name());
+            let object = self.factory.object_path(path.clone(), ObjectData::RealmFS(realmfs.name().to_string()))
+                .introspectable()
+                .add(self.realmfs_interface.clone());
+            tree.insert(object);
+            let props = realmfs_property_map(realmfs, REALMFS_PROPERTIES);
+            signals.push(Self::interfaces_added(path, REALMFS_INTERFACE_NAME, props));
+        }
+        self.realmfs = realmfs_names;
+
+        signals
+    }
+
+    fn interfaces_added(path: Path<'static>, iface: &str, props: PropertyMap) -> Message {
+        let mut interfaces = HashMap::new();
+        interfaces.insert(iface.to_string(), props);
+        interfaces.insert(PROPERTIES_INTERFACE_NAME.to_string(), HashMap::new());
+        interfaces.insert(INTROSPECTABLE_INTERFACE_NAME.to_string(), HashMap::new());
+        let signal = ObjectManagerInterfacesAdded { object: path, interfaces };
+        signal.to_emit_message(&Self::manager_path())
+    }
+
+    fn interfaces_removed(path: Path<'static>, iface: &str) -> Message {
+        let interfaces = vec![
+            iface.to_string(),
+            PROPERTIES_INTERFACE_NAME.to_string(),
+            INTROSPECTABLE_INTERFACE_NAME.to_string(),
+        ];
+        let signal = ObjectManagerInterfacesRemoved { object: path, interfaces };
+        signal.to_emit_message(&Self::manager_path())
+    }
+
+    fn manager_path() -> Path<'static> {
+        Path::new(OBJECT_PATH).unwrap()
+    }
+}
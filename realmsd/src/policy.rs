@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use dbus::blocking::LocalConnection;
+use dbus::Message;
+use libcitadel::{Result, RealmManager, util};
+
+use crate::objects;
+
+const POLICY_PATH: &str = "/storage/citadel-state/realmsd-policy.toml";
+
+const DEFAULT_RULE: &str = "default";
+const ANY: &str = "*";
+const SELF_TARGET: &str = "self";
+
+/// Methods which a realm may call when the policy file does not provide a
+/// rule for it. Apart from `TransferFile` these only query state, and since the
+/// target of `TransferFile` is the source realm a realm can only offer its own
+/// files, which the receiving realm confirms by default.
+const DEFAULT_REALM_METHODS: &[&str] = &[
+    "GetCurrent", "List", "ListRealmFS", "RealmConfig", "RealmFromCitadelPid",
+    "Introspect", "Get", "GetAll", "GetManagedObjects", "Ping", "GetMachineId",
+    "TransferFile",
+];
+
+/// Methods which only root on the host may call, whatever rule applies to
+/// the caller.
+const ROOT_ONLY_METHODS: &[&str] = &[
+    "ReportIntegrityFailures",
+];
+
+/// A set of methods a caller is permitted to invoke and the realm or
+/// RealmFS names those methods may be invoked on.
+///
+/// Either list may contain `"*"` to match anything. The target list may
+/// also contain `"self"` which matches the name of the realm the caller is
+/// running in.
+#[derive(Deserialize,Clone,Debug)]
+pub struct Rule {
+    #[serde(default)]
+    methods: Vec<String>,
+    #[serde(default)]
+    targets: Vec<String>,
+}
+
+impl Rule {
+    fn allow_all() -> Rule {
+        Rule {
+            methods: vec![ANY.to_string()],
+            targets: vec![ANY.to_string()],
+        }
+    }
+
+    fn realm_default() -> Rule {
+        Rule {
+            methods: DEFAULT_REALM_METHODS.iter().map(|s| s.to_string()).collect(),
+            targets: vec![SELF_TARGET.to_string()],
+        }
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m == ANY || m == method)
+    }
+
+    fn allows_target(&self, target: &str, caller_realm: Option<&str>) -> bool {
+        self.targets.iter().any(|t| {
+            t == ANY || t == target || (t == SELF_TARGET && caller_realm == Some(target))
+        })
+    }
+
+    fn allows(&self, method: &str, target: Option<&str>, caller_realm: Option<&str>) -> bool {
+        self.allows_method(method) && match target {
+            Some(target) => self.allows_target(target, caller_realm),
+            None => true,
+        }
+    }
+}
+
+/// Where a method call on realmsd originated.
+#[derive(Clone,Debug)]
+pub enum Origin {
+    /// A process running on the host (in the same mount namespace as realmsd)
+    Host,
+    /// A process running in the named realm
+    Realm(String),
+    /// A process in some other mount namespace which could not be matched to a realm
+    Unknown,
+}
+
+#[derive(Clone,Debug)]
+pub struct Caller {
+    uid: u32,
+    pid: u32,
+    origin: Origin,
+}
+
+impl Caller {
+
+    /// Identify the sender of `msg` by asking the bus daemon for the uid and pid
+    /// of the connection and then looking up which realm (if any) the process
+    /// belongs to.
+    pub fn identify(conn: &LocalConnection, msg: &Message, manager: &RealmManager) -> Result<Caller> {
+        let sender = msg.sender()
+            .ok_or_else(|| format_err!("message has no sender"))?
+            .to_string();
+
+        let proxy = conn.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", Duration::from_millis(5000));
+        let (uid,): (u32,) = proxy.method_call("org.freedesktop.DBus", "GetConnectionUnixUser", (sender.as_str(),))
+            .map_err(|e| format_err!("failed to look up uid of {}: {}", sender, e))?;
+        let (pid,): (u32,) = proxy.method_call("org.freedesktop.DBus", "GetConnectionUnixProcessID", (sender.as_str(),))
+            .map_err(|e| format_err!("failed to look up pid of {}: {}", sender, e))?;
+
+        let origin = if let Some(realm) = manager.realm_by_pid(pid) {
+            Origin::Realm(realm.name().to_string())
+        } else if Self::in_host_namespace(pid) {
+            Origin::Host
+        } else {
+            Origin::Unknown
+        };
+
+        Ok(Caller { uid, pid, origin })
+    }
+
+    /// A realm process can remove the file that `RealmManager::realm_by_pid()` reads
+    /// to identify it, so before treating an unidentified caller as a host process
+    /// make sure it shares a mount namespace with realmsd.
+    fn in_host_namespace(pid: u32) -> bool {
+        let caller_ns = fs::read_link(format!("/proc/{}/ns/mnt", pid));
+        let self_ns = fs::read_link("/proc/self/ns/mnt");
+        match (caller_ns, self_ns) {
+            (Ok(caller), Ok(ours)) => caller == ours,
+            _ => false,
+        }
+    }
+
+    fn realm_name(&self) -> Option<&str> {
+        match self.origin {
+            Origin::Realm(ref name) => Some(name.as_str()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Caller {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.origin {
+            Origin::Host => write!(f, "host process (pid={}, uid={})", self.pid, self.uid),
+            Origin::Realm(ref name) => write!(f, "realm-{} (pid={}, uid={})", name, self.pid, self.uid),
+            Origin::Unknown => write!(f, "unidentified process (pid={}, uid={})", self.pid, self.uid),
+        }
+    }
+}
+
+///
+/// Access control policy for methods exported by realmsd.
+///
+/// The policy is read from a TOML file with a table of rules for host users
+/// keyed by uid and a table of rules for realms keyed by realm name. In either
+/// table a rule named `default` applies to callers without a specific entry.
+///
+///     [users.1000]
+///     methods = ["*"]
+///     targets = ["*"]
+///
+///     [realms.main]
+///     methods = ["Start", "Stop", "Run", "Terminal", "List"]
+///     targets = ["self", "work"]
+///
+/// Without a policy file (or without a matching entry) host users may call
+/// any method and realms may only call read-only methods (and `TransferFile`) on themselves.
+/// Calls from root on the host are always permitted.
+///
+#[derive(Deserialize,Default,Debug)]
+pub struct Policy {
+    #[serde(default)]
+    users: HashMap<String, Rule>,
+    #[serde(default)]
+    realms: HashMap<String, Rule>,
+}
+
+impl Policy {
+
+    pub fn load() -> Policy {
+        match Self::load_from(POLICY_PATH) {
+            Ok(policy) => policy,
+            Err(e) => {
+                warn!("Failed to load policy file, using default policy: {}", e);
+                Policy::default()
+            }
+        }
+    }
+
+    fn load_from<P: AsRef<Path>>(path: P) -> Result<Policy> {
+        let path = path.as_ref();
+        if !path.exists() {
+            info!("No policy file found at {}, using default policy", path.display());
+            return Ok(Policy::default());
+        }
+        let s = util::read_to_string(path)?;
+        let policy = toml::from_str(&s)
+            .map_err(|e| format_err!("failed to parse policy file {}: {}", path.display(), e))?;
+        Ok(policy)
+    }
+
+    fn rule_for(&self, caller: &Caller) -> Rule {
+        let lookup = |table: &HashMap<String, Rule>, key: &str| {
+            table.get(key).or_else(|| table.get(DEFAULT_RULE)).cloned()
+        };
+        match caller.origin {
+            Origin::Host => lookup(&self.users, &caller.uid.to_string())
+                .unwrap_or_else(Rule::allow_all),
+            Origin::Realm(ref name) => lookup(&self.realms, name)
+                .unwrap_or_else(Rule::realm_default),
+            Origin::Unknown => self.realms.get(DEFAULT_RULE).cloned()
+                .unwrap_or_else(Rule::realm_default),
+        }
+    }
+
+    /// Return `true` if `caller` may invoke `method`, optionally on the realm
+    /// or RealmFS named by `target`. Denied calls are logged.
+    pub fn is_allowed(&self, caller: &Caller, method: &str, target: Option<&str>) -> bool {
+        if let Origin::Host = caller.origin {
+            if caller.uid == 0 {
+                return true;
+            }
+        }
+        let allowed = !ROOT_ONLY_METHODS.contains(&method) && self.rule_for(caller)
+            .allows(method, target, caller.realm_name());
+        if !allowed {
+            warn!("Denied call to {}({}) from {}", method, target.unwrap_or(""), caller);
+        }
+        allowed
+    }
+
+    /// Extract the method name and target name from an incoming method call.
+    ///
+    /// Methods on the manager object take the target realm or RealmFS name as the
+    /// first argument. Calls on realm or RealmFS objects target the object itself.
+    pub fn method_and_target(msg: &Message) -> (String, Option<String>) {
+        let method = msg.member()
+            .map(|m| m.to_string())
+            .unwrap_or_default();
+        let target = msg.path()
+            .and_then(|path| objects::name_from_path(&path))
+            .or_else(|| msg.get1::<&str>().map(|s| s.to_string()));
+        (method, target)
+    }
+}
+
+#[cfg(test)]
+fn test_caller(uid: u32, origin: Origin) -> Caller {
+    Caller { uid, pid: 1, origin }
+}
+
+#[test]
+fn test_rule_allows() {
+    let rule = Rule {
+        methods: vec!["Start".to_string(), "Stop".to_string()],
+        targets: vec!["self".to_string(), "work".to_string()],
+    };
+    assert!(rule.allows("Start", Some("work"), Some("main")));
+    assert!(rule.allows("Stop", Some("main"), Some("main")));
+    assert!(rule.allows("Start", None, Some("main")));
+    assert!(!rule.allows("Run", Some("work"), Some("main")));
+    assert!(!rule.allows("Start", Some("personal"), Some("main")));
+    // "self" only matches the realm of the caller
+    assert!(!rule.allows("Start", Some("main"), None));
+    assert!(!rule.allows("Start", Some("main"), Some("personal")));
+    assert!(Rule::allow_all().allows("Anything", Some("anywhere"), None));
+}
+
+#[test]
+fn test_policy_is_allowed() {
+    let policy: Policy = toml::from_str(r#"
+[users.1000]
+methods = ["List"]
+targets = ["*"]
+
+[realms.default]
+methods = ["List", "Start"]
+targets = ["self"]
+
+[realms.work]
+methods = ["Terminal"]
+targets = ["personal"]
+"#).unwrap();
+
+    let user = test_caller(1000, Origin::Host);
+    assert!(policy.is_allowed(&user, "List", None));
+    assert!(!policy.is_allowed(&user, "Start", Some("main")));
+
+    // root on the host bypasses the policy
+    let root = test_caller(0, Origin::Host);
+    assert!(policy.is_allowed(&root, "Start", Some("main")));
+    // but root inside a realm does not
+    let realm_root = test_caller(0, Origin::Realm("main".to_string()));
+    assert!(policy.is_allowed(&realm_root, "Start", Some("main")));
+    assert!(!policy.is_allowed(&realm_root, "Start", Some("work")));
+
+    // host users without an entry may call anything
+    let other = test_caller(1001, Origin::Host);
+    assert!(policy.is_allowed(&other, "Start", Some("main")));
+
+    let work = test_caller(1000, Origin::Realm("work".to_string()));
+    assert!(policy.is_allowed(&work, "Terminal", Some("personal")));
+    assert!(!policy.is_allowed(&work, "Terminal", Some("work")));
+    assert!(!policy.is_allowed(&work, "List", None));
+
+    let unknown = test_caller(1000, Origin::Unknown);
+    assert!(policy.is_allowed(&unknown, "List", None));
+    assert!(!policy.is_allowed(&unknown, "Start", Some("main")));
+}
+
+#[test]
+fn test_realm_default_rule() {
+    let policy = Policy::default();
+    let main = test_caller(1000, Origin::Realm("main".to_string()));
+    assert!(policy.is_allowed(&main, "RealmConfig", Some("main")));
+    assert!(!policy.is_allowed(&main, "RealmConfig", Some("work")));
+    assert!(!policy.is_allowed(&main, "Start", Some("main")));
+}
+
+#[test]
+fn test_root_only_methods() {
+    let policy = Policy::default();
+    let root = test_caller(0, Origin::Host);
+    let user = test_caller(1000, Origin::Host);
+    assert!(policy.is_allowed(&root, "ReportIntegrityFailures", None));
+    assert!(!policy.is_allowed(&user, "ReportIntegrityFailures", None));
+    assert!(policy.is_allowed(&user, "List", None));
+}
+
+#[test]
+fn test_method_and_target() {
+    let msg = Message::new_method_call("com.subgraph.realms", crate::dbus::OBJECT_PATH, crate::dbus::INTERFACE_NAME, "Start")
+        .unwrap()
+        .append1("main");
+    assert_eq!(Policy::method_and_target(&msg), ("Start".to_string(), Some("main".to_string())));
+
+    let msg = Message::new_method_call("com.subgraph.realms", crate::dbus::OBJECT_PATH, crate::dbus::INTERFACE_NAME, "List")
+        .unwrap();
+    assert_eq!(Policy::method_and_target(&msg), ("List".to_string(), None));
+
+    let msg = Message::new_method_call("com.subgraph.realms", objects::realm_path("my-realm"), "org.freedesktop.DBus.Properties", "GetAll")
+        .unwrap()
+        .append1("com.subgraph.realms.Realm");
+    assert_eq!(Policy::method_and_target(&msg), ("GetAll".to_string(), Some("my-realm".to_string())));
+}
+
+#[test]
+fn test_malformed_policy_file() {
+    let path = std::env::temp_dir().join(format!("realmsd-policy-test-{}.toml", std::process::id()));
+    fs::write(&path, "[users.1000]\nmethods = \"List\"\n").unwrap();
+    let result = Policy::load_from(&path);
+    fs::remove_file(&path).unwrap();
+    assert!(result.is_err());
+    assert!(Policy::load_from("/nonexistent/realmsd-policy.toml").unwrap().users.is_empty());
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::result;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use dbus::tree::MethodErr;
+use dbus::Message;
+use libcitadel::{FileTransfer, RealmManager};
+
+use crate::dbus::{ConnectionSender, OBJECT_PATH, INTERFACE_NAME};
+
+///
+/// Transfers of files between realms requested through realmsd.
+///
+/// If the target realm is configured with `confirm-transfers` the request is
+/// held until it is accepted or rejected with `ConfirmTransfer`, otherwise
+/// it is performed immediately. Transfers run on a separate thread and the
+/// outcome is announced with a `TransferCompleted` or `TransferFailed` signal.
+///
+#[derive(Clone)]
+pub struct Transfers {
+    sender: ConnectionSender,
+    state: Arc<Mutex<PendingState>>,
+}
+
+#[derive(Default)]
+struct PendingState {
+    next_id: u32,
+    pending: HashMap<u32, FileTransfer>,
+}
+
+impl PendingState {
+    fn add(&mut self, transfer: FileTransfer) -> u32 {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.pending.insert(id, transfer);
+        id
+    }
+}
+
+impl Transfers {
+    pub fn new(sender: ConnectionSender) -> Self {
+        Transfers {
+            sender,
+            state: Arc::new(Mutex::new(PendingState::default())),
+        }
+    }
+
+    /// Request a transfer of `path` from realm `source` to realm `target` and return
+    /// an id which identifies the request in signals and in `ConfirmTransfer`.
+    pub fn request(&self, manager: &RealmManager, source: &str, path: &str, target: &str) -> result::Result<u32, MethodErr> {
+        let source = manager.realm_by_name(source)
+            .ok_or_else(|| MethodErr::failed(&format!("Cannot find realm {}", source)))?;
+        let target = manager.realm_by_name(target)
+            .ok_or_else(|| MethodErr::failed(&format!("Cannot find realm {}", target)))?;
+
+        let transfer = FileTransfer::new(&source, &target, path)
+            .map_err(|e| MethodErr::failed(&e))?;
+
+        let confirm = target.config().confirm_transfers();
+        let (id, transfer) = {
+            let mut state = self.state.lock().unwrap();
+            if confirm {
+                let id = state.add(transfer);
+                info!("Transfer {} of {} from realm-{} to realm-{} is waiting for confirmation", id, path, source.name(), target.name());
+                (id, None)
+            } else {
+                state.next_id += 1;
+                (state.next_id, Some(transfer))
+            }
+        };
+
+        match transfer {
+            Some(transfer) => self.run(id, transfer),
+            None => {
+                let msg = Self::create_signal("TransferRequested")
+                    .append3(id, source.name(), path)
+                    .append1(target.name());
+                self.send(msg);
+            }
+        }
+        Ok(id)
+    }
+
+    /// Accept or reject the pending transfer `id`.
+    pub fn confirm(&self, id: u32, accept: bool) -> result::Result<(), MethodErr> {
+        let transfer = self.state.lock().unwrap().pending.remove(&id)
+            .ok_or_else(|| MethodErr::failed(&format!("No pending transfer with id {}", id)))?;
+
+        if accept {
+            self.run(id, transfer);
+        } else {
+            info!("Transfer {} of {} from realm-{} rejected", id, transfer.path().display(), transfer.source());
+            self.send_failed(id, "Transfer rejected");
+        }
+        Ok(())
+    }
+
+    /// Return (id, source, path, target) for each transfer waiting for confirmation.
+    pub fn pending(&self) -> Vec<(u32, String, String, String)> {
+        let state = self.state.lock().unwrap();
+        let mut list = state.pending.iter()
+            .map(|(id, t)| (*id, t.source().to_string(), t.path().display().to_string(), t.target().to_string()))
+            .collect::<Vec<_>>();
+        list.sort_by_key(|t| t.0);
+        list
+    }
+
+    fn run(&self, id: u32, transfer: FileTransfer) {
+        let transfers = self.clone();
+        thread::spawn(move || {
+            match transfer.run() {
+                Ok(record) => {
+                    let msg = Self::create_signal("TransferCompleted")
+                        .append3(id, record.target_path, record.sha256);
+                    transfers.send(msg);
+                },
+                Err(e) => {
+                    warn!("Transfer {} failed: {}", id, e);
+                    transfers.send_failed(id, &e.to_string());
+                },
+            }
+        });
+    }
+
+    fn send_failed(&self, id: u32, reason: &str) {
+        let msg = Self::create_signal("TransferFailed")
+            .append2(id, reason);
+        self.send(msg);
+    }
+
+    fn send(&self, msg: Message) {
+        if let Err(e) = self.sender.send(msg) {
+            warn!("Could not send transfer signal: {}", e);
+        }
+    }
+
+    fn create_signal(name: &str) -> Message {
+        let path = dbus::Path::new(OBJECT_PATH).unwrap();
+        let iface = dbus::strings::Interface::new(INTERFACE_NAME).unwrap();
+        let member = dbus::strings::Member::new(name).unwrap();
+        Message::signal(&path, &iface, &member)
+    }
+}